@@ -0,0 +1,114 @@
+#[macro_use]
+extern crate criterion;
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+use std::io::Cursor;
+
+use criterion::{black_box, Criterion};
+use libcindex::writer::IndexWriterBuilder;
+use libcsearch::reader::{IndexReader, PostSet};
+use libcsearch::regexp::{query_from_regexp, Query};
+use tempfile::NamedTempFile;
+
+/// A pattern whose query repeats the same trigrams in several branches
+const PATTERN: &str = "(read|write)_(buffer|stream)_(read|write)|(buffer|stream)_(read|write)";
+
+/// Writes an index of `n` small files built from a handful of words, with
+/// its posting lists compressed if `compress` is set
+fn build_index(n: usize, compress: bool) -> NamedTempFile {
+    let words = [
+        "read", "write", "buffer", "stream", "open", "close", "flush",
+    ];
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriterBuilder::new()
+        .compress(compress)
+        .build(f.path())
+        .unwrap();
+    for i in 0..n {
+        let contents = (0..8)
+            .map(|k| {
+                format!(
+                    "{}_{}",
+                    words[(i + k) % words.len()],
+                    words[(i * k) % words.len()]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let len = contents.len() as u64;
+        ix.add(
+            format!("file{}", i),
+            Cursor::new(contents.into_bytes()),
+            len,
+        )
+        .unwrap();
+    }
+    ix.flush().unwrap();
+    f
+}
+
+/// Evaluates `query` decoding every occurrence of a trigram on its own, the
+/// way queries were evaluated before posting lists were cached
+fn uncached(ix: &IndexReader, query: &Query) -> usize {
+    let mut n = 0;
+    for t in &query.trigram {
+        let t = (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32);
+        n += PostSet::new(ix).or(t).unwrap().into_inner().len();
+    }
+    n + query.sub.iter().map(|q| uncached(ix, q)).sum::<usize>()
+}
+
+fn occurrences(query: &Query) -> usize {
+    query.trigram.len() + query.sub.iter().map(occurrences).sum::<usize>()
+}
+
+fn query(c: &mut Criterion) {
+    let f = build_index(20_000, false);
+    let ix = IndexReader::open(f.path()).unwrap();
+    let q = query_from_regexp(PATTERN).unwrap();
+
+    ix.query(q.clone()).unwrap();
+    println!(
+        "{} trigram occurrences, {} posting lists decoded",
+        occurrences(&q),
+        ix.lists_decoded()
+    );
+
+    c.bench_function("query (cached)", |b| {
+        b.iter(|| ix.query(black_box(q.clone())).unwrap().into_inner().len())
+    });
+    c.bench_function("query (each trigram decoded)", |b| {
+        b.iter(|| uncached(&ix, black_box(&q)))
+    });
+}
+
+fn compressed(c: &mut Criterion) {
+    let plain = build_index(20_000, false);
+    let compressed = build_index(20_000, true);
+    let len = |f: &NamedTempFile| f.as_file().metadata().unwrap().len();
+    println!(
+        "index: {} bytes, compressed: {} bytes",
+        len(&plain),
+        len(&compressed)
+    );
+    let q = query_from_regexp(PATTERN).unwrap();
+
+    // a fresh reader each time, so the compressed blocks are read anew
+    for &(name, f) in &[
+        ("query (cold)", &plain),
+        ("query (cold, compressed)", &compressed),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let ix = IndexReader::open(f.path()).unwrap();
+                ix.query(black_box(q.clone())).unwrap().into_inner().len()
+            })
+        });
+    }
+}
+
+criterion_group!(benches, query, compressed);
+criterion_main!(benches);
@@ -10,7 +10,6 @@ extern crate libcsearch;
 extern crate libcustomlogger;
 
 use libcsearch::reader::{IndexReader, POST_ENTRY_SIZE};
-use libcsearch::regexp::Query;
 
 use std::collections::BTreeSet;
 use std::env;
@@ -54,7 +53,7 @@ fn main() {
         env::set_var("CSEARCHINDEX", p);
     }
 
-    let index_path = libcsearch::csearch_index();
+    let index_path = libcsearch::csearch_index().unwrap();
     let idx = IndexReader::open(index_path).unwrap();
 
     if matches.is_present("files") {
@@ -71,13 +70,15 @@ fn main() {
     if let Some(t) = matches.value_of("with-trigram") {
         let t_num = t.parse::<u32>().unwrap();
         let h: Option<BTreeSet<u32>> = None;
-        let file_ids = libcsearch::reader::PostReader::list(&idx, t_num, &h);
-        println!("{:?}", file_ids);
+        match libcsearch::reader::PostReader::list(&idx, t_num, &h) {
+            Ok(file_ids) => println!("{:?}", file_ids),
+            Err(e) => println!("{}", e),
+        }
     }
 }
 
 fn print_indexed_files(idx: &IndexReader) {
-    let post = idx.query(Query::all());
+    let post = idx.all_files();
     for each_fileid in post.into_inner() {
         println!("{}: {}", each_fileid, idx.name(each_fileid));
     }
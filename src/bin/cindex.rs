@@ -3,12 +3,23 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+extern crate chrono;
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
 extern crate glob;
+extern crate ignore;
+#[cfg(unix)]
+extern crate libc;
 extern crate regex;
 #[macro_use]
 extern crate log;
+extern crate notify;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate tempfile;
+extern crate toml;
 extern crate walkdir;
 
 extern crate consts;
@@ -18,24 +29,36 @@ extern crate libcustomlogger;
 extern crate libprofiling;
 extern crate libvarint;
 
-use libcindex::writer::{IndexErrorKind, IndexWriter};
-use libcsearch::reader::IndexReader;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use libcindex::writer::{
+    FileTrigrams, IndexError, IndexErrorKind, IndexResult, IndexWriterBuilder, LimitOverrides,
+    Trigrammer,
+};
+use libcsearch::reader::{IndexReader, OpenError, POST_ENTRY_SIZE};
+use libprofiling::FrameTiming;
 use log::LevelFilter;
-use walkdir::WalkDir;
+use notify::{RecursiveMode, Watcher};
+use tempfile::TempPath;
+use walkdir::{DirEntry, WalkDir};
 
-use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::env;
-use std::ffi::OsString;
-use std::fs::{self, File, FileType};
-use std::io::{self, BufRead, BufReader};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, FileType, OpenOptions, TryLockError};
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Read, Write};
 #[cfg(unix)]
 use std::os::unix::fs::FileTypeExt;
 #[cfg(windows)]
-use std::path::Component;
+use std::path::{self, Component, Prefix};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(not(unix))]
 fn is_regular_file(meta: FileType) -> bool {
@@ -52,32 +75,81 @@ fn is_regular_file(meta: FileType) -> bool {
         && !meta.is_char_device()
 }
 
+/// Makes `p` absolute, resolving `.` and `..` without touching the file
+/// system. Drive-relative paths like `C:foo` are resolved against the current
+/// directory of that drive, and UNC paths (`\\server\share\...`) keep their
+/// server and share.
 #[cfg(windows)]
 fn normalize<P: AsRef<Path>>(p: P) -> io::Result<PathBuf> {
-    let mut out = env::current_dir()?;
-    let mut it = p.as_ref().components();
-    // only drop current directory if the first part of p is a prefix (C:/) or root (/)
-    if let Some(c) = it.next() {
-        match c {
-            r @ Component::Prefix(_) | r @ Component::RootDir => {
-                out = PathBuf::new();
-                out.push(r.as_os_str());
+    let abs = path::absolute(p)?;
+    Ok(strip_verbatim(&abs).unwrap_or(abs))
+}
+
+/// Returns `p` without its `\\?\` extended-length prefix if the plain path
+/// names the same file, like `dunce::simplified`. `\\?\C:\x` becomes `C:\x`
+/// and `\\?\UNC\server\share\x` becomes `\\server\share\x`.
+#[cfg(windows)]
+fn strip_verbatim(p: &Path) -> Option<PathBuf> {
+    // longer plain paths hit MAX_PATH, which the prefix is there to avoid
+    const MAX_PATH: usize = 260;
+
+    let mut it = p.components();
+    let mut out = match it.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(drive) => PathBuf::from(format!("{}:", drive as char)),
+            Prefix::VerbatimUNC(server, share) => {
+                let mut unc = OsString::from(r"\\");
+                unc.push(server);
+                unc.push(r"\");
+                unc.push(share);
+                PathBuf::from(unc)
             }
-            r @ _ => out.push(r.as_os_str()),
-        }
-    } else {
-        return Ok(out);
+            // a plain prefix needs no change, and `\\?\Volume{...}` style
+            // prefixes have no plain form
+            _ => return None,
+        },
+        _ => return None,
     };
     for each_part in it {
         match each_part {
-            Component::CurDir => (),
-            Component::ParentDir => {
-                out.pop();
-            }
-            r @ _ => out.push(r.as_os_str()),
+            Component::RootDir => out.push(each_part.as_os_str()),
+            Component::Normal(name) if is_plain_name(name) => out.push(name),
+            // `.` and `..` are ordinary names after `\\?\`
+            _ => return None,
         }
     }
-    Ok(out)
+    if out.as_os_str().len() < MAX_PATH {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Returns whether a file name means the same with and without the `\\?\`
+/// prefix: plain paths lose trailing dots and spaces, can't hold some
+/// characters and map names like `CON` or `nul.txt` to devices
+#[cfg(windows)]
+fn is_plain_name(name: &OsStr) -> bool {
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    let name = match name.to_str() {
+        Some(n) => n,
+        None => return false,
+    };
+    if name.ends_with('.') || name.ends_with(' ') {
+        return false;
+    }
+    if name
+        .chars()
+        .any(|c| c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*'))
+    {
+        return false;
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    !RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem))
 }
 
 #[cfg(not(windows))]
@@ -91,13 +163,323 @@ fn get_value_from_matches<F: FromStr>(matches: &clap::ArgMatches, name: &str) ->
             if let Ok(t) = s.parse::<F>() {
                 Some(t)
             } else {
-                panic!("can't convert value '{}' to number", s);
+                error!("{}: can't convert value '{}' to number", name, s);
+                std::process::exit(101);
             }
         }
         _ => None,
     }
 }
 
+/// The `.gitignore` files that apply to the current position of a
+/// depth-first directory walk, innermost last
+#[derive(Default)]
+struct GitignoreStack {
+    stack: Vec<(usize, Gitignore)>,
+}
+
+impl GitignoreStack {
+    /// Returns whether `entry` should be walked. Must be called for every
+    /// entry in walk order, so the stack follows the walk.
+    fn keep(&mut self, entry: &DirEntry) -> bool {
+        // drop the rules of directories the walk has finished with
+        while self.stack.last().is_some_and(|&(d, _)| d >= entry.depth()) {
+            self.stack.pop();
+        }
+        let is_dir = entry.file_type().is_dir();
+        if is_dir && entry.file_name() == ".git" {
+            return false;
+        }
+        let decision = self
+            .stack
+            .iter()
+            .rev()
+            .map(|(_, gi)| gi.matched(entry.path(), is_dir))
+            .find(|m| !m.is_none());
+        if let Some(Match::Ignore(_)) = decision {
+            return false;
+        }
+        if is_dir {
+            let path = entry.path().join(".gitignore");
+            if path.is_file() {
+                let mut builder = GitignoreBuilder::new(entry.path());
+                if let Some(e) = builder.add(&path) {
+                    warn!("{}: {}", path.display(), e);
+                }
+                match builder.build() {
+                    Ok(gi) => self.stack.push((entry.depth(), gi)),
+                    Err(e) => warn!("{}: {}", path.display(), e),
+                }
+            }
+        }
+        true
+    }
+}
+
+/// The `--include-ext` and `--exclude-ext` lists. Extensions are stored
+/// lowercased with a leading `.`, and may have several parts (`.min.js`).
+struct ExtensionFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl ExtensionFilter {
+    fn new(include: Option<&str>, exclude: Option<&str>) -> ExtensionFilter {
+        let parse = |list: Option<&str>| {
+            list.unwrap_or("")
+                .split(',')
+                .map(|e| e.trim().trim_start_matches('.'))
+                .filter(|e| !e.is_empty())
+                .map(|e| format!(".{}", e.to_lowercase()))
+                .collect::<Vec<_>>()
+        };
+        ExtensionFilter {
+            include: parse(include),
+            exclude: parse(exclude),
+        }
+    }
+
+    /// Returns whether the file at `path` should be indexed
+    fn keep(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(n) => n.to_string_lossy().to_lowercase(),
+            None => return false,
+        };
+        let has = |exts: &[String]| exts.iter().any(|e| name.ends_with(&e[..]));
+        !has(&self.exclude) && (self.include.is_empty() || has(&self.include))
+    }
+}
+
+/// Limits from an `--index-config` file, for the files matching one pattern
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigLimits {
+    max_file_len: Option<u64>,
+    max_line_len: Option<u64>,
+    max_trigram_count: Option<u64>,
+    max_utf8_invalid: Option<f64>,
+}
+
+/// Per-file limits read from an `--index-config` file. It maps glob
+/// patterns to limits, e.g. in TOML:
+///
+/// ```toml
+/// ["*.min.js"]
+/// max_line_len = 1000000
+///
+/// ["*/generated/*"]
+/// max_file_len = 104857600
+/// ```
+///
+/// When several patterns match a file, the most specific one (the one
+/// with the most literal characters) wins.
+#[derive(Default)]
+struct IndexConfig {
+    /// (pattern, specificity, limits), most specific first
+    patterns: Vec<(glob::Pattern, usize, LimitOverrides)>,
+}
+
+impl IndexConfig {
+    /// Reads an `--index-config` file. Files ending in `.toml` are read as
+    /// TOML, anything else as JSON.
+    fn open(path: &Path) -> Result<IndexConfig, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let entries: BTreeMap<String, ConfigLimits> =
+            if path.extension().is_some_and(|e| e == "toml") {
+                toml::from_str(&text).map_err(|e| e.to_string())?
+            } else {
+                serde_json::from_str(&text).map_err(|e| e.to_string())?
+            };
+        let mut patterns = Vec::with_capacity(entries.len());
+        for (pattern, limits) in entries {
+            if let Some(u) = limits.max_utf8_invalid {
+                if !(0.0..=1.0).contains(&u) {
+                    return Err(format!(
+                        "{}: max_utf8_invalid must be between 0 and 1, got {}",
+                        pattern, u
+                    ));
+                }
+            }
+            let specificity = pattern.chars().filter(|c| !"*?[]".contains(*c)).count();
+            let glob = glob::Pattern::new(&pattern).map_err(|e| format!("{}: {}", pattern, e))?;
+            let overrides = LimitOverrides {
+                max_trigram_count: limits.max_trigram_count,
+                max_utf8_invalid: limits.max_utf8_invalid,
+                max_file_len: limits.max_file_len,
+                max_line_len: limits.max_line_len,
+            };
+            patterns.push((glob, specificity, overrides));
+        }
+        // stable, so equally specific patterns keep their sorted order
+        patterns.sort_by_key(|p| std::cmp::Reverse(p.1));
+        Ok(IndexConfig { patterns })
+    }
+
+    /// Returns the limits for `path`, from the most specific matching pattern
+    fn limits_for(&self, path: &Path) -> LimitOverrides {
+        self.patterns
+            .iter()
+            .find(|(p, _, _)| p.matches_path(path))
+            .map(|&(_, _, o)| o)
+            .unwrap_or_default()
+    }
+}
+
+/// Finds the files to index under the paths given to cindex
+struct Walker {
+    excludes: Vec<glob::Pattern>,
+    /// Names of directories that are skipped along with everything in them
+    exclude_dirs: Vec<String>,
+    extensions: ExtensionFilter,
+    follow_links: bool,
+    /// Also walk files and directories whose names start with `.`
+    hidden: bool,
+    use_gitignore: bool,
+    /// Only walk this many levels below each root, where a root's own
+    /// entries are level 1
+    max_depth: Option<usize>,
+    /// Only walk to files modified since this time
+    since: Option<SystemTime>,
+    log_skipped: bool,
+}
+
+impl Walker {
+    /// Calls `f` with every file to index under the directory `root`
+    fn walk<F: FnMut(&Path)>(&self, root: &Path, mut f: F) {
+        let mut gitignores = GitignoreStack::default();
+        // sorted, so the same tree always gives the same index
+        let files = WalkDir::new(root)
+            .follow_links(self.follow_links)
+            .max_depth(self.max_depth.unwrap_or(usize::MAX))
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|d| {
+                let p = d.path();
+                // the named paths themselves are indexed even if excluded
+                let excluded_dir =
+                    d.depth() > 0 && d.file_type().is_dir() && self.is_excluded_dir(d.file_name());
+                let hidden = d.depth() > 0 && !self.hidden && is_hidden(d.file_name());
+                !excluded_dir
+                    && !hidden
+                    && !self.is_excluded(p)
+                    && (!self.use_gitignore || gitignores.keep(d))
+            })
+            .filter_map(Result::ok)
+            .filter(|d| !d.file_type().is_dir())
+            .filter(|d| self.follow_links || !d.path_is_symlink())
+            .filter(|d| self.extensions.keep(d.path()))
+            .filter(|d| self.modified_since(d));
+        for d in files {
+            if stopping() {
+                break;
+            }
+            f(d.path());
+        }
+    }
+
+    /// Returns whether the file `d` was modified since `--since`. Files
+    /// whose modification time can't be read are kept.
+    fn modified_since(&self, d: &DirEntry) -> bool {
+        let since = match self.since {
+            Some(s) => s,
+            None => return true,
+        };
+        match d
+            .metadata()
+            .map_err(io::Error::from)
+            .and_then(|m| m.modified())
+        {
+            Ok(t) => t >= since,
+            Err(e) => {
+                if self.log_skipped {
+                    warn!(
+                        "{}: can't read modification time, indexing it anyway: {}",
+                        d.path().display(),
+                        e
+                    );
+                }
+                true
+            }
+        }
+    }
+
+    /// Returns whether `path`, which `--watch` saw change under the
+    /// indexed path `root`, isn't excluded. `.gitignore` files aren't
+    /// consulted for changed files.
+    fn keep_changed(&self, root: &Path, path: &Path) -> bool {
+        path.ancestors()
+            .take_while(|a| a.starts_with(root))
+            .all(|a| !self.is_excluded(a))
+            && !self.in_excluded_dir(path.strip_prefix(root).unwrap_or(path))
+            && !self.in_hidden(path.strip_prefix(root).unwrap_or(path))
+            && self.within_depth(path.strip_prefix(root).unwrap_or(path))
+    }
+
+    /// Returns whether `path`, relative to where the walk started, is no
+    /// deeper than `--max-depth`
+    fn within_depth(&self, path: &Path) -> bool {
+        self.max_depth
+            .is_none_or(|d| path.components().count() <= d)
+    }
+
+    /// Returns whether the file at `path` inside a tar archive isn't
+    /// excluded, along with the directories it's in
+    fn keep_entry(&self, path: &Path) -> bool {
+        path.ancestors()
+            .take_while(|a| !a.as_os_str().is_empty())
+            .all(|a| !self.is_excluded(a))
+            && !self.in_excluded_dir(path)
+            && !self.in_hidden(path)
+            && self.extensions.keep(path)
+    }
+
+    /// Returns whether `path`, relative to where the walk started, is a
+    /// hidden file or is in a hidden directory, unless `--hidden` was given
+    fn in_hidden(&self, path: &Path) -> bool {
+        !self.hidden && path.iter().any(is_hidden)
+    }
+
+    /// Returns whether `name` is one of the `--exclude-dir` names. Case is
+    /// ignored on Windows, like its file systems do.
+    fn is_excluded_dir(&self, name: &OsStr) -> bool {
+        let name = name.to_string_lossy();
+        self.exclude_dirs.iter().any(|d| {
+            if cfg!(windows) {
+                d.to_lowercase() == name.to_lowercase()
+            } else {
+                *d == name
+            }
+        })
+    }
+
+    /// Returns whether a directory that `path` is in, relative to where
+    /// the walk started, is one of the `--exclude-dir` names
+    fn in_excluded_dir(&self, path: &Path) -> bool {
+        path.parent().is_some_and(|dir| {
+            dir.components()
+                .any(|c| self.is_excluded_dir(c.as_os_str()))
+        })
+    }
+
+    /// Returns whether an exclude pattern matches either the whole of
+    /// `path` or its file name, so `*.min.js` and `node_modules` exclude
+    /// files and directories of that name anywhere, while
+    /// `/home/me/src/gen/*` excludes what's under one directory only
+    fn is_excluded(&self, path: &Path) -> bool {
+        let name = path.file_name().map(Path::new);
+        self.excludes
+            .iter()
+            .any(|r| r.matches_path(path) || name.is_some_and(|n| r.matches_path(n)))
+    }
+}
+
+/// Returns whether a file or directory `name` starts with `.`, which
+/// hides it on Unix. `.` and `..` themselves aren't hidden.
+fn is_hidden(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && name != "." && name != ".."
+}
+
 const ABOUT: &str = "
 cindex prepares the trigram index for use by csearch.  The index
 is the file named by $CSEARCHINDEX, or else $HOME/.csearchindex.
@@ -117,13 +499,123 @@ or, equivalently:
 
 If cindex is invoked with no paths, it reindexes the paths that have
 already been added, in case the files have changed.  Thus, 'cindex' by
-itself is a useful command to run in a nightly cron job.
+itself is a useful command to run in a nightly cron job.  With the
+--incremental flag, files whose modification time and size haven't
+changed since they were last indexed aren't read again.
 
 By default cindex adds the named paths to the index but preserves
 information about other paths that might already be indexed
 (the ones printed by cindex --list).  The --reset flag causes cindex to
 delete the existing index before indexing the new paths.
-With no path arguments, cindex -reset removes the index.";
+With no path arguments, cindex -reset removes the index.
+
+The file named by --exclude holds one glob pattern per line, and
+--exclude-pattern gives a single pattern inline.  Both may be repeated,
+and every pattern applies.  A file or directory is skipped if a pattern
+matches its full path or just its name, so '*.min.js' and 'node_modules'
+apply anywhere in the tree while '/home/me/src/gen/*' applies under that
+directory only.  '*' also matches '/' in full paths.
+
+--exclude-dir takes a list of directory names, such as
+node_modules,.git,target, and skips every directory with one of those
+names without looking inside it.  Names are matched exactly, ignoring case
+on Windows.
+
+--max-depth N limits how far below each path named on the command line
+cindex looks.  The path itself is depth 0, so a file named on the command
+line is always indexed, and with a directory, --max-depth 1 indexes the
+files directly in it, 2 those in its subdirectories too, and so on.  It
+doesn't limit the files inside tar archives.  Reindexing with no paths
+walks every level again unless --max-depth is given again.
+
+Files and directories whose names start with '.', such as .git, are
+skipped unless --hidden is given.  Paths named on the command line are
+indexed even if hidden.
+
+With --relative-to BASE, the files under the directory BASE are stored by
+their path relative to it, so an index built in one place can be used
+where the same files are under another directory, with csearch --prefix.
+Reindexing with no paths finds the files under BASE again, and every run
+that updates the index should be given --relative-to.
+
+Indexing the same files gives the same index byte for byte, except for
+the modification times recorded for --incremental.  With --reproducible
+they're left out, so the same files give the same index in another
+checkout too, as when an index is cached by content.
+
+With --compress, the posting lists, which make up most of the index, are
+compressed with zstd in blocks that csearch decompresses as it first reads
+them.  The index is usually less than half the size, but a search that
+reads many lists is slower, and older builds of csearch can't read it.
+An index stays compressed when it's updated or merged, until it's
+rebuilt with --reset and without --compress.
+
+With --retries N, a file that can't be read because of an I/O error, as
+can happen on a flaky network mount, is read up to N more times, waiting
+0.1s before the first retry and twice as long before each one after that.
+Files that are too long, binary, gone or unreadable for lack of permission
+aren't retried.  With --logskip, whether each retried file was read in the
+end is logged.
+
+With --progress, cindex prints how many files it has indexed and skipped
+so far and how many it reads per second, in place on a terminal, with
+log messages written above the count.
+
+With --watch, cindex keeps running after indexing and updates the index
+as files under the indexed paths change, until interrupted.
+
+With --max-index-bytes N, cindex stops before the index could grow past
+N bytes and exits with status 101.  An existing index is left unchanged;
+a new one holds the files that fit.
+
+A new or updated index is written to a temporary file beside the index,
+checked as --verify does, and only then renamed over it in one step, so
+the old index is left intact if cindex fails or is killed before that.
+While updating an index, cindex holds a lock on the file of the same name
+ending in .lock, and a second cindex run on that index fails instead of
+overwriting it.
+
+If cindex is interrupted while indexing, it stops reading files and exits
+with status 130.  An existing index is left unchanged; a new one holds the
+files indexed so far.
+
+With --stdin-name NAME, the contents piped on stdin are indexed as a file
+named NAME, within the same limits as other files.  csearch --files lists
+it when a search could match it, but can only show matching lines if a
+file named NAME exists, since cindex doesn't keep the contents.  Piping
+under the same name again replaces it, and reindexing with no paths drops
+it, as there's nothing to read it from.  For example:
+
+	make 2>&1 | cindex --indexpath /tmp/build.idx --stdin-name build.log
+
+With --tar, the files inside a tar archive, which may be gzipped, are
+indexed without extracting them, as archive.tar!path/in/archive.  csearch
+reads them from the archive.  Reindexing with no paths reads indexed
+archives the same way.  Archives aren't watched.
+
+With --since TIME, only the files modified since TIME are read from the
+directories being indexed, where TIME is an RFC 3339 date such as
+2024-05-01T09:00:00Z or @ followed by seconds since the epoch.  Older
+files already in the index are kept, but deleted files aren't noticed,
+so the index should still be rebuilt without --since now and then.
+
+With --dedup, a file with the same contents as one already indexed is
+stored as an alias of it rather than indexed again, and csearch reports
+matches under every name.  Older builds of csearch don't find aliases.
+
+cindex --trigram-histogram N lists the N trigrams found in the most files,
+20 if N isn't given.  Those are the trigrams that narrow a search the
+least, and a flood of one of them often comes from generated files that
+are better excluded.
+
+cindex --verify reads through the whole index, checking that its path and
+name lists are well formed and that every posting list is in order and
+refers to files in the index.  It prints ok, or reports the first problem
+and exits with a nonzero status.
+
+cindex --merge DEST SRC1 SRC2 combines two existing indexes into DEST
+without reading any files, for example to join indexes built separately.
+Files indexed in SRC2 replace those under the same paths in SRC1.";
 
 fn main() {
     let matches = clap::App::new("cindex")
@@ -144,13 +636,52 @@ fn main() {
                 .long("list")
                 .help("list indexed paths and exit"),
         )
+        .arg(
+            clap::Arg::with_name("list0")
+                .long("list0")
+                .conflicts_with("list-paths")
+                .help("list indexed paths, each followed by a NUL byte, and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("list-json")
+                .long("list-json")
+                .conflicts_with_all(&["list-paths", "list0"])
+                .help("list indexed paths as a JSON array of objects, and exit"),
+        )
         .arg(
             clap::Arg::with_name("reset-index")
                 .long("reset")
                 .conflicts_with("path")
                 .conflicts_with("list-paths")
+                .conflicts_with("list0")
+                .conflicts_with("list-json")
                 .help("discard existing index"),
         )
+        .arg(
+            clap::Arg::with_name("REMOVE_PATH")
+                .long("remove")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["path", "reset-index"])
+                .help("drop REMOVE_PATH and every file under it from the index"),
+        )
+        .arg(
+            clap::Arg::with_name("MERGE")
+                .long("merge")
+                .takes_value(true)
+                .number_of_values(3)
+                .value_names(&["DEST", "SRC1", "SRC2"])
+                .conflicts_with_all(&[
+                    "path",
+                    "reset-index",
+                    "REMOVE_PATH",
+                    "list-paths",
+                    "list0",
+                    "list-json",
+                ])
+                .help("merge the indexes SRC1 and the newer SRC2 into DEST, and exit"),
+        )
         .arg(
             clap::Arg::with_name("INDEX_FILE")
                 .long("indexpath")
@@ -162,6 +693,11 @@ fn main() {
                 .long("no-follow-simlinks")
                 .help("do not follow symlinked files and directories"),
         )
+        .arg(
+            clap::Arg::with_name("hidden")
+                .long("hidden")
+                .help("also index files and directories whose names start with '.'"),
+        )
         .arg(
             clap::Arg::with_name("MAX_FILE_SIZE_BYTES")
                 .long("maxFileLen")
@@ -189,23 +725,218 @@ fn main() {
                    sequences",
                 ),
         )
+        .arg(
+            clap::Arg::with_name("BINARY_SNIFF_BYTES")
+                .long("binary-sniff-len")
+                .takes_value(true)
+                .help("skip indexing a file if it has a NUL byte in its first BINARY_SNIFF_BYTES bytes (default 8192, 0 to disable)"),
+        )
+        .arg(
+            clap::Arg::with_name("MAX_INDEX_BYTES")
+                .long("max-index-bytes")
+                .takes_value(true)
+                .value_name("N")
+                .help("stop indexing, and fail, before the index grows past N bytes"),
+        )
         .arg(
             clap::Arg::with_name("EXCLUDE_FILE")
                 .long("exclude")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .help("path to file containing a list of file patterns to exclude from indexing"),
         )
+        .arg(
+            clap::Arg::with_name("EXCLUDE_PATTERN")
+                .long("exclude-pattern")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GLOB")
+                .help("exclude files matching GLOB from indexing, as if it were in an --exclude file"),
+        )
+        .arg(
+            clap::Arg::with_name("EXCLUDE_DIR")
+                .long("exclude-dir")
+                .takes_value(true)
+                .help("comma separated names of directories to skip with everything under them, like node_modules,.git,target"),
+        )
+        .arg(
+            clap::Arg::with_name("MAX_DEPTH")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("N")
+                .help("only index files at most N levels below each path, 1 being the files directly in it"),
+        )
+        .arg(
+            clap::Arg::with_name("INCLUDE_EXT")
+                .long("include-ext")
+                .takes_value(true)
+                .help("only index files with one of these comma separated extensions, e.g. rs,go,py"),
+        )
+        .arg(
+            clap::Arg::with_name("EXCLUDE_EXT")
+                .long("exclude-ext")
+                .takes_value(true)
+                .help("don't index files with one of these comma separated extensions, e.g. lock,min.js"),
+        )
+        .arg(
+            clap::Arg::with_name("TAR")
+                .long("tar")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("also index the files in the tar archive TAR, which may be gzipped"),
+        )
         .arg(
             clap::Arg::with_name("FILE")
                 .long("filelist")
                 .takes_value(true)
-                .help("path to file containing a list of file paths to index"),
+                .help("path to file containing a list of file paths to index, or - to read them from stdin"),
+        )
+        .arg(
+            clap::Arg::with_name("FILE0")
+                .long("filelist0")
+                .takes_value(true)
+                .conflicts_with("FILE")
+                .help("like --filelist, but the paths are separated by NUL bytes, as printed by find -print0"),
+        )
+        .arg(
+            clap::Arg::with_name("STDIN_NAME")
+                .long("stdin-name")
+                .takes_value(true)
+                .value_name("NAME")
+                .conflicts_with("watch")
+                .conflicts_with("reset-index")
+                .help("also index the contents piped on stdin as a file named NAME"),
+        )
+        .arg(
+            clap::Arg::with_name("INDEX_CONFIG")
+                .long("index-config")
+                .takes_value(true)
+                .help("JSON or TOML file of per-file limits, keyed by glob pattern"),
+        )
+        .arg(
+            clap::Arg::with_name("stats")
+                .long("stats")
+                .help("print statistics about the index and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("TRIGRAM_HISTOGRAM")
+                .long("trigram-histogram")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .value_name("N")
+                .help("print the N trigrams (default 20) in the most files, with how many, and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("verify")
+                .long("verify")
+                .help("check the whole index for damage, print ok or the first problem found, and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("gitignore").long("gitignore").help(
+                "skip files and directories ignored by .gitignore files in the indexed trees",
+            ),
+        )
+        .arg(
+            clap::Arg::with_name("THREADS")
+                .short("j")
+                .long("threads")
+                .takes_value(true)
+                .help("read files with THREADS threads (defaults to the number of CPUs)"),
+        )
+        .arg(
+            clap::Arg::with_name("RETRIES")
+                .long("retries")
+                .takes_value(true)
+                .value_name("N")
+                .help("read a file up to N more times after an I/O error, waiting longer each time"),
+        )
+        .arg(
+            clap::Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("report which files would be indexed or skipped, without writing the index"),
+        )
+        .arg(
+            clap::Arg::with_name("watch")
+                .long("watch")
+                .conflicts_with("dry-run")
+                .help("keep the index up to date as files change, until interrupted"),
+        )
+        .arg(
+            clap::Arg::with_name("SINCE")
+                .long("since")
+                .takes_value(true)
+                .help("only add the files modified since SINCE (RFC 3339, or @epoch seconds)"),
+        )
+        .arg(
+            clap::Arg::with_name("dedup")
+                .long("dedup")
+                .help("index files with the same contents once, as aliases of the first"),
+        )
+        .arg(
+            clap::Arg::with_name("RELATIVE_TO")
+                .long("relative-to")
+                .takes_value(true)
+                .value_name("BASE")
+                .help("store the paths of files under the directory BASE relative to it, so the index can be used on another machine (see csearch --prefix)"),
+        )
+        .arg(
+            clap::Arg::with_name("reproducible")
+                .long("reproducible")
+                .help("don't record modification times, so the same files always give the same index"),
+        )
+        .arg(
+            clap::Arg::with_name("compress")
+                .long("compress")
+                .help("compress the posting lists with zstd, for a smaller index that older builds can't read"),
+        )
+        .arg(
+            clap::Arg::with_name("incremental")
+                .long("incremental")
+                .help("don't reread files that are unchanged since they were last indexed"),
         )
         .arg(
             clap::Arg::with_name("verbose")
                 .long("verbose")
                 .help("print extra information"),
         )
+        .arg(
+            clap::Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .conflicts_with("verbose")
+                .help("print only errors"),
+        )
+        .arg(
+            clap::Arg::with_name("no-color")
+                .long("no-color")
+                .help("don't color warnings and errors, even on a terminal"),
+        )
+        .arg(
+            clap::Arg::with_name("log-json")
+                .long("log-json")
+                .help("print log records as JSON objects, one per line"),
+        )
+        .arg(
+            clap::Arg::with_name("LOG_FILTER")
+                .long("log-filter")
+                .takes_value(true)
+                .help("per-target log levels, e.g. libcindex=debug,walkdir=warn"),
+        )
+        .arg(
+            clap::Arg::with_name("PROFILE_JSON")
+                .long("profile-json")
+                .takes_value(true)
+                .help("write the timings collected by the profiler to PROFILE_JSON as JSON"),
+        )
+        .arg(
+            clap::Arg::with_name("progress")
+                .long("progress")
+                .help("print how many files have been indexed so far, and how fast"),
+        )
         .arg(
             clap::Arg::with_name("logskip")
                 .long("logskip")
@@ -215,10 +946,24 @@ fn main() {
 
     let max_log_level = if matches.is_present("verbose") {
         LevelFilter::Trace
+    } else if matches.is_present("quiet") {
+        LevelFilter::Error
     } else {
         LevelFilter::Info
     };
-    libcustomlogger::init(max_log_level).unwrap();
+    let log_format = if matches.is_present("log-json") {
+        libcustomlogger::Format::Json
+    } else {
+        libcustomlogger::Format::Plain
+    };
+    if matches.is_present("no-color") {
+        libcustomlogger::color::set_when(libcustomlogger::color::ColorWhen::Never);
+    }
+    let log_filter = matches.value_of("LOG_FILTER").unwrap_or("");
+    if let Err(e) = libcustomlogger::init_with_spec(max_log_level, log_format, log_filter) {
+        eprintln!("cindex: {}", e);
+        std::process::exit(2);
+    }
 
     let mut excludes: Vec<glob::Pattern> = vec![glob::Pattern::new(".csearchindex").unwrap()];
     let mut args = Vec::<String>::new();
@@ -226,35 +971,152 @@ fn main() {
     if let Some(p) = matches.values_of("path") {
         args.extend(p.map(String::from));
     }
+    let mut tar_args = matches
+        .values_of("TAR")
+        .map_or_else(Vec::new, |t| t.map(String::from).collect());
 
     if let Some(p) = matches.value_of("INDEX_FILE") {
         env::set_var("CSEARCHINDEX", p);
     }
 
-    if matches.is_present("list-paths") {
+    if let Some(merge) = matches.values_of("MERGE") {
+        let merge = merge.collect::<Vec<_>>();
+        let (dest_path, sources) = (merge[0], &merge[1..]);
+        // check the sources up front, so a bad one is named in the error
+        for each_src in sources {
+            if let Err(e) = IndexReader::open(each_src) {
+                error!("merge {}: {}", each_src, e);
+                std::process::exit(101);
+            }
+        }
+        // DEST may be one of the sources, so only replace it once merged
+        let _lock = lock_index_or_fail(dest_path);
+        let tmp_path = temp_index_or_fail(dest_path);
+        info!("merge {} {}", sources[0], sources[1]);
+        if let Err(e) = libcindex::merge::merge(&tmp_path, sources[0], sources[1]) {
+            error!("merge: {}", e);
+            drop(tmp_path);
+            std::process::exit(101);
+        }
+        publish_or_fail(tmp_path, dest_path);
+        return;
+    }
+    if matches.is_present("list-paths") || matches.is_present("list0") {
         let i = open_index_or_fail();
-        for each_file in i.indexed_paths() {
-            println!("{}", each_file);
+        let terminator = if matches.is_present("list0") {
+            "\0"
+        } else {
+            "\n"
+        };
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let written = i
+            .indexed_paths()
+            .iter()
+            .try_for_each(|each_file| write!(out, "{}{}", each_file, terminator))
+            .and_then(|_| out.flush());
+        if let Err(e) = written {
+            write_failed(e);
+        }
+        return;
+    }
+    if matches.is_present("list-json") {
+        let i = open_index_or_fail();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let written = serde_json::to_writer(&mut out, &indexed_path_infos(&i))
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(out))
+            .and_then(|_| out.flush());
+        if let Err(e) = written {
+            write_failed(e);
+        }
+        return;
+    }
+    if matches.is_present("stats") {
+        let i = open_index_or_fail();
+        for (key, value) in index_stats(&i) {
+            println!("{:<10}{}", format!("{}:", key), value);
+        }
+        return;
+    }
+    if matches.is_present("TRIGRAM_HISTOGRAM") {
+        let n = match matches
+            .value_of("TRIGRAM_HISTOGRAM")
+            .map(str::parse::<usize>)
+        {
+            None => 20,
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                error!("--trigram-histogram: {}", e);
+                std::process::exit(101);
+            }
+        };
+        let i = open_index_or_fail();
+        for (trigram, count) in trigram_histogram(&i, n) {
+            println!("{:>10}  {}", count, trigram);
+        }
+        return;
+    }
+    if matches.is_present("verify") {
+        let i = open_index_or_fail();
+        match i.verify() {
+            Ok(()) => println!("ok"),
+            Err(e) => {
+                error!("verify {}: {}", index_path_or_fail(), e);
+                std::process::exit(101);
+            }
+        }
+        return;
+    }
+    if let Some(remove) = matches.values_of("REMOVE_PATH") {
+        let remove = remove
+            .map(|r| {
+                // the path may already be gone, so it can't always be canonicalized
+                let r = env::current_dir().unwrap().join(r);
+                normalize(&r).unwrap_or(r).to_string_lossy().into_owned()
+            })
+            .collect::<Vec<_>>();
+        let base = relative_base(&matches).filter(|_| open_index_or_fail().is_relative());
+        let remove = remove
+            .iter()
+            .map(|r| stored_name(base.as_deref(), r))
+            .collect::<Vec<_>>();
+        let index_path = index_path_or_fail();
+        let _lock = lock_index_or_fail(&index_path);
+        let dest_path = temp_index_or_fail(&index_path);
+        info!("remove {}", remove.join(" "));
+        if let Err(e) = libcindex::merge::remove_paths(&dest_path, &index_path, &remove) {
+            error!("remove: {}", e);
+            drop(dest_path);
+            std::process::exit(101);
         }
+        publish_or_fail(dest_path, &index_path);
         return;
     }
     if matches.is_present("reset-index") {
-        let index_path = libcsearch::csearch_index();
+        let index_path = index_path_or_fail();
         let p = Path::new(&index_path);
-        if !p.exists() {
+        // don't follow a symlinked index: remove the link, not its target
+        let meta = match p.symlink_metadata() {
+            Ok(m) => m.file_type(),
             // does not exist so nothing to do
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("{}: can't reset index: {}", p.display(), e);
+                return;
+            }
+        };
+        if !meta.is_symlink() && !is_regular_file(meta) {
+            warn!("{}: not a regular file, not removing it", p.display());
             return;
         }
-        let meta = p
-            .metadata()
-            .expect("failed to get metadata for file!")
-            .file_type();
-        if is_regular_file(meta) {
-            std::fs::remove_file(p).expect("failed to remove file");
+        if let Err(e) = std::fs::remove_file(p) {
+            warn!("{}: can't reset index: {}", p.display(), e);
         }
         return;
     }
-    if let Some(exc_path_str) = matches.value_of("EXCLUDE_FILE") {
+    for exc_path_str in matches.values_of("EXCLUDE_FILE").into_iter().flatten() {
         let exclude_path = Path::new(exc_path_str);
         let f = BufReader::new(File::open(exclude_path).expect("exclude file open error"));
         excludes.extend(
@@ -262,137 +1124,1309 @@ fn main() {
                 .map(|f| glob::Pattern::new(f.unwrap().trim()).unwrap()),
         );
     }
+    for pattern in matches.values_of("EXCLUDE_PATTERN").into_iter().flatten() {
+        match glob::Pattern::new(pattern) {
+            Ok(p) => excludes.push(p),
+            Err(e) => {
+                error!("--exclude-pattern {}: {}", pattern, e);
+                std::process::exit(101);
+            }
+        }
+    }
+    let open_file_list = |file_list_str: &str| -> Box<dyn BufRead> {
+        if file_list_str == "-" {
+            Box::new(BufReader::new(io::stdin()))
+        } else {
+            let file_list = Path::new(file_list_str);
+            Box::new(BufReader::new(
+                File::open(file_list).expect("filelist file open error"),
+            ))
+        }
+    };
     if let Some(file_list_str) = matches.value_of("FILE") {
-        let file_list = Path::new(file_list_str);
-        let f = BufReader::new(File::open(file_list).expect("filelist file open error"));
+        let f = open_file_list(file_list_str);
         args.extend(f.lines().map(|f| f.unwrap().trim().to_string()));
     }
-
-    if args.is_empty() {
-        let i = open_index_or_fail();
-        for each_file in i.indexed_paths() {
-            args.push(each_file);
+    if let Some(file_list_str) = matches.value_of("FILE0") {
+        // names are taken as they are, since they may hold any byte but NUL
+        for each_name in open_file_list(file_list_str).split(b'\0') {
+            match String::from_utf8(each_name.unwrap()) {
+                Ok(name) => args.push(name),
+                Err(e) => warn!(
+                    "{}: skipped. not valid UTF-8",
+                    String::from_utf8_lossy(e.as_bytes())
+                ),
+            }
+        }
+    }
+
+    // contents piped in for --stdin-name, read before any file is
+    let stdin_file = matches.value_of("STDIN_NAME").map(|name| {
+        if name.is_empty() {
+            error!("--stdin-name: the name can't be empty");
+            std::process::exit(101);
+        }
+        if matches.value_of("FILE") == Some("-") || matches.value_of("FILE0") == Some("-") {
+            error!("--stdin-name: stdin is already the file list");
+            std::process::exit(101);
+        }
+        let mut data = Vec::new();
+        if let Err(e) = io::stdin().read_to_end(&mut data) {
+            error!("--stdin-name {}: can't read stdin: {}", name, e);
+            std::process::exit(101);
+        }
+        (name.to_string(), data)
+    });
+
+    if args.is_empty() && tar_args.is_empty() && stdin_file.is_none() {
+        let i = open_index_or_fail();
+        let base = relative_base(&matches).filter(|_| i.is_relative());
+        for each_file in i.indexed_paths() {
+            let each_file = match base {
+                Some(ref b) => b.join(each_file).to_string_lossy().into_owned(),
+                None => each_file,
+            };
+            if libcsearch::is_tar(&each_file) && Path::new(&each_file).is_file() {
+                tar_args.push(each_file);
+            } else {
+                args.push(each_file);
+            }
         }
     }
 
     let log_skipped = matches.is_present("logskip");
-    let mut paths: Vec<PathBuf> = args
+    let show_progress = matches.is_present("progress");
+    let since = matches.value_of("SINCE").map(|s| match parse_since(s) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("--since {}: {}", s, e);
+            std::process::exit(101);
+        }
+    });
+    let walker = Arc::new(Walker {
+        excludes,
+        exclude_dirs: matches
+            .value_of("EXCLUDE_DIR")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(String::from)
+            .collect(),
+        extensions: ExtensionFilter::new(
+            matches.value_of("INCLUDE_EXT"),
+            matches.value_of("EXCLUDE_EXT"),
+        ),
+        follow_links: !matches.is_present("no-follow-simlinks"),
+        hidden: matches.is_present("hidden"),
+        use_gitignore: matches.is_present("gitignore"),
+        max_depth: get_value_from_matches::<usize>(&matches, "MAX_DEPTH"),
+        since,
+        log_skipped,
+    });
+    let normalize_args = |args: &[String]| {
+        args.iter()
+            .filter(|f| !f.is_empty())
+            .map(|f| env::current_dir().unwrap().join(f))
+            .filter_map(|f| match normalize(&f) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    if log_skipped {
+                        warn!("{}: skipped. {}", f.to_str().unwrap_or_default(), e.kind());
+                    }
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let tars = normalize_args(&tar_args)
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+    let mut paths = normalize_args(&args);
+    paths.extend(tars.iter().cloned());
+    paths.sort();
+    paths.dedup();
+    let watched = paths
         .iter()
-        .filter(|f| !f.is_empty())
-        .map(|f| env::current_dir().unwrap().join(f))
-        .filter_map(|f| match normalize(&f) {
-            Ok(p) => Some(p),
+        .filter(|p| !tars.contains(*p))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let dry_run = matches.is_present("dry-run");
+    let final_path = index_path_or_fail();
+    // held until cindex exits, watching included
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(lock_index_or_fail(&final_path))
+    };
+    let needs_merge = Path::new(&final_path).exists();
+
+    let previous = if needs_merge && matches.is_present("incremental") {
+        Some(open_index_or_fail())
+    } else {
+        None
+    };
+
+    // only the files that were read replace their old versions, so the
+    // merge keeps the unmodified ones under --since
+    let indexed_before = if since.is_some() && needs_merge {
+        Some(open_index_or_fail().indexed_paths())
+    } else {
+        None
+    };
+
+    let mut builder = limits_builder(&matches);
+    if let Some(p) = previous {
+        builder = builder.previous(p);
+    }
+    let num_threads = match matches.value_of("THREADS") {
+        Some(s) => match s.parse::<usize>() {
+            Ok(0) => {
+                error!("THREADS: must be at least 1");
+                std::process::exit(101);
+            }
+            Ok(n) => n,
+            Err(parse_err) => {
+                error!("THREADS: {}", parse_err);
+                std::process::exit(101);
+            }
+        },
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let retries = Retries {
+        max: get_value_from_matches::<u32>(&matches, "RETRIES").unwrap_or(0),
+        log: log_skipped,
+    };
+    let config = match matches.value_of("INDEX_CONFIG") {
+        Some(p) => match IndexConfig::open(Path::new(p)) {
+            Ok(c) => c,
             Err(e) => {
-                if log_skipped {
-                    warn!("{}: skipped. {}", f.to_str().unwrap_or_default(), e.kind());
-                }
-                None
+                error!("{}: {}", p, e);
+                std::process::exit(101);
             }
+        },
+        None => IndexConfig::default(),
+    };
+    let config = Arc::new(config);
+
+    // an interrupt stops indexing new files, and either keeps the old
+    // index or flushes the files indexed so far, so it's never torn
+    let (events_tx, events) = mpsc::channel();
+    let interrupt_tx = events_tx.clone();
+    ctrlc::set_handler(move || {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        let _ = interrupt_tx.send(WatchEvent::Interrupt);
+    })
+    .expect("can't handle interrupts");
+
+    // subscribe before indexing, so changes made while the index is
+    // built aren't missed
+    let watching = if matches.is_present("watch") {
+        let mut watcher = notify::recommended_watcher(move |e| {
+            let _ = events_tx.send(WatchEvent::Fs(e));
         })
-        .collect();
-    paths.sort();
+        .expect("can't watch for changes");
+        for each_path in &watched {
+            if let Err(e) = watcher.watch(each_path, RecursiveMode::Recursive) {
+                warn!("{}: can't watch for changes: {}", each_path.display(), e);
+            }
+        }
+        Some((watcher, events))
+    } else {
+        None
+    };
 
-    let mut index_path = libcsearch::csearch_index();
-    let needs_merge = if Path::new(&index_path).exists() {
-        index_path.push('~');
-        true
+    // the files are indexed into a temporary file, which replaces the
+    // index or is merged into it once it's complete
+    let partial = if dry_run {
+        None
     } else {
-        false
+        Some(temp_index_or_fail(&final_path))
     };
 
     let (tx, rx) = mpsc::channel::<OsString>();
-    // copying these variables into the worker thread
-    let index_path_cloned = index_path.clone();
-    let paths_cloned = paths.clone();
-    let h = thread::spawn(move || {
-        let mut seen = HashSet::<OsString>::new();
-        let mut i = match IndexWriter::new(index_path_cloned) {
+    let h = if dry_run {
+        let trigrammer = match builder.trigrammer() {
+            Ok(t) => t,
+            Err(e) => {
+                error!("IndexWriter: {}", e);
+                std::process::exit(101);
+            }
+        };
+        let (config, walker, tars) = (config.clone(), walker.clone(), tars.clone());
+        thread::spawn(move || {
+            let mut report = DryRunReport::default();
+            let mut progress = Progress::new(show_progress);
+            let mut tar_trigrammer = trigrammer.clone();
+            let mut add = |f: OsString, t: IndexResult<FileTrigrams>| {
+                if let Err(ref e) = t {
+                    log_skip(&f, e, log_skipped);
+                }
+                progress.add(t.is_ok());
+                report.add(t.map(|_| ()));
+            };
+            trigram_files(rx, trigrammer, &config, num_threads, retries, &mut add);
+            for t in tars.iter().take_while(|_| !stopping()) {
+                trigram_tar(t, &mut tar_trigrammer, &config, &walker, &mut add);
+            }
+            if let Some((name, data)) = stdin_file {
+                let t = trigram_stdin(&name, &data, &mut tar_trigrammer, &config);
+                add(OsString::from(name), t);
+            }
+            progress.finish();
+            report.print();
+            libprofiling::profiling_report()
+        })
+    } else {
+        let partial_path = partial.as_ref().unwrap().to_path_buf();
+        let mut i = match builder.build(&partial_path) {
             Ok(i) => i,
-            Err(e) => panic!("IndexWriter: {}", e),
+            Err(e) => {
+                error!("IndexWriter: {}", e);
+                std::process::exit(101);
+            }
         };
-        if let Some(t) = get_value_from_matches::<u64>(&matches, "MAX_TRIGRAMS_COUNT") {
-            i.max_trigram_count = t;
+        // copying these variables into the worker thread
+        let paths_cloned = paths.clone();
+        let (config, walker, tars) = (config.clone(), walker.clone(), tars.clone());
+        thread::spawn(move || {
+            let mut read_files = Vec::new();
+            match indexed_before {
+                Some(ref before) => i.add_paths(
+                    paths_cloned
+                        .into_iter()
+                        .filter(|p| !before.iter().any(|b| p.starts_with(b)))
+                        .map(PathBuf::into_os_string),
+                ),
+                None => i.add_paths(paths_cloned.into_iter().map(PathBuf::into_os_string)),
+            }
+            // so piping under the same name again replaces the contents
+            i.add_paths(stdin_file.iter().map(|(name, _)| OsString::from(name)));
+            {
+                let _frame = libprofiling::profile("Index files");
+                let mut skips = SkipSummary::default();
+                let mut progress = Progress::new(show_progress);
+                let trigrammer = i.trigrammer();
+                let mut tar_trigrammer = i.trigrammer();
+                let mut add = |f: OsString, t: IndexResult<FileTrigrams>| {
+                    if indexed_before.is_some() {
+                        read_files.push(f.clone());
+                    }
+                    let added = t.and_then(|t| i.add_trigrams(&f, t));
+                    match added {
+                        Err(ref e) if e.kind() == IndexErrorKind::IndexFull => {
+                            INDEX_FULL.store(true, Ordering::SeqCst);
+                            return;
+                        }
+                        Err(ref e) => {
+                            log_skip(&f, e, log_skipped);
+                            skips.add(e);
+                        }
+                        Ok(_) => (),
+                    }
+                    progress.add(added.is_ok());
+                };
+                trigram_files(rx, trigrammer, &config, num_threads, retries, &mut add);
+                for t in tars.iter().take_while(|_| !stopping()) {
+                    trigram_tar(t, &mut tar_trigrammer, &config, &walker, &mut add);
+                }
+                if let Some((ref name, ref data)) = stdin_file {
+                    let t = trigram_stdin(name, data, &mut tar_trigrammer, &config);
+                    add(OsString::from(name), t);
+                }
+                progress.finish();
+                read_files.sort();
+                i.add_paths(read_files);
+                info!("flush index");
+                if let Err(e) = i.flush() {
+                    // the partial index is kept, to see how far it got
+                    error!("flush {}: {}", partial_path.display(), e);
+                    std::process::exit(101);
+                }
+                skips.print();
+            }
+            libprofiling::profiling_report()
+        })
+    };
+
+    for each_path in paths.iter().take_while(|_| !stopping()) {
+        if !each_path.exists() {
+            warn!("{} - path doesn't exist. Skipping...", each_path.display());
+            continue;
         }
-        if let Some(u) = get_value_from_matches::<f64>(&matches, "MAX_INVALID_UTF8_RATIO") {
-            i.max_utf8_invalid = u;
+        if tars.contains(each_path) {
+            // read by the indexing thread once the other files are done
+            continue;
         }
-        if let Some(s) = get_value_from_matches::<u64>(&matches, "MAX_FILE_SIZE_BYTES") {
-            i.max_file_len = s;
+        // the indexing thread stops receiving once interrupted or full
+        if each_path.is_dir() {
+            debug!("index {}", each_path.display());
+            walker.walk(each_path, |p| {
+                let _ = tx.send(OsString::from(p));
+            });
+        } else if each_path.is_file() {
+            debug!("index file {}", each_path.display());
+            let _ = tx.send(OsString::from(each_path));
         }
-        if let Some(b) = get_value_from_matches::<u64>(&matches, "MAX_LINE_LEN_BYTES") {
-            i.max_line_len = b;
+    }
+    drop(tx);
+    let index_profile = h.join().unwrap();
+    if interrupted() {
+        match partial {
+            None => warn!("interrupted"),
+            Some(partial) if needs_merge => {
+                // merging would drop the indexed files that weren't reached
+                drop(partial);
+                warn!("interrupted; {} is unchanged", final_path);
+            }
+            Some(partial) => {
+                publish_or_fail(partial, &final_path);
+                warn!("interrupted; {} has the files indexed so far", final_path);
+            }
+        }
+        std::process::exit(130);
+    }
+    let max_index_len = get_value_from_matches::<u64>(&matches, "MAX_INDEX_BYTES");
+    if INDEX_FULL.load(Ordering::SeqCst) {
+        let max = max_index_len.unwrap_or_default();
+        let partial = partial.unwrap();
+        if needs_merge {
+            drop(partial);
+            error!(
+                "{} would be larger than --max-index-bytes {}; it is unchanged",
+                final_path, max
+            );
+        } else {
+            publish_or_fail(partial, &final_path);
+            error!(
+                "{} reached --max-index-bytes {}; it has only the files that fit",
+                final_path, max
+            );
+        }
+        std::process::exit(101);
+    }
+    match partial {
+        Some(partial) if needs_merge => {
+            let merged = temp_index_or_fail(&final_path);
+            info!("merge {} {}", final_path, partial.display());
+            if let Err(e) = libcindex::merge::merge(&merged, &final_path, &partial) {
+                error!("merge {}: {}", final_path, e);
+                drop((merged, partial));
+                std::process::exit(101);
+            }
+            drop(partial);
+            let merged_len = fs::metadata(&merged).map_or(0, |m| m.len());
+            if let Some(max) = max_index_len.filter(|&max| merged_len > max) {
+                drop(merged);
+                error!(
+                    "{} would be larger than --max-index-bytes {}; it is unchanged",
+                    final_path, max
+                );
+                std::process::exit(101);
+            }
+            publish_or_fail(merged, &final_path);
+        }
+        Some(partial) => publish_or_fail(partial, &final_path),
+        None => (),
+    }
+
+    if let Some((_watcher, events)) = watching {
+        info!("watching for changes");
+        let base = relative_base(&matches);
+        watch(&watched, &events, &walker, |changed, removed| {
+            let removed = removed
+                .iter()
+                .map(|r| stored_name(base.as_deref(), r))
+                .collect();
+            let builder = limits_builder(&matches);
+            if let Err(e) = update_index(
+                &final_path,
+                builder,
+                &config,
+                changed,
+                &removed,
+                log_skipped,
+            ) {
+                error!("update {}: {}", final_path, e);
+            }
+        });
+    }
+
+    info!("done");
+    let main_profile = libprofiling::profiling_report();
+    log_profile("main", &main_profile);
+    log_profile("index", &index_profile);
+    if let Some(p) = matches.value_of("PROFILE_JSON") {
+        let report = profile_frames("main", main_profile)
+            .chain(profile_frames("index", index_profile))
+            .collect::<Vec<_>>();
+        let written = File::create(p)
+            .and_then(|f| serde_json::to_writer_pretty(f, &report).map_err(io::Error::from));
+        if let Err(e) = written {
+            error!("{}: {}", p, e);
+            std::process::exit(101);
+        }
+    }
+}
+
+/// A profiled frame, as written by `--profile-json`
+#[derive(Serialize)]
+struct ProfileFrame {
+    /// Thread the frame ran on, `main` or `index`
+    thread: &'static str,
+    name: String,
+    calls: u32,
+    /// Total time spent in the frame, in nanoseconds
+    nanos: u64,
+}
+
+/// Logs the time spent in each frame of `report`, profiled on `thread`.
+/// The timings go to the log rather than stdout so `--quiet` and the
+/// `--dry-run` report aren't disturbed.
+fn log_profile(thread: &str, report: &[FrameTiming]) {
+    for f in report {
+        info!(
+            "profile {}: {} - {} calls, {:?}",
+            thread, f.name, f.calls, f.total
+        );
+    }
+}
+
+fn profile_frames(
+    thread: &'static str,
+    report: Vec<FrameTiming>,
+) -> impl Iterator<Item = ProfileFrame> {
+    report.into_iter().map(move |f| ProfileFrame {
+        thread,
+        name: f.name,
+        calls: f.calls,
+        nanos: f.total.as_nanos() as u64,
+    })
+}
+
+/// Parses a `--since` time: an RFC 3339 date, or `@` and a number of
+/// seconds since the epoch
+fn parse_since(s: &str) -> Result<SystemTime, String> {
+    if let Some(secs) = s.strip_prefix('@') {
+        let secs = secs.parse::<u64>().map_err(|e| e.to_string())?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(SystemTime::from)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns an `IndexWriterBuilder` with the limits given on the command line
+fn limits_builder(matches: &clap::ArgMatches) -> IndexWriterBuilder {
+    let mut builder = IndexWriterBuilder::new();
+    if let Some(t) = get_value_from_matches::<u64>(matches, "MAX_TRIGRAMS_COUNT") {
+        builder = builder.max_trigram_count(t);
+    }
+    if let Some(u) = get_value_from_matches::<f64>(matches, "MAX_INVALID_UTF8_RATIO") {
+        builder = builder.max_utf8_invalid(u);
+    }
+    if let Some(s) = get_value_from_matches::<u64>(matches, "MAX_FILE_SIZE_BYTES") {
+        builder = builder.max_file_len(s);
+    }
+    if let Some(b) = get_value_from_matches::<u64>(matches, "MAX_LINE_LEN_BYTES") {
+        builder = builder.max_line_len(b);
+    }
+    if let Some(b) = get_value_from_matches::<u64>(matches, "BINARY_SNIFF_BYTES") {
+        builder = builder.binary_sniff_len(b);
+    }
+    if let Some(n) = get_value_from_matches::<u64>(matches, "MAX_INDEX_BYTES") {
+        builder = builder.max_index_len(n);
+    }
+    if let Some(base) = relative_base(matches) {
+        builder = builder.relative_to(base);
+    }
+    builder
+        .dedup(matches.is_present("dedup"))
+        .reproducible(matches.is_present("reproducible"))
+        .compress(matches.is_present("compress"))
+}
+
+/// Returns the `--relative-to` directory, if given
+fn relative_base(matches: &clap::ArgMatches) -> Option<PathBuf> {
+    matches.value_of("RELATIVE_TO").map(|b| match normalize(b) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("--relative-to {}: {}", b, e);
+            std::process::exit(101);
+        }
+    })
+}
+
+/// Returns the name `path` is stored under in an index relative to `base`,
+/// see `IndexWriterBuilder::relative_to`
+fn stored_name(base: Option<&Path>, path: &str) -> String {
+    match base.map(|b| Path::new(path).strip_prefix(b)) {
+        Some(Ok(p)) if p.as_os_str().is_empty() => ".".to_string(),
+        Some(Ok(p)) => p.to_string_lossy().into_owned(),
+        _ => path.to_string(),
+    }
+}
+
+/// Set once cindex is interrupted, after which no more files are indexed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether cindex was interrupted
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Set once the index reaches `--max-index-bytes`, after which no more
+/// files are indexed
+static INDEX_FULL: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether indexing should stop taking new files
+fn stopping() -> bool {
+    interrupted() || INDEX_FULL.load(Ordering::SeqCst)
+}
+
+/// How long `--watch` waits for a burst of changes to end before
+/// updating the index
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// What `--watch` waits for
+enum WatchEvent {
+    Fs(notify::Result<notify::Event>),
+    Interrupt,
+}
+
+/// Calls `update` with the files to reindex and the paths to remove from
+/// the index as `events` reports changes under `roots`, until an
+/// interrupt. Changes that come in quick succession are batched.
+fn watch<F>(roots: &[PathBuf], events: &mpsc::Receiver<WatchEvent>, walker: &Walker, mut update: F)
+where
+    F: FnMut(&BTreeSet<PathBuf>, &BTreeSet<String>),
+{
+    let index_path = index_path_or_fail();
+    loop {
+        let mut batch = match events.recv() {
+            Ok(e) => vec![e],
+            Err(_) => return,
+        };
+        while let Ok(e) = events.recv_timeout(WATCH_DEBOUNCE) {
+            batch.push(e);
         }
-        i.add_paths(paths_cloned.into_iter().map(PathBuf::into_os_string));
-        let _frame = libprofiling::profile("Index files");
-        while let Ok(f) = rx.recv() {
-            if seen.contains(&f) {
+        let mut interrupted = false;
+        let mut paths = BTreeSet::new();
+        for e in batch {
+            match e {
+                WatchEvent::Interrupt => interrupted = true,
+                WatchEvent::Fs(Ok(e)) if !e.kind.is_access() => paths.extend(e.paths),
+                WatchEvent::Fs(Ok(_)) => (),
+                WatchEvent::Fs(Err(e)) => warn!("watch: {}", e),
+            }
+        }
+        // whatever happened to a path, what matters is its state now
+        let mut changed = BTreeSet::new();
+        let mut removed = BTreeSet::new();
+        for p in paths {
+            // the index and its temporary files may be under a root
+            if p.to_string_lossy().starts_with(&index_path) {
+                continue;
+            }
+            let root = match roots.iter().find(|r| p.starts_with(r)) {
+                Some(r) => r,
+                None => continue,
+            };
+            if !walker.keep_changed(root, &p) {
                 continue;
             }
-            if let Err(ref e) = i.add_file(&f) {
-                match e.kind() {
-                    IndexErrorKind::IoError(_) => warn!("{}: {}", Path::new(&f).display(), e),
-                    _ if log_skipped => warn!("{:?}: skipped. {}", f, e),
-                    _ => (),
+            match fs::symlink_metadata(&p) {
+                Ok(m) if m.is_dir() => walker.walk(&p, |f| {
+                    changed.insert(f.to_path_buf());
+                }),
+                Ok(m) => {
+                    let is_link = m.file_type().is_symlink();
+                    let followed = if is_link { p.metadata().ok() } else { Some(m) };
+                    let is_file = followed.is_some_and(|m| m.is_file());
+                    if is_file && (walker.follow_links || !is_link) && walker.extensions.keep(&p) {
+                        changed.insert(p);
+                    }
+                }
+                Err(_) => {
+                    removed.insert(p.to_string_lossy().into_owned());
                 }
             }
-            seen.insert(f);
         }
-        info!("flush index");
-        i.flush().expect("failed to flush index to disk");
-        // drop(_frame);
-        libprofiling::print_profiling();
-    });
+        if !changed.is_empty() || !removed.is_empty() {
+            debug!("{} changed, {} removed", changed.len(), removed.len());
+            update(&changed, &removed);
+        }
+        if interrupted {
+            return;
+        }
+    }
+}
 
-    for each_path in paths {
-        if !each_path.exists() {
-            warn!("{} - path doesn't exist. Skipping...", each_path.display());
+/// Updates the index at `index_path` for `--watch`: drops the `removed`
+/// paths, then reindexes the `changed` files with `builder`
+fn update_index(
+    index_path: &str,
+    builder: IndexWriterBuilder,
+    config: &IndexConfig,
+    changed: &BTreeSet<PathBuf>,
+    removed: &BTreeSet<String>,
+    log_skipped: bool,
+) -> io::Result<()> {
+    if !removed.is_empty() {
+        let removed = removed.iter().cloned().collect::<Vec<_>>();
+        let tmp_path = temp_index(index_path)?;
+        libcindex::merge::remove_paths(&tmp_path, index_path, &removed)?;
+        publish(tmp_path, index_path)?;
+    }
+    if !changed.is_empty() {
+        // index the changed files on their own, then merge them in to
+        // replace their old versions
+        let tmp_path = temp_index(index_path)?;
+        let mut i = builder.build(&tmp_path)?;
+        i.add_paths(changed.iter().map(|p| p.clone().into_os_string()));
+        let mut trigrammer = i.trigrammer();
+        let mut skips = SkipSummary::default();
+        for f in changed {
+            let t = trigrammer.read_file_with(f, &config.limits_for(f));
+            if let Err(ref e) = t.and_then(|t| i.add_trigrams(f, t)) {
+                log_skip(&f.clone().into_os_string(), e, log_skipped);
+                skips.add(e);
+            }
+        }
+        i.flush()?;
+        skips.print();
+        let merged_path = temp_index(index_path)?;
+        libcindex::merge::merge(&merged_path, index_path, &tmp_path)?;
+        publish(merged_path, index_path)?;
+    }
+    info!(
+        "updated: {} changed, {} removed",
+        changed.len(),
+        removed.len()
+    );
+    Ok(())
+}
+
+/// Computes the trigrams of each file in the tar archive `archive` that
+/// `walker` doesn't filter out, passing them to `add` under the names from
+/// `libcsearch::archive_entry_name`
+fn trigram_tar<F>(
+    archive: &Path,
+    trigrammer: &mut Trigrammer,
+    config: &IndexConfig,
+    walker: &Walker,
+    mut add: F,
+) where
+    F: FnMut(OsString, IndexResult<FileTrigrams>),
+{
+    debug!("index archive {}", archive.display());
+    let mut tar = match libcsearch::open_tar(archive) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("{}: can't read archive: {}", archive.display(), e);
+            return;
+        }
+    };
+    let entries = match tar.entries() {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("{}: can't read archive: {}", archive.display(), e);
+            return;
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                // the rest of the archive can't be found either
+                warn!("{}: can't read archive: {}", archive.display(), e);
+                return;
+            }
+        };
+        // directories, links and devices have no contents to index
+        if !entry.header().entry_type().is_file() {
             continue;
         }
-        if each_path.is_dir() {
-            debug!("index {}", each_path.display());
-            let tx = tx.clone();
-            let files = WalkDir::new(each_path)
-                .follow_links(true)
+        let path = match entry.path() {
+            Ok(p) => p.into_owned(),
+            Err(e) => {
+                warn!("{}: bad entry name: {}", archive.display(), e);
+                continue;
+            }
+        };
+        let path = path.strip_prefix(".").unwrap_or(&path);
+        if !walker.keep_entry(path) {
+            continue;
+        }
+        let name = libcsearch::archive_entry_name(archive, path);
+        let size = entry.size();
+        let t = trigrammer.read_with(entry, size, &config.limits_for(Path::new(&name)));
+        add(OsString::from(name), t);
+    }
+}
+
+/// Computes the trigrams of the contents `data` piped in for
+/// `--stdin-name name`, with the limits for `name` in `config`
+fn trigram_stdin(
+    name: &str,
+    data: &[u8],
+    trigrammer: &mut Trigrammer,
+    config: &IndexConfig,
+) -> IndexResult<FileTrigrams> {
+    debug!("index stdin as {}", name);
+    trigrammer.read_with(data, data.len() as u64, &config.limits_for(Path::new(name)))
+}
+
+/// The paths of the files `trigram_files` has received, so each is only
+/// read once. Only a 64-bit hash of each path is kept, which saves most of
+/// the memory on trees of millions of files. Two different paths with the
+/// same hash would have the second one skipped, but even among ten million
+/// paths the odds of that are about one in 370,000, and the hashes are
+/// keyed differently on each run.
+#[derive(Default)]
+struct SeenPaths {
+    hasher: RandomState,
+    hashes: HashSet<u64>,
+}
+
+impl SeenPaths {
+    /// Returns whether `path` wasn't seen before
+    fn insert(&mut self, path: &OsStr) -> bool {
+        self.hashes.insert(self.hasher.hash_one(path))
+    }
+}
+
+/// How many more times a file is read after an I/O error, see `--retries`
+#[derive(Clone, Copy)]
+struct Retries {
+    max: u32,
+    /// Log how each retried file ended up, for `--logskip`
+    log: bool,
+}
+
+/// How long to wait before reading a file again the first time; each
+/// retry after that waits twice as long as the one before
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Returns whether reading a file again might get past `e`. Errors about
+/// its contents, and files that are gone or can't be opened, stay the same.
+fn is_transient(e: &IndexError) -> bool {
+    match e.kind() {
+        IndexErrorKind::IoError(io::ErrorKind::NotFound)
+        | IndexErrorKind::IoError(io::ErrorKind::PermissionDenied) => false,
+        IndexErrorKind::IoError(_) => true,
+        _ => false,
+    }
+}
+
+/// Computes the trigrams of the file `f` with `trigrammer`, reading it
+/// again up to `retries.max` times while that fails with a transient I/O
+/// error, with exponential backoff
+fn read_file_retrying(
+    trigrammer: &mut Trigrammer,
+    f: &OsStr,
+    overrides: &LimitOverrides,
+    retries: Retries,
+) -> IndexResult<FileTrigrams> {
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        let t = fail_point(&format!("read {}", tries))
+            .map_err(IndexError::from)
+            .and_then(|_| trigrammer.read_file_with(f, overrides));
+        match t {
+            Err(ref e) if tries <= retries.max && is_transient(e) && !stopping() => {
+                debug!("{}: {}, reading it again", Path::new(f).display(), e);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Ok(_) if tries > 1 && retries.log => {
+                info!(
+                    "{}: read after {} retries",
+                    Path::new(f).display(),
+                    tries - 1
+                );
+                return t;
+            }
+            Err(_) if tries > 1 && retries.log => {
+                warn!(
+                    "{}: giving up after {} retries",
+                    Path::new(f).display(),
+                    tries - 1
+                );
+                return t;
+            }
+            _ => return t,
+        }
+    }
+}
+
+/// Reads the files received on `rx` with `num_threads` copies of
+/// `trigrammer` and the limits in `config`, and calls `add` with each of
+/// them in the order they were received. Files received more than once
+/// are only read once, and files that fail with a transient I/O error are
+/// read again as `retries` says.
+fn trigram_files<F>(
+    rx: mpsc::Receiver<OsString>,
+    trigrammer: Trigrammer,
+    config: &IndexConfig,
+    num_threads: usize,
+    retries: Retries,
+    mut add: F,
+) where
+    F: FnMut(OsString, IndexResult<FileTrigrams>),
+{
+    // files are numbered in the order they were found, and added to
+    // the index in that order whichever worker finishes first
+    let (work_tx, work_rx) = mpsc::channel::<(usize, OsString)>();
+    let work_rx = Mutex::new(work_rx);
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::scope(|s| {
+        s.spawn(move || {
+            let mut seen = SeenPaths::default();
+            let files = rx
                 .into_iter()
-                .filter_entry(|d| {
-                    let p = d.path();
-                    !excludes.iter().any(|r| r.matches_path(p))
-                })
-                .filter_map(Result::ok)
-                .filter(|d| !d.file_type().is_dir());
+                .take_while(|_| !stopping())
+                .filter(|f| seen.insert(f));
+            for each_file in files.enumerate() {
+                if work_tx.send(each_file).is_err() {
+                    break;
+                }
+            }
+        });
+        for _ in 0..num_threads {
+            let mut trigrammer = trigrammer.clone();
+            let work_rx = &work_rx;
+            let done_tx = done_tx.clone();
+            s.spawn(move || loop {
+                let next = work_rx.lock().unwrap().recv();
+                let (n, f) = match next {
+                    Ok(work) => work,
+                    Err(_) => break,
+                };
+                let overrides = config.limits_for(Path::new(&f));
+                let t = read_file_retrying(&mut trigrammer, &f, &overrides, retries);
+                if done_tx.send((n, f, t)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done_tx);
 
-            for d in files {
-                tx.send(OsString::from(d.path())).unwrap();
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (n, f, t) in done_rx {
+            pending.insert(n, (f, t));
+            while let Some((f, t)) = pending.remove(&next) {
+                next += 1;
+                add(f, t);
             }
-        } else if each_path.is_file() {
-            debug!("index file {}", each_path.display());
-            tx.send(OsString::from(each_path)).unwrap();
         }
+    });
+}
+
+/// Logs why `f` wasn't indexed
+fn log_skip(f: &OsString, e: &IndexError, log_skipped: bool) {
+    match e.kind() {
+        IndexErrorKind::IoError(_) => warn!("{}: {}", Path::new(f).display(), e),
+        _ if log_skipped => warn!("{:?}: skipped. {}", f, e),
+        _ => (),
     }
-    drop(tx);
-    h.join().unwrap();
-    if needs_merge {
-        let dest_path = index_path.clone() + "~";
-        let src1_path = libcsearch::csearch_index();
-        let src2_path = index_path.clone();
-        info!("merge {} {}", src1_path, src2_path);
-        libcindex::merge::merge(dest_path, src1_path, src2_path).unwrap();
-        fs::remove_file(index_path.clone()).unwrap();
-        fs::remove_file(libcsearch::csearch_index()).unwrap();
-        fs::rename(index_path + "~", libcsearch::csearch_index()).unwrap();
+}
+
+/// Counts of the files that were skipped from the index, by reason
+#[derive(Default)]
+struct SkipSummary {
+    skipped: BTreeMap<&'static str, usize>,
+}
+
+impl SkipSummary {
+    fn add(&mut self, e: &IndexError) {
+        let reason = match e.kind() {
+            IndexErrorKind::IoError(_) | IndexErrorKind::FlushFailed(_) => "io error",
+            IndexErrorKind::FileNameError => "bad file name",
+            IndexErrorKind::FileTooLong => "too large",
+            IndexErrorKind::LineTooLong => "line too long",
+            IndexErrorKind::TooManyTrigrams => "too many trigrams",
+            IndexErrorKind::BinaryDataPresent | IndexErrorKind::BinaryFile => "binary",
+            IndexErrorKind::HighInvalidUtf8Ratio => "bad utf8",
+            IndexErrorKind::DecompressionFailed => "can't decompress",
+            IndexErrorKind::InvalidUtf16 => "bad utf16",
+            IndexErrorKind::NoTrigrams => "empty",
+            IndexErrorKind::IndexFull => "index full",
+        };
+        *self.skipped.entry(reason).or_insert(0) += 1;
+    }
+    /// Logs the counts, most common reason first, so a big tree's skipped
+    /// files show up even without `--logskip`
+    fn print(&self) {
+        if self.skipped.is_empty() {
+            return;
+        }
+        let mut counts = self.skipped.iter().collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let counts = counts
+            .into_iter()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect::<Vec<_>>();
+        info!("skipped: {}", counts.join(", "));
     }
+}
 
-    info!("done");
-    libprofiling::print_profiling();
+/// Counts of the files indexed and skipped so far, shown with `--progress`
+struct Progress {
+    enabled: bool,
+    start: Instant,
+    /// When the counts were last shown
+    shown: Instant,
+    indexed: usize,
+    skipped: usize,
+}
+
+impl Progress {
+    /// How often the counts are shown
+    const INTERVAL: Duration = Duration::from_millis(250);
+
+    fn new(enabled: bool) -> Progress {
+        let now = Instant::now();
+        Progress {
+            enabled,
+            start: now,
+            shown: now,
+            indexed: 0,
+            skipped: 0,
+        }
+    }
+    /// Counts a file as indexed, or skipped if `!indexed`
+    fn add(&mut self, indexed: bool) {
+        if indexed {
+            self.indexed += 1;
+        } else {
+            self.skipped += 1;
+        }
+        if self.enabled && self.shown.elapsed() >= Progress::INTERVAL {
+            self.show();
+        }
+    }
+    /// Shows the final counts, which stay on screen
+    fn finish(&mut self) {
+        if self.enabled {
+            self.show();
+            libcustomlogger::end_status();
+        }
+    }
+    fn show(&mut self) {
+        let secs = self.start.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 {
+            (self.indexed + self.skipped) as f64 / secs
+        } else {
+            0.0
+        };
+        libcustomlogger::set_status(&format!(
+            "{} files indexed, {} skipped, {:.0} files/s",
+            self.indexed, self.skipped, rate
+        ));
+        self.shown = Instant::now();
+    }
+}
+
+/// Counts of the files that `--dry-run` would index or skip
+#[derive(Default)]
+struct DryRunReport {
+    indexed: usize,
+    skipped: BTreeMap<String, usize>,
+}
+
+impl DryRunReport {
+    fn add(&mut self, result: IndexResult<()>) {
+        match result {
+            Ok(()) => self.indexed += 1,
+            Err(e) => *self.skipped.entry(e.kind().to_string()).or_insert(0) += 1,
+        }
+    }
+    fn print(&self) {
+        println!("would index: {}", self.indexed);
+        println!("would skip: {}", self.skipped.values().sum::<usize>());
+        for (reason, count) in &self.skipped {
+            println!("  {}: {}", reason, count);
+        }
+    }
+}
+
+fn index_path_or_fail() -> String {
+    match libcsearch::csearch_index() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(101);
+        }
+    }
+}
+
+/// Takes the lock on the index at `index_path`, `index_path.lock`, which
+/// is held until the returned file is closed, so two runs of cindex can't
+/// update the same index at once
+fn lock_index(index_path: &str) -> io::Result<File> {
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(format!("{}.lock", index_path))?;
+    match f.try_lock() {
+        Ok(()) => Ok(f),
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "another cindex is updating the index",
+        )),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+fn lock_index_or_fail(index_path: &str) -> File {
+    match lock_index(index_path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("{}: {}", index_path, e);
+            std::process::exit(101);
+        }
+    }
+}
+
+/// Creates an empty temporary file beside the index at `index_path` to
+/// write a new index to, which is removed when dropped unless persisted.
+/// Its name starts with the index's, so `--watch` ignores it.
+fn temp_index(index_path: &str) -> io::Result<TempPath> {
+    let index_path = Path::new(index_path);
+    let dir = match index_path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let name = index_path.file_name().unwrap_or_default().to_string_lossy();
+    let f = tempfile::Builder::new()
+        .prefix(&format!("{}.", name))
+        .tempfile_in(dir)?;
+    Ok(f.into_temp_path())
+}
+
+fn temp_index_or_fail(index_path: &str) -> TempPath {
+    match temp_index(index_path) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{}: can't create a temporary index: {}", index_path, e);
+            std::process::exit(101);
+        }
+    }
+}
+
+/// Checks the new index at `tmp_path` as `--verify` does, then moves it
+/// over `index_path` in one step. Until then the old index is left as it
+/// is, so a failure anywhere before the rename leaves it intact; the new
+/// index is removed.
+fn publish(tmp_path: TempPath, index_path: &str) -> io::Result<()> {
+    IndexReader::open(&tmp_path)?
+        .verify()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    fail_point("publish")?;
+    copy_permissions(&tmp_path, Path::new(index_path))?;
+    tmp_path.persist(index_path).map_err(|e| e.error)
+}
+
+/// Gives the new index at `tmp_path`, which only its owner can read, the
+/// permissions of the index at `index_path`, or those a new file gets
+/// under the umask if there's no index there yet
+#[cfg(unix)]
+fn copy_permissions(tmp_path: &Path, index_path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let permissions = match fs::metadata(index_path) {
+        Ok(m) => m.permissions(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            // the umask can only be read by setting it
+            let umask = unsafe {
+                let umask = libc::umask(0o022);
+                libc::umask(umask);
+                umask
+            };
+            fs::Permissions::from_mode(0o666 & !(umask as u32))
+        }
+        Err(e) => return Err(e),
+    };
+    fs::set_permissions(tmp_path, permissions)
+}
+
+#[cfg(not(unix))]
+fn copy_permissions(_tmp_path: &Path, _index_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Exits for the error `e` writing to stdout: quietly if stdout was
+/// closed by its reader, as with `| head`, or else with an error
+fn write_failed(e: io::Error) -> ! {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+    error!("write stdout: {}", e);
+    std::process::exit(101);
+}
+
+fn publish_or_fail(tmp_path: TempPath, index_path: &str) {
+    if let Err(e) = publish(tmp_path, index_path) {
+        error!("{}: {}; it is unchanged", index_path, e);
+        std::process::exit(101);
+    }
+}
+
+/// Fails if `$CINDEX_FAIL_AT` is `point`, so tests can check what a
+/// failure there leaves behind. Only built with the `fail-points` feature.
+#[cfg(feature = "fail-points")]
+fn fail_point(point: &str) -> io::Result<()> {
+    if env::var_os("CINDEX_FAIL_AT").is_some_and(|p| p == point) {
+        return Err(io::Error::other(format!("failed at {}", point)));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "fail-points"))]
+fn fail_point(_point: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// An indexed path, as listed by `--list-json`
+#[derive(Serialize)]
+struct IndexedPath {
+    path: String,
+    /// Number of indexed files under `path`
+    files: usize,
+}
+
+/// Returns the indexed paths of `i` along with what's known about them
+fn indexed_path_infos(i: &IndexReader) -> Vec<IndexedPath> {
+    let mut paths = i
+        .indexed_paths()
+        .into_iter()
+        .map(|path| IndexedPath { path, files: 0 })
+        .collect::<Vec<_>>();
+    for name in (0..i.num_name as u32).map(|n| i.name(n)) {
+        // paths are sorted, so the last one that contains the file is
+        // the innermost
+        let root = paths.iter_mut().rev().find(|p| {
+            Path::new(&name).starts_with(&p.path)
+                || libcsearch::split_archive_entry_name(&name)
+                    .is_some_and(|(a, _)| a == Path::new(&p.path))
+        });
+        if let Some(root) = root {
+            root.files += 1;
+        }
+    }
+    paths
+}
+
+/// Returns the `--stats` summary of an index as (name, value) pairs
+fn index_stats(i: &IndexReader) -> Vec<(&'static str, String)> {
+    let size = i.len() as f64;
+    let human_size = if size < 1024.0 {
+        format!("{} B", i.len())
+    } else if size < 1024.0 * 1024.0 {
+        format!("{:.1} KiB ({} bytes)", size / 1024.0, i.len())
+    } else {
+        format!("{:.1} MiB ({} bytes)", size / (1024.0 * 1024.0), i.len())
+    };
+    vec![
+        ("paths", i.indexed_paths().len().to_string()),
+        ("files", i.num_name.to_string()),
+        ("trigrams", i.num_trigrams().to_string()),
+        ("size", human_size),
+        ("version", i.format_version().to_string()),
+    ]
+}
+
+/// Returns the `n` trigrams with the longest posting lists, most files
+/// first, escaped to be printable, along with their number of files
+fn trigram_histogram(i: &IndexReader, n: usize) -> Vec<(String, u32)> {
+    let mut counts = (0..i.num_trigrams())
+        .map(|k| {
+            let (trigram, count, _) = i.list_at(k * POST_ENTRY_SIZE);
+            (count, trigram)
+        })
+        .collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    counts
+        .into_iter()
+        .take(n)
+        .map(|(count, t)| {
+            let bytes = [(t >> 16) as u8, (t >> 8) as u8, t as u8];
+            (bytes.escape_ascii().to_string(), count)
+        })
+        .collect()
 }
 
 fn open_index_or_fail() -> IndexReader {
-    let index_path = libcsearch::csearch_index();
+    let index_path = index_path_or_fail();
     match IndexReader::open(&index_path) {
         Ok(i) => i,
+        Err(OpenError::NotFound) => {
+            error!(
+                "{}: no index yet, run cindex with paths to index",
+                index_path
+            );
+            std::process::exit(101);
+        }
+        Err(e @ OpenError::BadMagic)
+        | Err(e @ OpenError::Truncated)
+        | Err(e @ OpenError::Corrupt(_)) => {
+            error!(
+                "open {}: {}; run cindex --reset to rebuild it",
+                index_path, e
+            );
+            std::process::exit(101);
+        }
         Err(e) => {
             error!("open {}: {}", index_path, e);
             std::process::exit(101);
         }
     }
 }
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_unc() {
+        assert_eq!(
+            normalize(r"\\server\share\a\..\b").unwrap(),
+            PathBuf::from(r"\\server\share\b")
+        );
+        assert_eq!(
+            normalize(r"\\?\UNC\server\share\b").unwrap(),
+            PathBuf::from(r"\\server\share\b")
+        );
+        assert_eq!(normalize(r"\\?\C:\x").unwrap(), PathBuf::from(r"C:\x"));
+        // these only mean the same thing with the prefix
+        assert_eq!(
+            normalize(r"\\?\C:\x\nul.txt").unwrap(),
+            PathBuf::from(r"\\?\C:\x\nul.txt")
+        );
+        assert_eq!(
+            normalize(r"\\?\C:\x\..").unwrap(),
+            PathBuf::from(r"\\?\C:\x\..")
+        );
+    }
+
+    #[test]
+    fn test_normalize_drive_relative() {
+        let cwd = env::current_dir().unwrap();
+        let drive = match cwd.components().next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(_) => prefix.as_os_str().to_owned(),
+                _ => return,
+            },
+            _ => return,
+        };
+        let mut p = drive;
+        p.push(r"a\..\b");
+        assert_eq!(normalize(p).unwrap(), cwd.join("b"));
+    }
+}
@@ -12,7 +12,9 @@ extern crate log;
 extern crate libc;
 extern crate memchr;
 extern crate regex;
-extern crate regex_syntax;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
 extern crate termcolor;
 
 extern crate consts;
@@ -20,19 +22,29 @@ extern crate libcsearch;
 extern crate libcustomlogger;
 extern crate libvarint;
 
-use libcsearch::reader::IndexReader;
-use libcsearch::regexp::{Query, RegexInfo};
+use libcsearch::reader::{CorruptIndex, IndexReader, PostReader};
+use libcsearch::regexp::{query_from_literal, query_from_regexp, Query};
+use libcsearch::searcher::SearchStats;
+use libcsearch::ContentsOpener;
+use libcustomlogger::color::{self, ColorWhen};
 
-use std::collections::BTreeSet;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::env;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
 
 use grep::{Grep, GrepBuilder};
 use regex::bytes;
 use regex::Regex;
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use termcolor::{Buffer, BufferWriter, Color, ColorChoice, ColorSpec, WriteColor};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PrintFormat {
@@ -48,16 +60,77 @@ pub enum LinePart {
     Match,
 }
 
-#[derive(Debug)]
+/// How `--column` counts the column of a match
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Column {
+    /// 1-based byte offset into the line
+    Bytes,
+    /// 1-based offset into the line in UTF-8 characters; each invalid
+    /// sequence counts as one character
+    Chars,
+}
+
+#[derive(Debug, Clone)]
 pub struct MatchOptions {
     pub pattern: String,
     pub print_format: PrintFormat,
     pub print_count: bool,
+    pub count_matches: bool,
     pub ignore_case: bool,
     pub files_with_matches_only: bool,
+    pub files_without_match: bool,
     pub line_number: bool,
+    pub column: Option<Column>,
+    pub byte_offset: bool,
+    pub null: bool,
     pub with_color: bool,
     pub max_count: Option<usize>,
+    pub json: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub multiline: bool,
+    /// Each of several `-e` patterns, as given and as matched, so `--json`
+    /// can tell which one a match is for
+    pub alternatives: Vec<(String, bytes::Regex)>,
+}
+
+/// Upper bound on the bytes of leading context buffered per file. Once
+/// exceeded the oldest lines are dropped, so a huge `-B` on a file with
+/// few matches can't hold the whole file in memory.
+const MAX_BEFORE_CONTEXT_BYTES: usize = 1 << 20;
+
+/// Files larger than this are skipped by `--multiline`, which reads each
+/// file into memory whole
+const MAX_MULTILINE_FILE_LEN: u64 = 64 << 20;
+
+/// A single match, as printed by `--json`
+#[derive(Serialize)]
+struct JsonMatch<'a> {
+    path: &'a str,
+    line_number: usize,
+    column: usize,
+    text: &'a str,
+    /// Number of lines the match spans, with `--multiline`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lines: Option<usize>,
+    /// Byte offset of the match in the file, with `--byte-offset`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    byte_offset: Option<u64>,
+    /// The `-e` pattern that matched, if several were given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<&'a str>,
+}
+
+/// Trailing record printed by `--json` once every file has been searched
+#[derive(Serialize)]
+struct JsonSummary {
+    summary: JsonCounts,
+}
+
+#[derive(Serialize)]
+struct JsonCounts {
+    matches: usize,
+    files: usize,
 }
 
 const ABOUT: &str = "
@@ -73,10 +146,125 @@ where path... is a list of directories or individual files to be included in the
 If no index exists, this command creates one.  If an index already exists, cindex
 overwrites it.  Run cindex --help for more.
 
-Csearch uses the index stored in $CSEARCHINDEX or, if that variable is unset or
-empty, $HOME/.csearchindex.
+Csearch uses the index named by --indexpath, or else the index stored in
+$CSEARCHINDEX or, if that variable is unset or empty, $HOME/.csearchindex.
+
+Like grep, csearch exits with status 0 if it found a match, 1 if it found none,
+and 2 if an error stopped the search, such as a missing index or a bad pattern.
+
+With --files-without-match (-L), csearch lists the indexed files that don't
+match instead, exiting with status 0 if it listed any.  The index only rules
+out files that can't match, so this goes through every indexed file and
+searches each one that might match, which is much slower than a normal search.
+
+Files are searched, and their matches printed, in the order they were
+indexed, which is the same from one run to the next (--order=path).  With
+--order=size the smallest files go first, so the first matches show up
+sooner, and with --order=relevance the files holding the most trigrams of
+the pattern go first.  Either way the order no longer follows the index,
+and it may change as files are edited.
+
+With --root PATH, only the files under PATH, one of the paths that were
+indexed, are searched.  It may be given more than once.
+
+The names in an index built with cindex --relative-to are relative to the
+directory it was built from.  They're opened and printed relative to the
+current directory, or under the directory BASE with --prefix BASE.
+
+With -F (--fixed-strings), the pattern is a literal string, so characters
+like + and [ in it match themselves.
+
+With --stats, csearch prints a line like
+
+	stats: candidates=12 scanned=12 matched_files=3 matches=5 elapsed=1.234ms
+
+to stderr after searching: the files the index selected, those searched, those
+with a match, the number of matches printed, and the time taken.  It shows how
+well the index narrows down the files for a pattern.
+
+With -e PATTERN, given any number of times, csearch searches for lines that
+match any of the patterns.  The candidate files of all of them are searched
+together, each one once, and with --json each match names the pattern that
+matched it.
+
+With --server-stdin, csearch opens the index once, then reads one regexp per
+line from stdin and searches for each in turn until end of input, so a
+front end can run many queries without starting csearch again.  The output
+for each regexp, which may be nothing, is followed by an empty line, or by a
+NUL byte with --null.  A bad regexp is reported on stderr and its output is
+empty.
+
+With --files-from FILE, csearch doesn't use the index: it searches every file
+listed in FILE, one path per line, or in stdin if FILE is -, so the output of
+find or git ls-files can be piped into it.  The path filters, --order=size
+and the output options work as usual; options that need the index, such as
+--root and --brute, can't be combined with it.
+
+With -o FILE (--output FILE), the results are written to FILE, which is
+created or truncated, instead of to stdout, in any of the output formats.
+Errors and --stats still go to stderr.  csearch exits with status 2 if the
+results can't all be written.
 ";
 
+/// Order in which the candidate files are searched and printed, see `--order`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SearchOrder {
+    Path,
+    Size,
+    Relevance,
+}
+
+/// Returns the files of `post` in the order they should be searched. Files
+/// that tie stay in path order.
+fn order_candidates(
+    index: &IndexReader,
+    query: &Query,
+    post: BTreeSet<u32>,
+    order: SearchOrder,
+) -> Result<Vec<u32>, CorruptIndex> {
+    let mut file_ids = post.into_iter().collect::<Vec<_>>();
+    match order {
+        SearchOrder::Path => (),
+        SearchOrder::Size => {
+            // prefer the size recorded in the index to a stat of every file;
+            // files whose size is unknown go last
+            file_ids.sort_by_cached_key(|&file_id| match index.file_metadata(file_id) {
+                Some(meta) => meta.size,
+                None => fs::metadata(index.name(file_id)).map_or(u64::MAX, |m| m.len()),
+            });
+        }
+        SearchOrder::Relevance => {
+            let mut trigrams = BTreeSet::new();
+            collect_trigrams(query, &mut trigrams);
+            let mut counts = HashMap::new();
+            for each_trigram in trigrams {
+                for file_id in PostReader::list(index, each_trigram, &None)? {
+                    *counts.entry(file_id).or_insert(0) += 1;
+                }
+            }
+            // aliases have no posting lists of their own
+            file_ids.sort_by_key(|&file_id| {
+                let id = index.alias_of(file_id).unwrap_or(file_id);
+                Reverse(counts.get(&id).cloned().unwrap_or(0))
+            });
+        }
+    }
+    Ok(file_ids)
+}
+
+/// Adds every trigram that appears anywhere in `query` to `trigrams`
+fn collect_trigrams(query: &Query, trigrams: &mut BTreeSet<u32>) {
+    trigrams.extend(
+        query
+            .trigram
+            .iter()
+            .map(|t| (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32)),
+    );
+    for each_sub in &query.sub {
+        collect_trigrams(each_sub, trigrams);
+    }
+}
+
 pub fn main() {
     libcustomlogger::init(log::LevelFilter::Info).unwrap();
 
@@ -90,10 +278,35 @@ pub fn main() {
         .arg(
             clap::Arg::with_name("PATTERN")
                 .help("a regular expression to search with")
-                .required(true)
+                .required_unless_one(&["PATTERN_FILE", "server-stdin", "REGEXP"])
+                .conflicts_with_all(&["PATTERN_FILE", "server-stdin", "REGEXP"])
                 .use_delimiter(false)
                 .index(1),
         )
+        .arg(
+            clap::Arg::with_name("REGEXP")
+                .short("e")
+                .long("regexp")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .conflicts_with_all(&["PATTERN_FILE", "server-stdin"])
+                .help("search for PATTERN; may be repeated to search for lines matching any of them"),
+        )
+        .arg(
+            clap::Arg::with_name("PATTERN_FILE")
+                .long("pattern-file")
+                .takes_value(true)
+                .help("read the regular expression from PATTERN_FILE, or from stdin if it is -"),
+        )
+        .arg(
+            clap::Arg::with_name("server-stdin")
+                .long("server-stdin")
+                .conflicts_with("PATTERN_FILE")
+                .help("read one regular expression per line from stdin and search for each, ending each one's output with an empty line (a NUL with --null)"),
+        )
         .arg(
             clap::Arg::with_name("count")
                 .short("c")
@@ -101,18 +314,25 @@ pub fn main() {
                 .help("print only a count of matching lines per file"),
         )
         .arg(
-            clap::Arg::with_name("color")
+            clap::Arg::with_name("count-matches")
+                .long("count-matches")
+                .help("print only a count of matches per file, counting each match on a line"),
+        )
+        .arg(
+            clap::Arg::with_name("WHEN")
                 .long("color")
-                .help("highlight matching strings")
+                .takes_value(true)
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto")
                 .overrides_with("nocolor")
-                .hidden(!cfg!(feature = "color")),
+                .help("color matches, paths and line numbers: auto (only on a terminal, and not if $NO_COLOR is set), always or never"),
         )
         .arg(
             clap::Arg::with_name("nocolor")
                 .long("nocolor")
-                .help("don't highlight matching strings")
-                .overrides_with("color")
-                .hidden(!cfg!(feature = "color")),
+                .alias("no-color")
+                .help("don't color the output, same as --color=never")
+                .overrides_with("WHEN"),
         )
         .arg(
             clap::Arg::with_name("FILE_PATTERN")
@@ -121,11 +341,51 @@ pub fn main() {
                 .help("limit search to filenames matching FILE_PATTERN")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("PATH_FILTER")
+                .short("f")
+                .long("path-filter")
+                .help("only search files whose path matches the regular expression PATH_FILTER")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ROOT")
+                .long("root")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("only search files under ROOT, one of the paths in the index (see cindex --list); may be repeated"),
+        )
+        .arg(
+            clap::Arg::with_name("PREFIX")
+                .long("prefix")
+                .takes_value(true)
+                .value_name("BASE")
+                .help("open and print the files of an index built with cindex --relative-to as under the directory BASE"),
+        )
+        .arg(
+            clap::Arg::with_name("PATH_EXCLUDE")
+                .long("path-exclude")
+                .help("don't search files whose path matches the regular expression PATH_EXCLUDE")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("ignore-case")
                 .short("i")
                 .long("ignore-case")
-                .help("Match case insensitively"),
+                .help("Match case insensitively (the index is queried for every case variant)"),
+        )
+        .arg(
+            clap::Arg::with_name("word-regexp")
+                .short("w")
+                .long("word-regexp")
+                .help("only match whole words, as if PATTERN were \\b(?:PATTERN)\\b"),
+        )
+        .arg(
+            clap::Arg::with_name("fixed-strings")
+                .short("F")
+                .long("fixed-strings")
+                .help("take PATTERN as a literal string rather than a regular expression"),
         )
         .arg(clap::Arg::with_name("files").long("files").help(
             "Print each file that would be searched without actually performing the \
@@ -135,254 +395,1385 @@ pub fn main() {
             clap::Arg::with_name("files-with-matches")
                 .short("l")
                 .long("files-with-matches")
+                .conflicts_with_all(&["count", "count-matches"])
                 .help("Only print filenames that contain matches (don't print the matching lines)"),
         )
+        .arg(
+            clap::Arg::with_name("files-without-match")
+                .short("L")
+                .long("files-without-match")
+                .alias("invert-match-files")
+                .conflicts_with_all(&["count", "count-matches", "files", "files-with-matches", "json"])
+                .help("Only print the names of indexed files that don't contain a match (slower: every indexed file is considered)"),
+        )
         .arg(
             clap::Arg::with_name("line-number")
                 .short("n")
                 .long("line-number")
                 .help("print line number with output lines"),
         )
+        .arg(
+            clap::Arg::with_name("column")
+                .long("column")
+                .help("print the 1-based byte column of the first match on each line (implies -n)"),
+        )
+        .arg(
+            clap::Arg::with_name("column-chars")
+                .long("column-chars")
+                .help("like --column, but count the column in characters instead of bytes"),
+        )
+        .arg(
+            clap::Arg::with_name("byte-offset")
+                .short("b")
+                .long("byte-offset")
+                .conflicts_with("visual-studio-format")
+                .help("print the 0-based byte offset in the file of the start of the first match on each line, before the line (context lines get the offset of their start)"),
+        )
+        .arg(
+            clap::Arg::with_name("null")
+                .long("null")
+                .conflicts_with_all(&["visual-studio-format", "json"])
+                .help("separate the path, line and column from each other and the line with NUL bytes"),
+        )
         .arg(
             clap::Arg::with_name("visual-studio-format")
                 .long("format-vs")
                 .help("print lines in a format that can be parsed by Visual Studio 2008"),
         )
+        .arg(
+            clap::Arg::with_name("json")
+                .long("json")
+                .conflicts_with_all(&["count", "count-matches", "files", "files-with-matches"])
+                .help("print one JSON object per match, followed by a summary object"),
+        )
+        .arg(
+            clap::Arg::with_name("multiline")
+                .short("U")
+                .long("multiline")
+                .conflicts_with_all(&["AFTER_NUM", "BEFORE_NUM", "CONTEXT_NUM"])
+                .help("let matches span lines: . also matches a newline, and each match is printed whole, after its first and last line numbers"),
+        )
+        .arg(
+            clap::Arg::with_name("AFTER_NUM")
+                .short("A")
+                .long("after-context")
+                .takes_value(true)
+                .help("print NUM lines of trailing context after each match"),
+        )
+        .arg(
+            clap::Arg::with_name("BEFORE_NUM")
+                .short("B")
+                .long("before-context")
+                .takes_value(true)
+                .help("print NUM lines of leading context before each match"),
+        )
+        .arg(
+            clap::Arg::with_name("CONTEXT_NUM")
+                .short("C")
+                .long("context")
+                .takes_value(true)
+                .help("print NUM lines of context around each match"),
+        )
         .arg(
             clap::Arg::with_name("NUM")
                 .short("m")
                 .long("max-count")
                 .takes_value(true)
-                .help("stop after NUM matches"),
+                .help("stop searching a file after NUM matching lines"),
+        )
+        .arg(
+            clap::Arg::with_name("LIMIT")
+                .long("limit")
+                .takes_value(true)
+                .conflicts_with_all(&["count", "count-matches"])
+                .help("stop the whole search after printing LIMIT matching lines"),
+        )
+        .arg(
+            clap::Arg::with_name("THREADS")
+                .short("j")
+                .long("threads")
+                .takes_value(true)
+                .help("search files with THREADS threads (defaults to the number of CPUs)"),
+        )
+        .arg(
+            clap::Arg::with_name("ORDER")
+                .long("order")
+                .takes_value(true)
+                .possible_values(&["path", "size", "relevance"])
+                .default_value("path")
+                .help("search and print files in index order (path), smallest first (size), or most pattern trigrams first (relevance)"),
+        )
+        .arg(
+            clap::Arg::with_name("explain")
+                .long("explain")
+                .help("print the trigram query derived from PATTERN and the number of candidate files to stderr, then search"),
+        )
+        .arg(
+            clap::Arg::with_name("stats")
+                .long("stats")
+                .conflicts_with_all(&["files", "files-without-match"])
+                .help("print the number of candidate files, files searched, files with matches and matches, and the time taken, to stderr"),
         )
         .arg(
             clap::Arg::with_name("bruteforce")
                 .long("brute")
                 .help("brute force - search all files in the index"),
         )
+        .arg(
+            clap::Arg::with_name("FILES_FROM")
+                .long("files-from")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["server-stdin", "bruteforce", "explain", "ROOT", "PREFIX"])
+                .help("search the files listed in FILE, one per line, or read from stdin if it is -, without using the index"),
+        )
+        .arg(
+            clap::Arg::with_name("OUTPUT")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("server-stdin")
+                .help("write the results to FILE instead of stdout"),
+        )
         .arg(
             clap::Arg::with_name("INDEX_FILE")
                 .long("indexpath")
                 .takes_value(true)
                 .help("use specified INDEX_FILE as the index path. overrides $CSEARCHINDEX."),
         )
-        .get_matches();
+        .get_matches_safe()
+        .unwrap_or_else(|e| {
+            if !e.use_stderr() {
+                // --help and --version
+                e.exit();
+            }
+            eprintln!("{}", e.message);
+            std::process::exit(2);
+        });
 
     // possibly add ignore case flag to the pattern
     let ignore_case = matches.is_present("ignore-case");
 
-    // get the pattern provided by the user
-    let pattern = matches.value_of("PATTERN").expect("Failed to get PATTERN");
-
-    // possibly override the csearchindex
-    if let Some(p) = matches.value_of("INDEX_FILE") {
-        env::set_var("CSEARCHINDEX", p);
-    }
+    let context_arg = |name: &str| {
+        matches.value_of(name).map(|s| match s.parse::<usize>() {
+            Ok(n) => n,
+            Err(parse_err) => fail(format!("{}: {}", name, parse_err)),
+        })
+    };
+    let context = context_arg("CONTEXT_NUM").unwrap_or(0);
+    let limit = context_arg("LIMIT");
+    color::set_when(match matches.value_of("WHEN") {
+        _ if matches.is_present("nocolor") => ColorWhen::Never,
+        Some("always") => ColorWhen::Always,
+        Some("never") => ColorWhen::Never,
+        _ => ColorWhen::Auto,
+    });
 
-    // combine cmdline options used for matching/output into a structure
+    // combine cmdline options used for matching/output into a structure;
+    // the pattern is filled in for each query
     let match_options = MatchOptions {
-        pattern: pattern.to_string(),
+        pattern: String::new(),
         print_format: if matches.is_present("visual-studio-format") {
             PrintFormat::VisualStudio
         } else {
             PrintFormat::Normal
         },
-        print_count: matches.is_present("count"),
+        print_count: matches.is_present("count") || matches.is_present("count-matches"),
+        count_matches: matches.is_present("count-matches"),
         ignore_case,
         files_with_matches_only: matches.is_present("files-with-matches"),
+        files_without_match: matches.is_present("files-without-match"),
         line_number: matches.is_present("line-number")
-            || matches.is_present("visual-studio-format"),
-        with_color: !matches.is_present("nocolor")
-            && !matches.is_present("visual-studio-format")
-            && atty::is(atty::Stream::Stdout),
-        max_count: matches.value_of("NUM").map(|s| match s.parse::<usize>() {
-            Ok(n) => n,
-            Err(parse_err) => panic!("NUM: {}", parse_err),
-        }),
+            || matches.is_present("multiline")
+            || matches.is_present("visual-studio-format")
+            || matches.is_present("column")
+            || matches.is_present("column-chars"),
+        column: if matches.is_present("column-chars") {
+            Some(Column::Chars)
+        } else if matches.is_present("column") {
+            Some(Column::Bytes)
+        } else {
+            None
+        },
+        byte_offset: matches.is_present("byte-offset"),
+        null: matches.is_present("null"),
+        // Visual Studio parses the output, so it's only colored on request,
+        // as is output to a file
+        with_color: !matches.is_present("json")
+            && (color::when() != ColorWhen::Auto || !matches.is_present("visual-studio-format"))
+            && if matches.is_present("OUTPUT") {
+                color::when() == ColorWhen::Always
+            } else {
+                color::enabled(color::Stream::Stdout)
+            },
+        // no single file can print more than the overall limit either
+        max_count: match (context_arg("NUM"), limit) {
+            (Some(n), Some(l)) => Some(std::cmp::min(n, l)),
+            (n, l) => n.or(l),
+        },
+        json: matches.is_present("json"),
+        before_context: context_arg("BEFORE_NUM").unwrap_or(context),
+        after_context: context_arg("AFTER_NUM").unwrap_or(context),
+        multiline: matches.is_present("multiline"),
+        alternatives: Vec::new(),
     };
 
     // Get the index from file
-    let index_path = libcsearch::csearch_index();
-    let index_reader = match IndexReader::open(index_path) {
-        Ok(i) => i,
-        Err(e) => panic!("{}", e),
+    let index_path = || match matches.value_of("INDEX_FILE") {
+        Some(p) => p.to_string(),
+        None => libcsearch::csearch_index().unwrap_or_else(|e| fail(e)),
     };
-
-    // Find all possibly matching files using the pseudo-regexp
-    let mut post: BTreeSet<u32> = if matches.is_present("bruteforce") {
-        index_reader.query(Query::all()).into_inner()
-    } else {
-        // Get the pseudo-regexp (built using trigrams)
-        let expr = regex_syntax::ExprBuilder::new()
-            .unicode(false)
-            .case_insensitive(matches.is_present("ignore-case"))
-            .parse(pattern)
-            .unwrap();
-        let q = RegexInfo::new(expr).unwrap().query;
-        // panic!("query = {} --- {:?}", q.format_as_string(), q);
-
-        index_reader.query(q).into_inner()
+    // with --files-from the index isn't used at all
+    let files_from = matches.value_of("FILES_FROM");
+    let source = match files_from {
+        Some(p) => {
+            if p == "-" && matches.value_of("PATTERN_FILE") == Some("-") {
+                fail("--files-from and --pattern-file can't both read stdin");
+            }
+            if matches.value_of("ORDER") == Some("relevance") {
+                fail("--order=relevance needs the index, it can't be used with --files-from");
+            }
+            let files = read_file_list(p).unwrap_or_else(|e| fail(format!("{}: {}", p, e)));
+            Source::Files(files)
+        }
+        None => match IndexReader::open(index_path()) {
+            Ok(i) => Source::Index(i),
+            Err(e) => fail(format!("open {}: {}", index_path(), e)),
+        },
     };
-    // println!("identified {} possible queries", post.len());
 
-    // If provided, filter possibly matching files via FILE_PATTERN
-    if let Some(file_pattern_str) = matches.value_of("FILE_PATTERN") {
-        let file_pattern = match Regex::new(file_pattern_str) {
+    // If provided, filter possibly matching files by path before any of
+    // them are opened
+    let path_regex = |name: &str| {
+        matches.value_of(name).map(|s| match Regex::new(s) {
             Ok(r) => r,
-            Err(e) => panic!("FILE_PATTERN: {}", e),
+            Err(e) => fail(format!("{}: {}", name, e)),
+        })
+    };
+    let prefix = matches.value_of("PREFIX").map(Path::new);
+    let num_threads = match matches.value_of("THREADS") {
+        Some(s) => match s.parse::<usize>() {
+            Ok(0) => fail("THREADS: must be at least 1"),
+            Ok(n) => n,
+            Err(parse_err) => fail(format!("THREADS: {}", parse_err)),
+        },
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let output = matches.value_of("OUTPUT").map(|p| match File::create(p) {
+        Ok(f) => Output {
+            path: p.to_string(),
+            file: RefCell::new(BufWriter::new(f)),
+        },
+        Err(e) => fail(format!("{}: {}", p, e)),
+    });
+    let search = Search {
+        source: &source,
+        match_options,
+        word_regexp: matches.is_present("word-regexp"),
+        fixed_strings: matches.is_present("fixed-strings"),
+        brute: matches.is_present("bruteforce"),
+        explain: matches.is_present("explain"),
+        stats: matches.is_present("stats"),
+        order: match matches.value_of("ORDER") {
+            Some("size") => SearchOrder::Size,
+            Some("relevance") => SearchOrder::Relevance,
+            _ => SearchOrder::Path,
+        },
+        includes: ["FILE_PATTERN", "PATH_FILTER"]
+            .iter()
+            .filter_map(|name| path_regex(name))
+            .collect(),
+        exclude: path_regex("PATH_EXCLUDE"),
+        roots: match (matches.values_of("ROOT"), &source) {
+            (Some(roots), Source::Index(index_reader)) => roots
+                .map(|r| indexed_root(index_reader, prefix, r))
+                .collect(),
+            _ => Vec::new(),
+        },
+        prefix: prefix.map(Path::to_path_buf),
+        list_files: matches.is_present("files"),
+        limit,
+        num_threads,
+        output,
+    };
+
+    if matches.is_present("server-stdin") {
+        // after each query's results comes an empty record, so a front end
+        // can tell where they end
+        let sentinel: &[u8] = if search.match_options.null {
+            b"\0"
+        } else {
+            b"\n"
+        };
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = line.unwrap_or_else(|e| fail(format!("stdin: {}", e)));
+            let pattern = line.strip_suffix('\r').unwrap_or(&line);
+            if let Err(e) = search.run(&[pattern]) {
+                error!("{}", e);
+            }
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            if out.write_all(sentinel).and_then(|_| out.flush()).is_err() {
+                // the front end went away
+                break;
+            }
+        }
+        std::process::exit(0);
+    }
+
+    // get the patterns provided by the user
+    let patterns = match (
+        matches.value_of("PATTERN_FILE"),
+        matches.values_of("REGEXP"),
+    ) {
+        (Some(p), _) => {
+            vec![read_pattern_file(p).unwrap_or_else(|e| fail(format!("{}: {}", p, e)))]
+        }
+        (None, Some(patterns)) => patterns.map(String::from).collect(),
+        (None, None) => vec![matches
+            .value_of("PATTERN")
+            .expect("Failed to get PATTERN")
+            .to_string()],
+    };
+    let patterns = patterns.iter().map(String::as_str).collect::<Vec<_>>();
+    match search.run(&patterns) {
+        // --files lists the files it would search without finding matches
+        Ok(n) if n > 0 || search.list_files => std::process::exit(0),
+        Ok(_) => std::process::exit(1),
+        Err(e) => fail(e),
+    }
+}
+
+/// Where the files to search come from
+enum Source {
+    /// The candidates for each pattern are found in the index
+    Index(IndexReader),
+    /// Every file of the list is searched, see `--files-from`
+    Files(Vec<String>),
+}
+
+/// The file given with `--output`, which the results are written to
+/// instead of stdout
+struct Output {
+    path: String,
+    file: RefCell<BufWriter<File>>,
+}
+
+/// What every query of a csearch run shares: the index, and the options
+/// that don't depend on the pattern
+struct Search<'a> {
+    source: &'a Source,
+    /// Options for printing matches; the pattern is set for each query
+    match_options: MatchOptions,
+    word_regexp: bool,
+    /// The pattern is a literal string, see `--fixed-strings`
+    fixed_strings: bool,
+    brute: bool,
+    explain: bool,
+    /// Print a `SearchStats` line to stderr after each search
+    stats: bool,
+    order: SearchOrder,
+    /// Only files matching all of these are searched
+    includes: Vec<Regex>,
+    /// Files matching this aren't searched
+    exclude: Option<Regex>,
+    /// If any, only files under one of these indexed paths are searched
+    roots: Vec<String>,
+    /// Directory the names in a relative index are under, see `--prefix`
+    prefix: Option<PathBuf>,
+    /// Only list the files that would be searched
+    list_files: bool,
+    limit: Option<usize>,
+    num_threads: usize,
+    output: Option<Output>,
+}
+
+impl<'a> Search<'a> {
+    /// Returns the name of a file to open and print
+    fn name(&self, file_id: u32) -> String {
+        let index_reader = match self.source {
+            Source::Index(ref index_reader) => index_reader,
+            Source::Files(_) => unreachable!("files from a list have no ids"),
         };
-        post = post
+        with_prefix(self.prefix.as_deref(), index_reader.name(file_id))
+    }
+
+    /// Returns the query for the files that might match the pattern
+    /// `given`, which is matched as `regexp`
+    fn query(&self, given: &str, regexp: &str, ignore_case: bool) -> Result<Query, String> {
+        // a literal is matched as a regexp that holds nothing else, but its
+        // query is made from its trigrams without parsing it
+        if self.fixed_strings && !ignore_case {
+            return Ok(query_from_literal(given.as_bytes()));
+        }
+        // Get the pseudo-regexp (built using trigrams)
+        let q = if ignore_case {
+            query_from_regexp(&format!("(?i){}", regexp))
+        } else {
+            query_from_regexp(regexp)
+        };
+        q.map_err(|e| format!("PATTERN: {}", e))
+    }
+
+    /// Returns the indexed files that might match any of `patterns`, each
+    /// one as given and as matched, in the order to search them, and with
+    /// `--files-without-match` the set of those that might match out of
+    /// every indexed file
+    fn indexed_files(
+        &self,
+        index_reader: &IndexReader,
+        patterns: &[(&str, String)],
+        options: &MatchOptions,
+    ) -> Result<(Vec<String>, HashSet<String>), String> {
+        // Find all possibly matching files using the pseudo-regexp; a file
+        // that might match any of the patterns is a candidate
+        let query = if self.brute {
+            Query::all()
+        } else {
+            let mut query = None;
+            for (given, regexp) in patterns {
+                let q = self.query(given, regexp, options.ignore_case)?;
+                query = Some(match query {
+                    Some(query) => Query::or(query, q),
+                    None => q,
+                });
+            }
+            query.unwrap_or_else(Query::none)
+        };
+        if self.explain {
+            eprintln!("query: {}", query);
+        }
+        let mut post: BTreeSet<u32> = if self.brute {
+            index_reader.all_files().into_inner()
+        } else {
+            index_reader
+                .query(query.clone())
+                .map_err(|e| e.to_string())?
+                .into_inner()
+        };
+        // println!("identified {} possible queries", post.len());
+
+        let keep = |file_id: &u32| self.keep(&self.name(*file_id));
+        if !self.includes.is_empty() || self.exclude.is_some() || !self.roots.is_empty() {
+            post = post.into_iter().filter(keep).collect::<BTreeSet<_>>();
+        }
+        if self.explain {
+            eprintln!(
+                "candidates: {} of {} files",
+                post.len(),
+                index_reader.num_name
+            );
+        }
+        // --files-without-match goes through every file; the ones that aren't
+        // candidates are listed without being read
+        let mut candidates = HashSet::new();
+        if options.files_without_match {
+            candidates = post.iter().map(|&file_id| self.name(file_id)).collect();
+            post = index_reader
+                .all_files()
+                .into_inner()
+                .into_iter()
+                .filter(keep)
+                .collect();
+        }
+
+        let names = order_candidates(index_reader, &query, post, self.order)
+            .map_err(|e| e.to_string())?
             .into_iter()
-            .filter(|file_id| {
-                let name = index_reader.name(*file_id);
-                file_pattern.is_match(&name)
-            })
-            .collect::<BTreeSet<_>>();
+            .map(|file_id| self.name(file_id))
+            .collect::<Vec<_>>();
+        Ok((names, candidates))
     }
 
-    let path_simplifier = PathSimplifier::from(&match_options);
-    if matches.is_present("files") {
-        for file_id in post {
-            let name = index_reader.name(file_id);
+    /// Returns the files of `--files-from` that pass the path filters, in
+    /// the order to search them. Without the index any of them might
+    /// match, so with `--files-without-match` they're all candidates.
+    fn listed_files(
+        &self,
+        files: &[String],
+        options: &MatchOptions,
+    ) -> (Vec<String>, HashSet<String>) {
+        let mut names = files
+            .iter()
+            .filter(|name| self.keep(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        if self.order == SearchOrder::Size {
+            // files that can't be read go last
+            names.sort_by_cached_key(|name| fs::metadata(name).map_or(u64::MAX, |m| m.len()));
+        }
+        let candidates = if options.files_without_match {
+            names.iter().cloned().collect()
+        } else {
+            HashSet::new()
+        };
+        (names, candidates)
+    }
+
+    /// Writes `data` to the `--output` file, or else to stdout, exiting
+    /// if it can't be written
+    fn write_output(&self, data: &[u8]) {
+        let written = match self.output {
+            Some(ref o) => o.file.borrow_mut().write_all(data),
+            None => io::stdout().lock().write_all(data),
+        };
+        if let Err(e) = written {
+            self.write_failed(e);
+        }
+    }
+
+    /// Exits for the error `e` writing the results, see `write_failed`
+    fn write_failed(&self, e: io::Error) -> ! {
+        write_failed(self.output_path(), e)
+    }
+
+    /// Returns the `--output` file, or `None` for stdout
+    fn output_path(&self) -> Option<&str> {
+        self.output.as_ref().map(|o| o.path.as_str())
+    }
+
+    /// Prints the output `out` of one file, to stdout through `stdout`, or
+    /// to the `--output` file after a `--` line if `separate` is set, as
+    /// `stdout` adds that itself
+    fn print(&self, stdout: &BufferWriter, out: &Buffer, separate: bool) {
+        if self.output.is_none() {
+            if let Err(e) = stdout.print(out) {
+                self.write_failed(e);
+            }
+            return;
+        }
+        if separate {
+            self.write_output(b"--\n");
+        }
+        self.write_output(out.as_slice());
+    }
+
+    /// Flushes what's left to write to the `--output` file or stdout
+    fn flush_output(&self) {
+        let flushed = match self.output {
+            Some(ref o) => o.file.borrow_mut().flush(),
+            None => io::stdout().flush(),
+        };
+        if let Err(e) = flushed {
+            self.write_failed(e);
+        }
+    }
+
+    /// Returns whether the file `name` passes the path filters
+    fn keep(&self, name: &str) -> bool {
+        self.includes.iter().all(|r| r.is_match(name))
+            && !self.exclude.as_ref().is_some_and(|r| r.is_match(name))
+            && (self.roots.is_empty() || self.roots.iter().any(|r| under_root(name, r)))
+    }
+
+    /// Searches the index, or the files of `--files-from`, for lines
+    /// matching any of `patterns` and prints what was found.
+    ///
+    /// Returns the number of matches, or of files listed with `--files`
+    /// or `--files-without-match`, or an error for a bad pattern.
+    fn run(&self, patterns: &[&str]) -> Result<usize, String> {
+        let started = Instant::now();
+        let limit = self.limit;
+        // each pattern as given, and as the regexp that matches it
+        let patterns = patterns
+            .iter()
+            .map(|&given| {
+                let regexp = if self.fixed_strings {
+                    regex::escape(given)
+                } else {
+                    given.to_string()
+                };
+                // the word boundaries add no trigrams, so the index is
+                // queried for the pattern's own literals as before
+                let regexp = if self.word_regexp {
+                    format!(r"\b(?:{})\b", regexp)
+                } else {
+                    regexp
+                };
+                (given, regexp)
+            })
+            .collect::<Vec<_>>();
+        let pattern = match &patterns[..] {
+            [(_, regexp)] => regexp.clone(),
+            _ => patterns
+                .iter()
+                .map(|(_, regexp)| format!("(?:{})", regexp))
+                .collect::<Vec<_>>()
+                .join("|"),
+        };
+        let mut match_options = MatchOptions {
+            pattern,
+            ..self.match_options.clone()
+        };
+        if patterns.len() > 1 && match_options.json {
+            for (given, regexp) in &patterns {
+                let matcher = build_matcher(regexp, &match_options)?;
+                match_options
+                    .alternatives
+                    .push((given.to_string(), matcher));
+            }
+        }
+        let with_context = (match_options.before_context > 0 || match_options.after_context > 0)
+            && !match_options.json
+            && !match_options.print_count
+            && !match_options.files_with_matches_only
+            && !match_options.files_without_match;
+
+        // the files to search, in order, and with --files-without-match the
+        // ones that might match
+        let (names, candidates) = match self.source {
+            Source::Index(ref index_reader) => {
+                self.indexed_files(index_reader, &patterns, &match_options)?
+            }
+            Source::Files(ref files) => self.listed_files(files, &match_options),
+        };
+
+        let path_simplifier = PathSimplifier::from(&match_options);
+        if self.list_files {
+            for name in &names {
+                let name = path_simplifier.maybe_make_relative(name);
+                self.write_output(format!("{}\n", name.display()).as_bytes());
+            }
+            self.flush_output();
+            return Ok(names.len());
+        }
+
+        // writeln!(io::stderr(), "searching").unwrap();
+        // the line matcher can't take patterns that match a newline, and
+        // --multiline doesn't need it
+        let g: Option<Grep> = if match_options.multiline {
+            None
+        } else {
+            let g = GrepBuilder::new(&match_options.pattern)
+                .case_insensitive(match_options.ignore_case)
+                .build()
+                .map_err(|e| format!("PATTERN: {}", e))?;
+            Some(g)
+        };
+        let matcher = build_matcher(&match_options.pattern, &match_options)?;
+        let mut stdout = BufferWriter::stdout(if match_options.with_color {
+            ColorChoice::Always
+        } else {
+            ColorChoice::Never
+        });
+        if with_context {
+            stdout.separator(Some(b"--".to_vec()));
+        }
+
+        // each archive is read once for the files in it that may be read
+        let opener = if match_options.files_without_match {
+            ContentsOpener::new(&candidates)
+        } else {
+            ContentsOpener::new(&names)
+        };
+
+        // Workers take files in index order and buffer the output of each file;
+        // the buffers are printed in that same order so the output doesn't
+        // depend on how the files were scheduled.
+        let search_matches = |name: &str, options: &MatchOptions| {
+            let mut out = stdout.buffer();
+            let num_matches = match opener.open(name) {
+                Ok(reader) => {
+                    let name = path_simplifier.maybe_make_relative(name);
+                    if options.multiline {
+                        search_file_multiline(&mut out, reader, &name, &matcher, options)
+                            .unwrap_or_else(|cause| {
+                                warn!("{} - File read failure: {}", name.display(), cause);
+                                0
+                            })
+                    } else if with_context {
+                        let reader = BufReader::new(reader);
+                        search_with_context(&mut out, reader, &name, &matcher, options)
+                            .unwrap_or_else(|cause| {
+                                warn!("{} - File read failure: {}", name.display(), cause);
+                                0
+                            })
+                    } else {
+                        let g = g.as_ref().unwrap();
+                        search_file(&mut out, reader, &name, g, &matcher, options).unwrap_or_else(
+                            |cause| {
+                                warn!("{} - File read failure: {}", name.display(), cause);
+                                0
+                            },
+                        )
+                    }
+                }
+                Err(cause) => {
+                    warn!("{} - File open failure: {}", name, cause);
+                    0
+                }
+            };
+            (out, num_matches)
+        };
+        let output_path = self.output_path();
+        // with --files-without-match, the "matches" of a file are 1 if its name
+        // was listed
+        let search_one = |name: &str, options: &MatchOptions| {
+            if !options.files_without_match {
+                return search_matches(name, options);
+            }
+            let found = candidates.contains(name) && {
+                let options = MatchOptions {
+                    files_with_matches_only: true,
+                    files_without_match: false,
+                    max_count: None,
+                    ..options.clone()
+                };
+                search_matches(name, &options).1 > 0
+            };
+            let mut out = stdout.buffer();
+            if found {
+                return (out, 0);
+            }
             let name = path_simplifier.maybe_make_relative(name);
-            println!("{}", name.display());
+            let written = if options.null {
+                write!(out, "{}\0", name.display())
+            } else {
+                writeln!(out, "{}", name.display())
+            };
+            if let Err(e) = written {
+                write_failed(output_path, e);
+            }
+            (out, 1)
+        };
+        let next_file = AtomicUsize::new(0);
+        // matches found so far in any order; once `--limit` of them have been
+        // found, the first `--limit` in index order are all in files that were
+        // already taken, so no more files need to be opened
+        let found = AtomicUsize::new(0);
+        // files taken by a worker, for --stats
+        let scanned = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel::<(usize, Buffer, usize)>();
+        let mut total_matches = 0;
+        let mut total_files = 0;
+        thread::scope(|scope| {
+            for _ in 0..self.num_threads {
+                let tx = tx.clone();
+                let (names, next_file, found, scanned) = (&names, &next_file, &found, &scanned);
+                let (search_one, match_options) = (&search_one, &match_options);
+                scope.spawn(move || loop {
+                    if limit.is_some_and(|l| found.load(Ordering::Relaxed) >= l) {
+                        break;
+                    }
+                    let i = next_file.fetch_add(1, Ordering::Relaxed);
+                    let name = match names.get(i) {
+                        Some(name) => name,
+                        None => break,
+                    };
+                    let (out, num_matches) = search_one(name, match_options);
+                    scanned.fetch_add(1, Ordering::Relaxed);
+                    found.fetch_add(num_matches, Ordering::Relaxed);
+                    if tx.send((i, out, num_matches)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+            let mut pending = BTreeMap::new();
+            let mut next_to_print = 0;
+            let mut remaining = limit;
+            'print: for (i, out, num_matches) in rx {
+                pending.insert(i, (out, num_matches));
+                while let Some((out, num_matches)) = pending.remove(&next_to_print) {
+                    next_to_print += 1;
+                    if num_matches == 0 {
+                        continue;
+                    }
+                    let (out, num_matches) = match remaining {
+                        // search the one file that crosses the limit again,
+                        // stopping at the limit
+                        Some(r) if num_matches > r => {
+                            let options = MatchOptions {
+                                max_count: Some(r),
+                                ..match_options.clone()
+                            };
+                            search_one(&names[next_to_print - 1], &options)
+                        }
+                        _ => (out, num_matches),
+                    };
+                    self.print(&stdout, &out, with_context && total_files > 0);
+                    total_matches += num_matches;
+                    total_files += 1;
+                    if let Some(ref mut r) = remaining {
+                        *r -= std::cmp::min(*r, num_matches);
+                        if *r == 0 {
+                            break 'print;
+                        }
+                    }
+                }
+            }
+        });
+        if match_options.json {
+            let summary = JsonSummary {
+                summary: JsonCounts {
+                    matches: total_matches,
+                    files: total_files,
+                },
+            };
+            let mut out = stdout.buffer();
+            if let Err(e) = serde_json::to_writer(&mut out, &summary)
+                .map_err(io::Error::from)
+                .and_then(|_| out.write_all(b"\n"))
+            {
+                self.write_failed(e);
+            }
+            self.print(&stdout, &out, false);
         }
-        std::process::exit(0);
+        self.flush_output();
+        if self.stats {
+            let stats = SearchStats {
+                candidates: names.len(),
+                files_scanned: scanned.into_inner(),
+                files_matched: total_files,
+                total_matches,
+                elapsed: started.elapsed(),
+            };
+            eprintln!("stats: {}", stats);
+        }
+        Ok(total_matches)
     }
+}
 
-    // writeln!(io::stderr(), "searching").unwrap();
-    let normal_alloc_size = 4096;
-    let mut buffer = vec![0; normal_alloc_size];
-    let g: Grep = GrepBuilder::new(&match_options.pattern)
-        .case_insensitive(match_options.ignore_case)
+/// Returns the regexp that finds the matches of `pattern` with `options`
+fn build_matcher(pattern: &str, options: &MatchOptions) -> Result<bytes::Regex, String> {
+    bytes::RegexBuilder::new(pattern)
+        .case_insensitive(options.ignore_case)
+        .multi_line(options.multiline)
+        .dot_matches_new_line(options.multiline)
         .build()
-        .unwrap();
-    let matcher = bytes::RegexBuilder::new(&match_options.pattern)
-        .case_insensitive(matches.is_present("ignore-case"))
-        .multi_line(false)
-        .build()
-        .unwrap();
-    let mut stdout = if match_options.with_color {
-        StandardStream::stdout(ColorChoice::Auto)
+        .map_err(|e| format!("PATTERN: {}", e))
+}
+
+/// Returns which of several `-e` patterns found the match at `start` of
+/// `text`: the first one that matches there, as the alternation does
+fn matched_pattern<'a>(options: &'a MatchOptions, text: &[u8], start: usize) -> Option<&'a str> {
+    options
+        .alternatives
+        .iter()
+        .find(|(_, matcher)| {
+            matcher
+                .find_at(text, start)
+                .is_some_and(|m| m.start() == start)
+        })
+        .map(|(given, _)| given.as_str())
+}
+
+/// Logs `message` and exits with the status for errors
+fn fail<T: fmt::Display>(message: T) -> ! {
+    error!("{}", message);
+    std::process::exit(2);
+}
+
+/// Exits for the error `e` writing the results to the `--output` file
+/// `output` or to stdout: quietly if stdout was closed by its reader, as
+/// with `| head`, or else as `fail` does
+fn write_failed(output: Option<&str>, e: io::Error) -> ! {
+    match output {
+        Some(path) => fail(format!("write {}: {}", path, e)),
+        None if e.kind() == io::ErrorKind::BrokenPipe => std::process::exit(0),
+        None => fail(format!("write stdout: {}", e)),
+    }
+}
+
+/// Returns the path in the index that `--root` `root` names, either as it
+/// was indexed or relative to the current directory, or fails listing the
+/// ones there are
+fn indexed_root(index_reader: &IndexReader, prefix: Option<&Path>, root: &str) -> String {
+    let paths = index_reader
+        .indexed_paths()
+        .into_iter()
+        .map(|p| with_prefix(prefix, p))
+        .collect::<Vec<_>>();
+    let cwd = env::current_dir().unwrap_or_default();
+    let found = paths.iter().find(|p| {
+        let p = Path::new(p.as_str());
+        p == Path::new(root) || p == cwd.join(root)
+    });
+    match found {
+        Some(p) => p.clone(),
+        None => fail(format!(
+            "--root {}: not an indexed path; the indexed paths are:\n  {}",
+            root,
+            paths.join("\n  ")
+        )),
+    }
+}
+
+/// Returns `name`, from the index, under the directory `prefix` if there
+/// is one. Absolute names are left as they are.
+fn with_prefix(prefix: Option<&Path>, name: String) -> String {
+    match prefix {
+        Some(p) => p.join(name).to_string_lossy().into_owned(),
+        None => name,
+    }
+}
+
+/// Returns whether the file `name` is under the indexed path `root`. A
+/// file in an indexed tar archive is named `archive.tar!path`.
+fn under_root(name: &str, root: &str) -> bool {
+    Path::new(name).starts_with(root)
+        || name
+            .strip_prefix(root)
+            .is_some_and(|rest| rest.starts_with('!'))
+}
+
+/// Reads a pattern from the file at `path`, or from stdin if `path` is
+/// `-`. A single trailing line ending is dropped, everything else is part
+/// of the pattern.
+fn read_pattern_file(path: &str) -> io::Result<String> {
+    let mut pattern = String::new();
+    if path == "-" {
+        io::stdin().read_to_string(&mut pattern)?;
     } else {
-        StandardStream::stdout(ColorChoice::Never)
+        File::open(path)?.read_to_string(&mut pattern)?;
+    }
+    if pattern.ends_with('\n') {
+        pattern.pop();
+        if pattern.ends_with('\r') {
+            pattern.pop();
+        }
+    }
+    Ok(pattern)
+}
+
+/// Reads the names of the files to search, one per line, from the file at
+/// `path`, or from stdin if `path` is `-`. Empty lines are skipped.
+fn read_file_list(path: &str) -> io::Result<Vec<String>> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
     };
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let name = line.strip_suffix('\r').unwrap_or(&line);
+        if !name.is_empty() {
+            files.push(name.to_string());
+        }
+    }
+    Ok(files)
+}
+
+/// Searches a single file, writing its output to `out`.
+///
+/// Returns the number of matches found.
+fn search_file<W: WriteColor, R: Read>(
+    out: &mut W,
+    mut reader: R,
+    name: &Path,
+    g: &Grep,
+    matcher: &bytes::Regex,
+    match_options: &MatchOptions,
+) -> io::Result<usize> {
+    let normal_alloc_size = 4096;
+    let mut buffer = vec![0; normal_alloc_size];
     let mut tmp = Vec::new();
-    for file_id in post {
-        // println!("next file");
-        buffer.resize(normal_alloc_size, 0);
-        let name = index_reader.name(file_id);
-        // writeln!(io::stderr(), "searching {}", name).unwrap();
-        let mut reader = match File::open(&name) {
-            Ok(r) => r,
-            Err(cause) => {
-                warn!("{} - File open failure: {}", name, cause);
+    let mut trailing_bytes = 0;
+    let mut line_count = 0;
+    // offset in the file of the start of `buffer`
+    let mut buffer_offset = 0;
+    let mut num_matches = 0;
+    let mut num_lines_matched = 0;
+    let reached_max = |n| match_options.max_count.is_some_and(|m| n >= m);
+    let mut spans = Vec::new();
+    let need_spans = match_options.count_matches
+        || match_options.json
+        || match_options.with_color
+        || match_options.column.is_some()
+        || match_options.byte_offset;
+    'file: while let Ok(bytes_read) = reader.read(&mut buffer[trailing_bytes..]) {
+        if bytes_read == 0 && trailing_bytes == 0 {
+            break;
+        }
+        let total_bytes = bytes_read + trailing_bytes;
+        debug_assert!(total_bytes > 0, "BUG: no bytes to process");
+        let mut last_line_end = 0;
+        let last_newline = memchr::memrchr(b'\n', &buffer[..total_bytes]);
+        let last_newline = match last_newline {
+            Some(nl) => nl + 1,
+            None if bytes_read == 0 => total_bytes,
+            None => {
+                // try to process an entire line
+                trailing_bytes = total_bytes;
+                buffer.resize(trailing_bytes + normal_alloc_size, 0);
                 continue;
             }
         };
-        let name = path_simplifier.maybe_make_relative(name);
-        let mut trailing_bytes = 0;
-        let mut line_count = 0;
-        let mut num_matches = 0;
-        'file: while let Ok(bytes_read) = reader.read(&mut buffer[trailing_bytes..]) {
-            if bytes_read == 0 && trailing_bytes == 0 {
-                break;
+        trailing_bytes = total_bytes - last_newline;
+        if match_options.files_with_matches_only {
+            // One matching line is enough; don't read the rest of the file.
+            if g.iter(&buffer[..last_newline]).next().is_some() {
+                if match_options.null {
+                    write!(out, "{}\0", name.display())?;
+                } else {
+                    writeln!(out, "{}", name.display())?;
+                }
+                num_matches += 1;
+                break 'file;
+            }
+            buffer.copy_within(last_newline..total_bytes, 0);
+            buffer_offset += last_newline as u64;
+            continue;
+        }
+        for each_match in g.iter(&buffer[..last_newline]) {
+            if reached_max(num_lines_matched) {
+                break 'file;
+            }
+            num_lines_matched += 1;
+            if match_options.line_number || match_options.json {
+                let num_lines = bytecount::count(&buffer[last_line_end..each_match.start()], b'\n');
+                line_count += num_lines + 1;
+                last_line_end = each_match.end();
+            }
+            let line = &buffer[each_match.start()..each_match.end()];
+            // the offsets of the matches within the line, found once and
+            // used for counting, the JSON column and coloring
+            spans.clear();
+            if need_spans {
+                let text = line.strip_suffix(b"\n").unwrap_or(line);
+                spans.extend(matcher.find_iter(text).map(|m| (m.start(), m.end())));
             }
-            let total_bytes = bytes_read + trailing_bytes;
-            debug_assert!(total_bytes > 0, "BUG: no bytes to process");
-            let mut last_line_end = 0;
             if match_options.print_count {
-                num_matches += g.iter(&buffer).count();
+                num_matches += if match_options.count_matches {
+                    std::cmp::max(1, spans.len())
+                } else {
+                    1
+                };
                 continue;
             }
-            let last_newline = memchr::memrchr(b'\n', &buffer[..total_bytes]);
-            let last_newline = match last_newline {
-                Some(nl) => nl + 1,
-                None if bytes_read == 0 => total_bytes,
-                None => {
-                    // try to process an entire line
-                    trailing_bytes = total_bytes;
-                    buffer.resize(trailing_bytes + normal_alloc_size, 0);
-                    continue;
-                }
-            };
-            trailing_bytes = total_bytes - last_newline;
-            for each_match in g.iter(&buffer[..last_newline]) {
-                if match_options.files_with_matches_only {
-                    writeln!(&mut stdout, "{}", name.display()).unwrap();
-                    break 'file;
-                }
-                stdout
-                    .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Green)))
-                    .unwrap();
-                write!(&mut stdout, "{}", name.display()).unwrap();
-                stdout.reset().unwrap();
-                if match_options.print_format == PrintFormat::VisualStudio {
-                    write!(&mut stdout, "(").unwrap();
-                } else {
-                    write!(&mut stdout, ":").unwrap();
-                }
-                if match_options.line_number {
-                    let num_lines =
-                        bytecount::count(&buffer[last_line_end..each_match.start()], b'\n');
-                    line_count += num_lines + 1;
-                    last_line_end = each_match.end();
-                    let line_number = line_count.to_string();
-                    stdout
-                        .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Blue)))
-                        .unwrap();
-                    stdout.write_all(line_number.as_bytes()).unwrap();
-                    stdout.reset().unwrap();
-                    if match_options.print_format == PrintFormat::VisualStudio {
-                        write!(&mut stdout, ")").unwrap();
-                    }
-                    write!(&mut stdout, ":").unwrap();
-                }
-                let line = &buffer[each_match.start()..each_match.end()];
-                if match_options.with_color {
-                    let mut start_from = 0;
-                    for m in matcher.find_iter(line) {
-                        let to_write = &line[start_from..m.start()];
-                        write!(&mut stdout, "{}", String::from_utf8_lossy(to_write)).unwrap();
-                        stdout
-                            .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Red)))
-                            .unwrap();
-                        let to_write = &line[m.start()..m.end()];
-                        write!(&mut stdout, "{}", String::from_utf8_lossy(to_write)).unwrap();
-                        stdout.reset().unwrap();
-                        start_from = m.end();
-                    }
-                    if start_from != line.len() {
-                        let to_write = String::from_utf8_lossy(&line[start_from..]);
-                        write!(&mut stdout, "{}", to_write).unwrap();
-                    }
-                } else {
-                    write!(&mut stdout, "{}", String::from_utf8_lossy(line)).unwrap()
-                }
-                if line.last() != Some(&b'\n') {
-                    stdout.write_all(&[b'\n']).unwrap();
-                }
-                stdout.flush().unwrap();
+            num_matches += 1;
+            let start = spans.first().map_or(0, |&(start, _)| start);
+            let byte_offset = buffer_offset + (each_match.start() + start) as u64;
+            if match_options.json {
+                let text = String::from_utf8_lossy(line);
+                let json_match = JsonMatch {
+                    path: &name.to_string_lossy(),
+                    line_number: line_count,
+                    column: start + 1,
+                    text: text.trim_end_matches(&['\r', '\n'][..]),
+                    lines: None,
+                    byte_offset: Some(byte_offset).filter(|_| match_options.byte_offset),
+                    pattern: matched_pattern(
+                        match_options,
+                        line.strip_suffix(b"\n").unwrap_or(line),
+                        start,
+                    ),
+                };
+                serde_json::to_writer(&mut *out, &json_match)?;
+                out.write_all(b"\n")?;
+                continue;
             }
-            if match_options.line_number {
-                let num_lines = bytecount::count(&buffer[last_line_end..last_newline], b'\n');
-                line_count += num_lines;
+            let column = spans
+                .first()
+                .map(|&(start, _)| column(match_options, line, start));
+            let byte_offset = Some(byte_offset);
+            write_prefix(
+                out,
+                match_options,
+                name,
+                line_count,
+                1,
+                column,
+                byte_offset,
+                ":",
+            )?;
+            write_line(out, match_options, line, &spans)?;
+        }
+        if match_options.line_number || match_options.json {
+            let num_lines = bytecount::count(&buffer[last_line_end..last_newline], b'\n');
+            line_count += num_lines;
+        }
+        if reached_max(num_lines_matched) {
+            // don't read the rest of the file
+            break;
+        }
+        tmp.clear();
+        tmp.extend_from_slice(&buffer[last_newline..total_bytes]);
+        assert_eq!(
+            trailing_bytes,
+            tmp.len(),
+            "BUG: expected {} bytes left in chunk, actually {} bytes left",
+            trailing_bytes,
+            tmp.len()
+        );
+        buffer[..tmp.len()].copy_from_slice(&tmp);
+        buffer_offset += last_newline as u64;
+    }
+    if match_options.print_count && num_matches != 0 {
+        let sep = if match_options.null { "\0" } else { ":" };
+        writeln!(out, "{}{}{}", name.display(), sep, num_matches)?;
+    }
+    Ok(num_matches)
+}
+
+/// Searches a whole file with `--multiline`, so matches can span lines.
+/// Each match is printed along with the rest of its first and last lines.
+///
+/// Returns the number of matches found.
+fn search_file_multiline<W: WriteColor, R: Read>(
+    out: &mut W,
+    reader: R,
+    name: &Path,
+    matcher: &bytes::Regex,
+    options: &MatchOptions,
+) -> io::Result<usize> {
+    let mut contents = Vec::new();
+    reader
+        .take(MAX_MULTILINE_FILE_LEN + 1)
+        .read_to_end(&mut contents)?;
+    if contents.len() as u64 > MAX_MULTILINE_FILE_LEN {
+        warn!(
+            "{} - skipped, too large for --multiline (> {} bytes)",
+            name.display(),
+            MAX_MULTILINE_FILE_LEN
+        );
+        return Ok(0);
+    }
+    let mut num_matches = 0;
+    // line number of the start of `contents[counted..]`
+    let mut line_number = 1;
+    let mut counted = 0;
+    for m in matcher.find_iter(&contents) {
+        if options.max_count.is_some_and(|max| num_matches >= max) {
+            break;
+        }
+        num_matches += 1;
+        if options.files_with_matches_only {
+            break;
+        }
+        if options.print_count {
+            continue;
+        }
+        line_number += bytecount::count(&contents[counted..m.start()], b'\n');
+        counted = m.start();
+        let block_start = memchr::memrchr(b'\n', &contents[..m.start()]).map_or(0, |nl| nl + 1);
+        // a match that ends with a newline ends on that line
+        let last = if m.end() > m.start() {
+            m.end() - 1
+        } else {
+            m.end()
+        };
+        let block_end =
+            memchr::memchr(b'\n', &contents[last..]).map_or(contents.len(), |nl| last + nl + 1);
+        let block = &contents[block_start..block_end];
+        let span = (m.start() - block_start, m.end() - block_start);
+        let lines = bytecount::count(block.strip_suffix(b"\n").unwrap_or(block), b'\n') + 1;
+        if options.json {
+            let text = String::from_utf8_lossy(block);
+            let json_match = JsonMatch {
+                path: &name.to_string_lossy(),
+                line_number,
+                column: span.0 + 1,
+                text: text.trim_end_matches(&['\r', '\n'][..]),
+                lines: Some(lines),
+                byte_offset: Some(m.start() as u64).filter(|_| options.byte_offset),
+                pattern: matched_pattern(options, &contents, m.start()),
+            };
+            serde_json::to_writer(&mut *out, &json_match)?;
+            out.write_all(b"\n")?;
+            continue;
+        }
+        let column = Some(column(options, block, span.0));
+        let byte_offset = Some(m.start() as u64);
+        write_prefix(
+            out,
+            options,
+            name,
+            line_number,
+            lines,
+            column,
+            byte_offset,
+            ":",
+        )?;
+        write_line(out, options, block, &[span])?;
+    }
+    if options.files_with_matches_only && num_matches > 0 {
+        if options.null {
+            write!(out, "{}\0", name.display())?;
+        } else {
+            writeln!(out, "{}", name.display())?;
+        }
+    } else if options.print_count && num_matches != 0 {
+        let sep = if options.null { "\0" } else { ":" };
+        writeln!(out, "{}{}{}", name.display(), sep, num_matches)?;
+    }
+    Ok(num_matches)
+}
+
+/// Returns the `--column` of a match that starts at byte `start` of `line`
+fn column(options: &MatchOptions, line: &[u8], start: usize) -> usize {
+    match options.column {
+        Some(Column::Chars) => String::from_utf8_lossy(&line[..start]).chars().count() + 1,
+        _ => start + 1,
+    }
+}
+
+/// Writes the `path:line:col:offset:` prefix of an output line, using
+/// `sep` in place of `:` (`-` marks a context line). A match that spans
+/// more than one of `lines` is written as `path:first-last:`. `column` is
+/// only written for matching lines with `--column`, and `byte_offset`
+/// with `--byte-offset`.
+#[allow(clippy::too_many_arguments)]
+fn write_prefix<W: WriteColor>(
+    out: &mut W,
+    options: &MatchOptions,
+    name: &Path,
+    line_number: usize,
+    lines: usize,
+    column: Option<usize>,
+    byte_offset: Option<u64>,
+    sep: &str,
+) -> io::Result<()> {
+    let sep = if options.null { "\0" } else { sep };
+    out.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)))?;
+    write!(out, "{}", name.display())?;
+    out.reset()?;
+    if options.print_format == PrintFormat::VisualStudio {
+        write!(out, "(")?;
+    } else {
+        write!(out, "{}", sep)?;
+    }
+    if options.line_number {
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+        write!(out, "{}", line_number)?;
+        if lines > 1 {
+            write!(out, "-{}", line_number + lines - 1)?;
+        }
+        out.reset()?;
+        if let (Some(c), Some(_)) = (column, options.column) {
+            if options.print_format == PrintFormat::VisualStudio {
+                write!(out, ",{}", c)?;
+            } else {
+                write!(out, "{}{}", sep, c)?;
             }
-            tmp.clear();
-            tmp.extend_from_slice(&buffer[last_newline..total_bytes]);
-            assert_eq!(
-                trailing_bytes,
-                tmp.len(),
-                "BUG: expected {} bytes left in chunk, actually {} bytes left",
-                trailing_bytes,
-                tmp.len()
-            );
-            buffer[..tmp.len()].copy_from_slice(&tmp);
         }
-        if match_options.print_count && num_matches != 0 {
-            writeln!(&mut stdout, "{}:{}", name.display(), num_matches).unwrap();
+        if options.print_format == PrintFormat::VisualStudio {
+            write!(out, ")")?;
+        }
+        write!(out, "{}", sep)?;
+    }
+    if let Some(b) = byte_offset.filter(|_| options.byte_offset) {
+        write!(out, "{}{}", b, sep)?;
+    }
+    Ok(())
+}
+
+/// Writes `line`, highlighting the `spans` (byte offsets of the matches
+/// in `line`) when color is enabled
+fn write_line<W: WriteColor>(
+    out: &mut W,
+    options: &MatchOptions,
+    line: &[u8],
+    spans: &[(usize, usize)],
+) -> io::Result<()> {
+    if options.with_color {
+        let mut start_from = 0;
+        for &(start, end) in spans {
+            let to_write = &line[start_from..start];
+            write!(out, "{}", String::from_utf8_lossy(to_write))?;
+            out.set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Red)))?;
+            let to_write = &line[start..end];
+            write!(out, "{}", String::from_utf8_lossy(to_write))?;
+            out.reset()?;
+            start_from = end;
+        }
+        if start_from != line.len() {
+            let to_write = String::from_utf8_lossy(&line[start_from..]);
+            write!(out, "{}", to_write)?;
+        }
+    } else {
+        write!(out, "{}", String::from_utf8_lossy(line))?;
+    }
+    if line.last() != Some(&b'\n') {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Searches `reader` line by line, printing each match along with the
+/// requested lines of context. Groups of lines that aren't adjacent are
+/// separated by `--`.
+///
+/// Returns the number of matching lines.
+fn search_with_context<W: WriteColor, R: BufRead>(
+    out: &mut W,
+    mut reader: R,
+    name: &Path,
+    matcher: &bytes::Regex,
+    options: &MatchOptions,
+) -> io::Result<usize> {
+    // the lines before a match, with their offsets in the file
+    let mut before = VecDeque::<(u64, Vec<u8>)>::new();
+    let mut before_bytes = 0;
+    let mut after_left = 0;
+    let mut last_printed: Option<usize> = None;
+    let mut num_matches = 0;
+    let mut line_number = 0;
+    // offset in the file of the start of the next line
+    let mut offset = 0;
+    let mut line = Vec::new();
+    let mut spans = Vec::new();
+    loop {
+        let reached_max = options.max_count.is_some_and(|m| num_matches >= m);
+        if reached_max && after_left == 0 {
+            // only the trailing context of the last match is left to print
+            break;
+        }
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let line_offset = offset;
+        offset += line.len() as u64;
+        let text = if line.last() == Some(&b'\n') {
+            &line[..line.len() - 1]
+        } else {
+            &line[..]
+        };
+        spans.clear();
+        if !reached_max {
+            spans.extend(matcher.find_iter(text).map(|m| (m.start(), m.end())));
+        }
+        if !spans.is_empty() {
+            num_matches += 1;
+            let first = line_number - before.len();
+            let adjacent = last_printed.is_some_and(|l| l + 1 >= first);
+            if last_printed.is_some() && !adjacent {
+                writeln!(out, "--")?;
+            }
+            for (i, (l_offset, l)) in before.drain(..).enumerate() {
+                write_prefix(out, options, name, first + i, 1, None, Some(l_offset), "-")?;
+                write_line(out, options, &l, &[])?;
+            }
+            before_bytes = 0;
+            let column = spans
+                .first()
+                .map(|&(start, _)| column(options, text, start));
+            let byte_offset = spans.first().map(|&(start, _)| line_offset + start as u64);
+            write_prefix(out, options, name, line_number, 1, column, byte_offset, ":")?;
+            write_line(out, options, &line, &spans)?;
+            last_printed = Some(line_number);
+            after_left = options.after_context;
+        } else if after_left > 0 {
+            after_left -= 1;
+            let byte_offset = Some(line_offset);
+            write_prefix(out, options, name, line_number, 1, None, byte_offset, "-")?;
+            write_line(out, options, &line, &[])?;
+            last_printed = Some(line_number);
+        } else if options.before_context > 0 {
+            before_bytes += line.len();
+            before.push_back((line_offset, line.clone()));
+            while before.len() > options.before_context || before_bytes > MAX_BEFORE_CONTEXT_BYTES {
+                before_bytes -= before.pop_front().map_or(0, |(_, l)| l.len());
+            }
         }
     }
+    out.flush()?;
+    Ok(num_matches)
 }
 
 struct PathSimplifier {
@@ -402,7 +1793,7 @@ impl PathSimplifier {
         if self.make_relative {
             PathBuf::from(
                 p.as_ref()
-                    .strip_prefix(&env::current_dir().unwrap())
+                    .strip_prefix(env::current_dir().unwrap())
                     .unwrap_or_else(|_| p.as_ref()),
             )
         } else {
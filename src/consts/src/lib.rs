@@ -1,2 +1,46 @@
-pub const MAGIC: &str = "csearch index 1\n";
+pub const MAGIC: &str = "csearch index 2\n";
+/// Header of indexes written before the trailer held a checksum
+pub const MAGIC_V1: &str = "csearch index 1\n";
 pub const TRAILER_MAGIC: &str = "\ncsearch trailr\n";
+/// Written after the header of an index whose paths and names are
+/// relative to a base directory
+pub const RELATIVE_MARKER: &str = "relative\n";
+
+/// Header of indexes whose trailer also holds the offset of the posting
+/// block index and flags. Only written for compressed indexes, so others
+/// can still be read by builds that only know version 2.
+pub const MAGIC_V3: &str = "csearch index 3\n";
+/// Trailer flag of a version 3 index whose posting lists are compressed
+/// with zstd, in blocks
+pub const FLAG_ZSTD_POSTINGS: u32 = 1;
+/// Uncompressed size a block of posting lists is filled to before it's
+/// compressed
+pub const POSTING_BLOCK_SIZE: usize = 64 << 10;
+
+/// Newest format version this build reads, named in `MAGIC_V3`
+pub const FORMAT_VERSION: u32 = 3;
+/// Oldest format version this build can read
+pub const MIN_FORMAT_VERSION: u32 = 1;
+
+const MAGIC_PREFIX: &str = "csearch index ";
+
+/// Returns the format version named by the header line at the start of
+/// `data`, e.g. 2 for `"csearch index 2\n"`, or `None` if `data` doesn't
+/// start with an index header.
+///
+/// ```
+/// assert_eq!(consts::parse_version(b"csearch index 12\nrest"), Some(12));
+/// assert_eq!(consts::parse_version(b"csearch index x\n"), None);
+/// ```
+pub fn parse_version(data: &[u8]) -> Option<u32> {
+    let rest = data.strip_prefix(MAGIC_PREFIX.as_bytes())?;
+    let len = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+    if len == 0 || len > 9 || rest.get(len) != Some(&b'\n') {
+        return None;
+    }
+    Some(
+        rest[..len]
+            .iter()
+            .fold(0, |v, c| v * 10 + u32::from(c - b'0')),
+    )
+}
@@ -1,7 +1,10 @@
 extern crate byteorder;
+extern crate crc32fast;
+extern crate flate2;
 #[macro_use]
 extern crate log;
 extern crate tempfile;
+extern crate zstd;
 
 extern crate consts;
 extern crate libcsearch;
@@ -10,3 +13,5 @@ extern crate libvarint;
 
 pub mod merge;
 pub mod writer;
+
+pub use writer::trigrams;
@@ -6,15 +6,15 @@
 
 // Merging indexes.
 //
-// To merge two indexes A and B (newer) into a combined index C:
+// To merge indexes A, B, ... (each newer than the last) into a combined index C:
 //
-// Load the path list from B and determine for each path the docid ranges
-// that it will replace in A.
+// Load the path lists from the newer indexes and determine for each path the
+// docid ranges that it will replace in each older index.
 //
-// Read A's and B's name lists together, merging them into C's name list.
-// Discard the identified ranges from A during the merge.  Also during the merge,
-// record the mapping from A's docids to C's docids, and also the mapping from
-// B's docids to C's docids.  Both mappings can be summarized in a table like
+// Read all the name lists together, merging them into C's name list.
+// Discard the identified ranges during the merge.  Also during the merge,
+// record the mapping from each index's docids to C's docids.  Each mapping
+// can be summarized in a table like
 //
 // 	10-14 map to 20-24
 // 	15-24 is deleted
@@ -22,17 +22,23 @@
 //
 // The number of ranges will be at most the combined number of paths.
 // Also during the merge, write the name index to a temporary file as usual.
+// Alias records refer to docids too, so they're rewritten once the mapping
+// is known; an alias whose file was discarded is left pointing nowhere.
 //
 // Now merge the posting lists (this is why they begin with the trigram).
 // During the merge, translate the docid numbers to the new C docid space.
+// The lists are merged with a heap keyed by (trigram, docid), so only the
+// current position in each source is held in memory.
 // Also during the merge, write the posting list index to a temporary file as usual.
 //
 // Copy the name index and posting list index into C's index and write the trailer.
 // Rename C's index onto the new index.
+//
+// C's posting lists are compressed if those of any of A, B, ... are.
 
 use libcsearch::reader::IndexReader;
 use libprofiling;
-use writer::{copy_file, get_offset};
+use writer::{copy_file, create_index_file, get_offset, write_trailer};
 
 use byteorder::{BigEndian, WriteBytesExt};
 use consts;
@@ -41,169 +47,233 @@ use tempfile::tempfile;
 use super::postdatawriter::PostDataWriter;
 use super::postmapreader::{IdRange, PostMapReader};
 
-use std::cmp;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fs::File;
 use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::u32;
 
+/// Merges `src1` and the newer `src2` into `dest`.
+///
+/// Files in `src2` replace the files in `src1` under the same indexed paths.
 pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
 where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
     P3: AsRef<Path>,
 {
-    let _frame_merge = libprofiling::profile("merge");
-    let ix1 = IndexReader::open(src1)?;
-    let ix2 = IndexReader::open(src2)?;
-    let paths1 = ix1.indexed_paths();
-    let paths2 = ix2.indexed_paths();
+    merge_many(dest, vec![src1.as_ref(), src2.as_ref()])
+}
 
-    let mut i1: u32 = 0;
-    let mut i2: u32 = 0;
-    let mut new: u32 = 0;
-    let mut map1 = Vec::<IdRange>::new();
-    let mut map2 = Vec::<IdRange>::new();
-    for path in &paths2 {
-        let _frame = libprofiling::profile("merge: merge indexed paths");
-        let old = i1;
-        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32) < *path {
-            i1 += 1;
-        }
-        let mut lo = i1;
-        let limit = {
-            let (l1, l2) = path.split_at(path.len() - 1);
-            assert!(l2.len() == 1);
-            let l2_u = l2.chars().next().unwrap() as u8;
-            l1.to_string() + &((l2_u + 1) as char).to_string()
-        };
-        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32) < limit {
-            i1 += 1;
-        }
+/// Merges every index in `sources` into `dest` in a single pass.
+///
+/// `sources` is ordered from oldest to newest. Files in a newer index
+/// replace the files in all older indexes under the same indexed paths.
+pub fn merge_many<P1, P2>(dest: P1, sources: Vec<P2>) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let _frame_merge = libprofiling::profile("merge");
+    let ixs = sources
+        .iter()
+        .map(IndexReader::open)
+        .collect::<Result<Vec<_>, _>>()?;
+    let paths = ixs
+        .iter()
+        .map(IndexReader::indexed_paths)
+        .collect::<Vec<_>>();
 
-        // Record range before the shadow
-        if old < lo {
-            map1.push(IdRange {
-                low: old,
-                high: lo,
-                new,
-            });
-            new += lo - old;
-        }
+    // Determine which files of each index survive, i.e. aren't
+    // under a path of a newer index.
+    let survivors = (0..ixs.len())
+        .map(|k| {
+            let mut shadow = paths[k + 1..]
+                .iter()
+                .flat_map(|p| p.iter().cloned())
+                .collect::<Vec<_>>();
+            shadow.sort();
+            surviving_ranges(&ixs[k], &shadow)
+        })
+        .collect::<Vec<_>>();
+    let all_paths = paths.into_iter().flatten().collect();
+    write_merged(dest.as_ref(), &ixs, all_paths, &survivors)
+}
 
-        // Determine range defined by this path.
-        // Because we are iterating over the ix2 paths,
-        // there can't be gaps, so it must start at i2.
-        if (i2 as usize) < ix2.num_name && ix2.name(i2) < *path {
-            panic!("merge: inconsistent index ({} < {})", ix2.name(i2), *path);
-        }
-        lo = i2;
-        while (i2 as usize) < ix2.num_name && ix2.name(i2) < limit {
-            i2 += 1;
+/// Writes to `dest` a copy of the index `src` without the files under
+/// any of the paths in `remove`.
+///
+/// Indexed paths that are under one of `remove` are dropped from the
+/// path list as well, so reindexing won't add them back. In a relative
+/// index, `remove` holds names as they're stored, where `.` is the base
+/// directory.
+pub fn remove_paths<P1, P2>(dest: P1, src: P2, remove: &[String]) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let _frame = libprofiling::profile("remove paths");
+    let ix = IndexReader::open(src)?;
+    let is_removed = |name: &str| {
+        remove.iter().any(|r| {
+            (r == "." && !Path::new(name).is_absolute())
+                || name.starts_with(r.as_str())
+                    && (name.len() == r.len()
+                        || r.ends_with('/')
+                        || name[r.len()..].starts_with('/'))
+        })
+    };
+    let paths = ix
+        .indexed_paths()
+        .into_iter()
+        .filter(|p| !is_removed(p))
+        .collect();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for i in 0..ix.num_name as u32 {
+        if is_removed(&ix.name(i)) {
+            continue;
         }
-        let hi = i2;
-        if lo < hi {
-            map2.push(IdRange {
-                low: lo,
-                high: hi,
-                new,
-            });
-            new += hi - lo;
+        match ranges.last_mut() {
+            Some(r) if r.1 == i => r.1 += 1,
+            _ => ranges.push((i, i + 1)),
         }
     }
+    write_merged(dest.as_ref(), &[ix], paths, &[ranges])
+}
 
-    if (i1 as usize) < ix1.num_name {
-        map1.push(IdRange {
-            low: i1,
-            high: ix1.num_name as u32,
-            new,
-        });
-        new += (ix1.num_name as u32) - i1;
+/// Writes the files of `ixs` that are in the `survivors` ranges of their
+/// index into a new index at `dest`, covering the indexed paths `paths`.
+fn write_merged(
+    dest: &Path,
+    ixs: &[IndexReader],
+    mut all_paths: Vec<String>,
+    survivors: &[Vec<(u32, u32)>],
+) -> io::Result<()> {
+    // relative names can't be told apart from absolute ones once merged
+    let relative = ixs.first().is_some_and(IndexReader::is_relative);
+    if ixs.iter().any(|ix| ix.is_relative() != relative) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't merge an index of relative paths with one of absolute paths",
+        ));
     }
-    if (i2 as usize) < ix2.num_name {
-        panic!("merge: inconsistent index ({} < {})", i2, ix2.num_name);
+    let compress = ixs.iter().any(IndexReader::is_compressed);
+    let mut ix3 = BufWriter::new(create_index_file(dest)?);
+    let magic = if compress {
+        consts::MAGIC_V3
+    } else {
+        consts::MAGIC
+    };
+    ix3.write_all(magic.as_bytes())?;
+    if relative {
+        ix3.write_all(consts::RELATIVE_MARKER.as_bytes())?;
     }
-    let num_name = new;
-    let mut ix3 = BufWriter::new(File::create(dest)?);
-    ix3.write_all(consts::MAGIC.as_bytes())?;
 
     let path_data = get_offset(&mut ix3)?;
-    let mut mi1 = 0;
-    let mut mi2 = 0;
+    all_paths.sort();
     let mut last = "\0".to_string(); // not a prefix of anything
-
-    while mi1 < paths1.len() || mi2 < paths2.len() {
+    for p in all_paths {
         let _frame = libprofiling::profile("merge: merge file_ids");
-        let p = if mi2 >= paths2.len() || mi1 < paths1.len() && paths1[mi1] <= paths2[mi2] {
-            let p = paths1[mi1].clone();
-            mi1 += 1;
-            p
-        } else {
-            let p = paths2[mi2].clone();
-            mi2 += 1;
-            p
-        };
         if p.starts_with(&last) {
             continue;
         }
-        last = p.clone();
         ix3.write_all(p.as_bytes())?;
         ix3.write_all("\0".as_bytes())?;
+        last = p;
     }
     ix3.write_all("\0".as_bytes())?;
 
     // Merged list of names
     let name_data = get_offset(&mut ix3)?;
     let mut name_index_file = BufWriter::new(tempfile()?);
+    let mut maps: Vec<Vec<IdRange>> = ixs.iter().map(|_| Vec::new()).collect();
 
-    new = 0;
-    mi1 = 0;
-    mi2 = 0;
-
-    while new < num_name {
+    let mut cursors = survivors
+        .iter()
+        .map(|ranges| ranges.iter().flat_map(|&(low, high)| low..high))
+        .collect::<Vec<_>>();
+    let mut heap = BinaryHeap::new();
+    for (k, c) in cursors.iter_mut().enumerate() {
+        if let Some(i) = c.next() {
+            heap.push(Reverse((ixs[k].name(i), k, i)));
+        }
+    }
+    // (offset, index, old docid, name) of each alias record written
+    let mut aliases = Vec::new();
+    let mut new: u32 = 0;
+    while let Some(Reverse((name, mut k, mut i))) = heap.pop() {
         let _frame = libprofiling::profile("merge: Merge list of names");
-        if mi1 < map1.len() && map1[mi1].new == new {
-            for i in map1[mi1].low..map1[mi1].high {
-                let name = ix1.name(i);
-                let new_offset: u32 = get_offset(&mut ix3)? as u32;
-                name_index_file
-                    .write_u32::<BigEndian>(new_offset - (name_data as u32))
-                    .unwrap();
-                ix3.write_all(name.as_bytes())?;
-                ix3.write_all("\0".as_bytes())?;
-                new += 1;
-            }
-            mi1 += 1;
-        } else if mi2 < map2.len() && map2[mi2].new == new {
-            for i in map2[mi2].low..map2[mi2].high {
-                let name = ix2.name(i);
-                let new_offset: u32 = get_offset(&mut ix3)? as u32;
-                name_index_file
-                    .write_u32::<BigEndian>(new_offset - (name_data as u32))
-                    .unwrap();
-                ix3.write_all(name.as_bytes())?;
-                ix3.write_all("\0".as_bytes())?;
-                new += 1;
+        // If more than one index has the same name, the newest one wins.
+        while heap
+            .peek()
+            .is_some_and(|&Reverse((ref n, _, _))| *n == name)
+        {
+            let Reverse((_, k2, i2)) = heap.pop().unwrap();
+            if let Some(next) = cursors[k].next() {
+                heap.push(Reverse((ixs[k].name(next), k, next)));
             }
-            mi2 += 1;
-        } else {
-            panic!("merge: inconsistent index");
+            k = k2;
+            i = i2;
+        }
+        let new_offset: u32 = get_offset(&mut ix3)? as u32;
+        name_index_file.write_u32::<BigEndian>(new_offset - (name_data as u32))?;
+        ix3.write_all(name.as_bytes())?;
+        ix3.write_all("\0".as_bytes())?;
+        if let Some(m) = ixs[k].file_metadata(i) {
+            ix3.write_all(&m.to_bytes())?;
+        }
+        if let Some(original) = ixs[k].alias_of(i) {
+            aliases.push((get_offset(&mut ix3)?, k, original, name.clone()));
+            ix3.write_u32::<BigEndian>(u32::MAX)?;
+        }
+        match maps[k].last_mut() {
+            Some(r) if r.high == i && r.new + (r.high - r.low) == new => r.high += 1,
+            _ => maps[k].push(IdRange {
+                low: i,
+                high: i + 1,
+                new,
+            }),
+        }
+        new += 1;
+        if let Some(next) = cursors[k].next() {
+            heap.push(Reverse((ixs[k].name(next), k, next)));
         }
     }
     if ((new * 4) as u64) != get_offset(&mut name_index_file)? {
         panic!("merge: inconsistent index");
     }
+    // Terminating empty name
+    let new_offset: u32 = get_offset(&mut ix3)? as u32;
     name_index_file
-        .write_u32::<BigEndian>(get_offset(&mut ix3)? as u32)
+        .write_u32::<BigEndian>(new_offset - (name_data as u32))
         .unwrap();
+    ix3.write_all("\0".as_bytes())?;
 
     let post_data = get_offset(&mut ix3)?;
+    for (offset, k, original, name) in aliases {
+        let mapped = maps[k]
+            .iter()
+            .find(|r| r.low <= original && original < r.high)
+            .map(|r| r.new + original - r.low);
+        match mapped {
+            Some(id) => {
+                ix3.seek(SeekFrom::Start(offset))?;
+                ix3.write_u32::<BigEndian>(id)?;
+            }
+            None => warn!(
+                "merge: {}: the file it has the same contents as was dropped, reindex it",
+                name
+            ),
+        }
+    }
+    ix3.seek(SeekFrom::Start(post_data))?;
 
-    let post_index_file = merge_list_of_posting_lists(
-        PostMapReader::new(&ix1, map1),
-        PostMapReader::new(&ix2, map2),
-        &mut ix3,
-    )?;
+    let readers = ixs
+        .iter()
+        .zip(maps)
+        .map(|(ix, map)| PostMapReader::new(ix, map))
+        .collect();
+    let (post_index_file, block_index) = merge_list_of_posting_lists(readers, &mut ix3, compress)?;
 
     // Name index
     let name_index = get_offset(&mut ix3)?;
@@ -211,14 +281,14 @@ where
     copy_file(
         &mut ix3,
         &mut BufReader::new(name_index_file.into_inner().unwrap()),
-    );
+    )?;
 
     // Posting list index
     let post_index = get_offset(&mut ix3).unwrap();
     copy_file(
         &mut ix3,
         &mut BufReader::new(post_index_file.into_inner().unwrap()),
-    );
+    )?;
 
     trace!("path_data  = {}", path_data);
     trace!("name_data  = {}", name_data);
@@ -226,70 +296,123 @@ where
     trace!("name_index = {}", name_index);
     trace!("post_index = {}", post_index);
 
-    ix3.write_u32::<BigEndian>(path_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(name_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(post_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(name_index as u32).unwrap();
-    ix3.write_u32::<BigEndian>(post_index as u32).unwrap();
-    ix3.write_all(consts::TRAILER_MAGIC.as_bytes())?;
-    Ok(())
+    let mut offsets = vec![path_data, name_data, post_data, name_index, post_index];
+    if compress {
+        // Posting block index
+        offsets.push(get_offset(&mut ix3)?);
+        offsets.push(u64::from(consts::FLAG_ZSTD_POSTINGS));
+        ix3.write_all(&block_index)?;
+    }
+    write_trailer(&mut ix3, &offsets)
+}
+
+/// Returns the ranges of file ids in `ix` that aren't under any of the
+/// sorted paths in `shadow`.
+fn surviving_ranges(ix: &IndexReader, shadow: &[String]) -> Vec<(u32, u32)> {
+    let _frame = libprofiling::profile("merge: merge indexed paths");
+    let num_name = ix.num_name as u32;
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    if shadow.iter().any(|p| p == ".") {
+        // the base directory of a relative index covers every relative
+        // name, leaving only the absolute ones of files outside it
+        for i in 0..num_name {
+            if !Path::new(&ix.name(i)).is_absolute() {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(r) if r.1 == i => r.1 += 1,
+                _ => ranges.push((i, i + 1)),
+            }
+        }
+        return ranges;
+    }
+    let mut i: u32 = 0;
+    for path in shadow {
+        let old = i;
+        while i < num_name && ix.name(i) < *path {
+            i += 1;
+        }
+        if old < i {
+            ranges.push((old, i));
+        }
+        let limit = {
+            let (l1, l2) = path.split_at(path.len() - 1);
+            assert!(l2.len() == 1);
+            let l2_u = l2.chars().next().unwrap() as u8;
+            l1.to_string() + &((l2_u + 1) as char).to_string()
+        };
+        while i < num_name && ix.name(i) < limit {
+            i += 1;
+        }
+    }
+    if i < num_name {
+        ranges.push((i, num_name));
+    }
+    ranges
 }
 
+/// Writes the merged posting lists of `readers` to `ix3`, compressed if
+/// `compress` is set. Returns the posting list index and the posting
+/// block index.
 fn merge_list_of_posting_lists(
-    mut r1: PostMapReader,
-    mut r2: PostMapReader,
+    mut readers: Vec<PostMapReader>,
     ix3: &mut BufWriter<File>,
-) -> io::Result<BufWriter<File>> {
+    compress: bool,
+) -> io::Result<(BufWriter<File>, Vec<u8>)> {
     // Merged list of posting lists.
-    let mut w = PostDataWriter::new(ix3)?;
+    let mut w = PostDataWriter::new(ix3, compress)?;
 
-    loop {
+    // Position each reader on its first surviving (trigram, file id)
+    let mut heap = BinaryHeap::new();
+    for (k, r) in readers.iter_mut().enumerate() {
+        if advance_trigram(r) {
+            heap.push(Reverse((r.trigram, r.file_id, k)));
+        }
+    }
+
+    let mut current: Option<(u32, u32)> = None;
+    while let Some(Reverse((trigram, file_id, k))) = heap.pop() {
         let _frame = libprofiling::profile("merge: merge list of posting lists");
-        match r1.trigram.cmp(&r2.trigram) {
-            cmp::Ordering::Less => {
-                w.trigram(r1.trigram);
-                while r1.next_id() {
-                    w.file_id(r1.file_id);
+        match current {
+            Some((t, last)) if t == trigram => {
+                if last == file_id {
+                    panic!("merge: inconsistent index");
                 }
-                r1.next_trigram();
-                w.end_trigram();
             }
-            cmp::Ordering::Greater => {
-                w.trigram(r2.trigram);
-                while r2.next_id() {
-                    w.file_id(r2.file_id);
-                }
-                r2.next_trigram();
+            Some(_) => {
                 w.end_trigram();
+                w.trigram(trigram);
             }
-            cmp::Ordering::Equal => {
-                if r1.trigram == u32::MAX {
-                    break;
-                }
-                w.trigram(r1.trigram);
-                r1.next_id();
-                r2.next_id();
-                while r1.file_id < u32::MAX || r2.file_id < u32::MAX {
-                    match r1.file_id.cmp(&r2.file_id) {
-                        cmp::Ordering::Less => {
-                            w.file_id(r1.file_id);
-                            r1.next_id();
-                        }
-                        cmp::Ordering::Greater => {
-                            w.file_id(r2.file_id);
-                            r2.next_id();
-                        }
-                        cmp::Ordering::Equal => {
-                            panic!("merge: inconsistent index");
-                        }
-                    }
-                }
-                r1.next_trigram();
-                r2.next_trigram();
-                w.end_trigram();
+            None => w.trigram(trigram),
+        }
+        w.file_id(file_id);
+        current = Some((trigram, file_id));
+
+        let r = &mut readers[k];
+        if r.next_id() {
+            heap.push(Reverse((r.trigram, r.file_id, k)));
+        } else {
+            r.next_trigram();
+            if advance_trigram(r) {
+                heap.push(Reverse((r.trigram, r.file_id, k)));
             }
         }
     }
+    if current.is_some() {
+        w.end_trigram();
+    }
+
+    w.into_inner()
+}
 
-    Ok(w.into_inner())
+/// Moves `r` forward to the first trigram with a surviving file id.
+/// Returns `false` if there are no more trigrams.
+fn advance_trigram(r: &mut PostMapReader) -> bool {
+    while r.trigram != u32::MAX {
+        if r.next_id() {
+            return true;
+        }
+        r.next_trigram();
+    }
+    false
 }
@@ -1,4 +1,4 @@
-pub use self::merger::merge;
+pub use self::merger::{merge, merge_many, remove_paths};
 
 mod merger;
 mod postdatawriter;
@@ -5,32 +5,29 @@
 // license that can be found in the LICENSE file.
 
 use std::fs::File;
-use std::io::{self, BufWriter, Seek, Write};
-use std::u32;
+use std::io::{self, BufWriter, Write};
 
 use libvarint;
-use writer::{get_offset, WriteTrigram};
+use writer::{PostingListWriter, WriteTrigram};
 
 use byteorder::{BigEndian, WriteBytesExt};
 use tempfile::tempfile;
 
-pub struct PostDataWriter<'a, W: 'a + Write + Seek> {
-    out: &'a mut BufWriter<W>,
+pub struct PostDataWriter<'a, W: 'a + Write> {
+    out: PostingListWriter<'a, BufWriter<W>>,
     post_index_file: BufWriter<File>,
-    base: u32,
     count: u32,
     offset: u32,
     last: u32,
     t: u32,
 }
 
-impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
-    pub fn new(out: &'a mut BufWriter<W>) -> io::Result<Self> {
-        let base = get_offset(out)? as u32;
+impl<'a, W: Write> PostDataWriter<'a, W> {
+    /// Writes posting lists to `out`, compressed if `compress` is set
+    pub fn new(out: &'a mut BufWriter<W>, compress: bool) -> io::Result<Self> {
         Ok(PostDataWriter {
-            out,
+            out: PostingListWriter::new(out, compress),
             post_index_file: BufWriter::with_capacity(256 << 10, tempfile()?),
-            base,
             count: 0,
             offset: 0,
             last: 0,
@@ -38,7 +35,7 @@ impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
         })
     }
     pub fn trigram(&mut self, t: u32) {
-        self.offset = get_offset(self.out).unwrap() as u32;
+        self.offset = self.out.offset() as u32;
         self.count = 0;
         self.t = t;
         self.last = u32::MAX;
@@ -47,7 +44,7 @@ impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
         if self.count == 0 {
             self.out.write_trigram(self.t).unwrap();
         }
-        libvarint::write_uvarint(self.out, id.wrapping_sub(self.last)).unwrap();
+        libvarint::write_uvarint(&mut self.out, id.wrapping_sub(self.last)).unwrap();
         self.last = id;
         self.count += 1;
     }
@@ -55,16 +52,19 @@ impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
         if self.count == 0 {
             return;
         }
-        libvarint::write_uvarint(self.out, 0).unwrap();
+        libvarint::write_uvarint(&mut self.out, 0).unwrap();
+        self.out.end_list().unwrap();
         self.post_index_file.write_trigram(self.t).unwrap();
         self.post_index_file
             .write_u32::<BigEndian>(self.count)
             .unwrap();
         self.post_index_file
-            .write_u32::<BigEndian>(self.offset - self.base)
+            .write_u32::<BigEndian>(self.offset)
             .unwrap();
     }
-    pub fn into_inner(self) -> BufWriter<File> {
-        self.post_index_file
+    /// Returns the posting list index, and the posting block index of
+    /// compressed lists
+    pub fn into_inner(self) -> io::Result<(BufWriter<File>, Vec<u8>)> {
+        Ok((self.post_index_file, self.out.finish()?))
     }
 }
@@ -9,8 +9,6 @@ use libcsearch::reader::{IndexReader, POST_ENTRY_SIZE};
 use libprofiling;
 use libvarint;
 
-use std::u32;
-
 #[derive(Debug)]
 pub struct IdRange {
     pub low: u32,
@@ -72,11 +70,9 @@ impl<'a> PostMapReader<'a> {
             self.file_id = u32::MAX;
             return;
         }
-        self.d = {
-            let s = self.index.as_slice();
-            let split_point = self.index.post_data + self.offset + 3;
-            let (_, right_side) = s.split_at(split_point as usize);
-            right_side
+        self.d = match self.index.lists_at(self.offset as usize) {
+            Ok(list) => &list[3..],
+            Err(e) => panic!("merge: {} at trigram {}", e, self.trigram),
         };
         self.old_id = u32::MAX;
         self.i = 0;
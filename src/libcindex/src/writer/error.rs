@@ -27,8 +27,40 @@ pub enum IndexErrorKind {
     TooManyTrigrams,
     /// Binary data is present in file (binary files are skipped)
     BinaryDataPresent,
+    /// The start of the file has a NUL byte, so it's taken to be binary
+    BinaryFile,
     /// The ratio of invalid utf-8 : valid utf-8 chars is too high
     HighInvalidUtf8Ratio,
+    /// A compressed file couldn't be decompressed
+    DecompressionFailed,
+    /// A file with a UTF-16 byte order mark ends in the middle of a character
+    InvalidUtf16,
+    /// The file is empty or holds only whitespace, so no search can match it
+    NoTrigrams,
+    /// Adding the file could make the index larger than its maximum size
+    IndexFull,
+    /// Writing out the index in `IndexWriter::flush` failed at this stage.
+    /// The underlying `io::Error` is kept as the error.
+    FlushFailed(FlushStage),
+}
+
+/// The part of the index `IndexWriter::flush` was writing when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushStage {
+    /// The magic string at the start of the index
+    Header,
+    /// The list of indexed paths
+    Paths,
+    /// The list of file names
+    Names,
+    /// The posting lists
+    PostingLists,
+    /// The offsets of the file names
+    NameIndex,
+    /// The offsets of the posting lists
+    PostingIndex,
+    /// The section offsets, checksum and trailer magic
+    Trailer,
 }
 
 impl IndexError {
@@ -74,27 +106,72 @@ impl From<IndexError> for io::Error {
     fn from(e: IndexError) -> Self {
         match e.kind() {
             IndexErrorKind::IoError(ekind) => io::Error::new(ekind, e),
-            _ => io::Error::new(io::ErrorKind::Other, e),
+            IndexErrorKind::FlushFailed(_) => {
+                let ekind = e
+                    .error
+                    .downcast_ref::<io::Error>()
+                    .map_or(io::ErrorKind::Other, io::Error::kind);
+                io::Error::new(ekind, e)
+            }
+            _ => io::Error::other(e),
         }
     }
 }
 
-impl Error for IndexError {}
+impl Error for IndexError {
+    /// The `io::Error` behind an `IoError` or `FlushFailed` error
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.kind {
+            IndexErrorKind::IoError(_) | IndexErrorKind::FlushFailed(_) => Some(&*self.error),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for IndexError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.kind {
             IndexErrorKind::IoError(_) => write!(f, "{}", self.error),
+            IndexErrorKind::FlushFailed(stage) => write!(f, "writing {}: {}", stage, self.error),
+            ref kind => kind.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for IndexErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            IndexErrorKind::IoError(kind) => write!(f, "{}", kind),
             IndexErrorKind::FileNameError => write!(f, "filename conversion error"),
             IndexErrorKind::FileTooLong => write!(f, "file too long"),
             IndexErrorKind::LineTooLong => write!(f, "line too long"),
             IndexErrorKind::TooManyTrigrams => write!(f, "too many trigrams in file"),
             IndexErrorKind::BinaryDataPresent => write!(f, "binary file"),
-            IndexErrorKind::HighInvalidUtf8Ratio => write!(f, "Too many invalid utf-8 sequences"),
+            IndexErrorKind::BinaryFile => write!(f, "NUL byte at start of file"),
+            IndexErrorKind::HighInvalidUtf8Ratio => write!(f, "too many invalid utf-8 sequences"),
+            IndexErrorKind::DecompressionFailed => write!(f, "can't decompress file"),
+            IndexErrorKind::InvalidUtf16 => write!(f, "truncated UTF-16 text"),
+            IndexErrorKind::NoTrigrams => write!(f, "empty file"),
+            IndexErrorKind::IndexFull => write!(f, "index full"),
+            IndexErrorKind::FlushFailed(stage) => write!(f, "failed writing {}", stage),
         }
     }
 }
 
+impl fmt::Display for FlushStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            FlushStage::Header => "index header",
+            FlushStage::Paths => "path list",
+            FlushStage::Names => "name list",
+            FlushStage::PostingLists => "posting lists",
+            FlushStage::NameIndex => "name index",
+            FlushStage::PostingIndex => "posting list index",
+            FlushStage::Trailer => "index trailer",
+        })
+    }
+}
+
 /// A specialized result type for Index operations.
 ///
 /// Behaves similarly to std::io::Result
@@ -0,0 +1,105 @@
+// Writes the list of posting lists of an index, compressing it in blocks
+// if asked to.
+
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use consts::POSTING_BLOCK_SIZE;
+use zstd;
+
+/// zstd level the blocks of posting lists are compressed at
+const ZSTD_LEVEL: i32 = 3;
+
+/// Writes posting lists to `out`, as they are or compressed with zstd.
+///
+/// Offsets in the posting list index are offsets in the uncompressed
+/// lists, as returned by `offset`. When compressing, whole posting lists
+/// are gathered into blocks of about `POSTING_BLOCK_SIZE` bytes and each
+/// block is compressed on its own, so a reader only decompresses the
+/// blocks holding the lists it reads.
+pub struct PostingListWriter<'a, W: 'a + Write> {
+    out: &'a mut W,
+    compress: bool,
+    /// Uncompressed bytes written so far
+    offset: u64,
+    /// Compressed bytes written so far
+    compressed_len: u64,
+    /// The uncompressed lists of the block being filled
+    block: Vec<u8>,
+    /// Where each block written starts in the uncompressed and in the
+    /// compressed lists
+    blocks: Vec<(u64, u64)>,
+}
+
+impl<'a, W: Write> PostingListWriter<'a, W> {
+    pub fn new(out: &'a mut W, compress: bool) -> PostingListWriter<'a, W> {
+        PostingListWriter {
+            out,
+            compress,
+            offset: 0,
+            compressed_len: 0,
+            block: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Returns the offset in the uncompressed lists of the next list
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Ends the posting list being written. A list never spans blocks, so
+    /// a block is only compressed once the list that fills it has ended.
+    pub fn end_list(&mut self) -> io::Result<()> {
+        if self.block.len() >= POSTING_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let compressed = zstd::bulk::compress(&self.block, ZSTD_LEVEL)?;
+        self.out.write_all(&compressed)?;
+        self.blocks
+            .push((self.offset - self.block.len() as u64, self.compressed_len));
+        self.compressed_len += compressed.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes out the last block, and returns the posting block index: an
+    /// entry for each block and one for the end of the lists. It's empty if
+    /// the lists aren't compressed.
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        let mut index = Vec::new();
+        if !self.compress {
+            return Ok(index);
+        }
+        self.write_block()?;
+        self.blocks.push((self.offset, self.compressed_len));
+        for &(offset, compressed_offset) in &self.blocks {
+            index.write_u32::<BigEndian>(offset as u32)?;
+            index.write_u32::<BigEndian>(compressed_offset as u32)?;
+        }
+        Ok(index)
+    }
+}
+
+impl<'a, W: Write> Write for PostingListWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = if self.compress {
+            self.block.extend_from_slice(buf);
+            buf.len()
+        } else {
+            self.out.write(buf)?
+        };
+        self.offset += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
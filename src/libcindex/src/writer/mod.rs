@@ -3,42 +3,108 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+use std::fs::{File, OpenOptions};
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
-pub use self::error::{IndexError, IndexErrorKind, IndexResult};
-pub use self::write::IndexWriter;
+use byteorder::{BigEndian, WriteBytesExt};
+use consts::TRAILER_MAGIC;
+use crc32fast::Hasher;
+
+pub use self::error::{FlushStage, IndexError, IndexErrorKind, IndexResult};
+pub use self::listwriter::PostingListWriter;
+pub use self::trigrammer::{trigrams, FileTrigrams, LimitOverrides, Trigrammer};
+pub use self::write::{FileStats, IndexWriter, IndexWriterBuilder};
 
 mod error;
+mod listwriter;
 mod sparseset;
 mod write;
 
 mod postentry;
 mod postheap;
 mod postinglist;
+mod previous;
 mod sort_post;
 mod trigramiter;
+mod trigrammer;
 
 const NPOST: usize = (64 << 20) / 8; // 64 MB worth of post entries
 
 /// Returns the offset in a seekable object.
 pub fn get_offset<S: Seek>(seekable: &mut S) -> io::Result<u64> {
-    seekable.seek(SeekFrom::Current(0))
+    seekable.stream_position()
 }
 
+/// Where an `IndexWriter` writes the index, see `IndexWriterBuilder::build_to`.
+///
+/// It's read back as well, so `write_trailer` can checksum what was written.
+pub trait IndexFile: Read + Write + Seek + Send {}
+
+impl<F: Read + Write + Seek + Send> IndexFile for F {}
+
 /// Copies the data from a reader into a writer
-pub fn copy_file<R: Read + Seek, W: Write>(dest: &mut BufWriter<W>, src: &mut R) {
-    src.seek(SeekFrom::Start(0)).unwrap();
+pub fn copy_file<R: Read + Seek, W: Write>(dest: &mut BufWriter<W>, src: &mut R) -> io::Result<()> {
+    src.seek(SeekFrom::Start(0))?;
     let mut buf_src = BufReader::new(src);
-    while let Ok(b) = buf_src.fill_buf() {
-        if b.is_empty() {
-            break;
-        }
-        dest.write_all(b).unwrap();
-        let length = b.len();
+    loop {
+        let length = {
+            let b = buf_src.fill_buf()?;
+            if b.is_empty() {
+                return Ok(());
+            }
+            dest.write_all(b)?;
+            b.len()
+        };
         buf_src.consume(length);
     }
 }
 
+/// Creates the file for a new index at `filename`. It's opened for
+/// reading as well, so `write_trailer` can checksum what was written.
+pub fn create_index_file<P: AsRef<Path>>(filename: P) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(filename)
+}
+
+/// Finishes an index by writing its trailer: the section `offsets` (and
+/// flags, in version 3), a crc32 of everything written to `out` so far,
+/// and `TRAILER_MAGIC`
+pub fn write_trailer<F: Read + Write + Seek>(
+    out: &mut BufWriter<F>,
+    offsets: &[u64],
+) -> io::Result<()> {
+    for &v in offsets {
+        out.write_u32::<BigEndian>(v as u32)?;
+    }
+    out.flush()?;
+    let end = get_offset(out.get_mut())?;
+    let mut hasher = Hasher::new();
+    {
+        let f = out.get_mut();
+        f.seek(SeekFrom::Start(0))?;
+        let mut r = BufReader::with_capacity(256 << 10, f.take(end));
+        loop {
+            let n = {
+                let b = r.fill_buf()?;
+                hasher.update(b);
+                b.len()
+            };
+            if n == 0 {
+                break;
+            }
+            r.consume(n);
+        }
+    }
+    out.get_mut().seek(SeekFrom::Start(end))?;
+    out.write_u32::<BigEndian>(hasher.finalize())?;
+    out.write_all(TRAILER_MAGIC.as_bytes())
+}
+
 /// Used for writing trigrams
 pub trait WriteTrigram: Write {
     /// Write a trigram to a stream
@@ -6,11 +6,11 @@ impl PostEntry {
         PostEntry(((trigram as u64) << 32) | (file_id as u64))
     }
     pub fn trigram(&self) -> u32 {
-        let &PostEntry(ref u) = self;
+        let PostEntry(u) = self;
         (u >> 32) as u32
     }
     pub fn file_id(&self) -> u32 {
-        let &PostEntry(ref u) = self;
+        let PostEntry(u) = self;
         (u & 0xffffffff) as u32
     }
     pub fn value(&self) -> u64 {
@@ -1,59 +1,64 @@
-use std::vec;
-
 use libprofiling;
 
 use super::postentry::PostEntry;
 
-struct PostChunk {
+struct PostChunk<'a> {
     e: PostEntry,
-    m: vec::IntoIter<PostEntry>,
-    size: usize,
+    m: Box<dyn Iterator<Item = PostEntry> + 'a>,
+    done: bool,
 }
 
-impl PostChunk {
-    pub fn new(v: Vec<PostEntry>) -> Option<PostChunk> {
-        let size = v.len();
-        if size == 0 {
-            None
-        } else {
-            let mut m = v.into_iter();
-            let e = m.next().unwrap();
-            Some(PostChunk { e, m, size })
-        }
+impl<'a> PostChunk<'a> {
+    pub fn new(v: Vec<PostEntry>) -> Option<PostChunk<'static>> {
+        PostChunk::from_iter(v.into_iter())
+    }
+    /// Creates a chunk from an iterator that yields entries in sorted order
+    pub fn from_iter<I>(it: I) -> Option<PostChunk<'a>>
+    where
+        I: Iterator<Item = PostEntry> + 'a,
+    {
+        let mut m = Box::new(it);
+        let e = m.next()?;
+        Some(PostChunk { e, m, done: false })
     }
     pub fn is_empty(&self) -> bool {
-        self.size == 0
+        self.done
     }
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
-        self.size
+        if self.done {
+            0
+        } else {
+            1 + self.m.size_hint().0
+        }
     }
 }
 
-impl Iterator for PostChunk {
+impl<'a> Iterator for PostChunk<'a> {
     type Item = PostEntry;
     fn next(&mut self) -> Option<Self::Item> {
         if self.is_empty() {
             return None;
         }
         let result = self.e;
-        if let Some(c) = self.m.next() {
-            self.e = c;
+        match self.m.next() {
+            Some(c) => self.e = c,
+            None => self.done = true,
         }
-        self.size -= 1;
         Some(result)
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.size, Some(self.size))
+        let n = self.len();
+        (n, Some(n))
     }
 }
 
-pub struct PostHeap {
-    ch: Vec<PostChunk>,
+pub struct PostHeap<'a> {
+    ch: Vec<PostChunk<'a>>,
 }
 
-impl PostHeap {
-    pub fn new() -> PostHeap {
+impl<'a> PostHeap<'a> {
+    pub fn new() -> PostHeap<'a> {
         PostHeap { ch: Vec::new() }
     }
     pub fn add_mem(&mut self, v: Vec<PostEntry>) {
@@ -62,12 +67,21 @@ impl PostHeap {
             self.add(p);
         }
     }
-    fn add(&mut self, ch: PostChunk) {
+    /// Adds a stream of entries to the heap. `it` must yield entries in sorted order.
+    pub fn add_iter<I>(&mut self, it: I)
+    where
+        I: Iterator<Item = PostEntry> + 'a,
+    {
+        if let Some(p) = PostChunk::from_iter(it) {
+            self.add(p);
+        }
+    }
+    fn add(&mut self, ch: PostChunk<'a>) {
         if !ch.is_empty() {
             self.push(ch);
         }
     }
-    fn push(&mut self, ch: PostChunk) {
+    fn push(&mut self, ch: PostChunk<'a>) {
         let n = self.ch.len();
         self.ch.push(ch);
         if self.ch.len() >= 2 {
@@ -107,39 +121,38 @@ impl PostHeap {
     }
 }
 
-impl IntoIterator for PostHeap {
+impl<'a> IntoIterator for PostHeap<'a> {
     type Item = PostEntry;
-    type IntoIter = IntoIter;
+    type IntoIter = IntoIter<'a>;
     fn into_iter(self) -> Self::IntoIter {
         IntoIter::new(self)
     }
 }
 
-pub struct IntoIter {
-    inner: PostHeap,
-    place: usize,
+pub struct IntoIter<'a> {
+    inner: PostHeap<'a>,
 }
 
-impl IntoIter {
-    pub fn new(inner: PostHeap) -> Self {
-        IntoIter { inner, place: 0 }
+impl<'a> IntoIter<'a> {
+    pub fn new(inner: PostHeap<'a>) -> Self {
+        IntoIter { inner }
     }
 }
 
-impl Iterator for IntoIter {
+impl<'a> Iterator for IntoIter<'a> {
     type Item = PostEntry;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.place < self.inner.ch.len() {
-            let e = self.inner.ch[self.place].next();
-            if self.inner.ch[self.place].is_empty() {
-                self.place += 1;
-            } else {
-                self.inner.sift_down(self.place);
-            }
-            e
-        } else {
-            None
+        if self.inner.ch.is_empty() {
+            return None;
+        }
+        let e = self.inner.ch[0].next();
+        if self.inner.ch[0].is_empty() {
+            self.inner.ch.swap_remove(0);
+        }
+        if !self.inner.ch.is_empty() {
+            self.inner.sift_down(0);
         }
+        e
     }
 }
 
@@ -211,4 +224,36 @@ mod tests {
         p.add_mem(v2.clone());
         assert!(p.into_iter().collect::<Vec<_>>() == v_comb);
     }
+
+    #[test]
+    fn test_postheap_iter_drained() {
+        let v1 = vec![PostEntry::new(0, 1)];
+        let v2 = vec![PostEntry::new(5, 2), PostEntry::new(6, 2)];
+        let v3 = vec![PostEntry::new(1, 3), PostEntry::new(8, 3)];
+        let mut v_comb = v1.clone();
+        v_comb.extend(v2.iter().cloned());
+        v_comb.extend(v3.iter().cloned());
+        v_comb.sort();
+        let mut p = PostHeap::new();
+        p.add_mem(v1);
+        p.add_mem(v2);
+        p.add_mem(v3);
+        assert_eq!(p.into_iter().collect::<Vec<_>>(), v_comb);
+    }
+
+    #[test]
+    fn test_postheap_iter_many() {
+        let v1 = vec![PostEntry::new(0, 1), PostEntry::new(9, 1)];
+        let v2 = vec![PostEntry::new(3, 2), PostEntry::new(4, 2)];
+        let v3 = vec![PostEntry::new(1, 3), PostEntry::new(8, 3)];
+        let mut v_comb = v1.clone();
+        v_comb.extend(v2.iter().cloned());
+        v_comb.extend(v3.iter().cloned());
+        v_comb.sort();
+        let mut p = PostHeap::new();
+        p.add_mem(v1);
+        p.add_mem(v2);
+        p.add_iter(v3.into_iter());
+        assert_eq!(p.into_iter().collect::<Vec<_>>(), v_comb);
+    }
 }
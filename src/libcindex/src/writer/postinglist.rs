@@ -2,7 +2,6 @@
 
 use std::iter::{self, Chain, Once, Peekable, Scan};
 use std::num::Wrapping;
-use std::u32;
 
 use super::postentry::PostEntry;
 
@@ -0,0 +1,156 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Support for incremental indexing.
+//
+// A previous index is consulted while adding files: a file whose recorded
+// modification time and size match the previous index is not read again.
+// Its name is written to the new index as usual, and its posting entries
+// are copied forward from the previous index when the posting lists are
+// merged.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+use std::vec;
+
+use libcsearch::reader::{CorruptIndex, FileMetadata, IndexReader, PostReader, POST_ENTRY_SIZE};
+
+use super::postentry::PostEntry;
+
+/// Marks an id in the previous index that isn't being carried forward
+const NOT_CARRIED: u32 = u32::MAX;
+
+/// Returns the metadata recorded in the index for a file
+pub fn file_metadata(m: &fs::Metadata) -> Option<FileMetadata> {
+    let mtime = m.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(FileMetadata {
+        mtime: mtime.as_secs() * 1_000_000_000 + u64::from(mtime.subsec_nanos()),
+        size: m.len(),
+    })
+}
+
+pub struct PreviousIndex {
+    reader: IndexReader,
+    ids: HashMap<String, u32>,
+    /// Maps an id in the previous index to its id in the new index
+    carried: Vec<u32>,
+    num_carried: usize,
+}
+
+impl PreviousIndex {
+    pub fn new(reader: IndexReader) -> PreviousIndex {
+        let num_name = reader.num_name;
+        let ids = (0..num_name as u32).map(|i| (reader.name(i), i)).collect();
+        PreviousIndex {
+            reader,
+            ids,
+            carried: vec![NOT_CARRIED; num_name],
+            num_carried: 0,
+        }
+    }
+
+    /// Returns the id of `name` in the previous index if it was indexed
+    /// with the same metadata as `meta`. Aliases have no posting entries
+    /// to carry forward, so they're never unchanged.
+    pub fn unchanged(&self, name: &str, meta: &FileMetadata) -> Option<u32> {
+        let &id = self.ids.get(name)?;
+        if self.carried[id as usize] != NOT_CARRIED || self.reader.alias_of(id).is_some() {
+            return None;
+        }
+        match self.reader.file_metadata(id) {
+            Some(ref m) if m == meta => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Returns the metadata recorded for each file in the previous index,
+    /// leaving out aliases
+    pub fn metadata(&self) -> HashMap<String, FileMetadata> {
+        self.ids
+            .iter()
+            .filter(|&(_, &id)| self.reader.alias_of(id).is_none())
+            .filter_map(|(name, &id)| Some((name.clone(), self.reader.file_metadata(id)?)))
+            .collect()
+    }
+
+    /// Record that `old_id` in the previous index is `new_id` in the new index
+    pub fn carry(&mut self, old_id: u32, new_id: u32) {
+        self.carried[old_id as usize] = new_id;
+        self.num_carried += 1;
+    }
+
+    /// Returns the number of files carried forward
+    pub fn num_carried(&self) -> usize {
+        self.num_carried
+    }
+
+    /// Returns the posting entries of every carried file, in sorted order
+    pub fn into_post_entries(self) -> CarriedPostings {
+        CarriedPostings {
+            index: self,
+            tri_num: 0,
+            pending: Vec::new().into_iter(),
+            error: Rc::new(Cell::new(None)),
+        }
+    }
+}
+
+/// Yields the posting entries of carried files, renumbered into the new index
+pub struct CarriedPostings {
+    index: PreviousIndex,
+    tri_num: usize,
+    pending: vec::IntoIter<PostEntry>,
+    /// Set if a posting list of the previous index couldn't be decoded,
+    /// which ends the entries
+    error: Rc<Cell<Option<CorruptIndex>>>,
+}
+
+impl CarriedPostings {
+    /// Returns where the error that ended the entries early is kept, to be
+    /// checked once they've been consumed
+    pub fn error(&self) -> Rc<Cell<Option<CorruptIndex>>> {
+        self.error.clone()
+    }
+}
+
+impl Iterator for CarriedPostings {
+    type Item = PostEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(e) = self.pending.next() {
+                return Some(e);
+            }
+            let reader = &self.index.reader;
+            if self.tri_num >= reader.num_post {
+                return None;
+            }
+            let (trigram, count, _) = reader.list_at(self.tri_num * POST_ENTRY_SIZE);
+            self.tri_num += 1;
+            if count == 0 {
+                continue;
+            }
+            let carried = &self.index.carried;
+            let list = match PostReader::list(reader, trigram, &None) {
+                Ok(list) => list,
+                Err(e) => {
+                    self.error.set(Some(e));
+                    self.tri_num = reader.num_post;
+                    return None;
+                }
+            };
+            let mut v = list
+                .into_iter()
+                .filter_map(|id| carried.get(id as usize))
+                .filter(|&&id| id != NOT_CARRIED)
+                .map(|&id| PostEntry::new(trigram, id))
+                .collect::<Vec<_>>();
+            v.sort();
+            self.pending = v.into_iter();
+        }
+    }
+}
@@ -83,7 +83,7 @@ impl<'a> IntoIterator for &'a SparseSet {
 #[test]
 fn test_init() {
     let s = SparseSet::new();
-    assert!(s.len() == 0);
+    assert_eq!(s.len(), 0);
     assert!(s.is_empty());
 }
 
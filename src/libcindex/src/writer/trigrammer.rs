@@ -0,0 +1,444 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Computing the trigrams of a file is independent of everything else the
+// IndexWriter does, so it can be split off and done on other threads.
+// A Trigrammer reads a file with the limits of the writer it came from
+// and hands back a FileTrigrams, which the writer then adds with
+// IndexWriter::add_trigrams.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use flate2::read::MultiGzDecoder;
+use libcsearch;
+use libcsearch::reader::FileMetadata;
+use libprofiling;
+
+use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::previous;
+use super::sparseset::SparseSet;
+use super::trigramiter::TrigramReader;
+use super::write::BINARY_SNIFF_LEN;
+
+/// Limits on the files that get indexed
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Max number of allowed trigrams in a file
+    pub max_trigram_count: u64,
+    /// Max ratio of invalid utf-8 sequences allowed
+    pub max_utf8_invalid: f64,
+    /// Don't index a file if its size in bytes is larger than this
+    pub max_file_len: u64,
+    /// Stop indexing a file if it has a line longer than this
+    pub max_line_len: u64,
+    /// Skip a file as binary if it has a NUL byte in this many bytes at its
+    /// start
+    pub binary_sniff_len: u64,
+    /// Hash the contents of each file, so files with the same contents
+    /// can be found
+    pub hash_contents: bool,
+}
+
+/// Returns the trigrams an `IndexWriter` records for a file with the
+/// contents `data` when the file is within all of its limits, computed
+/// the same way. Contents that start with a UTF-16 byte order mark are
+/// transcoded to UTF-8 first. A trigram whose last two bytes can't follow
+/// each other in UTF-8 isn't recorded, and contents shorter than 3 bytes
+/// have no trigrams.
+///
+/// Fails like the writer for contents it never indexes, whatever the
+/// limits: contents that are empty or only whitespace, binary contents,
+/// with a NUL byte, and UTF-16 that ends in the middle of a character.
+///
+/// ```
+/// let t = libcindex::trigrams(b"hello").unwrap();
+/// assert_eq!(t.into_iter().collect::<Vec<_>>(), vec![*b"ell", *b"hel", *b"llo"]);
+/// ```
+pub fn trigrams(data: &[u8]) -> IndexResult<BTreeSet<[u8; 3]>> {
+    let limits = Limits {
+        max_trigram_count: u64::MAX,
+        max_utf8_invalid: 1.0,
+        max_file_len: u64::MAX,
+        max_line_len: u64::MAX,
+        binary_sniff_len: BINARY_SNIFF_LEN,
+        hash_contents: false,
+    };
+    check_not_blank(data)?;
+    let (trigrams, _) = limits.trigrams(data, data.len() as u64, &mut SparseSet::new())?;
+    Ok(trigrams
+        .into_iter()
+        .map(|t| [(t >> 16) as u8, (t >> 8) as u8, t as u8])
+        .collect())
+}
+
+/// Per-file replacements for some of the limits of an `IndexWriter`,
+/// see `Trigrammer::read_file_with`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LimitOverrides {
+    pub max_trigram_count: Option<u64>,
+    pub max_utf8_invalid: Option<f64>,
+    pub max_file_len: Option<u64>,
+    pub max_line_len: Option<u64>,
+}
+
+impl Limits {
+    /// Returns these limits with `o` applied
+    pub fn with(&self, o: &LimitOverrides) -> Limits {
+        Limits {
+            max_trigram_count: o.max_trigram_count.unwrap_or(self.max_trigram_count),
+            max_utf8_invalid: o.max_utf8_invalid.unwrap_or(self.max_utf8_invalid),
+            max_file_len: o.max_file_len.unwrap_or(self.max_file_len),
+            max_line_len: o.max_line_len.unwrap_or(self.max_line_len),
+            binary_sniff_len: self.binary_sniff_len,
+            hash_contents: self.hash_contents,
+        }
+    }
+
+    /// Reads the trigrams of `f`, which is `size` bytes long, using `set`
+    /// as scratch space. Contents that start with a UTF-16 byte order mark
+    /// are transcoded to UTF-8 first, and the limits apply to the UTF-8.
+    ///
+    /// Returns the trigrams, along with a hash of the contents as they
+    /// were read if `hash_contents` is set.
+    pub fn trigrams<R: Read>(
+        &self,
+        f: R,
+        size: u64,
+        set: &mut SparseSet,
+    ) -> IndexResult<(Vec<u32>, Option<u64>)> {
+        let mut f = HashingReader {
+            inner: f,
+            hasher: if self.hash_contents {
+                Some(DefaultHasher::new())
+            } else {
+                None
+            },
+        };
+        let mut bom = Vec::with_capacity(2);
+        f.by_ref().take(2).read_to_end(&mut bom)?;
+        let trigrams = match libcsearch::utf16_bom(&bom) {
+            Some(order) => self.utf16_trigrams(&mut f, size, order, set)?,
+            None => self.text_trigrams(Cursor::new(bom).chain(&mut f), size, set)?,
+        };
+        Ok((trigrams, f.hasher.map(|h| h.finish())))
+    }
+
+    /// Transcodes the UTF-16 text `f` that follows a byte order mark, and
+    /// reads the trigrams of the result
+    fn utf16_trigrams<R: Read>(
+        &self,
+        f: R,
+        size: u64,
+        order: libcsearch::Utf16Order,
+        set: &mut SparseSet,
+    ) -> IndexResult<Vec<u32>> {
+        let _frame = libprofiling::profile("Limits::trigrams: Transcode UTF-16");
+        // every 2 byte code unit is at least 1 byte of UTF-8
+        let max_len = self.max_file_len.saturating_mul(2).saturating_add(2);
+        if size > max_len {
+            return Err(IndexError::new(
+                IndexErrorKind::FileTooLong,
+                format!("UTF-16 file too long, ignoring ({} > {})", size, max_len),
+            ));
+        }
+        let mut data = Vec::new();
+        f.take(max_len).read_to_end(&mut data)?;
+        let text = libcsearch::decode_utf16(&data, order)
+            .map_err(|e| IndexError::new(IndexErrorKind::InvalidUtf16, e))?;
+        let len = text.len() as u64;
+        if len > self.max_file_len {
+            return Err(IndexError::new(
+                IndexErrorKind::FileTooLong,
+                format!(
+                    "file too long once transcoded from UTF-16, ignoring ({} > {})",
+                    len, self.max_file_len
+                ),
+            ));
+        }
+        self.text_trigrams(text.as_bytes(), len, set)
+    }
+
+    /// Reads the trigrams of the UTF-8 text `f`, which is `size` bytes long
+    fn text_trigrams<R: Read>(
+        &self,
+        mut f: R,
+        size: u64,
+        set: &mut SparseSet,
+    ) -> IndexResult<Vec<u32>> {
+        if size > self.max_file_len {
+            return Err(IndexError::new(
+                IndexErrorKind::FileTooLong,
+                format!("file too long, ignoring ({} > {})", size, self.max_file_len),
+            ));
+        }
+        // like git, take a file with a NUL byte near its start to be binary
+        let mut head = Vec::new();
+        f.by_ref()
+            .take(self.binary_sniff_len)
+            .read_to_end(&mut head)?;
+        if let Some(offset) = head.iter().position(|&b| b == 0) {
+            return Err(IndexError::new(
+                IndexErrorKind::BinaryFile,
+                format!("binary file, NUL byte at offset {}", offset),
+            ));
+        }
+        let contents = Cursor::new(head).chain(f);
+        set.clear();
+        let max_utf8_invalid = ((size as f64) * self.max_utf8_invalid) as u64;
+        {
+            let mut trigrams = TrigramReader::new(contents, max_utf8_invalid, self.max_line_len);
+            let _trigram_insert_frame = libprofiling::profile("IndexWriter::add: Insert Trigrams");
+            for each_trigram in trigrams.by_ref() {
+                set.insert(each_trigram);
+            }
+            if let Some(Err(e)) = trigrams.take_error() {
+                return Err(e);
+            }
+        }
+        if (set.len() as u64) > self.max_trigram_count {
+            return Err(IndexError::new(
+                IndexErrorKind::TooManyTrigrams,
+                format!(
+                    "Too many trigrams ({} > {})",
+                    set.len(),
+                    self.max_trigram_count
+                ),
+            ));
+        }
+        Ok(set.take_dense())
+    }
+
+    /// Reads the trigrams of the file `f` named `path`, which is `size`
+    /// bytes long. Gzipped files are decompressed first and the limits
+    /// apply to their decompressed contents.
+    ///
+    /// Returns the number of bytes the trigrams were read from, along with
+    /// the trigrams and hash from `trigrams`. A file that's empty or only
+    /// whitespace, once decompressed, is skipped with `NoTrigrams`, since no
+    /// search could match it.
+    pub fn file_trigrams(
+        &self,
+        path: &Path,
+        f: File,
+        size: u64,
+        set: &mut SparseSet,
+    ) -> IndexResult<(u64, Vec<u32>, Option<u64>)> {
+        if !libcsearch::is_gzip(path) {
+            let mut f = BlankReader {
+                inner: f,
+                blank: true,
+            };
+            let (t, h) = self.trigrams(&mut f, size, set)?;
+            if f.blank {
+                return Err(blank_file());
+            }
+            return Ok((size, t, h));
+        }
+        let _frame = libprofiling::profile("Limits::file_trigrams: Decompress");
+        let mut contents = Vec::new();
+        MultiGzDecoder::new(f)
+            .take(self.max_file_len.saturating_add(1))
+            .read_to_end(&mut contents)
+            .map_err(|e| {
+                IndexError::new(
+                    IndexErrorKind::DecompressionFailed,
+                    format!("can't decompress file: {}", e),
+                )
+            })?;
+        let len = contents.len() as u64;
+        if len > self.max_file_len {
+            return Err(IndexError::new(
+                IndexErrorKind::FileTooLong,
+                format!(
+                    "decompressed file too long, ignoring (> {})",
+                    self.max_file_len
+                ),
+            ));
+        }
+        check_not_blank(&contents)?;
+        self.trigrams(&contents[..], len, set)
+            .map(|(t, h)| (len, t, h))
+    }
+}
+
+/// Fails with `NoTrigrams` if `contents` are empty or only whitespace
+fn check_not_blank(contents: &[u8]) -> IndexResult<()> {
+    if contents.iter().all(u8::is_ascii_whitespace) {
+        return Err(blank_file());
+    }
+    Ok(())
+}
+
+fn blank_file() -> IndexError {
+    IndexError::new(
+        IndexErrorKind::NoTrigrams,
+        "file is empty or only whitespace",
+    )
+}
+
+/// Passes reads through to `inner`, hashing what was read if there's a
+/// `hasher`
+struct HashingReader<R> {
+    inner: R,
+    hasher: Option<DefaultHasher>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref mut h) = self.hasher {
+            h.write(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Passes reads through to `inner`, noting whether everything read so far
+/// was whitespace
+struct BlankReader<R> {
+    inner: R,
+    blank: bool,
+}
+
+impl<R: Read> Read for BlankReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.blank {
+            self.blank = buf[..n].iter().all(u8::is_ascii_whitespace);
+        }
+        Ok(n)
+    }
+}
+
+/// The trigrams of a file, ready to be added to an index with
+/// `IndexWriter::add_trigrams`
+pub struct FileTrigrams {
+    size: u64,
+    metadata: Option<FileMetadata>,
+    /// `None` if the file was unchanged since the previous index, so it
+    /// wasn't read
+    trigrams: Option<Vec<u32>>,
+    /// Hash of the contents, if the writer deduplicates files
+    hash: Option<u64>,
+}
+
+impl FileTrigrams {
+    /// Returns the size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub(super) fn into_parts(self) -> (u64, Option<FileMetadata>, Option<Vec<u32>>, Option<u64>) {
+        (self.size, self.metadata, self.trigrams, self.hash)
+    }
+}
+
+/// Reads files and computes their trigrams for an `IndexWriter`.
+///
+/// Each thread should use its own `Trigrammer`; cloning one is cheap.
+///
+/// ```no_run
+/// # use libcindex::writer::IndexWriter;
+/// let mut index = IndexWriter::new("index").unwrap();
+/// let mut trigrammer = index.trigrammer();
+/// let t = trigrammer.read_file("/path/to/file").unwrap();
+/// index.add_trigrams("/path/to/file", t).unwrap();
+/// index.flush().unwrap();
+/// ```
+pub struct Trigrammer {
+    limits: Limits,
+    /// Metadata of the files in the previous index, if there is one
+    previous: Option<Arc<HashMap<String, FileMetadata>>>,
+    trigram: SparseSet,
+}
+
+impl Trigrammer {
+    pub fn new(limits: Limits, previous: Option<Arc<HashMap<String, FileMetadata>>>) -> Trigrammer {
+        Trigrammer {
+            limits,
+            previous,
+            trigram: SparseSet::new(),
+        }
+    }
+
+    /// Opens a file and computes its trigrams.
+    ///
+    /// Files that have the same modification time and size as in the
+    /// previous index of the writer aren't read.
+    pub fn read_file<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<FileTrigrams> {
+        self.read_file_with(filename, &LimitOverrides::default())
+    }
+
+    /// Like `read_file`, but with some of the limits replaced by `overrides`
+    /// for this file only
+    pub fn read_file_with<P: AsRef<Path>>(
+        &mut self,
+        filename: P,
+        overrides: &LimitOverrides,
+    ) -> IndexResult<FileTrigrams> {
+        let _frame = libprofiling::profile("Trigrammer::read_file");
+        let f = File::open(filename.as_ref())?;
+        let metadata = f.metadata()?;
+        let file_metadata = previous::file_metadata(&metadata);
+        let size = metadata.len();
+        if let (Some(ref m), Some(name), Some(p)) = (
+            file_metadata,
+            filename.as_ref().to_str(),
+            self.previous.as_ref(),
+        ) {
+            if p.get(name) == Some(m) {
+                return Ok(FileTrigrams {
+                    size,
+                    metadata: file_metadata,
+                    trigrams: None,
+                    hash: None,
+                });
+            }
+        }
+        let limits = self.limits.with(overrides);
+        let (size, trigrams, hash) =
+            limits.file_trigrams(filename.as_ref(), f, size, &mut self.trigram)?;
+        Ok(FileTrigrams {
+            size,
+            metadata: file_metadata,
+            trigrams: Some(trigrams),
+            hash,
+        })
+    }
+
+    /// Computes the trigrams of `size` bytes read from `f`, for contents
+    /// that aren't a file of their own, such as a file inside an archive.
+    /// Nothing is decompressed, and `overrides` apply like in
+    /// `read_file_with`.
+    pub fn read_with<R: Read>(
+        &mut self,
+        f: R,
+        size: u64,
+        overrides: &LimitOverrides,
+    ) -> IndexResult<FileTrigrams> {
+        let _frame = libprofiling::profile("Trigrammer::read");
+        let (trigrams, hash) = self
+            .limits
+            .with(overrides)
+            .trigrams(f, size, &mut self.trigram)?;
+        Ok(FileTrigrams {
+            size,
+            metadata: None,
+            trigrams: Some(trigrams),
+            hash,
+        })
+    }
+}
+
+impl Clone for Trigrammer {
+    fn clone(&self) -> Trigrammer {
+        Trigrammer::new(self.limits, self.previous.clone())
+    }
+}
@@ -7,28 +7,37 @@
 // license that can be found in the LICENSE file.
 
 #![allow(dead_code)]
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{self, BufWriter, Read, Write};
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use byteorder::{BigEndian, WriteBytesExt};
+use libcsearch::reader::{
+    FileMetadata, IndexReader, PostReader, FILE_METADATA_SIZE, POST_ENTRY_SIZE,
+};
 use libprofiling;
 use libvarint;
 use tempfile::tempfile;
 
-use consts::{MAGIC, TRAILER_MAGIC};
+use consts::{
+    FLAG_ZSTD_POSTINGS, MAGIC, MAGIC_V3, POSTING_BLOCK_SIZE, RELATIVE_MARKER, TRAILER_MAGIC,
+};
 
-use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::error::{FlushStage, IndexError, IndexErrorKind, IndexResult};
+use super::listwriter::PostingListWriter;
 use super::postentry::PostEntry;
 use super::postheap::PostHeap;
 use super::postinglist::{to_diffs, TakeWhilePeek};
+use super::previous::{self, PreviousIndex};
 use super::sort_post::sort_post;
 use super::sparseset::SparseSet;
-use super::trigramiter::TrigramReader;
+use super::trigrammer::{FileTrigrams, Limits, Trigrammer};
 use super::NPOST;
-use super::{copy_file, get_offset, WriteTrigram};
+use super::{copy_file, create_index_file, get_offset, write_trailer, IndexFile, WriteTrigram};
 
 // Index writing.  See read.rs for details of on-disk format.
 //
@@ -45,21 +54,37 @@ use super::{copy_file, get_offset, WriteTrigram};
 // of the files and then merge that index into an existing one.  This would
 // allow incremental updating of an existing index when a directory changes.
 // But we have not implemented that.
+//
+// What we do implement is skipping unchanged files: when an IndexWriter is
+// created with a previous index, files whose modification time and size
+// are unchanged aren't read again, and their posting entries are copied
+// from the previous index instead.  See previous.rs.
+//
+// With deduplication, a file with the same size and content hash as one
+// that was already added isn't trigrammed again: its name is written
+// with an alias record pointing at the earlier file instead.
 
 const MAX_FILE_LEN: u64 = 1 << 30;
 const MAX_TEXT_TRIGRAMS: u64 = 30000;
 const MAX_INVALID_UTF8_RATION: f64 = 0.1;
 const MAX_LINE_LEN: u64 = 2000;
+pub(super) const BINARY_SNIFF_LEN: u64 = 8 << 10;
+
+/// Bytes a posting list takes besides its entries: the trigram, the
+/// terminating zero, and its entry in the posting list index
+const LIST_OVERHEAD: u64 = 3 + 1 + POST_ENTRY_SIZE as u64;
+
+/// What `IndexWriter` added to the index for one file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileStats {
+    /// Number of distinct trigrams added for the file
+    pub trigram_count: usize,
+    /// Number of bytes read to find those trigrams
+    pub byte_len: u64,
+}
 
 pub struct IndexWriter {
-    /// Max number of allowed trigrams in a file
-    pub max_trigram_count: u64,
-    /// Max percentage of invalid utf-8 sequences allowed
-    pub max_utf8_invalid: f64,
-    /// Don't index a file if its size in bytes is larger than this
-    pub max_file_len: u64,
-    /// Stop indexing a file if it has a line longer than this
-    pub max_line_len: u64,
+    limits: Limits,
 
     paths: Vec<OsString>,
 
@@ -77,24 +102,174 @@ pub struct IndexWriter {
     post_files: Vec<Vec<PostEntry>>,
     post_index: BufWriter<File>,
 
-    index: BufWriter<File>,
+    index: BufWriter<Box<dyn IndexFile>>,
+
+    previous: Option<PreviousIndex>,
+
+    /// Maps the size and content hash of each file added so far to its id,
+    /// if files are deduplicated
+    contents: Option<HashMap<(u64, u64), u32>>,
+
+    /// Paths and names under this directory are stored relative to it
+    relative_to: Option<PathBuf>,
+
+    /// Leave out modification times, which differ between checkouts
+    reproducible: bool,
+
+    /// Adding a file fails if the index would be larger than this
+    max_index_len: Option<u64>,
+    /// Compress the posting lists in blocks
+    compress: bool,
+    /// One bit for each trigram that has a posting list so far
+    listed: Vec<u64>,
+    num_listed: u64,
+    /// Bytes of the name list so far
+    name_len: u64,
+    /// Most bytes the posting entries so far can take
+    post_len: u64,
 }
 
-impl IndexWriter {
-    /// Creates a new index file at `filename`
-    ///
-    /// ```no_run
-    /// # use libcindex::writer::IndexWriter;
-    /// let index = IndexWriter::new("index").unwrap();
-    /// ```
-    pub fn new<P: AsRef<Path>>(filename: P) -> io::Result<IndexWriter> {
-        let _frame = libprofiling::profile("IndexWriter::new");
-        let f = File::create(filename)?;
-        Ok(IndexWriter {
+/// Builds an `IndexWriter` with non-default limits
+///
+/// ```no_run
+/// # use libcindex::writer::IndexWriterBuilder;
+/// let index = IndexWriterBuilder::new()
+///     .max_file_len(1 << 20)
+///     .max_line_len(4000)
+///     .build("index")
+///     .unwrap();
+/// ```
+pub struct IndexWriterBuilder {
+    max_trigram_count: u64,
+    max_utf8_invalid: f64,
+    max_file_len: u64,
+    max_line_len: u64,
+    binary_sniff_len: u64,
+    previous: Option<IndexReader>,
+    dedup: bool,
+    relative_to: Option<PathBuf>,
+    reproducible: bool,
+    max_index_len: Option<u64>,
+    compress: bool,
+}
+
+impl Default for IndexWriterBuilder {
+    fn default() -> IndexWriterBuilder {
+        IndexWriterBuilder {
             max_trigram_count: MAX_TEXT_TRIGRAMS,
             max_utf8_invalid: MAX_INVALID_UTF8_RATION,
             max_file_len: MAX_FILE_LEN,
             max_line_len: MAX_LINE_LEN,
+            binary_sniff_len: BINARY_SNIFF_LEN,
+            previous: None,
+            dedup: false,
+            relative_to: None,
+            reproducible: false,
+            max_index_len: None,
+            compress: false,
+        }
+    }
+}
+
+impl IndexWriterBuilder {
+    pub fn new() -> IndexWriterBuilder {
+        IndexWriterBuilder::default()
+    }
+    /// Max number of allowed trigrams in a file
+    pub fn max_trigram_count(mut self, count: u64) -> IndexWriterBuilder {
+        self.max_trigram_count = count;
+        self
+    }
+    /// Max ratio of invalid utf-8 sequences allowed, between 0.0 and 1.0
+    pub fn max_utf8_invalid(mut self, ratio: f64) -> IndexWriterBuilder {
+        self.max_utf8_invalid = ratio;
+        self
+    }
+    /// Don't index a file if its size in bytes is larger than this
+    pub fn max_file_len(mut self, len: u64) -> IndexWriterBuilder {
+        self.max_file_len = len;
+        self
+    }
+    /// Stop indexing a file if it has a line longer than this
+    pub fn max_line_len(mut self, len: u64) -> IndexWriterBuilder {
+        self.max_line_len = len;
+        self
+    }
+    /// Skip a file as binary if any of its first `len` bytes is NUL.
+    /// Defaults to 8 KiB; 0 turns the check off.
+    pub fn binary_sniff_len(mut self, len: u64) -> IndexWriterBuilder {
+        self.binary_sniff_len = len;
+        self
+    }
+    /// Reuse the unchanged files of `previous`, see `IndexWriter::with_previous`
+    pub fn previous(mut self, previous: IndexReader) -> IndexWriterBuilder {
+        self.previous = Some(previous);
+        self
+    }
+    /// Store a file with the same contents as one that was already added
+    /// as an alias of it, instead of indexing it again. Off by default, as
+    /// older readers don't find the aliases.
+    pub fn dedup(mut self, yes: bool) -> IndexWriterBuilder {
+        self.dedup = yes;
+        self
+    }
+    /// Store the paths and file names under `base` relative to it, so the
+    /// index can be used where the files are under another directory. The
+    /// index is marked as relative, see `IndexReader::is_relative`.
+    ///
+    /// Names of files that aren't under `base` are stored as they are.
+    pub fn relative_to<P: AsRef<Path>>(mut self, base: P) -> IndexWriterBuilder {
+        self.relative_to = Some(base.as_ref().to_path_buf());
+        self
+    }
+    /// Don't record the modification times of files, so the same files
+    /// give a byte-identical index wherever and whenever they're indexed.
+    /// An index built on it with `previous` rereads every file.
+    pub fn reproducible(mut self, yes: bool) -> IndexWriterBuilder {
+        self.reproducible = yes;
+        self
+    }
+    /// Fail to add a file with `IndexErrorKind::IndexFull` if the index
+    /// could then be larger than `len` bytes, see `IndexWriter::size_estimate`
+    pub fn max_index_len(mut self, len: u64) -> IndexWriterBuilder {
+        self.max_index_len = Some(len);
+        self
+    }
+    /// Compress the posting lists with zstd, in blocks that are each
+    /// decompressed when one of their lists is first read. The index is
+    /// written in format version 3, which older builds can't read.
+    pub fn compress(mut self, yes: bool) -> IndexWriterBuilder {
+        self.compress = yes;
+        self
+    }
+    /// Creates the index file at `filename`
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `max_utf8_invalid`
+    /// isn't in `0.0..=1.0`.
+    pub fn build<P: AsRef<Path>>(self, filename: P) -> io::Result<IndexWriter> {
+        let limits = self.limits()?;
+        let f = create_index_file(filename)?;
+        self.build_with_limits(limits, Box::new(f))
+    }
+    /// Writes the index to `out` instead of a new file
+    ///
+    /// ```
+    /// # use libcindex::writer::IndexWriterBuilder;
+    /// # use std::io::Cursor;
+    /// let mut index = IndexWriterBuilder::new()
+    ///     .build_to(Cursor::new(Vec::new()))
+    ///     .unwrap();
+    /// index.add("a", Cursor::new(b"hello"), 5).unwrap();
+    /// index.flush().unwrap();
+    /// ```
+    pub fn build_to<F: IndexFile + 'static>(self, out: F) -> io::Result<IndexWriter> {
+        let limits = self.limits()?;
+        self.build_with_limits(limits, Box::new(out))
+    }
+    fn build_with_limits(self, limits: Limits, out: Box<dyn IndexFile>) -> io::Result<IndexWriter> {
+        let _frame = libprofiling::profile("IndexWriter::new");
+        Ok(IndexWriter {
+            limits,
             paths: Vec::new(),
             name_data: make_temp_buf()?,
             name_index: make_temp_buf()?,
@@ -104,9 +279,83 @@ impl IndexWriter {
             post: Vec::with_capacity(NPOST),
             post_files: Vec::new(),
             post_index: make_temp_buf()?,
-            index: BufWriter::with_capacity(256 << 10, f),
+            index: BufWriter::with_capacity(256 << 10, out),
+            previous: self.previous.map(PreviousIndex::new),
+            contents: if self.dedup {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            relative_to: self.relative_to,
+            reproducible: self.reproducible,
+            max_index_len: self.max_index_len,
+            compress: self.compress,
+            listed: vec![0; 1 << 18],
+            num_listed: 0,
+            name_len: 0,
+            post_len: 0,
         })
     }
+    /// Returns a `Trigrammer` with these limits, without creating an index.
+    ///
+    /// The previous index isn't consulted, so every file is read.
+    pub fn trigrammer(&self) -> io::Result<Trigrammer> {
+        Ok(Trigrammer::new(self.limits()?, None))
+    }
+    fn limits(&self) -> io::Result<Limits> {
+        if !(0.0..=1.0).contains(&self.max_utf8_invalid) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid utf-8 ratio must be between 0.0 and 1.0, got {}",
+                    self.max_utf8_invalid
+                ),
+            ));
+        }
+        Ok(Limits {
+            max_trigram_count: self.max_trigram_count,
+            max_utf8_invalid: self.max_utf8_invalid,
+            max_file_len: self.max_file_len,
+            max_line_len: self.max_line_len,
+            binary_sniff_len: self.binary_sniff_len,
+            hash_contents: self.dedup,
+        })
+    }
+}
+
+impl IndexWriter {
+    /// Creates a new index file at `filename`
+    ///
+    /// ```no_run
+    /// # use libcindex::writer::IndexWriter;
+    /// let index = IndexWriter::new("index").unwrap();
+    /// ```
+    pub fn new<P: AsRef<Path>>(filename: P) -> io::Result<IndexWriter> {
+        IndexWriterBuilder::new().build(filename)
+    }
+
+    /// Creates a new index file at `filename` that reuses `previous`.
+    ///
+    /// Files passed to `add_file` that have the same modification time
+    /// and size as in `previous` aren't read again; their posting
+    /// entries are copied over from `previous` instead.
+    ///
+    /// ```no_run
+    /// # extern crate libcindex;
+    /// # extern crate libcsearch;
+    /// # use libcindex::writer::IndexWriter;
+    /// # use libcsearch::reader::IndexReader;
+    /// # fn main() {
+    /// let old = IndexReader::open("index").unwrap();
+    /// let index = IndexWriter::with_previous("index~", old).unwrap();
+    /// # }
+    /// ```
+    pub fn with_previous<P: AsRef<Path>>(
+        filename: P,
+        previous: IndexReader,
+    ) -> io::Result<IndexWriter> {
+        IndexWriterBuilder::new().previous(previous).build(filename)
+    }
 
     /// Add the specified paths to the index.
     /// Note that this only writes the names of the paths into
@@ -116,65 +365,224 @@ impl IndexWriter {
         self.paths.extend(paths);
     }
 
+    /// Copies the files of `reader` whose names aren't in `skip` into this
+    /// index with their posting entries, along with its paths that aren't
+    /// in `skip`. The changed files can then be added with `add_file`,
+    /// rather than indexed on their own and merged in.
+    ///
+    /// Returns the number of files copied. An alias is copied only if the
+    /// file it's an alias of is. Names aren't checked against the other
+    /// files added, so a file that's added again should be in `skip`.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if only one of `reader`
+    /// and this index is relative, and with `IndexErrorKind::IndexFull` if
+    /// the copied files could take the index past `max_index_len`, which
+    /// leaves some of them copied.
+    ///
+    /// ```no_run
+    /// # extern crate libcindex;
+    /// # extern crate libcsearch;
+    /// # use libcindex::writer::IndexWriter;
+    /// # use libcsearch::reader::IndexReader;
+    /// # use std::collections::HashSet;
+    /// # use std::path::PathBuf;
+    /// # fn main() {
+    /// let old = IndexReader::open("index").unwrap();
+    /// let changed = vec![PathBuf::from("/src/main.rs")];
+    /// let mut index = IndexWriter::new("index~").unwrap();
+    /// index
+    ///     .carry_forward(&old, &changed.iter().cloned().collect::<HashSet<_>>())
+    ///     .unwrap();
+    /// for f in &changed {
+    ///     index.add_file(f).unwrap();
+    /// }
+    /// index.flush().unwrap();
+    /// # }
+    /// ```
+    pub fn carry_forward(
+        &mut self,
+        reader: &IndexReader,
+        skip: &HashSet<PathBuf>,
+    ) -> IndexResult<usize> {
+        let _frame = libprofiling::profile("IndexWriter::carry_forward");
+        if reader.is_relative() != self.relative_to.is_some() {
+            return Err(IndexError::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't carry files between a relative and an absolute index",
+            )));
+        }
+        let paths = reader.indexed_paths().into_iter();
+        self.paths.extend(
+            paths
+                .filter(|p| !skip.contains(Path::new(p)))
+                .map(OsString::from),
+        );
+
+        // maps an id in `reader` to its id here
+        let mut carried = vec![None; reader.num_name];
+        for old_id in 0..reader.num_name as u32 {
+            let name = reader.name(old_id);
+            if skip.contains(Path::new(&name)) {
+                continue;
+            }
+            let alias_of = match reader.alias_of(old_id) {
+                Some(original) => match carried[original as usize] {
+                    Some(id) => Some(id),
+                    None => continue,
+                },
+                None => None,
+            };
+            let metadata = reader.file_metadata(old_id);
+            self.check_fits(Path::new(&name), &[])?;
+            carried[old_id as usize] = Some(self.add_name(&name, metadata, alias_of)?);
+        }
+
+        for k in 0..reader.num_post {
+            let (trigram, count, _) = reader.list_at(k * POST_ENTRY_SIZE);
+            if count == 0 {
+                continue;
+            }
+            for old_id in PostReader::list(reader, trigram, &None).map_err(io::Error::from)? {
+                if let Some(&Some(file_id)) = carried.get(old_id as usize) {
+                    self.push_post(trigram, file_id)?;
+                }
+            }
+            self.check_room(0)?;
+        }
+        Ok(carried.iter().filter(|c| c.is_some()).count())
+    }
+
     /// Open a file and index it
     ///
+    /// Returns how many trigrams and bytes were indexed. Both are zero for a
+    /// file carried over unchanged from the previous index, or stored as an
+    /// alias of a file with the same contents.
+    ///
     /// ```no_run
     /// # use libcindex::writer::IndexWriter;
     /// let mut index = IndexWriter::new("index").unwrap();
-    /// index.add_file("/path/to/file").unwrap();
+    /// let stats = index.add_file("/path/to/file").unwrap();
+    /// println!("{} trigrams in {} bytes", stats.trigram_count, stats.byte_len);
     /// index.flush().unwrap();
     /// ```
-    pub fn add_file<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<()> {
+    pub fn add_file<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<FileStats> {
         let _frame = libprofiling::profile("IndexWriter::add_file");
         let f = File::open(filename.as_ref())?;
         let metadata = f.metadata()?;
-        self.add(filename, f, metadata.len())
+        let file_metadata = previous::file_metadata(&metadata);
+        let name = self.stored_name(filename.as_ref()).to_str();
+        if let (Some(ref m), Some(name)) = (file_metadata, name) {
+            let unchanged = self.previous.as_ref().and_then(|p| p.unchanged(name, m));
+            if let Some(old_id) = unchanged {
+                debug!("unchanged {:?}", filename.as_ref());
+                self.check_fits(filename.as_ref(), &[])?;
+                let file_id = self.add_name(filename, file_metadata, None)?;
+                self.previous.as_mut().unwrap().carry(old_id, file_id);
+                return Ok(FileStats::default());
+            }
+        }
+        let (size, v, hash) =
+            self.limits
+                .file_trigrams(filename.as_ref(), f, metadata.len(), &mut self.trigram)?;
+        self.add_file_trigrams(filename, size, file_metadata, v, hash)
     }
 
     /// Indexes a file
     ///
     /// `filename` is the name of the opened file referred to by `f`.
-    /// `size` is the size of the file referred to by `f`.
-    pub fn add<P, R>(&mut self, filename: P, f: R, size: u64) -> IndexResult<()>
+    /// `size` is the size of the file referred to by `f`. Unlike
+    /// `add_file`, gzipped contents aren't decompressed.
+    pub fn add<P, R>(&mut self, filename: P, f: R, size: u64) -> IndexResult<FileStats>
     where
         P: AsRef<Path>,
         R: Read,
     {
         let _frame = libprofiling::profile("IndexWriter::add");
-        if size > self.max_file_len {
-            return Err(IndexError::new(
-                IndexErrorKind::FileTooLong,
-                format!("file too long, ignoring ({} > {})", size, self.max_file_len),
-            ));
-        }
-        self.trigram.clear();
-        let max_utf8_invalid = ((size as f64) * self.max_utf8_invalid) as u64;
-        {
-            let mut trigrams = TrigramReader::new(f, max_utf8_invalid, self.max_line_len);
-            let _trigram_insert_frame = libprofiling::profile("IndexWriter::add: Insert Trigrams");
-            for each_trigram in trigrams.by_ref() {
-                self.trigram.insert(each_trigram);
-            }
-            if let Some(e) = trigrams.take_error() {
-                return e;
+        let (v, hash) = self.limits.trigrams(f, size, &mut self.trigram)?;
+        self.add_file_trigrams(filename, size, None, v, hash)
+    }
+
+    /// Returns a `Trigrammer` that reads files with the limits of this
+    /// writer, so their trigrams can be computed on other threads
+    pub fn trigrammer(&self) -> Trigrammer {
+        // the trigrammer looks files up by the names they're read under
+        let previous = self.previous.as_ref().map(|p| {
+            let metadata = p.metadata();
+            Arc::new(match self.relative_to {
+                Some(ref base) => metadata
+                    .into_iter()
+                    .filter_map(|(name, m)| {
+                        let path = base.join(name).into_os_string().into_string().ok()?;
+                        Some((path, m))
+                    })
+                    .collect(),
+                None => metadata,
+            })
+        });
+        Trigrammer::new(self.limits, previous)
+    }
+
+    /// Adds a file whose trigrams were computed by a `Trigrammer`
+    ///
+    /// `filename` is the name that was passed to `Trigrammer::read_file`.
+    pub fn add_trigrams<P: AsRef<Path>>(
+        &mut self,
+        filename: P,
+        t: FileTrigrams,
+    ) -> IndexResult<FileStats> {
+        let _frame = libprofiling::profile("IndexWriter::add_trigrams");
+        let (size, metadata, trigrams, hash) = t.into_parts();
+        let name = self.stored_name(filename.as_ref()).to_str();
+        if let (Some(ref m), Some(name)) = (metadata, name) {
+            let unchanged = self.previous.as_ref().and_then(|p| p.unchanged(name, m));
+            if let Some(old_id) = unchanged {
+                debug!("unchanged {:?}", filename.as_ref());
+                self.check_fits(filename.as_ref(), &[])?;
+                let file_id = self.add_name(filename, metadata, None)?;
+                self.previous.as_mut().unwrap().carry(old_id, file_id);
+                return Ok(FileStats::default());
             }
         }
-        if (self.trigram.len() as u64) > self.max_trigram_count {
-            return Err(IndexError::new(
-                IndexErrorKind::TooManyTrigrams,
-                format!(
-                    "Too many trigrams ({} > {})",
-                    self.trigram.len(),
-                    self.max_trigram_count
-                ),
-            ));
+        match trigrams {
+            Some(v) => self.add_file_trigrams(filename, size, metadata, v, hash),
+            // the trigrammer skipped a file the previous index can't supply
+            None => self.add_file(filename),
         }
-        debug!("{} {} {:?}", size, self.trigram.len(), filename.as_ref());
+    }
+
+    fn add_file_trigrams<P: AsRef<Path>>(
+        &mut self,
+        filename: P,
+        size: u64,
+        metadata: Option<FileMetadata>,
+        trigrams: Vec<u32>,
+        hash: Option<u64>,
+    ) -> IndexResult<FileStats> {
+        let key = hash.map(|h| (size, h));
+        let original = match (key, self.contents.as_ref()) {
+            (Some(ref k), Some(c)) => c.get(k).cloned(),
+            _ => None,
+        };
+        if let Some(original) = original {
+            debug!("duplicate of {} {:?}", original, filename.as_ref());
+            self.check_fits(filename.as_ref(), &[])?;
+            self.add_name(filename, metadata, Some(original))?;
+            return Ok(FileStats::default());
+        }
+        self.check_fits(filename.as_ref(), &trigrams)?;
+        debug!("{} {} {:?}", size, trigrams.len(), filename.as_ref());
         self.bytes_written += size as usize;
 
-        let file_id = self.add_name(filename)?;
-        let v = self.trigram.take_dense();
-        self.push_trigrams_to_post(file_id, v)
+        let file_id = self.add_name(filename, metadata, None)?;
+        if let (Some(k), Some(c)) = (key, self.contents.as_mut()) {
+            c.insert(k, file_id);
+        }
+        let stats = FileStats {
+            trigram_count: trigrams.len(),
+            byte_len: size,
+        };
+        self.push_trigrams_to_post(file_id, trigrams)?;
+        Ok(stats)
     }
 
     /// Take trigrams in `trigams` and push them to the post list,
@@ -182,25 +590,133 @@ impl IndexWriter {
     fn push_trigrams_to_post(&mut self, file_id: u32, trigrams: Vec<u32>) -> IndexResult<()> {
         let _frame = libprofiling::profile("IndexWriter::push_trigrams_to_post");
         for each_trigram in trigrams {
-            if self.post.len() >= NPOST {
-                self.flush_post()?;
-            }
-            self.post.push(PostEntry::new(each_trigram, file_id));
+            self.push_post(each_trigram, file_id)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `file_id` to the posting list of `trigram`
+    fn push_post(&mut self, trigram: u32, file_id: u32) -> io::Result<()> {
+        if self.post.len() >= NPOST {
+            self.flush_post()?;
+        }
+        self.post.push(PostEntry::new(trigram, file_id));
+        if !self.is_listed(trigram) {
+            self.listed[trigram as usize / 64] |= 1 << (trigram % 64);
+            self.num_listed += 1;
         }
+        self.post_len += uvarint_len(file_id + 1);
         Ok(())
     }
 
-    /// Add `filename` to the nameData section of the index
-    fn add_name<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<u32> {
+    fn is_listed(&self, trigram: u32) -> bool {
+        self.listed[trigram as usize / 64] & (1 << (trigram % 64)) != 0
+    }
+
+    /// Returns the most bytes `flush` could write if no more files were
+    /// added. Posting entries carried forward from a previous index
+    /// aren't counted.
+    ///
+    /// ```
+    /// # use libcindex::writer::IndexWriterBuilder;
+    /// # use std::io::Cursor;
+    /// let mut index = IndexWriterBuilder::new()
+    ///     .build_to(Cursor::new(Vec::new()))
+    ///     .unwrap();
+    /// let empty = index.size_estimate();
+    /// index.add("a", Cursor::new(b"hello"), 5).unwrap();
+    /// assert!(index.size_estimate() > empty);
+    /// ```
+    pub fn size_estimate(&self) -> u64 {
+        let header = (MAGIC.len() + RELATIVE_MARKER.len()) as u64;
+        let paths = self.paths.iter().map(|p| p.len() as u64 + 1).sum::<u64>() + 1;
+        // the name list and the posting lists each end with an empty entry
+        let names = self.name_len + 1 + 4 * (self.number_of_names_written as u64 + 1);
+        let lists = self.post_len + LIST_OVERHEAD * (self.num_listed + 1);
+        let trailer = (5 * 4 + 4 + TRAILER_MAGIC.len()) as u64;
+        // compressed lists are counted as if they weren't, plus the block
+        // index and the longer trailer
+        let blocks = if self.compress {
+            8 * (lists / POSTING_BLOCK_SIZE as u64 + 2) + 2 * 4
+        } else {
+            0
+        };
+        header + paths + names + lists + trailer + blocks
+    }
+
+    /// Fails with `IndexErrorKind::IndexFull` if adding `filename` as the
+    /// next file, with `trigrams`, could take the index past `max_index_len`
+    fn check_fits(&self, filename: &Path, trigrams: &[u32]) -> IndexResult<()> {
+        if self.max_index_len.is_none() {
+            return Ok(());
+        }
+        // the longest name record has both metadata and an alias
+        let name = filename.as_os_str().len() as u64 + 1 + FILE_METADATA_SIZE as u64 + 4 + 4;
+        let entry_len = uvarint_len(self.number_of_names_written as u32 + 1);
+        let lists = trigrams
+            .iter()
+            .map(|&t| entry_len + if self.is_listed(t) { 0 } else { LIST_OVERHEAD })
+            .sum::<u64>();
+        self.check_room(name + lists)
+    }
+
+    /// Fails with `IndexErrorKind::IndexFull` if `len` more bytes could take
+    /// the index past `max_index_len`
+    fn check_room(&self, len: u64) -> IndexResult<()> {
+        let max = match self.max_index_len {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        if self.size_estimate() + len > max {
+            return Err(IndexError::new(
+                IndexErrorKind::IndexFull,
+                format!("index would be larger than {} bytes", max),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the name `path` is stored under: relative to `relative_to`
+    /// if it's under that directory, which itself is `.`
+    fn stored_name<'a>(&self, path: &'a Path) -> &'a Path {
+        match self
+            .relative_to
+            .as_ref()
+            .map(|base| path.strip_prefix(base))
+        {
+            Some(Ok(p)) if p.as_os_str().is_empty() => Path::new("."),
+            Some(Ok(p)) => p,
+            _ => path,
+        }
+    }
+
+    /// Add `filename` and its metadata to the nameData section of the index,
+    /// as an alias of the file `alias_of` if there is one
+    fn add_name<P: AsRef<Path>>(
+        &mut self,
+        filename: P,
+        metadata: Option<FileMetadata>,
+        alias_of: Option<u32>,
+    ) -> IndexResult<u32> {
         let _frame = libprofiling::profile("IndexWriter::add_name");
         let offset = get_offset(&mut self.name_data)?;
         self.name_index.write_u32::<BigEndian>(offset as u32)?;
 
-        let s = filename.as_ref().to_str().ok_or_else(|| {
+        let filename = self.stored_name(filename.as_ref());
+        let s = filename.to_str().ok_or_else(|| {
             IndexError::new(IndexErrorKind::FileNameError, "UTF-8 Conversion error")
         })?;
         self.name_data.write_all(s.as_bytes())?;
         self.name_data.write_u8(0)?;
+        self.name_len += s.len() as u64 + 1;
+        if let Some(m) = metadata.filter(|_| !self.reproducible) {
+            self.name_data.write_all(&m.to_bytes())?;
+            self.name_len += FILE_METADATA_SIZE as u64;
+        }
+        if let Some(original) = alias_of {
+            self.name_data.write_u32::<BigEndian>(original)?;
+            self.name_len += 4;
+        }
 
         let id = self.number_of_names_written;
         self.number_of_names_written += 1;
@@ -208,42 +724,76 @@ impl IndexWriter {
     }
 
     /// Finalize the index, collecting all data and writing it out.
+    ///
+    /// An I/O error is returned as `IndexErrorKind::FlushFailed`, with the
+    /// section of the index that was being written.
+    ///
+    /// Paths are written sorted, so the same files added in the same order
+    /// give a byte-identical index whatever order the paths were added in.
     pub fn flush(mut self) -> IndexResult<()> {
         let _frame = libprofiling::profile("IndexWriter::flush");
-        self.add_name("")?;
-        self.index.write_all(MAGIC.as_bytes())?;
-
-        let mut off = [0; 5];
-        off[0] = get_offset(&mut self.index)?;
+        // offsets of the sections, and for a compressed index that of the
+        // posting block index and the flags
+        let mut off = vec![0; if self.compress { 7 } else { 5 }];
+
+        let magic = if self.compress { MAGIC_V3 } else { MAGIC };
+        during(FlushStage::Header, self.index.write_all(magic.as_bytes()))?;
+        if self.relative_to.is_some() {
+            during(
+                FlushStage::Header,
+                self.index.write_all(RELATIVE_MARKER.as_bytes()),
+            )?;
+        }
+        off[0] = during(FlushStage::Header, get_offset(&mut self.index))?;
 
+        self.paths.sort();
+        self.paths.dedup();
         for p in &self.paths {
+            let p = self.stored_name(Path::new(p));
             let path_as_bytes = p.to_str().map(str::as_bytes).ok_or_else(|| {
                 IndexError::new(IndexErrorKind::FileNameError, "UTF-8 Conversion error")
             })?;
-            self.index.write_all(path_as_bytes)?;
-            self.index.write_u8(0)?;
+            during(FlushStage::Paths, self.index.write_all(path_as_bytes))?;
+            during(FlushStage::Paths, self.index.write_u8(0))?;
         }
-        self.index.write_u8(0)?;
-        off[1] = get_offset(&mut self.index)?;
-
-        self.name_data.flush()?;
-        copy_file(&mut self.index, &mut self.name_data.get_mut());
-        off[2] = get_offset(&mut self.index)?;
-
-        self.merge_post()?;
-        off[3] = get_offset(&mut self.index)?;
-
-        self.name_index.flush()?;
-        copy_file(&mut self.index, &mut self.name_index.get_mut());
-        off[4] = get_offset(&mut self.index)?;
-
-        self.post_index.flush()?;
-        copy_file(&mut self.index, &mut self.post_index.get_mut());
-
-        for v in off.iter() {
-            self.index.write_u32::<BigEndian>(*v as u32)?;
+        during(FlushStage::Paths, self.index.write_u8(0))?;
+        off[1] = during(FlushStage::Paths, get_offset(&mut self.index))?;
+
+        during(
+            FlushStage::Names,
+            self.add_name("", None, None).map_err(io::Error::from),
+        )?;
+        during(FlushStage::Names, self.name_data.flush())?;
+        during(
+            FlushStage::Names,
+            copy_file(&mut self.index, self.name_data.get_mut()),
+        )?;
+        off[2] = during(FlushStage::Names, get_offset(&mut self.index))?;
+
+        let block_index = during(FlushStage::PostingLists, self.merge_post())?;
+        off[3] = during(FlushStage::PostingLists, get_offset(&mut self.index))?;
+
+        during(FlushStage::NameIndex, self.name_index.flush())?;
+        during(
+            FlushStage::NameIndex,
+            copy_file(&mut self.index, self.name_index.get_mut()),
+        )?;
+        off[4] = during(FlushStage::NameIndex, get_offset(&mut self.index))?;
+
+        during(FlushStage::PostingIndex, self.post_index.flush())?;
+        during(
+            FlushStage::PostingIndex,
+            copy_file(&mut self.index, self.post_index.get_mut()),
+        )?;
+        if self.compress {
+            off[5] = during(FlushStage::PostingIndex, get_offset(&mut self.index))?;
+            off[6] = u64::from(FLAG_ZSTD_POSTINGS);
+            during(FlushStage::PostingIndex, self.index.write_all(&block_index))?;
         }
-        self.index.write_all(TRAILER_MAGIC.as_bytes())?;
+        during(FlushStage::PostingIndex, self.index.flush())?;
+
+        during(FlushStage::Trailer, write_trailer(&mut self.index, &off))?;
+        during(FlushStage::Trailer, self.index.flush())?;
         info!(
             "{} data bytes, {} index bytes",
             self.bytes_written,
@@ -251,8 +801,9 @@ impl IndexWriter {
         );
         Ok(())
     }
-    /// Merge the posting lists together
-    fn merge_post(&mut self) -> io::Result<()> {
+    /// Merge the posting lists together. Returns the posting block index,
+    /// which is empty unless the lists are compressed.
+    fn merge_post(&mut self) -> io::Result<Vec<u8>> {
         let _frame = libprofiling::profile("IndexWriter::merge_post");
         let mut heap = PostHeap::new();
         info!("merge {} files + mem", self.post_files.len());
@@ -264,9 +815,18 @@ impl IndexWriter {
         let mut v = Vec::new();
         mem::swap(&mut v, &mut self.post);
         heap.add_mem(v);
+        let mut carry_error = None;
+        if let Some(p) = self.previous.take() {
+            info!("carry forward {} unchanged files", p.num_carried());
+            if p.num_carried() > 0 {
+                let entries = p.into_post_entries();
+                carry_error = Some(entries.error());
+                heap.add_iter(entries);
+            }
+        }
 
         let mut h = heap.into_iter().peekable();
-        let offset0 = get_offset(&mut self.index)?;
+        let mut lists = PostingListWriter::new(&mut self.index, self.compress);
 
         let _frame_write = libprofiling::profile(
             "IndexWriter::merge_post: Generate/Write post \
@@ -277,17 +837,18 @@ impl IndexWriter {
                 "IndexWriter::merge_post: Write \
                                                                post index",
             );
-            let offset = get_offset(&mut self.index)? - offset0;
+            let offset = lists.offset();
 
             // posting list
             let plist_trigram = plist.trigram();
-            self.index.write_trigram(plist_trigram)?;
+            lists.write_trigram(plist_trigram)?;
             let mut written = 0;
             let _fname_diffs = libprofiling::profile("IndexWriter::merge_post: Write file diffs");
             for each_file in to_diffs(plist.map(|p| p.file_id())) {
-                libvarint::write_uvarint(&mut self.index, each_file)?;
+                libvarint::write_uvarint(&mut lists, each_file)?;
                 written += 1;
             }
+            lists.end_list()?;
             // drop(_fname_diffs);
 
             let _fname_diffs = libprofiling::profile("IndexWriter::merge_post: Write file diffs");
@@ -295,15 +856,18 @@ impl IndexWriter {
             self.post_index.write_u32::<BigEndian>(written - 1)?;
             self.post_index.write_u32::<BigEndian>(offset as u32)?;
         }
+        if let Some(e) = carry_error.and_then(|e| e.get()) {
+            return Err(e.into());
+        }
         // NOTE: write last entry like how the go version works
-        let offset = get_offset(&mut self.index)? - offset0;
-        self.index.write_trigram(0xffffff)?; // END trigram
-        libvarint::write_uvarint(&mut self.index, 0)?; // NUL byte for END postlist
+        let offset = lists.offset();
+        lists.write_trigram(0xffffff)?; // END trigram
+        libvarint::write_uvarint(&mut lists, 0)?; // NUL byte for END postlist
         self.post_index.write_trigram(0xffffff)?; // END trigram
         self.post_index.write_u32::<BigEndian>(0)?; // nothing written
         self.post_index.write_u32::<BigEndian>(offset as u32)?;
 
-        Ok(())
+        lists.finish()
     }
 
     /// Flush the post data to a temporary file
@@ -321,3 +885,13 @@ fn make_temp_buf() -> io::Result<BufWriter<File>> {
     let w = tempfile()?;
     Ok(BufWriter::with_capacity(256 << 10, w))
 }
+
+/// Tags an error from `IndexWriter::flush` with the section being written
+fn during<T>(stage: FlushStage, r: io::Result<T>) -> IndexResult<T> {
+    r.map_err(|e| IndexError::new(IndexErrorKind::FlushFailed(stage), e))
+}
+
+/// Returns how many bytes `x` takes as a uvarint
+fn uvarint_len(x: u32) -> u64 {
+    u64::from((32 - x.leading_zeros()).max(1)).div_ceil(7)
+}
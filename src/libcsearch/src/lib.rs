@@ -1,23 +1,317 @@
 extern crate byteorder;
+extern crate crc32fast;
+extern crate flate2;
+#[macro_use]
+extern crate log;
 extern crate memmap;
 extern crate num;
 extern crate regex;
 extern crate regex_syntax;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+extern crate tar;
+extern crate zstd;
 
 extern crate consts;
 extern crate libvarint;
 
 pub mod reader;
 pub mod regexp;
+pub mod searcher;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-pub fn csearch_index() -> String {
+use flate2::read::MultiGzDecoder;
+
+/// Environment variables consulted by `csearch_index`, in order
+pub const INDEX_PATH_VARS: [&str; 3] = ["CSEARCHINDEX", "HOME", "USERPROFILE"];
+
+/// Error returned when no location for the index can be determined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexPathError {
+    /// None of the listed environment variables were set
+    NotSet(&'static [&'static str]),
+}
+
+impl fmt::Display for IndexPathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexPathError::NotSet(vars) => write!(
+                f,
+                "no valid path to index: none of {} are set",
+                vars.join(", ")
+            ),
+        }
+    }
+}
+
+impl Error for IndexPathError {}
+
+/// Returns the path to the index: `$CSEARCHINDEX` if set, otherwise
+/// `.csearchindex` in the user's home directory.
+pub fn csearch_index() -> Result<String, IndexPathError> {
     env::var("CSEARCHINDEX")
         .or_else(|_| {
             env::var("HOME")
                 .or_else(|_| env::var("USERPROFILE"))
                 .map(|s| s + "/.csearchindex")
         })
-        .expect("no valid path to index")
+        .map_err(|_| IndexPathError::NotSet(&INDEX_PATH_VARS))
+}
+
+/// Returns whether the file at `path` is indexed and searched by its
+/// gzip-decompressed contents, which is the case if it ends in `.gz`
+pub fn is_gzip<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension() == Some(OsStr::new("gz"))
+}
+
+/// Returns whether the file at `path` is a tar archive, possibly gzipped,
+/// going by its name: `.tar`, `.tar.gz` or `.tgz`
+pub fn is_tar<P: AsRef<Path>>(path: P) -> bool {
+    let name = path.as_ref().to_string_lossy();
+    name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Returns the entries of the tar archive at `path`, decompressing it
+/// first if it's gzipped
+pub fn open_tar<P: AsRef<Path>>(path: P) -> io::Result<tar::Archive<Box<dyn Read + Send>>> {
+    let f = File::open(path.as_ref())?;
+    let name = path.as_ref().to_string_lossy();
+    let r: Box<dyn Read + Send> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(MultiGzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    Ok(tar::Archive::new(r))
+}
+
+/// Returns the name a file inside the tar archive at `archive` is indexed
+/// under: `archive!path/in/archive`
+pub fn archive_entry_name(archive: &Path, entry: &Path) -> String {
+    let entry = entry.strip_prefix(".").unwrap_or(entry);
+    format!("{}!{}", archive.display(), entry.display())
+}
+
+/// Splits a name from `archive_entry_name` into the path of the archive
+/// and the path of the file inside it. Returns `None` for other names.
+pub fn split_archive_entry_name(name: &str) -> Option<(&Path, &str)> {
+    name.match_indices('!')
+        .map(|(i, _)| (Path::new(&name[..i]), &name[i + 1..]))
+        .find(|&(archive, _)| is_tar(archive) && archive.is_file())
+}
+
+/// Byte order of UTF-16 text
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Utf16Order {
+    LittleEndian,
+    BigEndian,
+}
+
+/// Returns the byte order named by the UTF-16 byte order mark that `data`
+/// starts with, if it starts with one
+pub fn utf16_bom(data: &[u8]) -> Option<Utf16Order> {
+    match data {
+        [0xff, 0xfe, ..] => Some(Utf16Order::LittleEndian),
+        [0xfe, 0xff, ..] => Some(Utf16Order::BigEndian),
+        _ => None,
+    }
+}
+
+/// Transcodes the UTF-16 text `data`, which follows the byte order mark,
+/// to UTF-8. Unpaired surrogates are replaced with U+FFFD.
+///
+/// Fails with `io::ErrorKind::InvalidData` if the text ends in the middle
+/// of a code unit or surrogate pair.
+pub fn decode_utf16(data: &[u8], order: Utf16Order) -> io::Result<String> {
+    if !data.len().is_multiple_of(2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "UTF-16 text ends in the middle of a character",
+        ));
+    }
+    let units = data
+        .chunks(2)
+        .map(|c| match order {
+            Utf16Order::LittleEndian => u16::from_le_bytes([c[0], c[1]]),
+            Utf16Order::BigEndian => u16::from_be_bytes([c[0], c[1]]),
+        })
+        .collect::<Vec<_>>();
+    if units.last().is_some_and(|u| (0xd800..0xdc00).contains(u)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "UTF-16 text ends in the middle of a surrogate pair",
+        ));
+    }
+    Ok(char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect())
+}
+
+/// Opens an indexed file for reading its contents, decompressing it on
+/// the fly if `is_gzip` says so. Files inside tar archives are named as
+/// `archive_entry_name` says, and read as they are stored. Contents that
+/// start with a UTF-16 byte order mark are transcoded to UTF-8.
+pub fn open_contents<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read + Send>> {
+    let f = match File::open(path.as_ref()) {
+        Ok(f) => f,
+        Err(e) => {
+            let name = path.as_ref().to_string_lossy();
+            return match split_archive_entry_name(&name) {
+                Some((archive, entry)) => open_archive_entry(archive, entry).and_then(decode_text),
+                None => Err(e),
+            };
+        }
+    };
+    if is_gzip(path) {
+        decode_text(Box::new(MultiGzDecoder::new(f)))
+    } else {
+        decode_text(Box::new(f))
+    }
+}
+
+/// Opens the contents of indexed files as `open_contents` does, but reads
+/// each tar archive only once for all the files inside it that it was
+/// made for, rather than once for each of them. The files of an archive
+/// are held in memory from when the first of them is opened until each is
+/// opened, so it's made for the files of one search.
+///
+/// ```no_run
+/// # use libcsearch::ContentsOpener;
+/// # use std::io::Read;
+/// let names = vec!["/src/vendor.tar.gz!a.c", "/src/vendor.tar.gz!b.c"];
+/// let opener = ContentsOpener::new(&names);
+/// for name in &names {
+///     let mut contents = String::new();
+///     opener.open(name).unwrap().read_to_string(&mut contents).unwrap();
+/// }
+/// ```
+pub struct ContentsOpener {
+    archives: HashMap<PathBuf, Mutex<ArchiveEntries>>,
+}
+
+/// The files wanted from one archive, and once it's been read their
+/// contents that haven't been opened yet
+struct ArchiveEntries {
+    wanted: HashSet<PathBuf>,
+    read: Option<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl ContentsOpener {
+    /// Returns an opener for the files `names`, which may be opened in any
+    /// order and from any thread
+    pub fn new<I, P>(names: I) -> ContentsOpener
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut archives = HashMap::new();
+        for name in names {
+            let name = name.as_ref().to_string_lossy();
+            if let Some((archive, entry)) = split_archive_entry_name(&name) {
+                archives
+                    .entry(archive.to_path_buf())
+                    .or_insert_with(|| {
+                        Mutex::new(ArchiveEntries {
+                            wanted: HashSet::new(),
+                            read: None,
+                        })
+                    })
+                    .get_mut()
+                    .unwrap()
+                    .wanted
+                    .insert(PathBuf::from(entry));
+            }
+        }
+        ContentsOpener { archives }
+    }
+
+    /// Opens the file `path` as `open_contents` does. A file inside an
+    /// archive this opener was made for can only be opened once.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<Box<dyn Read + Send>> {
+        let name = path.as_ref().to_string_lossy();
+        let (archive, entry) = match split_archive_entry_name(&name) {
+            Some(split) if !path.as_ref().is_file() => split,
+            _ => return open_contents(path),
+        };
+        let entries = match self.archives.get(archive) {
+            Some(entries) => entries,
+            None => return open_contents(path),
+        };
+        let mut entries = entries.lock().unwrap();
+        if entries.read.is_none() {
+            entries.read = Some(read_archive_entries(archive, &entries.wanted)?);
+        }
+        match entries.read.as_mut().unwrap().remove(Path::new(entry)) {
+            Some(contents) => decode_text(Box::new(Cursor::new(contents))),
+            None => Err(not_in_archive(archive, entry)),
+        }
+    }
+}
+
+/// Transcodes the contents of `r` to UTF-8 if they're UTF-16
+fn decode_text(mut r: Box<dyn Read + Send>) -> io::Result<Box<dyn Read + Send>> {
+    let mut bom = Vec::with_capacity(2);
+    r.by_ref().take(2).read_to_end(&mut bom)?;
+    match utf16_bom(&bom) {
+        Some(order) => {
+            let mut data = Vec::new();
+            r.read_to_end(&mut data)?;
+            Ok(Box::new(Cursor::new(
+                decode_utf16(&data, order)?.into_bytes(),
+            )))
+        }
+        None => Ok(Box::new(Cursor::new(bom).chain(r))),
+    }
+}
+
+/// Reads the file named `entry` from the tar archive at `archive`
+fn open_archive_entry(archive: &Path, entry: &str) -> io::Result<Box<dyn Read + Send>> {
+    let wanted = Some(PathBuf::from(entry)).into_iter().collect();
+    match read_archive_entries(archive, &wanted)?.remove(Path::new(entry)) {
+        Some(contents) => Ok(Box::new(Cursor::new(contents))),
+        None => Err(not_in_archive(archive, entry)),
+    }
+}
+
+/// Reads the files named in `wanted` from the tar archive at `archive`, in
+/// one pass that stops once they've all been found. Files that aren't in
+/// the archive are left out.
+fn read_archive_entries(
+    archive: &Path,
+    wanted: &HashSet<PathBuf>,
+) -> io::Result<HashMap<PathBuf, Vec<u8>>> {
+    let mut found = HashMap::new();
+    for e in open_tar(archive)?.entries()? {
+        if found.len() == wanted.len() {
+            break;
+        }
+        let mut e = e?;
+        let name = {
+            let p = e.path()?;
+            p.strip_prefix(".").unwrap_or(&p).to_path_buf()
+        };
+        if !wanted.contains(&name) || found.contains_key(&name) {
+            continue;
+        }
+        let mut contents = Vec::new();
+        e.read_to_end(&mut contents)?;
+        found.insert(name, contents);
+    }
+    Ok(found)
+}
+
+fn not_in_archive(archive: &Path, entry: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} isn't in {}", entry, archive.display()),
+    )
 }
@@ -1,7 +1,17 @@
 mod read;
 mod search;
 
+pub use self::read::CorruptIndex;
+pub use self::read::FileMetadata;
 pub use self::read::IndexReader;
+pub use self::read::OpenError;
 pub use self::read::PostReader;
 pub use self::read::PostSet;
+pub use self::read::ReadMode;
+pub use self::read::UnsupportedVersion;
+pub use self::read::VerifyError;
+pub use self::read::FILE_ALIAS_SIZE;
+pub use self::read::FILE_METADATA_SIZE;
 pub use self::read::POST_ENTRY_SIZE;
+
+pub use regexp::query_from_regexp;
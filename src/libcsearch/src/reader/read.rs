@@ -7,7 +7,7 @@
 //
 // An index stored on disk has the format:
 //
-// 	"csearch index 1\n"
+// 	"csearch index 2\n"
 // 	list of paths
 // 	list of names
 // 	list of posting lists
@@ -19,11 +19,35 @@
 // The index covers the file trees rooted at those paths.
 // The list ends with an empty name ("\x00").
 //
+// The header may be followed by "relative\n" before the list of paths, if
+// the paths and names are relative to a base directory rather than
+// absolute.  The base directory itself is named ".".  Names of files that
+// weren't under the base directory are absolute.  The path list offset in
+// the trailer skips the marker, so older readers are unaffected by it.
+//
 // The list of names is a sorted sequence of NUL-terminated file names.
 // The initial entry in the list corresponds to file #0,
 // the next to file #1, and so on.  The list ends with an
 // empty name ("\x00").
 //
+// A name may be followed by a file metadata record:
+//
+// 	modification time in nanoseconds since the epoch [8]
+// 	file size in bytes [8]
+//
+// An index written with deduplication may also follow a name, after any
+// metadata record, with an alias record:
+//
+// 	file ID [4]
+//
+// The file has the same contents as the file with that ID, and isn't in
+// any posting list of its own; queries that match the file with that ID
+// match its aliases too.
+//
+// Whether a record is present is determined by the gap between the end
+// of the name and the offset of the next name in the name index, so
+// readers that only use the name index to find names are unaffected.
+//
 // The list of posting lists are a sequence of posting lists.
 // Each posting list has the form:
 //
@@ -61,45 +85,332 @@
 // 	offset of posting lists [4]
 // 	offset of name index [4]
 // 	offset of posting list index [4]
+// 	checksum [4]
 // 	"\ncsearch trailr\n"
+//
+// The checksum is the crc32 of everything in the file before it.
+// Indexes written with the "csearch index 1\n" header have no checksum.
+//
+// An index written with the "csearch index 3\n" header has a posting block
+// index after the posting list index, and its trailer has two more fields
+// before the checksum:
+//
+// 	offset of posting block index [4]
+// 	flags [4]
+//
+// If flag 1 is set, the list of posting lists is compressed with zstd in
+// blocks: whole posting lists are gathered into blocks of about 64kB, and
+// each block is compressed on its own.  Offsets in the posting list index
+// are offsets in the uncompressed lists.  The posting block index has an
+// entry for each block, and a last one for the end of the lists:
+//
+// 	offset in the uncompressed lists [4]
+// 	offset in the compressed lists [4]
+//
+// Without the flag the posting lists aren't compressed and the posting
+// block index is empty.  Only compressed indexes are written in version 3.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
 use std::io;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
+use std::ops::Deref;
 use std::path::Path;
+use std::rc::Rc;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::vec;
 
-use byteorder::{BigEndian, ReadBytesExt};
-use consts::TRAILER_MAGIC;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use consts::{
+    self, FLAG_ZSTD_POSTINGS, FORMAT_VERSION, MAGIC, MIN_FORMAT_VERSION, RELATIVE_MARKER,
+    TRAILER_MAGIC,
+};
 use libvarint;
 use memmap::Mmap;
+use zstd;
 
 use super::search;
 use regexp::{Query, QueryOperation};
 
 pub const POST_ENTRY_SIZE: usize = 3 + 4 + 4;
+pub const FILE_METADATA_SIZE: usize = 8 + 8;
+pub const FILE_ALIAS_SIZE: usize = 4;
+/// Size of an entry of the posting block index
+pub const BLOCK_ENTRY_SIZE: usize = 4 + 4;
+
+/// Most file ids the posting lists cached during one query may hold, about
+/// 16MB. Lists decoded past that aren't cached.
+const MAX_CACHED_IDS: usize = 1 << 22;
 
 /// Simple alias for an ID representing a filename in the Index.
 pub type FileID = u32;
 
+/// Modification time and size of an indexed file, as recorded when it was indexed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// Modification time in nanoseconds since the unix epoch
+    pub mtime: u64,
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+impl FileMetadata {
+    /// Returns the on-disk representation of this record
+    pub fn to_bytes(&self) -> [u8; FILE_METADATA_SIZE] {
+        let mut buf = [0; FILE_METADATA_SIZE];
+        BigEndian::write_u64(&mut buf[..8], self.mtime);
+        BigEndian::write_u64(&mut buf[8..], self.size);
+        buf
+    }
+    fn from_bytes(b: &[u8]) -> FileMetadata {
+        FileMetadata {
+            mtime: BigEndian::read_u64(&b[..8]),
+            size: BigEndian::read_u64(&b[8..FILE_METADATA_SIZE]),
+        }
+    }
+}
+
+/// Describes why an index file was rejected as truncated or malformed.
+///
+/// `IndexReader::open` reports a truncated index or bad magic as
+/// `OpenError::Truncated` and `OpenError::BadMagic`, and the others as
+/// `OpenError::Corrupt`. Converted to an `io::Error`, these are of kind
+/// `io::ErrorKind::InvalidData` carrying a `CorruptIndex`, which can be
+/// recovered with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CorruptIndex {
+    /// The file is too short to hold the header and trailer
+    TooShort,
+    /// The header or trailer magic doesn't match
+    BadMagic,
+    /// The section offsets in the trailer are out of order or out of bounds
+    BadTrailer,
+    /// The path or name list isn't NUL-terminated
+    UnterminatedList,
+    /// An entry in the name index points outside of the name list
+    BadNameOffset,
+    /// An entry in the posting list index points outside of the posting lists
+    BadPostOffset,
+    /// The checksum in the trailer doesn't match the contents of the index
+    BadChecksum,
+    /// A posting list is for the wrong trigram, ends early or refers to
+    /// files that aren't in the index
+    BadPostingList,
+    /// The posting block index is out of order or out of bounds, or a
+    /// block doesn't decompress to its size
+    BadPostingBlock,
+}
+
+impl fmt::Display for CorruptIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match *self {
+            CorruptIndex::TooShort => "file is truncated",
+            CorruptIndex::BadMagic => "bad magic",
+            CorruptIndex::BadTrailer => "bad section offsets in trailer",
+            CorruptIndex::UnterminatedList => "unterminated path or name list",
+            CorruptIndex::BadNameOffset => "name index entry out of bounds",
+            CorruptIndex::BadPostOffset => "posting list index entry out of bounds",
+            CorruptIndex::BadChecksum => "checksum mismatch, the index is damaged",
+            CorruptIndex::BadPostingList => "malformed posting list",
+            CorruptIndex::BadPostingBlock => "malformed compressed posting list block",
+        };
+        write!(f, "corrupt index: {}", reason)
+    }
+}
+
+impl Error for CorruptIndex {}
+
+impl From<CorruptIndex> for io::Error {
+    fn from(e: CorruptIndex) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The first inconsistency found by `IndexReader::verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The kind of damage
+    pub kind: CorruptIndex,
+    /// Where it was found
+    pub detail: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.detail)
+    }
+}
+
+impl Error for VerifyError {}
+
+/// An index format version this build can't read, as reported by
+/// `OpenError::VersionTooOld` and `OpenError::VersionTooNew`. Wrapped in an
+/// `io::Error` of kind `io::ErrorKind::InvalidData`, like `CorruptIndex`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnsupportedVersion(pub u32);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < MIN_FORMAT_VERSION {
+            write!(
+                f,
+                "index format {} is too old for this build (expected {}); run cindex --reset",
+                self.0, FORMAT_VERSION
+            )
+        } else {
+            write!(
+                f,
+                "index format {} is newer than this build supports (expected {}); \
+                 upgrade codesearch or run cindex --reset",
+                self.0, FORMAT_VERSION
+            )
+        }
+    }
+}
+
+impl Error for UnsupportedVersion {}
+
+impl From<UnsupportedVersion> for io::Error {
+    fn from(e: UnsupportedVersion) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Error returned by `IndexReader::open`
+#[derive(Debug)]
+pub enum OpenError {
+    /// There's no index at the path
+    NotFound,
+    /// The index couldn't be read
+    Io(io::Error),
+    /// The file doesn't start or end like an index
+    BadMagic,
+    /// The file is too short to be an index
+    Truncated,
+    /// The index was written in a format that's no longer supported
+    VersionTooOld(u32),
+    /// The index was written in a format newer than this build supports
+    VersionTooNew(u32),
+    /// The index is malformed in some other way
+    Corrupt(CorruptIndex),
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpenError::NotFound => write!(f, "index not found"),
+            OpenError::Io(ref e) => write!(f, "{}", e),
+            OpenError::BadMagic => write!(f, "{}", CorruptIndex::BadMagic),
+            OpenError::Truncated => write!(f, "{}", CorruptIndex::TooShort),
+            OpenError::VersionTooOld(v) | OpenError::VersionTooNew(v) => {
+                write!(f, "{}", UnsupportedVersion(v))
+            }
+            OpenError::Corrupt(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for OpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            OpenError::Io(ref e) => Some(e),
+            OpenError::Corrupt(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OpenError {
+    fn from(e: io::Error) -> OpenError {
+        if e.kind() == io::ErrorKind::NotFound {
+            OpenError::NotFound
+        } else {
+            OpenError::Io(e)
+        }
+    }
+}
+
+impl From<CorruptIndex> for OpenError {
+    fn from(e: CorruptIndex) -> OpenError {
+        match e {
+            CorruptIndex::TooShort => OpenError::Truncated,
+            CorruptIndex::BadMagic => OpenError::BadMagic,
+            e => OpenError::Corrupt(e),
+        }
+    }
+}
+
+impl From<UnsupportedVersion> for OpenError {
+    fn from(e: UnsupportedVersion) -> OpenError {
+        if e.0 < MIN_FORMAT_VERSION {
+            OpenError::VersionTooOld(e.0)
+        } else {
+            OpenError::VersionTooNew(e.0)
+        }
+    }
+}
+
+/// So that functions returning `io::Result` can open indexes with `?`
+impl From<OpenError> for io::Error {
+    fn from(e: OpenError) -> io::Error {
+        match e {
+            OpenError::NotFound => io::Error::new(io::ErrorKind::NotFound, "index not found"),
+            OpenError::Io(e) => e,
+            OpenError::BadMagic => CorruptIndex::BadMagic.into(),
+            OpenError::Truncated => CorruptIndex::TooShort.into(),
+            OpenError::VersionTooOld(v) | OpenError::VersionTooNew(v) => {
+                UnsupportedVersion(v).into()
+            }
+            OpenError::Corrupt(e) => e.into(),
+        }
+    }
+}
+
+/// How `IndexReader::open_with` reads an index
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Map the index into memory, so only the parts that are used are read
+    #[default]
+    Mmap,
+    /// Read the whole index into memory when it's opened. Slower to open,
+    /// but searching can't fault (SIGBUS) if the file is truncated or on a
+    /// network filesystem that doesn't map well.
+    Buffered,
+}
+
+/// The contents of an index file, read as chosen by a `ReadMode`
+enum IndexData {
+    Mapped(Mmap),
+    Buffered(Vec<u8>),
+}
+
+impl Deref for IndexData {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match *self {
+            IndexData::Mapped(ref m) => m,
+            IndexData::Buffered(ref v) => v,
+        }
+    }
+}
+
 /// Representation of an Index
 ///
 /// ```rust
-/// # extern crate regex_syntax;
 /// # extern crate libcsearch;
-/// # use libcsearch::reader::IndexReader;
-/// # use libcsearch::regexp::RegexInfo;
-/// # use regex_syntax::Expr;
+/// # use libcsearch::reader::{query_from_regexp, IndexReader};
 /// # use std::io;
 /// # fn main() { foo(); }
 /// # fn foo() -> io::Result<()> {
-/// let expr = Expr::parse(r"Pattern").unwrap();
-/// let q = RegexInfo::new(expr).unwrap().query;
+/// let q = query_from_regexp(r"Pattern").unwrap();
 ///
 /// let idx = IndexReader::open("foo.txt")?;
 ///
-/// let matching_file_ids = idx.query(q);
+/// let matching_file_ids = idx.query(q)?;
 ///
 /// for each in matching_file_ids.into_inner() {
 ///    println!("filename = {}", idx.name(each));
@@ -108,14 +419,27 @@ pub type FileID = u32;
 /// # }
 /// ```
 pub struct IndexReader {
-    data: Mmap,
+    data: IndexData,
     path_data: u32,
     name_data: u32,
     pub post_data: u32,
     name_index: usize,
     pub post_index: usize,
+    /// Offset of the posting block index, where the posting list index ends
+    block_index: usize,
     pub num_name: usize,
     pub num_post: usize,
+    /// Length of the posting lists, uncompressed
+    lists_len: usize,
+    /// The blocks of compressed posting lists, decompressed on first use;
+    /// empty if the posting lists aren't compressed
+    blocks: Vec<OnceLock<Vec<u8>>>,
+    /// Maps a file ID to the IDs of its aliases, read on first use
+    aliases: OnceLock<BTreeMap<FileID, Vec<FileID>>>,
+    /// Number of posting lists decoded so far
+    lists_decoded: AtomicUsize,
+    /// Whether paths and names are relative to a base directory
+    relative: bool,
 }
 
 impl Debug for IndexReader {
@@ -134,48 +458,172 @@ impl Debug for IndexReader {
     }
 }
 
-fn extract_data_from_mmap(data: &Mmap, offset: usize) -> u32 {
+fn extract_data_from_slice(data: &[u8], offset: usize) -> u32 {
     let mut buf = Cursor::new(&data[offset..offset + 4]);
     buf.read_u32::<BigEndian>().unwrap()
 }
 
 impl IndexReader {
     fn extract_data(&self, offset: usize) -> u32 {
-        extract_data_from_mmap(&self.data, offset)
+        extract_data_from_slice(&self.data, offset)
     }
     /// Open an index file from path
     ///
+    /// Returns an `OpenError` saying whether the file is missing, can't be
+    /// read, is in an unsupported format version, or is truncated or
+    /// malformed: its sections don't fit inside it, or its checksum
+    /// doesn't match. Indexes in the older format without a checksum are
+    /// opened with a warning.
+    ///
     /// ```no_run
-    /// # use libcsearch::reader::IndexReader;
-    /// # use std::io;
-    /// # fn foo() -> io::Result<()> {
-    /// let idx = IndexReader::open("foo.txt")?;
+    /// # use libcsearch::reader::{IndexReader, OpenError};
+    /// match IndexReader::open("foo.txt") {
+    ///     Ok(idx) => println!("{} files", idx.num_name),
+    ///     Err(OpenError::NotFound) => println!("no index yet"),
+    ///     Err(e) => println!("rebuild the index: {}", e),
+    /// }
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<IndexReader, OpenError> {
+        IndexReader::open_with(path, ReadMode::Mmap)
+    }
+
+    /// Like `open`, but reads the index as `mode` says instead of always
+    /// mapping it
+    ///
+    /// ```no_run
+    /// # use libcsearch::reader::{IndexReader, OpenError, ReadMode};
+    /// # fn foo() -> Result<(), OpenError> {
+    /// let idx = IndexReader::open_with("/nfs/home/me/.csearchindex", ReadMode::Buffered)?;
     /// # Ok(())
     /// # }
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<IndexReader> {
-        let file = std::fs::File::open(&path)?;
-        let m = unsafe { Mmap::map(&file)? };
-        let n = m.len() - (TRAILER_MAGIC.bytes().len()) - 5 * 4;
-        let path_data = extract_data_from_mmap(&m, n);
-        let name_data = extract_data_from_mmap(&m, n + 4);
-        let post_data = extract_data_from_mmap(&m, n + 8);
-        let name_index = extract_data_from_mmap(&m, n + 12) as usize;
-        let post_index = extract_data_from_mmap(&m, n + 16) as usize;
+    /// ```
+    pub fn open_with<P: AsRef<Path>>(path: P, mode: ReadMode) -> Result<IndexReader, OpenError> {
+        let mut file = std::fs::File::open(&path)?;
+        let len = file.metadata()?.len();
+        // an empty file can't be mapped
+        if len < MAGIC.len() as u64 {
+            return Err(OpenError::Truncated);
+        }
+        let m = match mode {
+            ReadMode::Mmap => IndexData::Mapped(unsafe { Mmap::map(&file)? }),
+            ReadMode::Buffered => {
+                let mut v = Vec::with_capacity(len as usize);
+                file.read_to_end(&mut v)?;
+                IndexData::Buffered(v)
+            }
+        };
+        let version = match consts::parse_version(&m) {
+            Some(v) => v,
+            None if m.len() < MAGIC.len() => return Err(CorruptIndex::TooShort.into()),
+            None => return Err(CorruptIndex::BadMagic.into()),
+        };
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(UnsupportedVersion(version).into());
+        }
+        // version 1 indexes have no checksum
+        let has_checksum = version >= 2;
+        if !has_checksum {
+            warn!(
+                "{}: index has no checksum, run cindex --reset to rebuild it",
+                path.as_ref().display()
+            );
+        }
+        let checksum_len = if has_checksum { 4 } else { 0 };
+        // version 3 adds the offset of the posting block index and flags
+        let num_fields = if version >= 3 { 7 } else { 5 };
+        if m.len() < MAGIC.len() + num_fields * 4 + checksum_len + TRAILER_MAGIC.len() {
+            return Err(CorruptIndex::TooShort.into());
+        }
+        let n = m.len() - TRAILER_MAGIC.len() - checksum_len - num_fields * 4;
+        if !m.ends_with(TRAILER_MAGIC.as_bytes()) {
+            return Err(CorruptIndex::BadMagic.into());
+        }
+        let path_data = extract_data_from_slice(&m, n);
+        let name_data = extract_data_from_slice(&m, n + 4);
+        let post_data = extract_data_from_slice(&m, n + 8);
+        let name_index = extract_data_from_slice(&m, n + 12) as usize;
+        let post_index = extract_data_from_slice(&m, n + 16) as usize;
+        let (block_index, flags) = if version >= 3 {
+            (
+                extract_data_from_slice(&m, n + 20) as usize,
+                extract_data_from_slice(&m, n + 24),
+            )
+        } else {
+            (n, 0)
+        };
+        let compressed = flags & FLAG_ZSTD_POSTINGS != 0;
+        if (path_data as usize) < MAGIC.len()
+            || path_data >= name_data
+            || name_data >= post_data
+            || post_data as usize > name_index
+            || name_index > post_index
+            || post_index > block_index
+            || block_index > n
+            || !(post_index - name_index).is_multiple_of(4)
+            || !(block_index - post_index).is_multiple_of(POST_ENTRY_SIZE)
+            || flags & !FLAG_ZSTD_POSTINGS != 0
+            || (compressed && (n - block_index) < BLOCK_ENTRY_SIZE)
+            || (compressed && !(n - block_index).is_multiple_of(BLOCK_ENTRY_SIZE))
+            || (!compressed && block_index != n)
+        {
+            return Err(CorruptIndex::BadTrailer.into());
+        }
+        // Both lists end with an empty name, so every string read from
+        // inside them stops at a NUL before the end of the list.
+        if m[name_data as usize - 1] != 0 || m[post_data as usize - 1] != 0 {
+            return Err(CorruptIndex::UnterminatedList.into());
+        }
         let num_name: usize = if post_index > name_index {
             let d = (post_index - name_index) / 4;
             if d == 0 {
                 0
             } else {
-                (d - 1) as usize
+                d - 1
             }
         } else {
             0
         };
-        let num_post = if n > (post_index as usize) {
-            (n - (post_index as usize)) / (3 + 4 + 4)
-        } else {
-            0
-        };
+        let num_post = (block_index - post_index) / POST_ENTRY_SIZE;
+        let names_len = post_data - name_data;
+        let name_offsets_valid = m[name_index..post_index]
+            .chunks(4)
+            .all(|c| BigEndian::read_u32(c) < names_len);
+        if !name_offsets_valid {
+            return Err(CorruptIndex::BadNameOffset.into());
+        }
+        // blocks start at increasing offsets in both the compressed and
+        // the uncompressed lists, and the last entry is where they end
+        let mut lists_len = name_index - post_data as usize;
+        let mut num_blocks = 0;
+        if compressed {
+            let entries = m[block_index..n]
+                .chunks(BLOCK_ENTRY_SIZE)
+                .map(|c| (BigEndian::read_u32(c), BigEndian::read_u32(&c[4..])))
+                .collect::<Vec<_>>();
+            let in_order = entries[0] == (0, 0)
+                && entries
+                    .windows(2)
+                    .all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1)
+                && entries[entries.len() - 1].1 as usize == lists_len;
+            if !in_order {
+                return Err(CorruptIndex::BadPostingBlock.into());
+            }
+            lists_len = entries[entries.len() - 1].0 as usize;
+            num_blocks = entries.len() - 1;
+        }
+        let post_offsets_valid = m[post_index..block_index]
+            .chunks(POST_ENTRY_SIZE)
+            .all(|c| (BigEndian::read_u32(&c[3 + 4..]) as usize) + 3 < lists_len);
+        if !post_offsets_valid {
+            return Err(CorruptIndex::BadPostOffset.into());
+        }
+        if has_checksum {
+            let checksum = BigEndian::read_u32(&m[n + num_fields * 4..]);
+            if crc32fast::hash(&m[..n + num_fields * 4]) != checksum {
+                return Err(CorruptIndex::BadChecksum.into());
+            }
+        }
+        let relative = &m[MAGIC.len()..path_data as usize] == RELATIVE_MARKER.as_bytes();
         Ok(IndexReader {
             data: m,
             path_data,
@@ -183,68 +631,215 @@ impl IndexReader {
             post_data,
             name_index,
             post_index,
+            block_index,
             num_name,
             num_post,
+            lists_len,
+            blocks: (0..num_blocks).map(|_| OnceLock::new()).collect(),
+            aliases: OnceLock::new(),
+            lists_decoded: AtomicUsize::new(0),
+            relative,
         })
     }
 
     /// Takes a query and returns a list of matching file IDs.
-    pub fn query(&self, query: Query) -> PostSet {
-        // writeln!(io::stderr(), "query {:?}", query).unwrap();
-        match query.operation {
-            QueryOperation::None => PostSet::new(self),
-            QueryOperation::All => PostSet {
-                index: self,
-                list: (0..self.num_name as u32).collect::<BTreeSet<FileID>>(),
-            },
+    ///
+    /// Aliases of the matching files, see `alias_of`, are matches too. A
+    /// query without trigram constraints, like the one for `.`, selects every
+    /// file and logs a warning that the search can't use the index.
+    ///
+    /// Each posting list is decoded at most once per query, however often
+    /// its trigram appears in `query`. A posting list that can't be decoded
+    /// is reported as a `CorruptIndex`.
+    pub fn query(&self, query: Query) -> Result<PostSet<'_>, CorruptIndex> {
+        if query.is_unconstrained() {
+            warn!(
+                "pattern has no trigrams to look up in the index, searching all {} files; \
+                 this may be slow",
+                self.num_name
+            );
+            return Ok(self.all_files());
+        }
+        let mut post_set = PostSet {
+            index: self,
+            list: self.query_postings(query, &mut PostingCache::default())?,
+        };
+        if post_set.list.is_empty() {
+            return Ok(post_set);
+        }
+        let aliases = self.aliases();
+        if !aliases.is_empty() {
+            let matched = post_set
+                .list
+                .iter()
+                .filter_map(|id| aliases.get(id))
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            post_set.list.extend(matched);
+        }
+        Ok(post_set)
+    }
+
+    /// Returns every indexed file, without consulting any posting list
+    pub fn all_files(&self) -> PostSet<'_> {
+        PostSet {
+            index: self,
+            list: (0..self.num_name as u32).collect::<BTreeSet<FileID>>(),
+        }
+    }
+
+    /// Returns the files whose posting lists match `query`
+    fn query_postings(
+        &self,
+        query: Query,
+        cache: &mut PostingCache,
+    ) -> Result<BTreeSet<FileID>, CorruptIndex> {
+        let trigrams = query
+            .trigram
+            .into_iter()
+            .map(|t| (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32));
+        Ok(match query.operation {
+            QueryOperation::None => BTreeSet::new(),
+            QueryOperation::All => self.all_files().into_inner(),
             QueryOperation::And => {
-                // writeln!(io::stderr(), "AND {:?}", query.trigram).unwrap();
-                let mut trigram_it = query
-                    .trigram
-                    .into_iter()
-                    .map(|t| (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32));
-                let mut sub_iter = query.sub.into_iter().map(|q| self.query(q));
-                let post_set = if let Some(i) = trigram_it.next() {
-                    let s = PostSet::new(self)
-                        .or(i)
-                        .unwrap_or_else(|| PostSet::new(self));
-                    Some(trigram_it.fold(s, |a, b| a.and(b).unwrap_or_else(|| PostSet::new(self))))
-                } else {
-                    sub_iter.next()
-                };
-                let post_set = if let Some(ps) = post_set {
-                    ps
-                } else {
-                    return PostSet::new(self);
-                };
-                let sub_iter = sub_iter.map(|q| q.into_inner());
-                sub_iter.fold(post_set, |mut a, b| {
-                    a.list = &a.list & &b;
-                    a
-                })
+                let mut list: Option<BTreeSet<FileID>> = None;
+                for t in trigrams {
+                    let ids = cache.get(self, t)?;
+                    list = Some(match list {
+                        Some(l) => ids.iter().filter(|id| l.contains(id)).cloned().collect(),
+                        None => ids.iter().cloned().collect(),
+                    });
+                }
+                for each_sub in query.sub {
+                    let sub = self.query_postings(each_sub, cache)?;
+                    list = Some(match list {
+                        Some(l) => &l & &sub,
+                        None => sub,
+                    });
+                }
+                list.unwrap_or_default()
             }
             QueryOperation::Or => {
-                // writeln!(io::stderr(), "OR {:?}", query.trigram).unwrap();
-                let trigram_it = query
-                    .trigram
-                    .into_iter()
-                    .map(|t| (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32));
-                let post_set = trigram_it.fold(PostSet::new(self), |a, b| {
-                    a.or(b).unwrap_or_else(|| PostSet::new(self))
-                });
-                // writeln!(io::stderr(), "post set size = {:?}", post_set.list.len()).unwrap();
-                query
-                    .sub
-                    .into_iter()
-                    .map(|q| self.query(q).into_inner())
-                    .fold(post_set, |mut a, b| {
-                        a.list.extend(b.into_iter());
-                        a
-                    })
+                let mut list = BTreeSet::new();
+                for t in trigrams {
+                    list.extend(cache.get(self, t)?.iter().cloned());
+                }
+                for each_sub in query.sub {
+                    list.extend(self.query_postings(each_sub, cache)?);
+                }
+                list
+            }
+        })
+    }
+
+    /// Returns how many posting lists this reader has decoded, including
+    /// through `PostReader` and `PostSet`
+    pub fn lists_decoded(&self) -> usize {
+        self.lists_decoded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the encoded posting list of `trigram` and its number of files,
+    /// or `None` if no file contains it. Counts as decoding the list.
+    fn list_view(&self, trigram: u32) -> Result<Option<(&[u8], usize)>, CorruptIndex> {
+        let (count, offset) = self.find_list(trigram);
+        if count == 0 {
+            return Ok(None);
+        } else if count < 0 {
+            return Err(CorruptIndex::BadPostingList);
+        }
+        self.lists_decoded.fetch_add(1, Ordering::Relaxed);
+        let list = self.lists_at(offset as usize)?;
+        Ok(Some((&list[3..], count as usize)))
+    }
+
+    /// Returns the posting lists from the one at `offset` in the
+    /// uncompressed lists up to the end of its block, or of all the lists
+    /// if they aren't compressed. A compressed block is decompressed the
+    /// first time one of its lists is read.
+    pub fn lists_at(&self, offset: usize) -> Result<&[u8], CorruptIndex> {
+        if offset + 3 >= self.lists_len {
+            return Err(CorruptIndex::BadPostOffset);
+        }
+        if self.blocks.is_empty() {
+            return Ok(&self.as_slice()[self.post_data as usize + offset..self.name_index]);
+        }
+        // the last block that starts at or before `offset`
+        let k = search::search(self.blocks.len(), |k| self.block_entry(k + 1).0 > offset);
+        let block = match self.blocks[k].get() {
+            Some(block) => block,
+            None => {
+                let block = self.decompress_block(k)?;
+                // another thread may have decompressed it meanwhile
+                let _ = self.blocks[k].set(block);
+                self.blocks[k].get().unwrap()
             }
+        };
+        let start = self.block_entry(k).0;
+        if offset + 3 >= start + block.len() {
+            return Err(CorruptIndex::BadPostingList);
+        }
+        Ok(&block[offset - start..])
+    }
+
+    /// Returns where block `k` of the compressed posting lists starts in
+    /// the uncompressed and in the compressed lists
+    fn block_entry(&self, k: usize) -> (usize, usize) {
+        let entry = self.block_index + k * BLOCK_ENTRY_SIZE;
+        (
+            self.extract_data(entry) as usize,
+            self.extract_data(entry + 4) as usize,
+        )
+    }
+
+    /// Decompresses block `k` of the posting lists
+    fn decompress_block(&self, k: usize) -> Result<Vec<u8>, CorruptIndex> {
+        let ((start, compressed_start), (end, compressed_end)) =
+            (self.block_entry(k), self.block_entry(k + 1));
+        let lists = &self.as_slice()[self.post_data as usize..self.name_index];
+        let block = zstd::bulk::decompress(&lists[compressed_start..compressed_end], end - start)
+            .map_err(|_| CorruptIndex::BadPostingBlock)?;
+        if block.len() != end - start {
+            return Err(CorruptIndex::BadPostingBlock);
+        }
+        Ok(block)
+    }
+
+    /// Returns whether the posting lists are compressed, as written by
+    /// `cindex --compress`
+    pub fn is_compressed(&self) -> bool {
+        !self.blocks.is_empty()
+    }
+
+    /// Returns the format version named in the index header
+    /// (`"csearch index 1\n"` is version 1)
+    pub fn format_version(&self) -> u32 {
+        // checked by open
+        consts::parse_version(&self.data).unwrap()
+    }
+
+    /// Returns whether the indexed paths and file names are relative to a
+    /// base directory, as written by `cindex --relative-to`. Names of files
+    /// that weren't under the base directory are still absolute.
+    pub fn is_relative(&self) -> bool {
+        self.relative
+    }
+
+    /// Returns the number of distinct trigrams in the index, not counting
+    /// the `"\xff\xff\xff"` entry that ends the posting lists
+    pub fn num_trigrams(&self) -> usize {
+        match self.num_post.checked_sub(1) {
+            Some(last) if self.list_at(last * POST_ENTRY_SIZE).0 == 0xffffff => last,
+            _ => self.num_post,
         }
     }
 
+    /// Returns the number of files in the index, the ids of which run from
+    /// 0 up to it
+    pub fn num_files(&self) -> usize {
+        self.num_name
+    }
+
     /// Returns the size of the index
     pub fn len(&self) -> usize {
         self.data.len()
@@ -282,6 +877,280 @@ impl IndexReader {
         self.extract_string_at((self.name_data + offset) as usize)
     }
 
+    /// Returns the name of the file identified by `file_id`, or `None` if
+    /// there's no such file or its name isn't valid UTF-8
+    pub fn file_name(&self, file_id: FileID) -> Option<&str> {
+        if file_id as usize >= self.num_name {
+            return None;
+        }
+        let offset = self.extract_data(self.name_index + 4 * file_id as usize);
+        // `open` checked that the offset is inside the name list, and that
+        // the list ends with a NUL
+        let names = &self.as_slice()[(self.name_data + offset) as usize..self.post_data as usize];
+        let len = names.iter().position(|&c| c == 0)?;
+        str::from_utf8(&names[..len]).ok()
+    }
+
+    /// Returns the names of the files in the index, in order of file id,
+    /// reading each as the iterator reaches it. Names that aren't valid
+    /// UTF-8, which cindex doesn't write, are skipped.
+    ///
+    /// ```no_run
+    /// # use libcsearch::reader::IndexReader;
+    /// let idx = IndexReader::open("index").unwrap();
+    /// for name in idx.file_names().filter(|n| n.ends_with(".rs")) {
+    ///     println!("{}", name);
+    /// }
+    /// ```
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        (0..self.num_name as FileID).filter_map(move |id| self.file_name(id))
+    }
+
+    /// Returns the ids of the files that contain `trigram`, in increasing
+    /// order. Files not containing any trigram of the index aren't listed.
+    ///
+    /// Unlike `PostReader` and `PostSet`, the list is checked to be for
+    /// `trigram` and to hold only files of the index.
+    pub fn posting_list(&self, trigram: [u8; 3]) -> Result<Vec<FileID>, CorruptIndex> {
+        let t = (trigram[0] as u32) << 16 | (trigram[1] as u32) << 8 | trigram[2] as u32;
+        let (count, offset) = self.find_list(t);
+        if count == 0 {
+            return Ok(Vec::new());
+        } else if count < 0 {
+            return Err(CorruptIndex::BadPostingList);
+        }
+        self.decode_list(t, count as usize, offset as usize)
+            .map(|(ids, _)| ids)
+    }
+
+    /// Decodes the posting list for `trigram` at `offset` in the posting
+    /// lists, which the posting list index says holds `count` files.
+    /// Returns the file ids and the length of the list in bytes.
+    fn decode_list(
+        &self,
+        trigram: u32,
+        count: usize,
+        offset: usize,
+    ) -> Result<(Vec<FileID>, usize), CorruptIndex> {
+        // each list starts with its trigram, then the deltas between
+        // file ids, ending with a zero delta
+        let list = self.lists_at(offset)?;
+        if list[..3] != [(trigram >> 16) as u8, (trigram >> 8) as u8, trigram as u8] {
+            return Err(CorruptIndex::BadPostingList);
+        }
+        self.lists_decoded.fetch_add(1, Ordering::Relaxed);
+        let mut deltas = Vec::with_capacity(count);
+        let len = libvarint::decode_batch(&list[3..], &mut deltas);
+        if deltas.len() != count || len == 0 || list[3 + len - 1] != 0 {
+            return Err(CorruptIndex::BadPostingList);
+        }
+        let mut ids = Vec::with_capacity(deltas.len());
+        let mut file_id: i64 = -1;
+        for delta in deltas {
+            file_id += delta as i64;
+            if file_id >= self.num_name as i64 {
+                return Err(CorruptIndex::BadPostingList);
+            }
+            ids.push(file_id as FileID);
+        }
+        Ok((ids, 3 + len))
+    }
+
+    /// Checks the whole index for consistency, beyond what `open` checks:
+    /// that the path and name lists are well formed, the name index is in
+    /// order, the posting list index is sorted by trigram with offsets in
+    /// order, and every posting list decodes to files in the index.
+    ///
+    /// Returns the first inconsistency found. Every posting list is decoded,
+    /// so this reads the whole index.
+    ///
+    /// ```no_run
+    /// # use libcsearch::reader::IndexReader;
+    /// let idx = IndexReader::open("index").unwrap();
+    /// match idx.verify() {
+    ///     Ok(()) => println!("ok"),
+    ///     Err(e) => println!("{}", e),
+    /// }
+    /// ```
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let fail = |kind, detail: String| Err(VerifyError { kind, detail });
+        let data = self.as_slice();
+
+        // the path list ends with an empty path right before the names
+        let mut offset = self.path_data as usize;
+        loop {
+            let len = match data[offset..self.name_data as usize]
+                .iter()
+                .position(|&c| c == 0)
+            {
+                Some(len) => len,
+                None => {
+                    return fail(
+                        CorruptIndex::UnterminatedList,
+                        format!("path at offset {} runs into the name list", offset),
+                    )
+                }
+            };
+            offset += len + 1;
+            if len == 0 {
+                break;
+            }
+        }
+        if offset != self.name_data as usize {
+            return fail(
+                CorruptIndex::UnterminatedList,
+                format!(
+                    "{} bytes after the end of the path list",
+                    self.name_data as usize - offset
+                ),
+            );
+        }
+
+        // each name is NUL-terminated before the next one starts, and the
+        // entry after the last name is the empty name that ends the list
+        let names = &data[self.name_data as usize..self.post_data as usize];
+        for file_id in 0..=self.num_name {
+            let start = self.extract_data(self.name_index + 4 * file_id) as usize;
+            let end = if file_id < self.num_name {
+                self.extract_data(self.name_index + 4 * (file_id + 1)) as usize
+            } else {
+                names.len()
+            };
+            if end <= start {
+                return fail(
+                    CorruptIndex::BadNameOffset,
+                    format!(
+                        "name of file {} at offset {} is out of order",
+                        file_id, start
+                    ),
+                );
+            }
+            let len = names[start..end].iter().position(|&c| c == 0);
+            match len {
+                None => {
+                    return fail(
+                        CorruptIndex::UnterminatedList,
+                        format!("name of file {} is truncated", file_id),
+                    )
+                }
+                Some(0) if file_id < self.num_name => {
+                    return fail(
+                        CorruptIndex::UnterminatedList,
+                        format!("file {} has an empty name", file_id),
+                    )
+                }
+                Some(len) if file_id == self.num_name && (len != 0 || end - start != 1) => {
+                    return fail(
+                        CorruptIndex::UnterminatedList,
+                        "the name list doesn't end with an empty name".to_string(),
+                    )
+                }
+                _ => (),
+            }
+        }
+
+        // posting lists are sorted by trigram, and laid out in that order
+        // without overlapping
+        let lists_len = self.lists_len;
+        let mut last: Option<(u32, usize)> = None;
+        for i in 0..self.num_post {
+            let (trigram, count, offset) = self.list_at(i * POST_ENTRY_SIZE);
+            let offset = offset as usize;
+            if let Some((last_trigram, end)) = last {
+                if trigram <= last_trigram {
+                    return fail(
+                        CorruptIndex::BadPostOffset,
+                        format!("trigram {:06x} comes after {:06x}", trigram, last_trigram),
+                    );
+                }
+                if offset < end {
+                    return fail(
+                        CorruptIndex::BadPostOffset,
+                        format!(
+                            "posting list of trigram {:06x} at offset {} overlaps the one before",
+                            trigram, offset
+                        ),
+                    );
+                }
+            }
+            if offset + 3 >= lists_len {
+                return fail(
+                    CorruptIndex::BadPostOffset,
+                    format!("posting list of trigram {:06x} is out of range", trigram),
+                );
+            }
+            let len = match self.decode_list(trigram, count as usize, offset) {
+                Ok((_, len)) => len,
+                Err(kind) => {
+                    return fail(
+                        kind,
+                        format!(
+                            "posting list of trigram {:06x} at offset {} doesn't hold {} files of the index",
+                            trigram, offset, count
+                        ),
+                    )
+                }
+            };
+            last = Some((trigram, offset + len));
+        }
+        Ok(())
+    }
+
+    /// Returns the metadata stored alongside the name of `file_id`, if any
+    pub fn file_metadata(&self, file_id: FileID) -> Option<FileMetadata> {
+        let (start, records) = self.name_records(file_id)?;
+        if records != FILE_METADATA_SIZE && records != FILE_METADATA_SIZE + FILE_ALIAS_SIZE {
+            return None;
+        }
+        Some(FileMetadata::from_bytes(
+            &self.as_slice()[start..start + FILE_METADATA_SIZE],
+        ))
+    }
+
+    /// Returns the ID of the file that `file_id` was stored as an alias of
+    /// because they have the same contents, if it was
+    pub fn alias_of(&self, file_id: FileID) -> Option<FileID> {
+        let (start, records) = self.name_records(file_id)?;
+        if records != FILE_ALIAS_SIZE && records != FILE_METADATA_SIZE + FILE_ALIAS_SIZE {
+            return None;
+        }
+        let record = start + records - FILE_ALIAS_SIZE;
+        let target = BigEndian::read_u32(&self.as_slice()[record..record + FILE_ALIAS_SIZE]);
+        // a merge writes an out of range ID for an alias whose file was dropped
+        if target as usize >= self.num_name || target == file_id {
+            return None;
+        }
+        Some(target)
+    }
+
+    /// Returns the offset of the records that follow the name of
+    /// `file_id`, and their total size
+    fn name_records(&self, file_id: FileID) -> Option<(usize, usize)> {
+        if file_id as usize >= self.num_name {
+            return None;
+        }
+        let file_id_usize = file_id as usize;
+        let offset = self.extract_data(self.name_index + 4 * file_id_usize) as usize;
+        let next = self.extract_data(self.name_index + 4 * (file_id_usize + 1)) as usize;
+        let start = self.name_data as usize + offset;
+        let name_len = self.as_slice()[start..].iter().position(|&c| c == 0)?;
+        let records = next.checked_sub(offset + name_len + 1)?;
+        Some((start + name_len + 1, records))
+    }
+
+    /// Returns the aliases of each file that has any
+    fn aliases(&self) -> &BTreeMap<FileID, Vec<FileID>> {
+        self.aliases.get_or_init(|| {
+            let mut aliases = BTreeMap::<FileID, Vec<FileID>>::new();
+            for file_id in 0..self.num_name as FileID {
+                if let Some(target) = self.alias_of(file_id) {
+                    aliases.entry(target).or_default().push(file_id);
+                }
+            }
+            aliases
+        })
+    }
+
     pub fn list_at(&self, offset: usize) -> (u32, u32, u32) {
         let d: &[u8] = {
             let s = &self.data;
@@ -350,6 +1219,18 @@ impl IndexReader {
     }
 }
 
+/// Decodes the `count` deltas of the posting list that starts at `d`
+fn decode_postings(d: &[u8], count: usize) -> Result<Vec<u32>, CorruptIndex> {
+    let mut deltas = Vec::with_capacity(count);
+    libvarint::decode_batch(d, &mut deltas);
+    if deltas.len() < count {
+        // the list ended early with a zero delta, or ran out of data
+        return Err(CorruptIndex::BadPostingList);
+    }
+    deltas.truncate(count);
+    Ok(deltas)
+}
+
 // index and offset are never read
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -358,7 +1239,7 @@ pub struct PostReader<'a, 'b> {
     count: isize,
     offset: u32,
     fileid: i64,
-    d: &'a [u8],
+    deltas: vec::IntoIter<u32>,
     restrict: &'b Option<BTreeSet<u32>>,
 }
 
@@ -367,32 +1248,31 @@ impl<'a, 'b> PostReader<'a, 'b> {
         index: &'a IndexReader,
         trigram: u32,
         restrict: &'b Option<BTreeSet<u32>>,
-    ) -> Option<Self> {
+    ) -> Result<Option<Self>, CorruptIndex> {
         let (count, offset) = index.find_list(trigram);
         if count == 0 {
-            return None;
+            return Ok(None);
+        } else if count < 0 {
+            return Err(CorruptIndex::BadPostingList);
         }
-        let view = {
-            let v = &index.data;
-            let split_point = (index.post_data as usize) + (offset as usize) + 3;
-            v.split_at(split_point).1
-        };
-        Some(PostReader {
+        index.lists_decoded.fetch_add(1, Ordering::Relaxed);
+        let view = &index.lists_at(offset as usize)?[3..];
+        Ok(Some(PostReader {
             index,
             count,
             offset,
             fileid: -1,
-            d: view,
+            deltas: decode_postings(view, count as usize)?.into_iter(),
             restrict,
-        })
+        }))
     }
     pub fn and(
         index: &'a IndexReader,
         list: BTreeSet<u32>,
         trigram: u32,
         restrict: &'b Option<BTreeSet<u32>>,
-    ) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
+    ) -> Result<BTreeSet<u32>, CorruptIndex> {
+        if let Some(mut r) = Self::new(index, trigram, restrict)? {
             let mut h = BTreeSet::new();
             while r.next() {
                 let fileid = r.fileid;
@@ -400,9 +1280,9 @@ impl<'a, 'b> PostReader<'a, 'b> {
                     h.insert(fileid as u32);
                 }
             }
-            h
+            Ok(h)
         } else {
-            BTreeSet::new()
+            Ok(BTreeSet::new())
         }
     }
     pub fn or(
@@ -410,41 +1290,35 @@ impl<'a, 'b> PostReader<'a, 'b> {
         list: BTreeSet<u32>,
         trigram: u32,
         restrict: &'b Option<BTreeSet<u32>>,
-    ) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
+    ) -> Result<BTreeSet<u32>, CorruptIndex> {
+        if let Some(mut r) = Self::new(index, trigram, restrict)? {
             let mut h = list;
             while r.next() {
                 h.insert(r.fileid as u32);
             }
-            h
+            Ok(h)
         } else {
-            BTreeSet::new()
+            Ok(BTreeSet::new())
         }
     }
     pub fn list(
         index: &'a IndexReader,
         trigram: u32,
         restrict: &'b Option<BTreeSet<u32>>,
-    ) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
+    ) -> Result<BTreeSet<u32>, CorruptIndex> {
+        if let Some(mut r) = Self::new(index, trigram, restrict)? {
             let mut x = BTreeSet::<u32>::new();
             while r.next() {
                 x.insert(r.fileid as u32);
             }
-            x
+            Ok(x)
         } else {
-            BTreeSet::new()
+            Ok(BTreeSet::new())
         }
     }
     // FIXME: refactor either to use rust iterator or don't look like an iterator
     fn next(&mut self) -> bool {
-        while self.count > 0 {
-            self.count -= 1;
-            let (delta, n) = libvarint::read_uvarint(self.d).unwrap();
-            if n == 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            self.d = self.d.split_at(n as usize).1;
+        for delta in self.deltas.by_ref() {
             self.fileid += delta as i64;
             let is_fileid_found = match *self.restrict {
                 Some(ref r) if r.contains(&(self.fileid as u32)) => true,
@@ -456,8 +1330,6 @@ impl<'a, 'b> PostReader<'a, 'b> {
             }
             return true;
         }
-        // list should end with terminating 0 delta
-        // FIXME: add bounds checking
         self.fileid = -1;
         false
     }
@@ -478,53 +1350,76 @@ impl<'a> PostSet<'a> {
     pub fn into_inner(self) -> BTreeSet<u32> {
         self.list
     }
-    pub fn and(self, trigram: u32) -> Option<Self> {
-        let (mut d, count) = Self::make_view(self.index, trigram)?;
+    pub fn and(self, trigram: u32) -> Result<Option<Self>, CorruptIndex> {
+        let (d, count) = match Self::make_view(self.index, trigram)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
         let mut fileid = -1;
         let mut h = BTreeSet::new();
-        for _ in 0..count {
-            let (delta, n) = libvarint::read_uvarint(d).unwrap();
-            if n == 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            d = d.split_at(n as usize).1;
+        for delta in decode_postings(d, count)? {
             fileid += delta as i64;
             if self.list.contains(&(fileid as u32)) {
                 h.insert(fileid as u32);
             }
         }
-        Some(PostSet {
+        Ok(Some(PostSet {
             index: self.index,
             list: h,
-        })
+        }))
     }
-    pub fn or(mut self, trigram: u32) -> Option<Self> {
-        let (mut d, count) = if let Some(tup) = Self::make_view(self.index, trigram) {
+    pub fn or(mut self, trigram: u32) -> Result<Self, CorruptIndex> {
+        let (d, count) = if let Some(tup) = Self::make_view(self.index, trigram)? {
             tup
         } else {
-            return Some(self);
+            return Ok(self);
         };
         let mut fileid = -1;
         // writeln!(io::stderr(), "TRI 0x{:6x}: {}", trigram, count).unwrap();
-        for _ in 0..count {
-            let (delta, n) = libvarint::read_uvarint(d).unwrap();
-            if n == 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            d = d.split_at(n as usize).1;
+        for delta in decode_postings(d, count)? {
             fileid += delta as i64;
             self.list.insert(fileid as u32);
         }
-        Some(self)
+        Ok(self)
     }
-    fn make_view(index: &'a IndexReader, trigram: u32) -> Option<(&'a [u8], usize)> {
-        let (count, offset) = index.find_list(trigram);
-        if count == 0 {
-            // writeln!(io::stderr(), "TRI 0x{:6x}: 0", trigram).unwrap();
-            return None;
+    fn make_view(
+        index: &'a IndexReader,
+        trigram: u32,
+    ) -> Result<Option<(&'a [u8], usize)>, CorruptIndex> {
+        index.list_view(trigram)
+    }
+}
+
+/// The posting lists decoded while evaluating one query, by trigram
+#[derive(Default)]
+struct PostingCache {
+    lists: HashMap<u32, Rc<Vec<FileID>>>,
+    /// Total number of file ids in `lists`
+    num_ids: usize,
+}
+
+impl PostingCache {
+    /// Returns the ids of the files that contain `trigram`, decoding its
+    /// posting list unless it was decoded before
+    fn get(&mut self, index: &IndexReader, trigram: u32) -> Result<Rc<Vec<FileID>>, CorruptIndex> {
+        if let Some(list) = self.lists.get(&trigram) {
+            return Ok(list.clone());
+        }
+        let mut ids = match index.list_view(trigram)? {
+            Some((d, count)) => decode_postings(d, count)?,
+            None => Vec::new(),
+        };
+        let mut file_id: i64 = -1;
+        for each_id in ids.iter_mut() {
+            file_id += *each_id as i64;
+            *each_id = file_id as FileID;
+        }
+        let list = Rc::new(ids);
+        // past the limit, lists are decoded again each time they're needed
+        if self.num_ids + list.len() <= MAX_CACHED_IDS {
+            self.num_ids += list.len();
+            self.lists.insert(trigram, list.clone());
         }
-        let v = &index.data;
-        let split_point = (index.post_data as usize) + (offset as usize) + 3;
-        Some((v.split_at(split_point).1, count as usize))
+        Ok(list)
     }
 }
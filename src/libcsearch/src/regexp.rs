@@ -4,12 +4,53 @@
 // license that can be found in the LICENSE file.
 use std::char;
 use std::collections::BTreeSet;
+use std::fmt;
 use std::iter::FromIterator;
 
 pub type StringSet = BTreeSet<Vec<u8>>;
 
 // use regex::Regex;
-use regex_syntax::{ByteClass, ByteRange, ClassRange, Expr, Repeater};
+use regex_syntax::{ByteClass, ByteRange, ClassRange, Expr, ExprBuilder, Repeater};
+
+use reader::{CorruptIndex, IndexReader};
+
+/// Builds the trigram query that any file matching `pattern` must satisfy.
+///
+/// The pattern is parsed the same way `csearch` parses it (byte oriented,
+/// no Unicode classes); use the `(?i)` flag for a case-insensitive query.
+///
+/// ```rust
+/// # extern crate libcsearch;
+/// # use libcsearch::regexp::query_from_regexp;
+/// # fn main() {
+/// let q = query_from_regexp(r"abc(def|ghi)").unwrap();
+/// assert_eq!(q.to_string(), r#""abc" AND ("bcd" AND "cde" AND "def" OR "bcg" AND "cgh" AND "ghi")"#);
+/// # }
+/// ```
+pub fn query_from_regexp(pattern: &str) -> Result<Query, String> {
+    let expr = ExprBuilder::new()
+        .unicode(false)
+        .parse(pattern)
+        .map_err(|e| e.to_string())?;
+    RegexInfo::new(expr).map(|info| info.query)
+}
+
+/// Builds the trigram query for the literal string `s`: every trigram of
+/// `s`, or a query matching every file if `s` is shorter than a trigram.
+///
+/// ```rust
+/// # extern crate libcsearch;
+/// # use libcsearch::regexp::query_from_literal;
+/// # fn main() {
+/// assert_eq!(query_from_literal(b"a+b(c)").to_string(), r#""(c)" AND "+b(" AND "a+b" AND "b(c""#);
+/// assert_eq!(query_from_literal(b"ab").to_string(), "+");
+/// # }
+/// ```
+pub fn query_from_literal(s: &[u8]) -> Query {
+    let mut set = StringSet::new();
+    set.insert(s.to_vec());
+    and_trigrams(Query::all(), &set)
+}
 
 /// Operation on a Query
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -76,6 +117,129 @@ impl Query {
     }
 }
 
+/// A trigram query as a plain tree, for inspecting queries or storing them.
+///
+/// Unlike `Query`, which `RegexInfo` simplifies as it goes, this type is
+/// meant to stay the same between releases. With the `serde` feature it
+/// can be serialized.
+///
+/// ```rust
+/// # extern crate libcsearch;
+/// # use libcsearch::regexp::{query_from_regexp, QueryAst};
+/// # fn main() {
+/// let q = QueryAst::from(&query_from_regexp(r"abc|xyz").unwrap());
+/// assert_eq!(
+///     q,
+///     QueryAst::Or(vec![QueryAst::Trigram(*b"abc"), QueryAst::Trigram(*b"xyz")])
+/// );
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum QueryAst {
+    /// Matches every file
+    All,
+    /// Matches no file
+    None,
+    /// Matches the files that contain the trigram
+    Trigram([u8; 3]),
+    /// Matches the files that match every subquery
+    And(Vec<QueryAst>),
+    /// Matches the files that match any subquery
+    Or(Vec<QueryAst>),
+}
+
+impl QueryAst {
+    /// Returns the ids of the files in `reader` that may match, in
+    /// increasing order, like `IndexReader::query`
+    pub fn evaluate(&self, reader: &IndexReader) -> Result<Vec<u32>, CorruptIndex> {
+        Ok(reader
+            .query(Query::from(self))?
+            .into_inner()
+            .into_iter()
+            .collect())
+    }
+}
+
+impl<'a> From<&'a Query> for QueryAst {
+    fn from(q: &'a Query) -> QueryAst {
+        let operation = q.operation;
+        let mut subs = q
+            .trigram
+            .iter()
+            .map(|t| QueryAst::Trigram([t[0], t[1], t[2]]))
+            .chain(q.sub.iter().map(QueryAst::from))
+            .collect::<Vec<_>>();
+        match operation {
+            QueryOperation::All => QueryAst::All,
+            QueryOperation::None => QueryAst::None,
+            _ if subs.len() == 1 => subs.pop().unwrap(),
+            QueryOperation::And => QueryAst::And(subs),
+            QueryOperation::Or => QueryAst::Or(subs),
+        }
+    }
+}
+
+impl<'a> From<&'a QueryAst> for Query {
+    fn from(q: &'a QueryAst) -> Query {
+        match *q {
+            QueryAst::All => Query::all(),
+            QueryAst::None => Query::none(),
+            QueryAst::Trigram(ref t) => Query {
+                operation: QueryOperation::And,
+                trigram: BTreeSet::from_iter(vec![t.to_vec()]),
+                sub: Vec::new(),
+            },
+            QueryAst::And(ref subs) => subs
+                .iter()
+                .map(Query::from)
+                .fold(Query::all(), |a, b| a.and(b)),
+            QueryAst::Or(ref subs) => subs
+                .iter()
+                .map(Query::from)
+                .fold(Query::none(), |a, b| a.or(b)),
+        }
+    }
+}
+
+/// Formats the query with explicit `AND`/`OR` operators, e.g.
+/// `"abc" AND ("def" OR "ghi")`. `+` matches every file and `-` none.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = match self.operation {
+            QueryOperation::None => return f.write_str("-"),
+            QueryOperation::All => return f.write_str("+"),
+            QueryOperation::And => " AND ",
+            QueryOperation::Or => " OR ",
+        };
+        let mut first = true;
+        for t in &self.trigram {
+            if !first {
+                f.write_str(join)?;
+            }
+            first = false;
+            write!(f, "\"{}\"", String::from_utf8_lossy(&t[..]))?;
+        }
+        for sub in &self.sub {
+            if !first {
+                f.write_str(join)?;
+            }
+            first = false;
+            // AND binds tighter than OR, so only an OR nested in an AND
+            // needs parentheses to read unambiguously.
+            if self.operation == QueryOperation::And
+                && sub.operation == QueryOperation::Or
+                && !sub.is_atom()
+            {
+                write!(f, "({})", sub)?;
+            } else {
+                write!(f, "{}", sub)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Default for Query {
     fn default() -> Self {
         Query {
@@ -147,6 +311,11 @@ impl Query {
     pub fn is_atom(&self) -> bool {
         self.trigram.len() == 1 && self.sub.is_empty()
     }
+    /// Returns true if the query places no trigram constraint on the files,
+    /// so every indexed file has to be searched
+    pub fn is_unconstrained(&self) -> bool {
+        self.operation == QueryOperation::All
+    }
 }
 
 #[derive(Default, Debug)]
@@ -177,7 +346,7 @@ impl RegexInfo {
             | Expr::NotWordBoundary => Ok(Self::empty_string()),
             Expr::WordBoundaryAscii | Expr::NotWordBoundaryAscii => Ok(Self::empty_string()),
             Expr::Literal { chars, casei } => Self::analyze(Expr::LiteralBytes {
-                bytes: String::from_iter(chars.into_iter()).into_bytes(),
+                bytes: String::from_iter(chars).into_bytes(),
                 casei,
             }),
             Expr::LiteralBytes { bytes, casei: true } => {
@@ -196,12 +365,12 @@ impl RegexInfo {
                     _ => {
                         // Multi-letter case-folded string:
                         // treat as concatenation of single-letter case-folded strings.
-                        bytes.into_iter().fold(Ok(Self::empty_string()), |info, c| {
+                        bytes.into_iter().try_fold(Self::empty_string(), |info, c| {
                             let analyzed = Self::analyze(Expr::LiteralBytes {
                                 bytes: vec![c],
                                 casei: true,
                             })?;
-                            info.map(|info| concat(info, analyzed))
+                            Ok(concat(info, analyzed))
                         })
                     }
                 }
@@ -406,16 +575,16 @@ impl RegexInfo {
             s.push_str("exact: ");
             let as_vec: Vec<&[u8]> = exact.iter().map(|v| v as &[u8]).collect();
             let flattened: Vec<u8> = as_vec.join(&b',');
-            s.push_str(&*String::from_utf8_lossy(&flattened));
+            s.push_str(&String::from_utf8_lossy(&flattened));
         } else {
             s.push_str("prefix: ");
             let as_vec: Vec<&[u8]> = self.prefix.iter().map(|v| v as &[u8]).collect();
             let flattened: Vec<u8> = as_vec.join(&b',');
-            s.push_str(&*String::from_utf8_lossy(&flattened));
+            s.push_str(&String::from_utf8_lossy(&flattened));
             s.push_str(" suffix: ");
             let as_vec: Vec<&[u8]> = self.suffix.iter().map(|v| v as &[u8]).collect();
             let flattened: Vec<u8> = as_vec.join(&b',');
-            s.push_str(&*String::from_utf8_lossy(&flattened));
+            s.push_str(&String::from_utf8_lossy(&flattened));
         }
         s.push_str(&format!(" match: {}", self.query.format_as_string()));
         s
@@ -428,10 +597,10 @@ fn concat(x: RegexInfo, y: RegexInfo) -> RegexInfo {
         ..Default::default()
     };
 
-    if let (&Some(ref x_s), &Some(ref y_s)) = (&x.exact_set, &y.exact_set) {
+    if let (Some(x_s), Some(y_s)) = (&x.exact_set, &y.exact_set) {
         xy.exact_set = Some(cross_product(x_s, y_s));
     } else {
-        if let &Some(ref x_s) = &x.exact_set {
+        if let Some(x_s) = &x.exact_set {
             xy.prefix = cross_product(x_s, &y.prefix);
         } else {
             xy.prefix = if x.can_empty {
@@ -440,7 +609,7 @@ fn concat(x: RegexInfo, y: RegexInfo) -> RegexInfo {
                 x.prefix
             };
         }
-        if let &Some(ref y_s) = &y.exact_set {
+        if let Some(y_s) = &y.exact_set {
             xy.suffix = cross_product(&x.suffix, y_s);
         } else {
             xy.suffix = if y.can_empty {
@@ -477,15 +646,15 @@ fn alternate(x: RegexInfo, y: RegexInfo) -> RegexInfo {
     let mut add_exact_x = false;
     let mut add_exact_y = false;
     match (&x.exact_set, &y.exact_set) {
-        (&Some(ref x_s), &Some(ref y_s)) => {
+        (Some(x_s), Some(y_s)) => {
             xy.exact_set = Some(union(x_s, y_s));
         }
-        (&Some(ref x_s), &None) => {
+        (Some(x_s), &None) => {
             xy.prefix = union(x_s, &y.prefix);
             xy.suffix = union(x_s, &y.suffix);
             add_exact_x = true;
         }
-        (&None, &Some(ref y_s)) => {
+        (&None, Some(y_s)) => {
             xy.prefix = union(&x.prefix, y_s);
             xy.suffix = union(&x.suffix, y_s);
             add_exact_y = true;
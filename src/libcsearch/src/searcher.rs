@@ -0,0 +1,302 @@
+// Copyright 2015 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! High level search over an index: find the candidate files with the
+//! trigram index, then scan each of them for matching lines.
+//!
+//! ```no_run
+//! # extern crate libcsearch;
+//! # use libcsearch::searcher::Searcher;
+//! # fn main() {
+//! let searcher = Searcher::builder()
+//!     .ignore_case(true)
+//!     .path_filter(r"\.rs$")
+//!     .open("/home/me/.csearchindex")
+//!     .unwrap();
+//! for m in searcher.search(r"fn\s+main").unwrap() {
+//!     println!("{}:{}:{}", m.path.display(), m.line_number, m.line);
+//! }
+//! # }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::vec;
+
+use regex::bytes;
+use regex::Regex;
+
+use reader::{CorruptIndex, IndexReader, OpenError, ReadMode};
+use regexp::query_from_regexp;
+use ContentsOpener;
+
+/// Error returned when a `Searcher` can't be opened or a search can't start
+#[derive(Debug)]
+pub enum SearchError {
+    /// The index couldn't be opened
+    Open(OpenError),
+    /// The search pattern or path filter isn't a valid regular expression
+    Regex(String),
+    /// A posting list of the index couldn't be decoded
+    Corrupt(CorruptIndex),
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SearchError::Open(ref e) => e.fmt(f),
+            SearchError::Regex(ref e) => write!(f, "invalid regular expression: {}", e),
+            SearchError::Corrupt(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for SearchError {}
+
+impl From<OpenError> for SearchError {
+    fn from(e: OpenError) -> SearchError {
+        SearchError::Open(e)
+    }
+}
+
+impl From<CorruptIndex> for SearchError {
+    fn from(e: CorruptIndex) -> SearchError {
+        SearchError::Corrupt(e)
+    }
+}
+
+/// A matching line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Path of the file, as stored in the index
+    pub path: PathBuf,
+    /// 1-based line number of the match
+    pub line_number: usize,
+    /// The matching line, without its line ending
+    pub line: String,
+}
+
+/// How selective a search was: how many files the index picked, and how
+/// many of those turned out to match. See `Matches::stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SearchStats {
+    /// Files selected by the trigram index and the path filter
+    pub candidates: usize,
+    /// Candidate files opened and read so far
+    pub files_scanned: usize,
+    /// Files with at least one matching line
+    pub files_matched: usize,
+    /// Matching lines
+    pub total_matches: usize,
+    /// Time since the search started
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "candidates={} scanned={} matched_files={} matches={} elapsed={:.3}ms",
+            self.candidates,
+            self.files_scanned,
+            self.files_matched,
+            self.total_matches,
+            self.elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Options for opening a `Searcher`
+#[derive(Debug, Default, Clone)]
+pub struct SearcherBuilder {
+    ignore_case: bool,
+    path_filter: Option<String>,
+    read_mode: ReadMode,
+}
+
+impl SearcherBuilder {
+    pub fn new() -> SearcherBuilder {
+        SearcherBuilder::default()
+    }
+    /// Match patterns case insensitively
+    pub fn ignore_case(mut self, yes: bool) -> SearcherBuilder {
+        self.ignore_case = yes;
+        self
+    }
+    /// Only search files whose path matches the regular expression `pattern`
+    pub fn path_filter(mut self, pattern: &str) -> SearcherBuilder {
+        self.path_filter = Some(pattern.to_string());
+        self
+    }
+    /// Read the index as `mode` says, see `IndexReader::open_with`
+    pub fn read_mode(mut self, mode: ReadMode) -> SearcherBuilder {
+        self.read_mode = mode;
+        self
+    }
+    /// Opens the index at `index_path`
+    pub fn open<P: AsRef<Path>>(self, index_path: P) -> Result<Searcher, SearchError> {
+        let path_filter = match self.path_filter {
+            Some(ref p) => Some(Regex::new(p).map_err(|e| SearchError::Regex(e.to_string()))?),
+            None => None,
+        };
+        Ok(Searcher {
+            index: IndexReader::open_with(index_path, self.read_mode)?,
+            ignore_case: self.ignore_case,
+            path_filter,
+        })
+    }
+}
+
+/// Searches the files of an index for lines matching a regular expression
+pub struct Searcher {
+    index: IndexReader,
+    ignore_case: bool,
+    path_filter: Option<Regex>,
+}
+
+impl Searcher {
+    /// Opens the index at `index_path` with the default options
+    pub fn open<P: AsRef<Path>>(index_path: P) -> Result<Searcher, SearchError> {
+        SearcherBuilder::new().open(index_path)
+    }
+
+    /// Returns a builder to set search options before opening an index
+    pub fn builder() -> SearcherBuilder {
+        SearcherBuilder::new()
+    }
+
+    /// Returns the underlying index
+    pub fn index(&self) -> &IndexReader {
+        &self.index
+    }
+
+    /// Returns the paths of the files that may contain a match for
+    /// `pattern`, as selected by the trigram index and the path filter
+    pub fn candidates(&self, pattern: &str) -> Result<Vec<PathBuf>, SearchError> {
+        let query = if self.ignore_case {
+            query_from_regexp(&format!("(?i){}", pattern))
+        } else {
+            query_from_regexp(pattern)
+        };
+        let query = query.map_err(SearchError::Regex)?;
+        Ok(self
+            .index
+            .query(query)?
+            .into_inner()
+            .into_iter()
+            .map(|file_id| self.index.name(file_id))
+            .filter(|name| self.path_filter.as_ref().is_none_or(|f| f.is_match(name)))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// Returns every line of the indexed files that matches `pattern`.
+    ///
+    /// Files are read lazily as the iterator advances, and gzipped files
+    /// are decompressed. Files that can't be read are skipped.
+    pub fn search(&self, pattern: &str) -> Result<Matches, SearchError> {
+        let started = Instant::now();
+        let matcher = bytes::RegexBuilder::new(pattern)
+            .case_insensitive(self.ignore_case)
+            .build()
+            .map_err(|e| SearchError::Regex(e.to_string()))?;
+        let candidates = self.candidates(pattern)?;
+        Ok(Matches {
+            matcher,
+            stats: SearchStats {
+                candidates: candidates.len(),
+                ..SearchStats::default()
+            },
+            started,
+            opener: ContentsOpener::new(&candidates),
+            candidates: candidates.into_iter(),
+            current: None,
+            current_matched: false,
+            line: Vec::new(),
+        })
+    }
+}
+
+/// The file being scanned: its path, contents and last line number read
+type CurrentFile = (PathBuf, BufReader<Box<dyn Read + Send>>, usize);
+
+/// Iterator over the matches of a search, returned by `Searcher::search`
+pub struct Matches {
+    matcher: bytes::Regex,
+    /// Counts so far; `elapsed` is only filled in by `stats`
+    stats: SearchStats,
+    started: Instant,
+    /// Opens the candidates, reading each archive among them once
+    opener: ContentsOpener,
+    candidates: vec::IntoIter<PathBuf>,
+    current: Option<CurrentFile>,
+    /// Whether a line of the current file has matched yet
+    current_matched: bool,
+    line: Vec<u8>,
+}
+
+impl Matches {
+    /// Returns the numbers of files and matches found so far, which are
+    /// the totals of the search once the iterator is exhausted
+    pub fn stats(&self) -> SearchStats {
+        SearchStats {
+            elapsed: self.started.elapsed(),
+            ..self.stats
+        }
+    }
+}
+
+impl Iterator for Matches {
+    type Item = Match;
+    fn next(&mut self) -> Option<Match> {
+        loop {
+            if self.current.is_none() {
+                let path = self.candidates.next()?;
+                if let Ok(f) = self.opener.open(&path) {
+                    self.stats.files_scanned += 1;
+                    self.current = Some((path, BufReader::new(f), 0));
+                    self.current_matched = false;
+                }
+                continue;
+            }
+            let done = {
+                let (ref path, ref mut reader, ref mut line_number) =
+                    *self.current.as_mut().unwrap();
+                self.line.clear();
+                match reader.read_until(b'\n', &mut self.line) {
+                    Ok(0) | Err(_) => true,
+                    Ok(_) => {
+                        *line_number += 1;
+                        let mut text = &self.line[..];
+                        if text.last() == Some(&b'\n') {
+                            text = &text[..text.len() - 1];
+                        }
+                        if text.last() == Some(&b'\r') {
+                            text = &text[..text.len() - 1];
+                        }
+                        if self.matcher.is_match(text) {
+                            self.stats.total_matches += 1;
+                            if !self.current_matched {
+                                self.current_matched = true;
+                                self.stats.files_matched += 1;
+                            }
+                            return Some(Match {
+                                path: path.clone(),
+                                line_number: *line_number,
+                                line: String::from_utf8_lossy(text).into_owned(),
+                            });
+                        }
+                        false
+                    }
+                }
+            };
+            if done {
+                self.current = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Whether to color output, decided the same way for search results and
+//! log records.
+//!
+//! A binary sets the choice from its command line with `set_when`, then
+//! asks `enabled` before coloring a stream. Under `ColorWhen::Auto`, a
+//! stream is colored only if it's a terminal and `NO_COLOR` isn't set to a
+//! non-empty value (see <https://no-color.org>); both are checked once.
+
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// When to color output, from `--color` and `--no-color`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorWhen {
+    /// Only on a terminal, unless `NO_COLOR` is set
+    Auto,
+    Always,
+    Never,
+}
+
+/// An output stream that may be colored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+static WHEN: AtomicU8 = AtomicU8::new(0);
+
+/// Sets when `enabled` colors output. Defaults to `ColorWhen::Auto`.
+pub fn set_when(when: ColorWhen) {
+    let v = match when {
+        ColorWhen::Auto => 0,
+        ColorWhen::Always => 1,
+        ColorWhen::Never => 2,
+    };
+    WHEN.store(v, Ordering::Relaxed);
+}
+
+/// Returns the choice made with `set_when`
+pub fn when() -> ColorWhen {
+    match WHEN.load(Ordering::Relaxed) {
+        1 => ColorWhen::Always,
+        2 => ColorWhen::Never,
+        _ => ColorWhen::Auto,
+    }
+}
+
+/// Returns whether output to `stream` should be colored
+pub fn enabled(stream: Stream) -> bool {
+    static NO_COLOR: OnceLock<bool> = OnceLock::new();
+    static STDOUT_TTY: OnceLock<bool> = OnceLock::new();
+    static STDERR_TTY: OnceLock<bool> = OnceLock::new();
+    let is_terminal = match stream {
+        Stream::Stdout => *STDOUT_TTY.get_or_init(|| io::stdout().is_terminal()),
+        Stream::Stderr => *STDERR_TTY.get_or_init(|| io::stderr().is_terminal()),
+    };
+    let no_color = *NO_COLOR.get_or_init(|| env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()));
+    decide(when(), is_terminal, no_color)
+}
+
+/// Applies a `ColorWhen` to a stream that `is_terminal`, where `no_color`
+/// says whether `NO_COLOR` is set. An explicit `Always` or `Never` wins.
+pub fn decide(when: ColorWhen, is_terminal: bool, no_color: bool) -> bool {
+    match when {
+        ColorWhen::Always => true,
+        ColorWhen::Never => false,
+        ColorWhen::Auto => is_terminal && !no_color,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        assert!(decide(ColorWhen::Auto, true, false));
+        assert!(!decide(ColorWhen::Auto, true, true));
+        assert!(!decide(ColorWhen::Auto, false, false));
+        assert!(decide(ColorWhen::Always, false, true));
+        assert!(!decide(ColorWhen::Never, true, false));
+    }
+}
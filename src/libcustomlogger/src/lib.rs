@@ -1,30 +1,413 @@
 /// custom logger
 extern crate chrono;
 extern crate log;
+#[macro_use]
+extern crate serde_json;
 
-use chrono::Local;
-use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+pub mod color;
 
-static LOGGER: Logger = Logger;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
 
-pub struct Logger;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, UTC};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// The line `set_status` keeps at the bottom of a terminal, empty if none
+static STATUS: Mutex<String> = Mutex::new(String::new());
+
+/// Erases the line the cursor is on
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// How each log record is written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// `2024/01/01 12:00:00 message`
+    Plain,
+    /// One JSON object per line with `timestamp` (RFC 3339), `level`,
+    /// `target` and `message` fields
+    Json,
+}
+
+/// How the time of each record is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeConfig {
+    /// Write the time in UTC rather than in the local time zone
+    pub utc: bool,
+    /// chrono `strftime` format of the time in `Format::Plain` records,
+    /// e.g. `%+` for RFC 3339. `Format::Json` timestamps are always
+    /// RFC 3339.
+    pub format: String,
+}
+
+impl Default for TimeConfig {
+    /// Local time, as `2024/01/01 12:00:00`
+    fn default() -> TimeConfig {
+        TimeConfig {
+            utc: false,
+            format: "%Y/%m/%d %H:%M:%S".to_string(),
+        }
+    }
+}
+
+/// Error returned by `init_with_spec` and `init_with_time`
+#[derive(Debug)]
+pub enum InitError {
+    /// An entry of the filter spec couldn't be parsed
+    BadSpec(String),
+    /// The time format has an unknown or incomplete specifier
+    BadTimeFormat(String),
+    /// A logger was already installed
+    SetLogger(SetLoggerError),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InitError::BadSpec(ref entry) => write!(f, "invalid log filter `{}`", entry),
+            InitError::BadTimeFormat(ref format) => write!(f, "invalid time format `{}`", format),
+            InitError::SetLogger(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for InitError {}
+
+impl From<SetLoggerError> for InitError {
+    fn from(e: SetLoggerError) -> InitError {
+        InitError::SetLogger(e)
+    }
+}
+
+pub struct Logger {
+    format: Format,
+    time: TimeConfig,
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// Whether the writer is stderr, so warnings and errors may be colored
+    /// as `color::enabled` says
+    to_stderr: bool,
+    /// Level for targets not matched by `targets`
+    level: LevelFilter,
+    /// Per-target levels, longest prefix first
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Logger {
+    fn new(level: LevelFilter, format: Format, writer: Box<dyn Write + Send>) -> Logger {
+        Logger {
+            format,
+            time: TimeConfig::default(),
+            writer: Mutex::new(writer),
+            to_stderr: false,
+            level,
+            targets: Vec::new(),
+        }
+    }
+    fn to_stderr(level: LevelFilter, format: Format) -> Logger {
+        let mut logger = Logger::new(level, format, Box::new(io::stderr()));
+        logger.to_stderr = true;
+        logger
+    }
+    /// Returns the level that applies to records from `target`
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map_or(self.level, |&(_, level)| level)
+    }
+    fn format_record(&self, now: &DateTime<Local>, record: &Record) -> String {
+        match self.format {
+            Format::Plain => {
+                let format = &self.time.format[..];
+                let time = if self.time.utc {
+                    now.with_timezone(&UTC).format(format).to_string()
+                } else {
+                    now.format(format).to_string()
+                };
+                let color = match record.level() {
+                    Level::Error => "31",
+                    Level::Warn => "33",
+                    _ => "",
+                };
+                if !color.is_empty() && self.to_stderr && color::enabled(color::Stream::Stderr) {
+                    format!("{} \x1b[{}m{}\x1b[0m", time, color, record.args())
+                } else {
+                    format!("{} {}", time, record.args())
+                }
+            }
+            Format::Json => json!({
+                "timestamp": if self.time.utc {
+                    now.with_timezone(&UTC).to_rfc3339()
+                } else {
+                    now.to_rfc3339()
+                },
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        }
+    }
+}
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        metadata.level() <= self.level_for(metadata.target())
     }
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let now = Local::now();
-            let now_time = now.format("%Y/%m/%d %H:%M:%S");
-            println!("{} {}", now_time, record.args());
+            let line = self.format_record(&Local::now(), record);
+            // taken first, as `set_status` does, so a record can't be
+            // written in the middle of a status line
+            let status = STATUS.lock();
+            let status = match status {
+                Ok(ref s) if self.to_stderr => s.as_str(),
+                _ => "",
+            };
+            if let Ok(mut w) = self.writer.lock() {
+                // there's nowhere left to report a failure to log
+                let _ = write_record(&mut *w, &line, status);
+            }
+        }
+    }
+    fn flush(&self) {
+        if let Ok(mut w) = self.writer.lock() {
+            let _ = w.flush();
+        }
+    }
+}
+
+/// Writes `line`, first erasing the `status` line if there is one and
+/// then drawing it again below
+fn write_record<W: Write + ?Sized>(w: &mut W, line: &str, status: &str) -> io::Result<()> {
+    if status.is_empty() {
+        return writeln!(w, "{}", line);
+    }
+    write!(w, "{}{}\n{}", CLEAR_LINE, line, status)?;
+    w.flush()
+}
+
+/// Shows `line` as a status line on stderr, such as a progress count.
+///
+/// On a terminal it replaces the last status line in place, and records
+/// logged to stderr are written above it rather than through it.
+/// Otherwise it's written as a line of its own.
+pub fn set_status(line: &str) {
+    let mut status = match STATUS.lock() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let stderr = io::stderr();
+    let mut w = stderr.lock();
+    if stderr.is_terminal() {
+        let _ = write!(w, "{}{}", CLEAR_LINE, line);
+        *status = line.to_string();
+    } else {
+        let _ = writeln!(w, "{}", line);
+    }
+}
+
+/// Leaves the line shown by `set_status` on screen, and moves on to a
+/// new line for whatever is written next
+pub fn end_status() {
+    if let Ok(mut status) = STATUS.lock() {
+        if !status.is_empty() {
+            let _ = writeln!(io::stderr());
+            status.clear();
         }
     }
-    fn flush(&self) {}
 }
 
+/// Installs the logger, writing records at or above `level` to stderr
 pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER)?;
-    log::set_max_level(level);
+    init_with_format(level, Format::Plain)
+}
+
+/// Installs the logger, writing records at or above `level` to stderr in
+/// the given `format`
+pub fn init_with_format(level: LevelFilter, format: Format) -> Result<(), SetLoggerError> {
+    install(Logger::to_stderr(level, format))
+}
+
+/// Installs the logger, writing to stderr in the given `format`.
+///
+/// `spec` is a comma separated list of `target=level` entries, e.g.
+/// `libcindex=debug,walkdir=warn`. A record whose target starts with one
+/// of the listed targets is filtered at that level (the longest matching
+/// target wins); every other record is filtered at `level`.
+pub fn init_with_spec(level: LevelFilter, format: Format, spec: &str) -> Result<(), InitError> {
+    let mut logger = Logger::to_stderr(level, format);
+    logger.targets = parse_spec(spec)?;
+    Ok(install(logger)?)
+}
+
+/// Installs the logger, writing records at or above `level` to stderr
+/// with their time written as `time` says
+pub fn init_with_time(level: LevelFilter, time: TimeConfig) -> Result<(), InitError> {
+    check_time_format(&time.format)?;
+    let mut logger = Logger::to_stderr(level, Format::Plain);
+    logger.time = time;
+    Ok(install(logger)?)
+}
+
+/// Checks that every specifier of the `strftime` format `format` is one
+/// chrono knows, since a bad one only shows when a record is formatted
+pub fn check_time_format(format: &str) -> Result<(), InitError> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(InitError::BadTimeFormat(format.to_string()));
+    }
+    Ok(())
+}
+
+/// Parses a `target=level,...` filter spec, returning the entries sorted
+/// longest target first
+pub fn parse_spec(spec: &str) -> Result<Vec<(String, LevelFilter)>, InitError> {
+    let mut targets = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            match (parts.next(), parts.next().map(str::parse::<LevelFilter>)) {
+                (Some(target), Some(Ok(level))) if !target.is_empty() => {
+                    Ok((target.to_string(), level))
+                }
+                _ => Err(InitError::BadSpec(entry.to_string())),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    targets.sort_by_key(|t| std::cmp::Reverse(t.0.len()));
+    Ok(targets)
+}
+
+/// Installs the logger, writing records at or above `level` to `writer`
+pub fn init_with_writer(
+    level: LevelFilter,
+    writer: Box<dyn Write + Send>,
+) -> Result<(), SetLoggerError> {
+    install(Logger::new(level, Format::Plain, writer))
+}
+
+fn install(logger: Logger) -> Result<(), SetLoggerError> {
+    // the global max level has to let through the most verbose target
+    let max_level = logger
+        .targets
+        .iter()
+        .map(|&(_, level)| level)
+        .fold(logger.level, std::cmp::max);
+    log::set_logger(Box::leak(Box::new(logger)))?;
+    log::set_max_level(max_level);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_init_with_writer() {
+        let buf = SharedBuf::default();
+        init_with_writer(LevelFilter::Info, Box::new(buf.clone())).unwrap();
+        log::info!("hello {}", 42);
+        log::debug!("not shown");
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.ends_with(" hello 42\n"), "{:?}", out);
+    }
+
+    #[test]
+    fn test_write_record_keeps_status() {
+        let mut out = Vec::new();
+        write_record(&mut out, "plain", "").unwrap();
+        write_record(&mut out, "record", "10 files").unwrap();
+        assert_eq!(out, b"plain\n\r\x1b[Krecord\n10 files");
+    }
+
+    #[test]
+    fn test_json_format() {
+        let logger = Logger::new(LevelFilter::Info, Format::Json, Box::new(io::sink()));
+        let args = format_args!("say \"hi\"\n\tbye");
+        let record = Record::builder()
+            .args(args)
+            .level(log::Level::Warn)
+            .target("cindex")
+            .build();
+        let line = logger.format_record(&Local::now(), &record);
+        assert!(!line.contains('\n'));
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["level"], "WARN");
+        assert_eq!(v["target"], "cindex");
+        assert_eq!(v["message"], "say \"hi\"\n\tbye");
+        let ts = v["timestamp"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(ts).is_ok(), "{}", ts);
+    }
+
+    #[test]
+    fn test_time_config() {
+        let mut logger = Logger::new(LevelFilter::Info, Format::Plain, Box::new(io::sink()));
+        logger.time = TimeConfig {
+            utc: true,
+            format: "%+".to_string(),
+        };
+        let record = Record::builder()
+            .args(format_args!("hello"))
+            .level(log::Level::Info)
+            .build();
+        let line = logger.format_record(&Local::now(), &record);
+        let (ts, message) = line.split_once(' ').unwrap();
+        assert_eq!(message, "hello");
+        assert!(DateTime::parse_from_rfc3339(ts).is_ok(), "{}", ts);
+        assert!(ts.ends_with("+00:00"), "{}", ts);
+
+        logger.format = Format::Json;
+        let line = logger.format_record(&Local::now(), &record);
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(
+            v["timestamp"].as_str().unwrap().ends_with("+00:00"),
+            "{}",
+            line
+        );
+
+        assert!(check_time_format(&TimeConfig::default().format).is_ok());
+        assert!(check_time_format("%Y-%m-%dT%H:%M:%S%.3fZ").is_ok());
+        assert!(check_time_format("%Q").is_err());
+        assert!(check_time_format("%Y %").is_err());
+    }
+
+    #[test]
+    fn test_spec_filters() {
+        let mut logger = Logger::new(LevelFilter::Info, Format::Plain, Box::new(io::sink()));
+        logger.targets = parse_spec("libcindex=debug, libcindex::writer=warn,walkdir=off").unwrap();
+        let enabled = |level, target| {
+            let m = Metadata::builder().level(level).target(target).build();
+            logger.enabled(&m)
+        };
+        assert!(enabled(log::Level::Debug, "libcindex::merge"));
+        assert!(!enabled(log::Level::Trace, "libcindex::merge"));
+        assert!(!enabled(log::Level::Info, "libcindex::writer::write"));
+        assert!(enabled(log::Level::Warn, "libcindex::writer::write"));
+        assert!(!enabled(log::Level::Error, "walkdir"));
+        assert!(enabled(log::Level::Info, "cindex"));
+        assert!(!enabled(log::Level::Debug, "cindex"));
+
+        assert!(parse_spec("").unwrap().is_empty());
+        assert!(parse_spec("libcindex").is_err());
+        assert!(parse_spec("libcindex=loud").is_err());
+        assert!(parse_spec("=debug").is_err());
+    }
+}
@@ -1,7 +1,24 @@
+use std::time::Duration;
+
+/// Time spent in one profiled frame, as returned by `profiling_report`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameTiming {
+    /// Name of the frame, after the names of the frames it's nested in,
+    /// separated by `/`
+    pub name: String,
+    /// Number of times the frame was entered
+    pub calls: u32,
+    /// Total time spent in the frame
+    pub total: Duration,
+}
+
 #[cfg(feature = "profile")]
 #[macro_use]
 mod profiling {
     extern crate hprof;
+    use super::FrameTiming;
+    use std::time::Duration;
+
     #[allow(dead_code)]
     pub fn profile(name: &'static str) -> self::hprof::ProfileGuard<'static> {
         self::hprof::enter(name)
@@ -10,16 +27,50 @@ mod profiling {
     pub fn print_profiling() {
         self::hprof::profiler().print_timing();
     }
+    /// Returns the timings of the frames profiled on this thread, each
+    /// frame before the frames nested in it
+    #[allow(dead_code)]
+    pub fn profiling_report() -> Vec<FrameTiming> {
+        fn visit(node: &self::hprof::ProfileNode, prefix: &str, report: &mut Vec<FrameTiming>) {
+            for child in &*node.children.borrow() {
+                let name = if prefix.is_empty() {
+                    child.name.to_string()
+                } else {
+                    format!("{}/{}", prefix, child.name)
+                };
+                report.push(FrameTiming {
+                    name: name.clone(),
+                    calls: child.calls.get(),
+                    total: Duration::from_nanos(child.total_time.get()),
+                });
+                visit(child, &name, report);
+            }
+        }
+        let mut report = Vec::new();
+        visit(&self::hprof::profiler().root(), "", &mut report);
+        report
+    }
 }
 #[cfg(not(feature = "profile"))]
 #[macro_use]
 mod profiling {
+    use super::FrameTiming;
+
+    /// Stand-in for hprof's guard so call sites can hold a frame either way
+    pub struct ProfileGuard;
     #[allow(dead_code)]
-    pub fn profile(_: &'static str) {}
+    pub fn profile(_: &'static str) -> ProfileGuard {
+        ProfileGuard
+    }
     #[allow(dead_code)]
     pub fn print_profiling() {
         // no-op
     }
+    /// Nothing is profiled without the `profile` feature
+    #[allow(dead_code)]
+    pub fn profiling_report() -> Vec<FrameTiming> {
+        Vec::new()
+    }
 }
 
 pub use self::profiling::*;
@@ -0,0 +1,49 @@
+#[macro_use]
+extern crate criterion;
+extern crate libvarint;
+
+use criterion::{black_box, Criterion};
+
+/// A posting list of `n` deltas, mostly small with the odd large gap
+fn posting_list(n: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    for i in 1..=n {
+        let delta = if i.is_multiple_of(50) {
+            1000 + i
+        } else {
+            1 + i % 7
+        };
+        libvarint::write_uvarint(&mut out, delta).unwrap();
+    }
+    out.push(0);
+    out
+}
+
+fn decode(c: &mut Criterion) {
+    let input = posting_list(100_000);
+    c.bench_function("read_uvarint", |b| {
+        b.iter(|| {
+            let mut d = black_box(&input[..]);
+            let mut out = Vec::with_capacity(100_000);
+            loop {
+                let (x, n) = libvarint::read_uvarint(d).unwrap();
+                if x == 0 {
+                    break;
+                }
+                out.push(x as u32);
+                d = &d[n as usize..];
+            }
+            out
+        })
+    });
+    c.bench_function("decode_batch", |b| {
+        b.iter(|| {
+            let mut out = Vec::with_capacity(100_000);
+            libvarint::decode_batch(black_box(&input), &mut out);
+            out
+        })
+    });
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
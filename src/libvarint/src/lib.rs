@@ -9,6 +9,10 @@ pub fn read_uvarint(b: &[u8]) -> Result<(u64, u64), u64> {
     let mut x: u64 = 0;
     let mut s: usize = 0;
     for (i, b) in b.iter().enumerate() {
+        if i == 10 {
+            // overflow
+            return Err((i + 1) as u64);
+        }
         if *b < 0x80 {
             if i > 9 || i == 9 && *b > 1 {
                 return Err((i + 1) as u64);
@@ -33,3 +37,212 @@ pub fn write_uvarint<W: Write>(writer: &mut W, x: u32) -> io::Result<usize> {
     writer.write_all(&[(x & 0xff) as u8])?;
     Ok(bytes_written + 1)
 }
+
+/// Appends the zigzag varint encoding of `value` to `out`, so values of
+/// small magnitude take few bytes whatever their sign.
+///
+/// ```
+/// let mut out = Vec::new();
+/// libvarint::encode_signed(-1, &mut out);
+/// assert_eq!(out, vec![1]);
+/// ```
+pub fn encode_signed(value: i64, out: &mut Vec<u8>) {
+    let mut x = ((value << 1) ^ (value >> 63)) as u64;
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+/// Decodes a zigzag varint written by `encode_signed` from the start of
+/// `input`. Returns the value and the number of bytes read, or `(0, 0)` if
+/// `input` doesn't start with a valid varint.
+pub fn decode_signed(input: &[u8]) -> (i64, usize) {
+    match read_uvarint(input) {
+        Ok((x, n)) => (((x >> 1) as i64) ^ -((x & 1) as i64), n as usize),
+        Err(_) => (0, 0),
+    }
+}
+
+/// Decodes a run of uvarints from `input` into `out`.
+///
+/// Decoding stops after a zero value, which isn't pushed to `out`, at the end
+/// of `input`, or before a varint that is truncated or doesn't fit in a u32.
+/// A posting list (a run of nonzero deltas terminated by a zero) is decoded
+/// in a single call. Returns the number of bytes consumed, including the
+/// terminating zero.
+///
+/// ```
+/// let mut out = Vec::new();
+/// assert_eq!(libvarint::decode_batch(&[1, 0x80, 0x01, 0, 7], &mut out), 4);
+/// assert_eq!(out, vec![1, 128]);
+/// ```
+pub fn decode_batch(input: &[u8], out: &mut Vec<u32>) -> usize {
+    let mut pos = 0;
+    loop {
+        pos += decode_single_bytes(&input[pos..], out);
+        match read_uvarint(&input[pos..]) {
+            Ok((0, n)) => return pos + n as usize,
+            Ok((x, n)) if x <= u32::MAX as u64 => {
+                out.push(x as u32);
+                pos += n as usize;
+            }
+            _ => return pos,
+        }
+    }
+}
+
+/// Decodes the leading run of nonzero one byte varints in `input`,
+/// 16 bytes at a time. Returns the number of bytes consumed.
+#[cfg(target_arch = "x86_64")]
+fn decode_single_bytes(input: &[u8], out: &mut Vec<u32>) -> usize {
+    use std::arch::x86_64::{
+        __m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_setzero_si128,
+    };
+
+    let mut pos = 0;
+    while input.len() - pos >= 16 {
+        // SSE2 is always available on x86_64, and the load is unaligned
+        // and within bounds.
+        let stop = unsafe {
+            let chunk = _mm_loadu_si128(input.as_ptr().add(pos) as *const __m128i);
+            let zero = _mm_cmpeq_epi8(chunk, _mm_setzero_si128());
+            // bytes with the continuation bit set, or zero
+            (_mm_movemask_epi8(chunk) | _mm_movemask_epi8(zero)) as u32
+        };
+        let n = if stop == 0 {
+            16
+        } else {
+            stop.trailing_zeros() as usize
+        };
+        out.extend(input[pos..pos + n].iter().map(|&b| b as u32));
+        pos += n;
+        if n < 16 {
+            break;
+        }
+    }
+    pos
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn decode_single_bytes(_input: &[u8], _out: &mut Vec<u32>) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes `input` one varint at a time, the way `decode_batch` should
+    fn decode_one_by_one(input: &[u8], out: &mut Vec<u32>) -> usize {
+        let mut pos = 0;
+        loop {
+            match read_uvarint(&input[pos..]) {
+                Ok((0, n)) => return pos + n as usize,
+                Ok((x, n)) if x <= u32::MAX as u64 => {
+                    out.push(x as u32);
+                    pos += n as usize;
+                }
+                _ => return pos,
+            }
+        }
+    }
+
+    /// xorshift64, so failures are reproducible
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn check(input: &[u8]) {
+        let (mut want, mut have) = (Vec::new(), Vec::new());
+        let want_n = decode_one_by_one(input, &mut want);
+        let have_n = decode_batch(input, &mut have);
+        assert_eq!((have_n, &have), (want_n, &want), "input {:?}", input);
+    }
+
+    #[test]
+    fn test_signed_round_trip() {
+        let values = [
+            i64::MIN,
+            i64::MIN + 1,
+            -300,
+            -64,
+            -1,
+            0,
+            1,
+            63,
+            64,
+            i64::MAX - 1,
+            i64::MAX,
+        ];
+        for &v in &values {
+            let mut out = vec![];
+            encode_signed(v, &mut out);
+            assert_eq!(decode_signed(&out), (v, out.len()), "value {}", v);
+        }
+        let sizes = [(0, 1), (-1, 1), (63, 1), (-64, 1), (64, 2), (-65, 2)];
+        for &(v, len) in &sizes {
+            let mut out = vec![];
+            encode_signed(v, &mut out);
+            assert_eq!(out.len(), len, "value {}", v);
+        }
+        let mut out = vec![];
+        encode_signed(i64::MIN, &mut out);
+        assert_eq!(out.len(), 10);
+        assert_eq!(decode_signed(&out[..9]), (0, 0));
+    }
+
+    #[test]
+    fn test_decode_batch() {
+        let mut values = Vec::new();
+        let mut input = Vec::new();
+        for x in (1..40).chain(vec![127, 128, 300, 1 << 20, u32::MAX]) {
+            write_uvarint(&mut input, x).unwrap();
+            values.push(x);
+        }
+        input.push(0);
+        input.extend_from_slice(&[5, 6, 7]);
+        let mut out = Vec::new();
+        assert_eq!(decode_batch(&input, &mut out), input.len() - 3);
+        assert_eq!(out, values);
+
+        // truncated
+        let mut out = Vec::new();
+        assert_eq!(decode_batch(&[3, 0x80], &mut out), 1);
+        assert_eq!(out, vec![3]);
+        // too large for a u32
+        let mut out = Vec::new();
+        assert_eq!(decode_batch(&[0xff, 0xff, 0xff, 0xff, 0x7f], &mut out), 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_decode_batch_fuzz() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for round in 0..20000 {
+            let len = (next_random(&mut state) % 100) as usize;
+            let mut input = Vec::with_capacity(len);
+            for _ in 0..len {
+                let r = next_random(&mut state);
+                // mostly small values, like posting list deltas
+                let b = match round % 3 {
+                    0 => r as u8,
+                    1 => (r % 0x80) as u8 | 1,
+                    _ => {
+                        if r.is_multiple_of(8) {
+                            (r >> 8) as u8 | 0x80
+                        } else {
+                            (r % 0x7f) as u8 + 1
+                        }
+                    }
+                };
+                input.push(b);
+            }
+            check(&input);
+        }
+    }
+}
@@ -0,0 +1,1904 @@
+extern crate flate2;
+extern crate libc;
+extern crate serde_json;
+extern crate tar;
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use self::flate2::write::GzEncoder;
+use self::flate2::Compression;
+use self::tempfile::TempDir;
+
+use libcsearch::reader::IndexReader;
+
+use common::{cindex, csearch, indexed_names, TestDirs};
+
+#[cfg(unix)]
+#[test]
+fn test_no_follow_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    File::create(root.join("hello.txt"))
+        .unwrap()
+        .write_all(b"hello world\n")
+        .unwrap();
+    symlink(root, root.join("loop")).unwrap();
+    symlink(root.join("hello.txt"), root.join("link.txt")).unwrap();
+
+    let status = cindex(index)
+        .arg("--no-follow-simlinks")
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let names = indexed_names(index);
+    assert_eq!(names, vec![root.join("hello.txt").to_str().unwrap()]);
+}
+
+#[test]
+fn test_incremental_drops_deleted_files() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("keep.txt"), "kept around\n").unwrap();
+    fs::write(root.join("gone.txt"), "deleted soon\n").unwrap();
+
+    assert!(cindex(index).arg(root).status().unwrap().success());
+
+    fs::remove_file(root.join("gone.txt")).unwrap();
+    fs::write(root.join("new.txt"), "brand new\n").unwrap();
+    assert!(cindex(index)
+        .arg("--incremental")
+        .arg(root)
+        .status()
+        .unwrap()
+        .success());
+
+    let mut names = indexed_names(index);
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            root.join("keep.txt").to_str().unwrap(),
+            root.join("new.txt").to_str().unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn test_no_index_path() {
+    let out = Command::new(env!("CARGO_BIN_EXE_cindex"))
+        .env_remove("CSEARCHINDEX")
+        .env_remove("HOME")
+        .env_remove("USERPROFILE")
+        .arg("--list")
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(101));
+    assert!(out.stdout.is_empty());
+    let msg = String::from_utf8_lossy(&out.stderr);
+    assert!(msg.contains("CSEARCHINDEX, HOME, USERPROFILE"), "{}", msg);
+}
+
+#[test]
+fn test_open_errors() {
+    let out = TempDir::new().unwrap();
+    let index = out.path().join("index");
+    let list = |index: &Path| {
+        let out = cindex(index).arg("--list").output().unwrap();
+        assert_eq!(out.status.code(), Some(101));
+        String::from_utf8(out.stderr).unwrap()
+    };
+    let msg = list(&index);
+    assert!(msg.contains("no index yet"), "{}", msg);
+
+    fs::write(&index, "not an index at all, but long enough\n").unwrap();
+    let msg = list(&index);
+    assert!(msg.contains("bad magic"), "{}", msg);
+    assert!(msg.contains("cindex --reset"), "{}", msg);
+}
+
+#[test]
+fn test_gitignore() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let files = [
+        (".gitignore", "target/\n*.log\n"),
+        ("src/main.rs", "fn main() {}\n"),
+        ("target/debug/build.rs", "generated\n"),
+        ("debug.log", "noise\n"),
+        ("sub/.gitignore", "!keep.log\nlocal.txt\n"),
+        ("sub/keep.log", "wanted\n"),
+        ("sub/local.txt", "ignored here\n"),
+        ("sub/target/out.txt", "generated\n"),
+        ("other/local.txt", "not ignored here\n"),
+    ];
+    for &(name, contents) in &files {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, contents).unwrap();
+    }
+
+    let status = cindex(index).arg("--gitignore").arg(root).status().unwrap();
+    assert!(status.success());
+
+    let mut names = indexed_names(index);
+    names.sort();
+    // the .gitignore files themselves are hidden
+    let expected = ["other/local.txt", "src/main.rs", "sub/keep.log"];
+    let expected = expected
+        .iter()
+        .map(|n| root.join(n).to_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn test_stats() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "abcd\n").unwrap();
+    fs::write(root.join("b.txt"), "abce\n").unwrap();
+
+    assert!(cindex(index).arg(root).status().unwrap().success());
+
+    let out = cindex(index).arg("--stats").output().unwrap();
+    assert!(out.status.success());
+    let stats = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|l| {
+            let mut kv = l.splitn(2, ':');
+            let key = kv.next().unwrap().to_string();
+            (key, kv.next().unwrap().trim().to_string())
+        })
+        .collect::<Vec<_>>();
+    let get = |k: &str| stats.iter().find(|s| s.0 == k).unwrap().1.clone();
+    assert_eq!(get("paths"), "1");
+    assert_eq!(get("files"), "2");
+    // abc, bcd, bce, plus the two trigrams ending in a newline
+    assert_eq!(get("trigrams"), "5");
+    let size = fs::metadata(index).unwrap().len();
+    assert!(get("size").contains(&size.to_string()));
+    assert_eq!(get("version"), "2");
+}
+
+#[test]
+fn test_progress() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "abcd\n").unwrap();
+    fs::write(root.join("b.txt"), "abce\n").unwrap();
+    fs::write(root.join("c.bin"), "\0binary\n").unwrap();
+
+    let out = cindex(index).arg("--progress").arg(root).output().unwrap();
+    assert!(out.status.success());
+    // stderr isn't a terminal, so each count is a line of its own
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let last = stderr
+        .lines()
+        .rfind(|l| l.contains("files indexed"))
+        .unwrap();
+    assert!(
+        last.starts_with("2 files indexed, 1 skipped, ") && last.ends_with(" files/s"),
+        "{}",
+        last
+    );
+
+    let out = cindex(index).arg(root).output().unwrap();
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("files indexed"));
+}
+
+#[test]
+fn test_trigram_histogram() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "abcd\n").unwrap();
+    fs::write(root.join("b.txt"), "abce\n").unwrap();
+    fs::write(root.join("c.txt"), "abc\tx\n").unwrap();
+
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    let histogram = |args: &[&str]| {
+        let out = cindex(index)
+            .arg("--trigram-histogram")
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| l.split_whitespace().map(String::from).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(histogram(&["1"]), vec![vec!["3", "abc"]]);
+    // abc, then the others in byte order, non-printables escaped
+    let all = histogram(&[]);
+    assert_eq!(all.len(), 8);
+    assert_eq!(all[0], vec!["3", "abc"]);
+    assert_eq!(all[1], vec!["1", "\\tx\\n"]);
+    assert_eq!(all[2], vec!["1", "bc\\t"]);
+}
+
+#[test]
+fn test_verify() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "abcd\n").unwrap();
+
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    let verified = cindex(index).arg("--verify").output().unwrap();
+    assert!(verified.status.success());
+    assert_eq!(String::from_utf8_lossy(&verified.stdout), "ok\n");
+
+    let mut data = fs::read(index).unwrap();
+    let at = data.len() / 2;
+    data[at] ^= 0x10;
+    fs::write(index, &data).unwrap();
+    let verified = cindex(index).arg("--verify").output().unwrap();
+    assert!(!verified.status.success());
+    assert_eq!(String::from_utf8_lossy(&verified.stdout), "");
+    assert!(String::from_utf8_lossy(&verified.stderr).contains("corrupt index"));
+}
+
+#[test]
+fn test_remove_path() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for &(name, contents) in &[
+        ("keep/a.txt", "shared words\n"),
+        ("drop/b.txt", "shared words\nonly in dropped\n"),
+        ("dropped/c.txt", "similar prefix\n"),
+    ] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, contents).unwrap();
+    }
+
+    let status = cindex(index)
+        .arg(root.join("keep"))
+        .arg(root.join("drop"))
+        .arg(root.join("dropped"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    fs::remove_dir_all(root.join("drop")).unwrap();
+    let status = cindex(index)
+        .arg("--remove")
+        .arg(root.join("drop"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut names = indexed_names(index);
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            root.join("dropped/c.txt").to_str().unwrap(),
+            root.join("keep/a.txt").to_str().unwrap(),
+        ]
+    );
+    let out = cindex(index).arg("--list").output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!(
+            "{}\n{}\n",
+            root.join("dropped").display(),
+            root.join("keep").display()
+        )
+    );
+    let out = csearch(index).arg("only in dropped").output().unwrap();
+    assert!(out.stdout.is_empty());
+    let out = csearch(index).arg("-l").arg("shared").output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!("{}\n", root.join("keep/a.txt").display())
+    );
+}
+
+#[test]
+fn test_filelist_stdin() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &["a", "b", "c", "d"] {
+        fs::create_dir(root.join(name)).unwrap();
+        fs::write(root.join(name).join("f.txt"), "hello\n").unwrap();
+    }
+
+    let mut child = cindex(index)
+        .arg("--filelist")
+        .arg("-")
+        .arg(root.join("d"))
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        for name in &["a", "b", "c"] {
+            writeln!(stdin, "{}", root.join(name).display()).unwrap();
+            writeln!(stdin).unwrap();
+        }
+    }
+    assert!(child.wait().unwrap().success());
+
+    let ix = libcsearch::reader::IndexReader::open(index).unwrap();
+    let want = ["a", "b", "c", "d"]
+        .iter()
+        .map(|n| root.join(n).to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(ix.indexed_paths(), want);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_filelist0() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let odd = root.join("two\nlines.txt");
+    fs::write(&odd, "hello\n").unwrap();
+    fs::write(root.join("plain.txt"), "hello\n").unwrap();
+
+    let list = dirs.out().join("list");
+    let mut contents = Vec::new();
+    for each_path in &[&odd, &root.join("plain.txt")] {
+        contents.extend_from_slice(each_path.to_str().unwrap().as_bytes());
+        contents.push(0);
+    }
+    fs::write(&list, contents).unwrap();
+    let status = cindex(index)
+        .arg("--filelist0")
+        .arg(&list)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert_eq!(
+        indexed_names(index),
+        vec![
+            root.join("plain.txt").to_str().unwrap(),
+            odd.to_str().unwrap()
+        ]
+    );
+}
+
+#[test]
+fn test_list0() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let dirs = ["a", "b\nc"];
+    for name in &dirs {
+        fs::create_dir(root.join(name)).unwrap();
+        fs::write(root.join(name).join("f.txt"), "hello\n").unwrap();
+    }
+
+    let status = cindex(index)
+        .arg(root.join(dirs[0]))
+        .arg(root.join(dirs[1]))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let want = dirs
+        .iter()
+        .map(|n| format!("{}\0", root.join(n).display()))
+        .collect::<String>();
+    let out = cindex(index).arg("--list0").output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), want);
+
+    let out = cindex(index).arg("--list").output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        want.replace('\0', "\n")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_index_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::CommandExt;
+
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    let run = || {
+        let mut c = cindex(index);
+        unsafe {
+            c.pre_exec(|| {
+                libc::umask(0o022);
+                Ok(())
+            });
+        }
+        assert!(c.arg(root).status().unwrap().success());
+        fs::metadata(index).unwrap().permissions().mode() & 0o777
+    };
+
+    // a new index follows the umask
+    assert_eq!(run(), 0o644);
+    // an updated one keeps the permissions it had
+    fs::set_permissions(index, fs::Permissions::from_mode(0o640)).unwrap();
+    fs::write(root.join("b.txt"), "world\n").unwrap();
+    assert_eq!(run(), 0o640);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_reset_symlinked_index() {
+    use std::os::unix::fs::symlink;
+
+    let dirs = TestDirs::new();
+    let root = &dirs.root;
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+
+    let target = dirs.out().join("real-index");
+    let link = dirs.out().join("index");
+    assert!(cindex(&target).arg(root).status().unwrap().success());
+    symlink(&target, &link).unwrap();
+
+    assert!(cindex(&link).arg("--reset").status().unwrap().success());
+    assert!(fs::symlink_metadata(&link).is_err());
+    assert!(target.exists());
+
+    // a directory in place of the index is left alone
+    fs::create_dir(&link).unwrap();
+    let status = cindex(&link).arg("--reset").status().unwrap();
+    assert!(status.success());
+    assert!(link.is_dir());
+}
+
+#[test]
+fn test_threads() {
+    let dirs = TestDirs::new();
+    let root = &dirs.root;
+    for i in 0..50 {
+        let dir = root.join(format!("d{}", i % 5));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(format!("f{}.txt", i)),
+            format!("file number {}\n", i),
+        )
+        .unwrap();
+    }
+
+    let serial = dirs.out().join("serial");
+    let parallel = dirs.out().join("parallel");
+    let status = cindex(&serial)
+        .args(["-j", "1"])
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = cindex(&parallel)
+        .args(["--threads", "8"])
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert_eq!(indexed_names(&serial).len(), 50);
+    assert_eq!(indexed_names(&parallel), indexed_names(&serial));
+    assert_eq!(fs::read(&parallel).unwrap(), fs::read(&serial).unwrap());
+}
+
+#[test]
+fn test_bad_numbers() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+    let index = out.path().join("index");
+    for (flag, value, msg) in [
+        ("--threads", "0", "must be at least 1"),
+        ("--threads", "many", "invalid digit"),
+        ("--max-depth", "deep", "can't convert value 'deep'"),
+        ("--retries", "x", "can't convert value 'x'"),
+        ("--max-index-bytes", "1MB", "can't convert value '1MB'"),
+    ] {
+        let output = cindex(&index)
+            .args([flag, value])
+            .arg(src.path())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(101), "{} {}", flag, value);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(msg), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        assert!(!index.exists());
+    }
+}
+
+#[test]
+fn test_dry_run() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    fs::write(root.join("b.txt"), "world\n").unwrap();
+    fs::write(root.join("long.txt"), format!("{}\n", "x".repeat(100))).unwrap();
+    fs::write(root.join("big.txt"), "y\n".repeat(1000)).unwrap();
+
+    let out = cindex(index)
+        .args(["--dry-run", "--maxLineLen", "50", "--maxFileLen", "1000"])
+        .arg(root)
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let report = String::from_utf8_lossy(&out.stdout);
+    let lines = report.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            "would index: 2",
+            "would skip: 2",
+            "  file too long: 1",
+            "  line too long: 1",
+        ]
+    );
+    assert!(!index.exists());
+    assert!(fs::read_dir(index.parent().unwrap())
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[test]
+fn test_index_config() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::create_dir_all(root.join("generated")).unwrap();
+    fs::write(root.join("small.txt"), "hello\n").unwrap();
+    fs::write(root.join("big.txt"), "y\n".repeat(1000)).unwrap();
+    fs::write(root.join("generated/big.txt"), "z\n".repeat(1000)).unwrap();
+    fs::write(root.join("generated/huge.txt"), "w\n".repeat(5000)).unwrap();
+
+    let config = dirs.out().join("limits.toml");
+    fs::write(
+        &config,
+        "[\"*/generated/*\"]\nmax_file_len = 4000\n\n[\"*/generated/huge.txt\"]\nmax_file_len = 20000\n",
+    )
+    .unwrap();
+    let status = cindex(index)
+        .args(["--maxFileLen", "1000"])
+        .arg("--index-config")
+        .arg(&config)
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let root = root.to_str().unwrap();
+    assert_eq!(
+        indexed_names(index),
+        vec![
+            format!("{}/generated/big.txt", root),
+            format!("{}/generated/huge.txt", root),
+            format!("{}/small.txt", root),
+        ]
+    );
+
+    let config = dirs.out().join("limits.json");
+    fs::write(&config, r#"{"*.txt": {"max_file_len": 10, "bogus": 1}}"#).unwrap();
+    let status = cindex(dirs.out().join("index2"))
+        .arg("--index-config")
+        .arg(&config)
+        .arg(root)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn test_gzip() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let gzip = |name: &str, text: &str| {
+        let mut e = GzEncoder::new(
+            File::create(root.join(name)).unwrap(),
+            Compression::default(),
+        );
+        e.write_all(text.as_bytes()).unwrap();
+        e.finish().unwrap();
+    };
+    gzip("app.log.gz", "started\nneedle in a haystack\n");
+    // compresses well below the limit, but is too long once decompressed
+    gzip("big.log.gz", &"y\n".repeat(1000));
+    fs::write(root.join("broken.gz"), "not gzip data\n").unwrap();
+
+    let output = cindex(index)
+        .args(["--logskip", "--maxFileLen", "1000"])
+        .arg(root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("broken.gz\": skipped. can't decompress file"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("big.log.gz\": skipped. file too long"),
+        "{}",
+        stderr
+    );
+    let app = root.join("app.log.gz").to_string_lossy().into_owned();
+    assert_eq!(indexed_names(index), vec![app.clone()]);
+
+    let output = csearch(index).args(["-n", "needle"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}:2:needle in a haystack\n", app)
+    );
+}
+
+#[test]
+fn test_list_json() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &["a/1.txt", "a/2.txt", "ab/3.txt"] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+
+    let status = cindex(index)
+        .arg(root.join("a"))
+        .arg(root.join("ab"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let out = cindex(index).arg("--list-json").output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!(
+            "[{{\"path\":\"{0}/a\",\"files\":2}},{{\"path\":\"{0}/ab\",\"files\":1}}]\n",
+            root.display()
+        )
+    );
+}
+
+#[test]
+fn test_list_write_errors() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for i in 0..3000 {
+        let dir = root.join(format!("dir{}", i));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("f.txt"), "hello\n").unwrap();
+    }
+    let paths = (0..3000)
+        .map(|i| root.join(format!("dir{}", i)))
+        .collect::<Vec<_>>();
+    assert!(cindex(index).args(&paths).status().unwrap().success());
+
+    for flag in ["--list", "--list0", "--list-json"] {
+        if Path::new("/dev/full").exists() {
+            let full = fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/full")
+                .unwrap();
+            let out = cindex(index).arg(flag).stdout(full).output().unwrap();
+            assert_eq!(out.status.code(), Some(101), "{}", flag);
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            assert!(stderr.contains("write stdout"), "{}", stderr);
+        }
+
+        // a reader that stops reading, as with `| head`, isn't an error
+        let mut child = cindex(index)
+            .arg(flag)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        drop(child.stdout.take());
+        let out = child.wait_with_output().unwrap();
+        assert_eq!(out.status.code(), Some(0), "{}", flag);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.is_empty(), "{}: {}", flag, stderr);
+    }
+}
+
+#[test]
+fn test_profile_json() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+
+    let profile = dirs.out().join("profile.json");
+    let status = cindex(index)
+        .arg("--profile-json")
+        .arg(&profile)
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&profile).unwrap()).unwrap();
+    let frames = report.as_array().unwrap();
+    if cfg!(feature = "profile") {
+        assert!(frames
+            .iter()
+            .any(|f| f["thread"] == "index" && f["name"] == "Index files" && f["calls"] == 1));
+    } else {
+        assert!(frames.is_empty());
+    }
+}
+
+#[test]
+fn test_binary_file_skipped() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.out"), b"\x7fELF\x02\x01\x01\x00 text\n").unwrap();
+    fs::write(root.join("a.txt"), "text\n").unwrap();
+
+    let output = cindex(index).arg("--logskip").arg(root).output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("a.out\": skipped. NUL byte at start of file"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        indexed_names(index),
+        vec![root.join("a.txt").to_string_lossy().into_owned()]
+    );
+}
+
+#[test]
+fn test_empty_file_skipped() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("__init__.py"), "").unwrap();
+    fs::write(root.join("blank.txt"), " \n\t\n\n").unwrap();
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+
+    let output = cindex(index).arg("--logskip").arg(root).output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("__init__.py\": skipped. empty file"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("skipped: 2 empty"), "{}", stderr);
+    let a = root.join("a.txt").to_string_lossy().into_owned();
+    assert_eq!(indexed_names(index), vec![a.clone()]);
+    // "hel", "ell", "llo" and "lo\n"
+    let ix = libcsearch::reader::IndexReader::open(index).unwrap();
+    assert_eq!(ix.num_trigrams(), 4);
+
+    let output = csearch(index).args(["-L", "hello"]).output().unwrap();
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_skip_summary() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.out"), b"\x7fELF\x00\n").unwrap();
+    fs::write(root.join("big1.txt"), "some long text\n").unwrap();
+    fs::write(root.join("big2.txt"), "more long text\n").unwrap();
+    fs::write(root.join("a.txt"), "text\n").unwrap();
+
+    let output = cindex(index)
+        .args(["--maxFileLen", "10"])
+        .arg(root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("skipped: 2 too large, 1 binary"),
+        "{}",
+        stderr
+    );
+    // the files themselves are only listed with --logskip
+    assert!(!stderr.contains("big1.txt"), "{}", stderr);
+    assert_eq!(
+        indexed_names(index),
+        vec![root.join("a.txt").to_string_lossy().into_owned()]
+    );
+}
+
+#[test]
+fn test_since() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("old.txt"), "old text\n").unwrap();
+    fs::write(root.join("new.txt"), "new text\n").unwrap();
+    let set_mtime = |name: &str, secs: u64| {
+        File::options()
+            .write(true)
+            .open(root.join(name))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    set_mtime("old.txt", 1_000_000_000);
+    set_mtime("new.txt", 1_500_000_000);
+
+    let status = cindex(index)
+        .args(["--since", "2010-01-01T00:00:00+01:00"])
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(relative_names(index, root), vec!["new.txt"]);
+
+    // topping up an index keeps the files that weren't modified
+    assert!(cindex(index).arg("--reset").status().unwrap().success());
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    fs::write(root.join("new.txt"), "newer text\n").unwrap();
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        - 60;
+    let status = cindex(index)
+        .arg("--since")
+        .arg(format!("@{}", since))
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(relative_names(index, root), vec!["new.txt", "old.txt"]);
+    let listed = cindex(index).arg("--list").output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&listed.stdout),
+        format!("{}\n", root.display())
+    );
+
+    let out = cindex(index)
+        .args(["--since", "yesterday"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+}
+
+#[test]
+fn test_extension_filters() {
+    let dirs = TestDirs::new();
+    let root = &dirs.root;
+    let files = [
+        "main.rs",
+        "lib.RS",
+        "tool.go",
+        "Cargo.lock",
+        "app.min.js",
+        "app.js",
+        "script.py",
+        "README",
+    ];
+    for name in &files {
+        fs::write(root.join(name), "hello\n").unwrap();
+    }
+    let names = |args: &[&str]| {
+        let out = TempDir::new().unwrap();
+        let index = out.path().join("index");
+        let status = cindex(&index).args(args).arg(root).status().unwrap();
+        assert!(status.success());
+        let mut names = indexed_names(&index)
+            .into_iter()
+            .map(|n| n[root.to_str().unwrap().len() + 1..].to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        names(&["--include-ext", "rs,go,.py"]),
+        vec!["lib.RS", "main.rs", "script.py", "tool.go"]
+    );
+    assert_eq!(
+        names(&["--exclude-ext", "LOCK,min.js"]),
+        vec![
+            "README",
+            "app.js",
+            "lib.RS",
+            "main.rs",
+            "script.py",
+            "tool.go"
+        ]
+    );
+    // exclude wins over include
+    assert_eq!(
+        names(&["--include-ext", "js,rs", "--exclude-ext", "min.js,rs"]),
+        vec!["app.js"]
+    );
+}
+
+#[test]
+fn test_exclude_patterns() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &[
+        "app.js",
+        "app.min.js",
+        "lib/vendor.min.js",
+        "node_modules/dep/index.js",
+        "gen/out.js",
+        "src/gen/keep.js",
+    ] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+    let exclude = dirs.out().join("exclude");
+    // two basename globs, and a full-path glob that only excludes the
+    // top-level gen directory
+    let patterns = format!("*.min.js\nnode_modules\n{}/gen\n", root.display());
+    fs::write(&exclude, patterns).unwrap();
+    let status = cindex(index)
+        .arg("--exclude")
+        .arg(&exclude)
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        relative_names(index, root),
+        vec!["app.js", "src/gen/keep.js"]
+    );
+}
+
+#[test]
+fn test_exclude_multiple() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &["a.txt", "b.log", "c.tmp", "d.bak", "e.rs"] {
+        fs::write(root.join(name), "hello\n").unwrap();
+    }
+    let (logs, temps) = (dirs.out().join("logs"), dirs.out().join("temps"));
+    fs::write(&logs, "*.log\n").unwrap();
+    fs::write(&temps, "*.tmp\n").unwrap();
+    let status = cindex(index)
+        .arg("--exclude")
+        .arg(&logs)
+        .arg("--exclude-pattern")
+        .arg("*.bak")
+        .arg("--exclude")
+        .arg(&temps)
+        .args(["--exclude-pattern", "a.*"])
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(relative_names(index, root), vec!["e.rs"]);
+
+    let output = cindex(index)
+        .args(["--exclude-pattern", "[a"])
+        .arg(root)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(101));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--exclude-pattern [a"));
+}
+
+#[test]
+fn test_exclude_dir() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &[
+        "app.js",
+        "target",
+        "web/node_modules/dep/index.js",
+        "web/node_modules/dep/node_modules/inner.js",
+        "web/src/main.js",
+        ".git/config",
+        "target_dir/keep.rs",
+    ] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+    let status = cindex(index)
+        .args(["--exclude-dir", "node_modules, .git,target"])
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // only directories are skipped, and only by their whole name
+    assert_eq!(
+        relative_names(index, root),
+        vec!["app.js", "target", "target_dir/keep.rs", "web/src/main.js"]
+    );
+}
+
+#[test]
+fn test_reproducible() {
+    let names = [
+        "b.txt",
+        "a/z.txt",
+        "a/b/c.txt",
+        "a.txt",
+        "c/d.txt",
+        "a/a.txt",
+    ];
+    let contents = |name: &str| format!("contents of {}\nshared line\n", name);
+    let (src1, src2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let root1 = fs::canonicalize(src1.path()).unwrap();
+    let root2 = fs::canonicalize(src2.path()).unwrap();
+    // the same tree, created in opposite orders
+    for name in &names {
+        let p = root1.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, contents(name)).unwrap();
+    }
+    for name in names.iter().rev() {
+        let p = root2.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, contents(name)).unwrap();
+    }
+
+    let out = TempDir::new().unwrap();
+    let build = |index: &str, root: &Path| {
+        let index = out.path().join(index);
+        let status = cindex(&index)
+            .arg("--reproducible")
+            .arg("--relative-to")
+            .arg(root)
+            .arg(root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        fs::read(index).unwrap()
+    };
+    let first = build("first", &root1);
+    assert_eq!(first, build("second", &root1));
+    assert_eq!(first, build("other", &root2));
+}
+
+#[test]
+fn test_relative_to() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &["build/repo/a.txt", "build/repo/sub/b.txt"] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+    let built = root.join("build/repo");
+    let sorted_names = |index: &Path| {
+        let mut names = indexed_names(index);
+        names.sort();
+        names
+    };
+    let status = cindex(index)
+        .arg("--relative-to")
+        .arg(&built)
+        .arg(&built)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let ix = IndexReader::open(index).unwrap();
+    assert!(ix.is_relative());
+    assert_eq!(ix.indexed_paths(), vec!["."]);
+    assert_eq!(sorted_names(index), vec!["a.txt", "sub/b.txt"]);
+
+    // the files are found wherever the tree was copied to
+    let copy = root.join("home/repo");
+    fs::create_dir_all(copy.join("sub")).unwrap();
+    fs::write(copy.join("a.txt"), "hello\n").unwrap();
+    fs::write(copy.join("sub/b.txt"), "hello\n").unwrap();
+    fs::remove_dir_all(&built).unwrap();
+    let found = csearch(index)
+        .arg("--prefix")
+        .arg(&copy)
+        .args(["-l", "hello"])
+        .output()
+        .unwrap();
+    assert!(found.status.success());
+    let mut found = String::from_utf8_lossy(&found.stdout)
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(
+        found,
+        vec![
+            copy.join("a.txt").display().to_string(),
+            copy.join("sub/b.txt").display().to_string(),
+        ]
+    );
+
+    // and reindexed there too
+    fs::write(copy.join("c.txt"), "hello\n").unwrap();
+    let status = cindex(index)
+        .arg("--relative-to")
+        .arg(&copy)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(sorted_names(index), vec!["a.txt", "c.txt", "sub/b.txt"]);
+
+    // an absolute index can't take relative names
+    let absolute = dirs.out().join("absolute");
+    assert!(cindex(&absolute).arg(&copy).status().unwrap().success());
+    let status = cindex(&absolute)
+        .arg("--relative-to")
+        .arg(&copy)
+        .arg(&copy)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn test_relative_to_incremental() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    fs::write(root.join("b.txt"), "apple\n").unwrap();
+    let relative_cindex = || {
+        let mut c = cindex(index);
+        c.arg("--relative-to").arg(root);
+        c
+    };
+    assert!(relative_cindex().arg(root).status().unwrap().success());
+
+    // same size and mtime: the old contents are carried forward
+    let mtime = fs::metadata(root.join("b.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    fs::write(root.join("b.txt"), "berry\n").unwrap();
+    File::options()
+        .write(true)
+        .open(root.join("b.txt"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let status = relative_cindex().arg("--incremental").status().unwrap();
+    assert!(status.success());
+    let found = csearch(index)
+        .arg("--prefix")
+        .arg(root)
+        .args(["--files", "apple"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&found.stdout),
+        format!("{}\n", root.join("b.txt").display())
+    );
+}
+
+#[test]
+fn test_relative_to_remove() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &["keep/a.txt", "drop/b.txt"] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+    let relative_cindex = || {
+        let mut c = cindex(index);
+        c.arg("--relative-to").arg(root);
+        c
+    };
+    assert!(relative_cindex().arg(root).status().unwrap().success());
+
+    let status = relative_cindex()
+        .arg("--remove")
+        .arg(root.join("drop"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(indexed_names(index), vec!["keep/a.txt"]);
+
+    // the base directory holds every relative name
+    let status = relative_cindex()
+        .arg("--remove")
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(indexed_names(index).is_empty());
+    assert!(IndexReader::open(index).unwrap().indexed_paths().is_empty());
+}
+
+#[test]
+fn test_hidden() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for name in &[
+        "main.rs",
+        ".git/config",
+        ".git/objects/pack/info",
+        "src/.main.rs.swp",
+        "src/lib.rs",
+    ] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+    let status = cindex(index).arg(root).status().unwrap();
+    assert!(status.success());
+    assert_eq!(relative_names(index, root), vec!["main.rs", "src/lib.rs"]);
+
+    let index = dirs.out().join("hidden");
+    let status = cindex(&index).arg("--hidden").arg(root).status().unwrap();
+    assert!(status.success());
+    assert_eq!(
+        relative_names(&index, root),
+        vec![
+            ".git/config",
+            ".git/objects/pack/info",
+            "main.rs",
+            "src/.main.rs.swp",
+            "src/lib.rs"
+        ]
+    );
+
+    // a hidden directory named on the command line is still indexed
+    let index = dirs.out().join("git");
+    let status = cindex(&index).arg(root.join(".git")).status().unwrap();
+    assert!(status.success());
+    assert_eq!(
+        relative_names(&index, root),
+        vec![".git/config", ".git/objects/pack/info"]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("keep.txt"), "hello\n").unwrap();
+    fs::write(root.join("gone.txt"), "goodbye\n").unwrap();
+    fs::create_dir(root.join("sub")).unwrap();
+    fs::write(root.join("sub/old.txt"), "old\n").unwrap();
+
+    let exclude = dirs.out().join("exclude");
+    fs::write(&exclude, "*.tmp\n").unwrap();
+    let mut child = cindex(index)
+        .arg("--watch")
+        .arg("--exclude")
+        .arg(&exclude)
+        .arg(root)
+        .spawn()
+        .unwrap();
+    // waits until the index holds exactly `want`, relative to `root`
+    let wait_for = |want: &[&str]| {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let mut names = Vec::new();
+        while Instant::now() < deadline {
+            names = match libcsearch::reader::IndexReader::open(index) {
+                Ok(_) => relative_names(index, root),
+                Err(_) => Vec::new(),
+            };
+            if names == want {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("index has {:?}, want {:?}", names, want);
+    };
+    wait_for(&["gone.txt", "keep.txt", "sub/old.txt"]);
+
+    fs::write(root.join("new.txt"), "fresh\n").unwrap();
+    fs::write(root.join("skip.tmp"), "excluded\n").unwrap();
+    fs::remove_file(root.join("gone.txt")).unwrap();
+    wait_for(&["keep.txt", "new.txt", "sub/old.txt"]);
+
+    fs::write(root.join("keep.txt"), "changed\n").unwrap();
+    fs::rename(root.join("sub"), root.join("moved")).unwrap();
+    wait_for(&["keep.txt", "moved/old.txt", "new.txt"]);
+    let output = csearch(index).arg("changed").output().unwrap();
+    let keep = root.join("keep.txt");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}:changed\n", keep.display())
+    );
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_max_index_bytes() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    for n in 0..100 {
+        let contents = format!("file number {} with its own words {}\n", n, n * 7919);
+        fs::write(root.join(format!("{:03}.txt", n)), contents).unwrap();
+    }
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    let full_len = fs::metadata(index).unwrap().len();
+    let full = fs::read(index).unwrap();
+
+    // an existing index is left alone
+    let max = (full_len / 2).to_string();
+    let output = cindex(index)
+        .args(["--max-index-bytes", &max])
+        .arg(root)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(101));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--max-index-bytes"));
+    assert_eq!(fs::read(index).unwrap(), full);
+    assert!(temp_indexes(dirs.out()).is_empty());
+
+    // a new one has what fits
+    let limited = dirs.out().join("limited");
+    let output = cindex(&limited)
+        .args(["--max-index-bytes", &max])
+        .arg(root)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(101));
+    assert!(fs::metadata(&limited).unwrap().len() <= full_len / 2);
+    let names = indexed_names(&limited);
+    assert!(!names.is_empty() && names.len() < 100, "{}", names.len());
+    IndexReader::open(&limited).unwrap().verify().unwrap();
+}
+
+#[test]
+fn test_quiet() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    for _ in 0..2 {
+        // the second run merges into the first index
+        let output = cindex(index).arg("-q").arg(root).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    }
+
+    // errors are still printed
+    let output = cindex(dirs.out().join("index/nested"))
+        .arg("--quiet")
+        .arg(root)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!output.stderr.is_empty());
+
+    let output = cindex(index)
+        .args(["--quiet", "--verbose"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_interrupt() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("old.txt"), "old\n").unwrap();
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    let before = fs::read(index).unwrap();
+
+    for n in 0..5000 {
+        let dir = root.join(format!("d{}", n % 50));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{}.txt", n)), format!("file {}\n", n)).unwrap();
+    }
+    let mut child = cindex(index)
+        .arg(root)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the partial index is created once the interrupt handler is set
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while temp_indexes(dirs.out()).is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(1));
+    }
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    let status = child.wait().unwrap();
+    // indexing may have finished before the interrupt
+    assert!(status.success() || status.code() == Some(130), "{}", status);
+    if !status.success() {
+        assert_eq!(fs::read(index).unwrap(), before);
+    }
+    assert!(temp_indexes(dirs.out()).is_empty());
+    IndexReader::open(index).unwrap().verify().unwrap();
+}
+
+#[test]
+fn test_lock() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+
+    let lock = File::create(dirs.out().join("index.lock")).unwrap();
+    lock.lock().unwrap();
+    let output = cindex(index).arg(root).output().unwrap();
+    assert_eq!(output.status.code(), Some(101));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("another cindex"));
+    assert!(!index.exists());
+    assert!(temp_indexes(dirs.out()).is_empty());
+
+    drop(lock);
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    assert_eq!(relative_names(index, root), vec!["a.txt"]);
+    assert!(temp_indexes(dirs.out()).is_empty());
+}
+
+/// Returns the names of the temporary indexes cindex left in `dir` for
+/// the index `dir/index`
+fn temp_indexes(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|n| n.starts_with("index.") && n != "index.lock")
+        .collect()
+}
+
+/// Returns the sorted names in the index at `index`, relative to `root`
+fn relative_names(index: &Path, root: &Path) -> Vec<String> {
+    let mut names = indexed_names(index)
+        .into_iter()
+        .map(|n| n[root.to_str().unwrap().len() + 1..].to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_tar() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let build_tar = |w: &mut dyn Write| {
+        let mut b = tar::Builder::new(w);
+        let mut add = |name: &str, contents: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            b.append_data(&mut header, name, contents).unwrap();
+        };
+        add("./src/main.rs", b"fn main() { needle(); }\n");
+        add("src/lib.min.js", b"needle\n");
+        add("blob.bin", b"needle\0\0\0");
+        let mut dir = tar::Header::new_gnu();
+        dir.set_entry_type(tar::EntryType::Directory);
+        dir.set_size(0);
+        dir.set_cksum();
+        b.append_data(&mut dir, "empty/", &b""[..]).unwrap();
+        b.finish().unwrap();
+    };
+    let plain = root.join("code.tar");
+    build_tar(&mut File::create(&plain).unwrap());
+    let zipped = root.join("code.tgz");
+    let mut gz = GzEncoder::new(File::create(&zipped).unwrap(), Compression::default());
+    build_tar(&mut gz);
+    gz.finish().unwrap();
+
+    let status = cindex(index)
+        .args(["--exclude-ext", "min.js"])
+        .arg("--tar")
+        .arg(&plain)
+        .arg("--tar")
+        .arg(&zipped)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut want = vec![
+        format!("{}!src/main.rs", plain.display()),
+        format!("{}!src/main.rs", zipped.display()),
+    ];
+    want.sort();
+    let names = |index: &Path| {
+        let mut names = indexed_names(index);
+        names.sort();
+        names
+    };
+    assert_eq!(names(index), want);
+
+    let output = csearch(index).arg("needle").output().unwrap();
+    assert!(output.status.success());
+    let mut lines = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    lines.sort();
+    let want_lines = want
+        .iter()
+        .map(|n| format!("{}:fn main() {{ needle(); }}", n))
+        .collect::<Vec<_>>();
+    assert_eq!(lines, want_lines);
+
+    let out = cindex(index).arg("--list-json").output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!(
+            "[{{\"path\":\"{}\",\"files\":1}},{{\"path\":\"{}\",\"files\":1}}]\n",
+            plain.display(),
+            zipped.display()
+        )
+    );
+
+    // reindexing reads the archives again
+    let status = cindex(index)
+        .args(["--exclude-ext", "min.js"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(names(index), want);
+}
+
+#[test]
+fn test_dedup() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let header = "#define NEEDLE 1\n";
+    for dir in &["a", "b", "c"] {
+        fs::create_dir(root.join(dir)).unwrap();
+    }
+    fs::write(root.join("a/config.h"), header).unwrap();
+    fs::write(root.join("b/config.h"), header).unwrap();
+    fs::write(root.join("c/config.h"), "#define NEEDLE 2\n").unwrap();
+
+    let search = |index: &Path| {
+        let output = csearch(index).arg("NEEDLE 1").output().unwrap();
+        assert!(output.status.success());
+        let mut lines = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines
+    };
+    let want = vec![
+        format!(
+            "{}:{}",
+            root.join("a/config.h").display(),
+            header.trim_end()
+        ),
+        format!(
+            "{}:{}",
+            root.join("b/config.h").display(),
+            header.trim_end()
+        ),
+    ];
+    let stored_once = |index: &Path| {
+        let ix = libcsearch::reader::IndexReader::open(index).unwrap();
+        let aliases = (0..ix.num_name as u32)
+            .filter(|&i| ix.alias_of(i).is_some())
+            .count();
+        assert_eq!(aliases, 1);
+        assert_eq!(ix.posting_list(*b"E 1").unwrap().len(), 1);
+        assert_eq!(ix.posting_list(*b"E 2").unwrap().len(), 1);
+    };
+
+    let status = cindex(index).arg("--dedup").arg(root).status().unwrap();
+    assert!(status.success());
+    stored_once(index);
+    assert_eq!(search(index), want);
+
+    // merging into the existing index keeps the aliases
+    let status = cindex(index).arg("--dedup").status().unwrap();
+    assert!(status.success());
+    stored_once(index);
+    assert_eq!(search(index), want);
+
+    // without --dedup every file has its own postings
+    assert!(cindex(index).arg("--reset").status().unwrap().success());
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    let ix = libcsearch::reader::IndexReader::open(index).unwrap();
+    assert_eq!(ix.posting_list(*b"E 1").unwrap().len(), 2);
+    assert_eq!(search(index), want);
+}
+
+#[test]
+fn test_utf16() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    let utf16le = |text: &str| {
+        let mut data = vec![0xff, 0xfe];
+        data.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        data
+    };
+    fs::write(
+        root.join("le.txt"),
+        utf16le("first line\r\nneedle \u{1f600} here\r\n"),
+    )
+    .unwrap();
+    let mut be = vec![0xfe, 0xff];
+    be.extend("be needle\n".encode_utf16().flat_map(u16::to_be_bytes));
+    fs::write(root.join("be.txt"), be).unwrap();
+    // ends with the first half of a surrogate pair
+    let mut truncated = utf16le("needle \u{1f600}");
+    truncated.truncate(truncated.len() - 2);
+    fs::write(root.join("truncated.txt"), truncated).unwrap();
+
+    let output = cindex(index).arg("--logskip").arg(root).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(relative_names(index, root), vec!["be.txt", "le.txt"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("truncated UTF-16"), "{}", stderr);
+
+    let output = csearch(index).arg("needle .* here").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{}:needle \u{1f600} here\r\n",
+            root.join("le.txt").display()
+        )
+    );
+    let output = csearch(index).arg("be needle").output().unwrap();
+    assert!(output.status.success());
+
+    // the limit applies to the transcoded text, which is half the size
+    let index = dirs.out().join("limited");
+    fs::remove_file(root.join("truncated.txt")).unwrap();
+    fs::remove_file(root.join("be.txt")).unwrap();
+    let len = fs::metadata(root.join("le.txt")).unwrap().len();
+    let status = cindex(&index)
+        .arg("--maxFileLen")
+        .arg((len / 2 + 2).to_string())
+        .arg(root)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(relative_names(&index, root), vec!["le.txt"]);
+}
+
+#[test]
+fn test_merge() {
+    let dirs = TestDirs::new();
+    let root = &dirs.root;
+    let (index1, index2) = (dirs.out().join("index1"), dirs.out().join("index2"));
+    common::index_dir(root.join("one"), &index1, &[("a.txt", "first file\n")]);
+    common::index_dir(root.join("two"), &index2, &[("b.txt", "second file\n")]);
+
+    let dest = dirs.out().join("merged");
+    let status = Command::new(env!("CARGO_BIN_EXE_cindex"))
+        .arg("--merge")
+        .args([&dest, &index1, &index2])
+        .env("CSEARCHINDEX", dirs.out().join("untouched"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(!dirs.out().join("untouched").exists());
+    assert_eq!(
+        indexed_names(&dest),
+        vec![
+            root.join("one/a.txt").to_str().unwrap(),
+            root.join("two/b.txt").to_str().unwrap(),
+        ]
+    );
+
+    let missing = dirs.out().join("missing");
+    let out = Command::new(env!("CARGO_BIN_EXE_cindex"))
+        .arg("--merge")
+        .args([&dest, &index1, &missing])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(&format!("merge {}: index not found", missing.display())));
+}
+
+#[test]
+#[cfg(feature = "fail-points")]
+fn test_failed_publish_keeps_index() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    let before = fs::read(index).unwrap();
+
+    // the new index is complete, but cindex fails just before renaming it
+    fs::write(root.join("b.txt"), "world\n").unwrap();
+    let output = cindex(index)
+        .arg(root)
+        .env("CINDEX_FAIL_AT", "publish")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(101));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is unchanged"));
+    assert_eq!(fs::read(index).unwrap(), before);
+    assert!(temp_indexes(dirs.out()).is_empty());
+
+    assert!(cindex(index).arg(root).status().unwrap().success());
+    assert_eq!(relative_names(index, root), vec!["a.txt", "b.txt"]);
+}
+
+#[test]
+fn test_compress() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "needle in a haystack\n").unwrap();
+    fs::write(root.join("b.txt"), "just hay\n").unwrap();
+
+    let status = cindex(index).arg("--compress").arg(root).status().unwrap();
+    assert!(status.success());
+    assert!(IndexReader::open(index).unwrap().is_compressed());
+    let search = || {
+        let output = csearch(index).arg("-l").arg("needle").output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(search(), format!("{}\n", root.join("a.txt").display()));
+
+    // reindexing without --compress keeps the index compressed
+    fs::write(root.join("c.txt"), "another needle\n").unwrap();
+    let status = cindex(index).status().unwrap();
+    assert!(status.success());
+    let ix = IndexReader::open(index).unwrap();
+    assert!(ix.is_compressed());
+    ix.verify().unwrap();
+    assert_eq!(
+        search(),
+        format!(
+            "{}\n{}\n",
+            root.join("a.txt").display(),
+            root.join("c.txt").display()
+        )
+    );
+}
+
+#[test]
+fn test_stdin_name() {
+    let out = TempDir::new().unwrap();
+    let index = out.path().join("index");
+    let pipe = |index: &Path, contents: &str, extra: &[&str]| {
+        let mut child = cindex(index)
+            .args(extra)
+            .arg("--stdin-name")
+            .arg("build.log")
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        child.wait().unwrap().success()
+    };
+    let candidates = |pattern: &str| {
+        let output = csearch(&index)
+            .arg("--files")
+            .arg(pattern)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(pipe(&index, "error: widget exploded\n", &[]));
+    assert_eq!(indexed_names(&index), vec!["build.log"]);
+    assert_eq!(candidates("exploded"), "build.log\n");
+
+    // piping again under the same name replaces the contents
+    assert!(pipe(&index, "all good\n", &[]));
+    assert_eq!(indexed_names(&index), vec!["build.log"]);
+    assert_eq!(candidates("exploded"), "");
+    assert_eq!(candidates("good"), "build.log\n");
+
+    // the limits apply as they do to files
+    let limited = out.path().join("limited");
+    assert!(pipe(&limited, "a very long line\n", &["--maxLineLen", "4"]));
+    assert!(indexed_names(&limited).is_empty());
+}
+
+#[test]
+fn test_max_depth() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::create_dir_all(root.join("d/e")).unwrap();
+    for name in &["a.txt", "d/b.txt", "d/e/c.txt"] {
+        fs::write(root.join(name), format!("contents of {}\n", name)).unwrap();
+    }
+
+    let indexed = |depth: &str, path: &Path| {
+        let status = cindex(index)
+            .arg("--max-depth")
+            .arg(depth)
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let names = indexed_names(index);
+        fs::remove_file(index).unwrap();
+        names
+            .iter()
+            .map(|n| {
+                Path::new(n)
+                    .strip_prefix(root)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(indexed("0", root), Vec::<String>::new());
+    assert_eq!(indexed("1", root), vec!["a.txt"]);
+    assert_eq!(indexed("2", root), vec!["a.txt", "d/b.txt"]);
+    assert_eq!(indexed("3", root), vec!["a.txt", "d/b.txt", "d/e/c.txt"]);
+    // a file named on the command line is depth 0
+    assert_eq!(indexed("0", &root.join("d/b.txt")), vec!["d/b.txt"]);
+}
+
+#[test]
+fn test_files_read_once() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::create_dir(root.join("d")).unwrap();
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    fs::write(root.join("d/b.txt"), "hello\n").unwrap();
+
+    // d/b.txt is reached from both paths, and a.txt is also listed twice
+    let mut child = cindex(index)
+        .arg("--filelist")
+        .arg("-")
+        .arg(root)
+        .arg(root.join("d"))
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        writeln!(stdin, "{}", root.join("a.txt").display()).unwrap();
+        writeln!(stdin, "{}", root.join("a.txt").display()).unwrap();
+    }
+    assert!(child.wait().unwrap().success());
+
+    let want = ["a.txt", "d/b.txt"]
+        .iter()
+        .map(|n| root.join(n).to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(indexed_names(index), want);
+}
+
+#[test]
+#[cfg(feature = "fail-points")]
+fn test_retries() {
+    let dirs = TestDirs::new();
+    let (root, index) = (&dirs.root, &dirs.index);
+    fs::write(root.join("a.txt"), "hello\n").unwrap();
+    fs::write(root.join("b.txt"), "world\n").unwrap();
+    // try number `failing` of reading each file fails with an I/O error
+    let run = |failing: &str, retries: &str| {
+        let output = cindex(index).args(["--reset"]).output().unwrap();
+        assert!(output.status.success());
+        let output = cindex(index)
+            .args(["--logskip", "--retries", retries])
+            .arg(root)
+            .env("CINDEX_FAIL_AT", format!("read {}", failing))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = run("1", "2");
+    assert_eq!(relative_names(index, root), vec!["a.txt", "b.txt"]);
+    assert!(stderr.contains("a.txt: read after 1 retries"), "{}", stderr);
+
+    // without retries, a file that fails once is left out
+    let stderr = run("1", "0");
+    assert!(relative_names(index, root).is_empty());
+    assert!(!stderr.contains("retries"), "{}", stderr);
+}
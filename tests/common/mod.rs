@@ -7,8 +7,10 @@ extern crate libcsearch;
 use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use self::libcindex::writer::IndexWriter;
+use self::tempfile::TempDir;
 
 pub fn build_index<P: AsRef<Path>>(
     out: P,
@@ -42,3 +44,63 @@ pub fn build_flush_index<P: AsRef<Path>>(
 pub fn tri<T: Into<char>>(x: T, y: T, z: T) -> u32 {
     ((x.into() as u32) << 16) | ((y.into() as u32) << 8) | (z.into() as u32)
 }
+
+/// Returns a `cindex` command that writes to the index at `index`
+pub fn cindex<P: AsRef<Path>>(index: P) -> Command {
+    let mut c = Command::new(env!("CARGO_BIN_EXE_cindex"));
+    c.arg("--indexpath").arg(index.as_ref());
+    c
+}
+
+/// Returns a `csearch` command that reads the index at `index`
+pub fn csearch<P: AsRef<Path>>(index: P) -> Command {
+    let mut c = Command::new(env!("CARGO_BIN_EXE_csearch"));
+    c.arg("--indexpath").arg(index.as_ref());
+    c
+}
+
+/// A directory of files to index and a separate one for the index, both
+/// removed when dropped
+pub struct TestDirs {
+    /// The canonical path of the directory of files
+    pub root: PathBuf,
+    /// The path of an index named `index` in the other directory
+    pub index: PathBuf,
+    _src: TempDir,
+    out: TempDir,
+}
+
+impl TestDirs {
+    pub fn new() -> TestDirs {
+        let src = TempDir::new().unwrap();
+        let out = TempDir::new().unwrap();
+        TestDirs {
+            root: std::fs::canonicalize(src.path()).unwrap(),
+            index: out.path().join("index"),
+            _src: src,
+            out,
+        }
+    }
+
+    /// Returns the directory the index is in
+    pub fn out(&self) -> &Path {
+        self.out.path()
+    }
+}
+
+/// Writes each of `files` under `dir`, then indexes `dir` into `index`
+pub fn index_dir<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, index: Q, files: &[(&str, &str)]) {
+    for &(name, contents) in files {
+        let p = dir.as_ref().join(name);
+        std::fs::create_dir_all(p.parent().unwrap()).unwrap();
+        std::fs::write(p, contents).unwrap();
+    }
+    let status = cindex(index).arg(dir.as_ref()).status().unwrap();
+    assert!(status.success());
+}
+
+/// Returns every file name stored in the index at `index`
+pub fn indexed_names<P: AsRef<Path>>(index: P) -> Vec<String> {
+    let ix = libcsearch::reader::IndexReader::open(index).unwrap();
+    (0..ix.num_name as u32).map(|i| ix.name(i)).collect()
+}
@@ -0,0 +1,1058 @@
+extern crate serde_json;
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use self::serde_json::Value;
+use self::tempfile::TempDir;
+
+use common::{cindex, csearch, index_dir, indexed_names};
+
+struct Fixture {
+    _src: TempDir,
+    _out: TempDir,
+    root: PathBuf,
+    index: PathBuf,
+}
+
+fn fixture(files: &[(&str, &str)]) -> Fixture {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+    let root = fs::canonicalize(src.path()).unwrap();
+    let index = out.path().join("index");
+    index_dir(&root, &index, files);
+    Fixture {
+        _src: src,
+        _out: out,
+        root,
+        index,
+    }
+}
+
+fn sample() -> Fixture {
+    fixture(&[
+        ("a.txt", "first line\nsome: hello world\nlast line\n"),
+        ("b.txt", "nothing to see\n"),
+        ("c.txt", "hello\nhello again\n"),
+    ])
+}
+
+fn json_lines(out: &[u8]) -> Vec<Value> {
+    String::from_utf8_lossy(out)
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+fn stdout_lines(out: &[u8]) -> Vec<String> {
+    let mut lines = String::from_utf8_lossy(out)
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn test_files_with_matches() {
+    let f = sample();
+    let out = csearch(&f.index).arg("-l").arg("hello").output().unwrap();
+    assert!(out.status.success());
+    let expected = vec![f.root.join("a.txt"), f.root.join("c.txt")]
+        .into_iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(stdout_lines(&out.stdout), expected);
+
+    // -l stops at the first match in a file, so it can't count them
+    let out = csearch(&f.index)
+        .args(["-l", "-c", "hello"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}
+
+#[test]
+fn test_files_without_match() {
+    let f = fixture(&[
+        ("good.rs", "// Copyright 2024 Someone\nfn main() {}\n"),
+        ("bad.rs", "fn main() {}\n"),
+        ("notes.txt", "no header here\n"),
+    ]);
+    let out = csearch(&f.index)
+        .args(["-L", "-f", r"\.rs$", "^// Copyright"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let expected = vec![f.root.join("bad.rs").to_string_lossy().into_owned()];
+    assert_eq!(stdout_lines(&out.stdout), expected);
+
+    // every file matches, so none is listed
+    let out = csearch(&f.index)
+        .args(["--files-without-match", "main|header"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn test_indexpath_overrides_env() {
+    let f = sample();
+    let other = fixture(&[("d.txt", "hello from elsewhere\n")]);
+    let out = csearch(&f.index)
+        .env("CSEARCHINDEX", &other.index)
+        .args(["-l", "hello"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout).len(), 2);
+
+    let out = std::process::Command::new(env!("CARGO_BIN_EXE_csearch"))
+        .env("CSEARCHINDEX", &other.index)
+        .args(["-l", "hello"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let expected = vec![other.root.join("d.txt").to_string_lossy().into_owned()];
+    assert_eq!(stdout_lines(&out.stdout), expected);
+}
+
+#[test]
+fn test_explain() {
+    let f = sample();
+    let out = csearch(&f.index)
+        .args(["--explain", "hel+o"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "query: \"hel\"\ncandidates: 2 of 3 files\n"
+    );
+    assert_eq!(stdout_lines(&out.stdout).len(), 3);
+}
+
+#[test]
+fn test_no_trigrams() {
+    let f = sample();
+    let out = csearch(&f.index).args(["-l", "."]).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout).len(), 3);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("searching all 3 files"));
+
+    // --brute asks for every file, so there is nothing to warn about
+    let out = csearch(&f.index)
+        .args(["--brute", "-l", "hello"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout_lines(&out.stdout).len(), 2);
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn test_order() {
+    let f = fixture(&[
+        ("a.txt", "hello, a long line with many words in it\n"),
+        ("b.txt", "hello world\n"),
+        ("c.txt", "hello\n"),
+    ]);
+    let names = |order: &str, pattern: &str| {
+        let out = csearch(&f.index)
+            .args(["-l", "--order", order, pattern])
+            .output()
+            .unwrap();
+        // in the order printed, unlike stdout_lines
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(|l| {
+                Path::new(l)
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("size", "hello"), vec!["c.txt", "b.txt", "a.txt"]);
+    // only b.txt has the trigrams of both words
+    assert_eq!(names("relevance", "hello|world")[0], "b.txt");
+}
+
+#[test]
+fn test_server_stdin() {
+    let f = sample();
+    let mut child = csearch(&f.index)
+        .args(["--server-stdin", "-l"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"again\nbad(\nnothing\r\nzzz\n")
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    // each query's output ends with an empty line, even if there's none
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!(
+            "{}\n\n\n{}\n\n\n",
+            f.root.join("c.txt").display(),
+            f.root.join("b.txt").display()
+        )
+    );
+    assert!(String::from_utf8_lossy(&out.stderr).contains("PATTERN: "));
+}
+
+#[test]
+fn test_count() {
+    let f = fixture(&[
+        ("a.txt", "hello hello\nworld\nhello\n"),
+        ("b.txt", "nothing to see\n"),
+    ]);
+    let a = f.root.join("a.txt");
+    let out = csearch(&f.index).arg("-c").arg("hello").output().unwrap();
+    assert_eq!(
+        stdout_lines(&out.stdout),
+        vec![format!("{}:2", a.display())]
+    );
+    let out = csearch(&f.index)
+        .arg("--count-matches")
+        .arg("hello")
+        .output()
+        .unwrap();
+    assert_eq!(
+        stdout_lines(&out.stdout),
+        vec![format!("{}:3", a.display())]
+    );
+}
+
+#[test]
+fn test_context() {
+    let f = fixture(&[(
+        "a.txt",
+        "one\ntwo\nmatch three\nfour\nfive\nsix\nseven\nmatch eight\nmatch nine\nten\n",
+    )]);
+    let a = f.root.join("a.txt");
+    let out = csearch(&f.index)
+        .args(["-n", "-B", "1", "-A", "1", "match"])
+        .output()
+        .unwrap();
+    let expected = [
+        "-2-two",
+        ":3:match three",
+        "-4-four",
+        "--",
+        "-7-seven",
+        ":8:match eight",
+        ":9:match nine",
+        "-10-ten",
+    ]
+    .iter()
+    .map(|l| match l {
+        &"--" => l.to_string(),
+        _ => format!("{}{}", a.display(), l),
+    })
+    .collect::<Vec<_>>();
+    let got = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    assert_eq!(got, expected);
+
+    // overlapping windows collapse into a single block
+    let out = csearch(&f.index)
+        .args(["-C", "3", "match"])
+        .output()
+        .unwrap();
+    let got = String::from_utf8_lossy(&out.stdout);
+    assert_eq!(got.lines().count(), 10);
+    assert!(!got.contains("\n--\n"));
+}
+
+#[test]
+fn test_threads_output_is_ordered() {
+    let files = (0..40)
+        .map(|i| {
+            (
+                format!("f{:02}.txt", i),
+                format!("line {}\nneedle {}\n", i, i),
+            )
+        })
+        .collect::<Vec<_>>();
+    let files = files
+        .iter()
+        .map(|(n, c)| (n.as_str(), c.as_str()))
+        .collect::<Vec<_>>();
+    let f = fixture(&files);
+    for args in &[vec!["-n"], vec!["-l"], vec!["-c"]] {
+        let run = |threads: &str| {
+            let out = csearch(&f.index)
+                .args(args)
+                .args(["-j", threads, "needle"])
+                .output()
+                .unwrap();
+            assert!(out.status.success());
+            out.stdout
+        };
+        let serial = run("1");
+        assert_eq!(String::from_utf8_lossy(&serial).lines().count(), 40);
+        assert_eq!(run("8"), serial, "{:?}", args);
+    }
+}
+
+#[test]
+fn test_ignore_case() {
+    let f = fixture(&[
+        ("lower.txt", "some foobar here\n"),
+        ("other.txt", "nothing\n"),
+    ]);
+    let out = csearch(&f.index).arg("FOOBAR").output().unwrap();
+    assert!(out.stdout.is_empty());
+    let out = csearch(&f.index).args(["-i", "FOOBAR"]).output().unwrap();
+    let lower = f.root.join("lower.txt");
+    assert_eq!(
+        stdout_lines(&out.stdout),
+        vec![format!("{}:some foobar here", lower.display())]
+    );
+}
+
+#[test]
+fn test_json_output() {
+    let f = sample();
+    let out = csearch(&f.index)
+        .arg("--json")
+        .arg("hello")
+        .output()
+        .unwrap();
+    let mut records = json_lines(&out.stdout);
+    assert_eq!(records.len(), 4);
+
+    let summary = records.pop().unwrap();
+    assert_eq!(summary["summary"]["matches"], 3);
+    assert_eq!(summary["summary"]["files"], 2);
+
+    let a = f.root.join("a.txt");
+    let from_a = records
+        .iter()
+        .filter(|r| r["path"] == a.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(from_a.len(), 1);
+    assert_eq!(from_a[0]["line_number"], 2);
+    assert_eq!(from_a[0]["column"], 7);
+    assert_eq!(from_a[0]["text"], "some: hello world");
+
+    let c = f.root.join("c.txt");
+    let from_c = records
+        .iter()
+        .filter(|r| r["path"] == c.to_str().unwrap())
+        .map(|r| r["line_number"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(from_c, vec![1, 2]);
+}
+
+#[test]
+fn test_json_output_no_matches() {
+    let f = sample();
+    let out = csearch(&f.index)
+        .arg("--json")
+        .arg("zzzzz")
+        .output()
+        .unwrap();
+    let records = json_lines(&out.stdout);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["summary"]["matches"], 0);
+    assert_eq!(records[0]["summary"]["files"], 0);
+}
+
+#[test]
+fn test_path_filter() {
+    let f = fixture(&[
+        ("serviceA/a.txt", "hello from a\n"),
+        ("serviceA/gen/g.txt", "hello from gen\n"),
+        ("serviceB/b.txt", "hello from b\n"),
+    ]);
+    // files outside the filter must not even be opened
+    fs::remove_file(f.root.join("serviceB/b.txt")).unwrap();
+    let out = csearch(&f.index)
+        .args(["-l", "-f", "/serviceA/", "hello"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stderr), "");
+    let expected = vec![
+        f.root.join("serviceA/a.txt"),
+        f.root.join("serviceA/gen/g.txt"),
+    ]
+    .into_iter()
+    .map(|p| p.to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+    assert_eq!(stdout_lines(&out.stdout), expected);
+
+    let out = csearch(&f.index)
+        .args(["-l", "--path-filter", "/serviceA/"])
+        .args(["--path-exclude", "/gen/", "hello"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stderr), "");
+    assert_eq!(
+        stdout_lines(&out.stdout),
+        vec![f.root.join("serviceA/a.txt").to_string_lossy().into_owned()]
+    );
+}
+
+#[test]
+fn test_root() {
+    let src = TempDir::new().unwrap();
+    let root = fs::canonicalize(src.path()).unwrap();
+    for name in &["serviceA/a.txt", "serviceA2/a2.txt", "serviceB/b.txt"] {
+        let p = root.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, "hello\n").unwrap();
+    }
+    let out = TempDir::new().unwrap();
+    let index = out.path().join("index");
+    let status = cindex(&index)
+        .arg(root.join("serviceA"))
+        .arg(root.join("serviceA2"))
+        .arg(root.join("serviceB"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // names are printed relative to the current directory, if under it
+    let names = |out: &[u8]| {
+        stdout_lines(out)
+            .into_iter()
+            .map(|n| {
+                let n = Path::new(&n);
+                n.strip_prefix(&root).unwrap_or(n).display().to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let out = csearch(&index)
+        .arg("--root")
+        .arg(root.join("serviceA"))
+        .args(["-l", "hello"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(names(&out.stdout), vec!["serviceA/a.txt"]);
+
+    // relative to the current directory, and repeated
+    let out = csearch(&index)
+        .current_dir(&root)
+        .args(["--root", "serviceA2/", "--root", "serviceB", "-l", "hello"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        names(&out.stdout),
+        vec!["serviceA2/a2.txt", "serviceB/b.txt"]
+    );
+
+    let out = csearch(&index)
+        .current_dir(&root)
+        .args(["--root", "serviceC", "hello"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("--root serviceC: not an indexed path"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains(root.join("serviceB").to_str().unwrap()));
+}
+
+#[test]
+fn test_color() {
+    let f = fixture(&[("a.txt", "say hello world\nnothing\n")]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let run = |args: &[&str]| {
+        let out = csearch(&f.index).args(args).output().unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap()
+    };
+    let plain = format!("{}:1:say hello world\n", a);
+    // not a terminal, so auto means no color
+    assert_eq!(run(&["-n", "hello"]), plain);
+    assert_eq!(run(&["-n", "--color=auto", "hello"]), plain);
+    assert_eq!(run(&["-n", "--color=never", "hello"]), plain);
+    assert_eq!(run(&["-n", "--color=always", "--nocolor", "hello"]), plain);
+    assert_eq!(run(&["-n", "--color=always", "--no-color", "hello"]), plain);
+    assert_eq!(
+        run(&["-n", "--color=always", "hello"]),
+        format!(
+            "\x1b[0m\x1b[35m{}\x1b[0m:\x1b[0m\x1b[32m1\x1b[0m:say \
+             \x1b[0m\x1b[1m\x1b[31mhello\x1b[0m world\n",
+            a
+        )
+    );
+    // an explicit --color=always wins over NO_COLOR
+    let out = csearch(&f.index)
+        .env("NO_COLOR", "1")
+        .args(["--color=always", "hello"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(out.stdout).unwrap().contains("\x1b[31m"));
+}
+
+#[test]
+fn test_pattern_file() {
+    let f = fixture(&[("a.txt", "C:\\dir\\file\n"), ("b.txt", "C:/dir/file\n")]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let pattern = f._out.path().join("pattern");
+    fs::write(&pattern, "C:\\\\dir\\\\\n").unwrap();
+    let out = csearch(&f.index)
+        .arg("-l")
+        .arg("--pattern-file")
+        .arg(&pattern)
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout), vec![a.clone()]);
+
+    let mut child = csearch(&f.index)
+        .args(["-l", "--pattern-file", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"C:\\\\dir\\\\")
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout), vec![a]);
+
+    let out = csearch(&f.index)
+        .arg("--pattern-file")
+        .arg(&pattern)
+        .arg("file")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot be used with"));
+}
+
+#[test]
+fn test_max_count() {
+    let text = (1..=100)
+        .map(|i| format!("match {}\n", i))
+        .collect::<String>();
+    let f = fixture(&[("a.txt", &text), ("b.txt", "match\nmatch\n")]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let b = f.root.join("b.txt").to_string_lossy().into_owned();
+    let run = |args: &[&str]| {
+        let out = csearch(&f.index).args(args).output().unwrap();
+        assert!(out.status.success());
+        out.stdout
+    };
+
+    let mut want = (1..=5)
+        .map(|i| format!("{}:{}:match {}", a, i, i))
+        .collect::<Vec<_>>();
+    want.push(format!("{}:1:match", b));
+    want.push(format!("{}:2:match", b));
+    assert_eq!(stdout_lines(&run(&["-n", "-m", "5", "match"])), want);
+
+    assert_eq!(
+        stdout_lines(&run(&["-c", "--max-count", "5", "match"])),
+        vec![format!("{}:5", a), format!("{}:2", b)]
+    );
+
+    // the trailing context of the last match is still printed
+    assert_eq!(
+        String::from_utf8(run(&["-n", "-m", "2", "-A", "1", "match 1"])).unwrap(),
+        format!(
+            "{a}:1:match 1\n{a}-2-match 2\n--\n{a}:10:match 10\n{a}-11-match 11\n",
+            a = a
+        )
+    );
+}
+
+#[test]
+fn test_limit() {
+    let files = (0..20)
+        .map(|i| (format!("f{:02}.txt", i), "match 1\nmatch 2\nmatch 3\n"))
+        .collect::<Vec<_>>();
+    let files = files
+        .iter()
+        .map(|(name, text)| (&name[..], *text))
+        .collect::<Vec<_>>();
+    let f = fixture(&files);
+    // files after the limit is reached must not even be opened
+    let names = indexed_names(&f.index);
+    for name in &names[10..] {
+        fs::remove_file(name).unwrap();
+    }
+    for threads in &["1", "4"] {
+        let out = csearch(&f.index)
+            .args(["-n", "-j", threads, "--limit", "7", "match"])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stderr), "");
+        let mut want = names[..3]
+            .iter()
+            .flat_map(|name| (1..=3).map(move |i| format!("{}:{}:match {}", name, i, i)))
+            .take(7)
+            .collect::<Vec<_>>();
+        want.sort();
+        assert_eq!(stdout_lines(&out.stdout), want);
+    }
+}
+
+#[test]
+fn test_column() {
+    let f = fixture(&[("a.txt", "first\nnaïve café here\n")]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let run = |args: &[&str]| {
+        let out = csearch(&f.index).args(args).output().unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap()
+    };
+    // "café" starts after the 2-byte "ï"
+    assert_eq!(
+        run(&["--column", "caf"]),
+        format!("{}:2:8:naïve café here\n", a)
+    );
+    assert_eq!(
+        run(&["--column-chars", "caf"]),
+        format!("{}:2:7:naïve café here\n", a)
+    );
+    assert_eq!(
+        run(&["--column", "-A", "1", "first"]),
+        format!("{}:1:1:first\n{}-2-naïve café here\n", a, a)
+    );
+    assert_eq!(
+        run(&["--column", "--null", "here"]),
+        format!("{}\u{0}2\u{0}14\u{0}naïve café here\n", a)
+    );
+    assert_eq!(run(&["-l", "--null", "here"]), format!("{}\u{0}", a));
+}
+
+#[test]
+fn test_byte_offset() {
+    let long = "x\n".repeat(3000) + "the needle\n";
+    let f = fixture(&[("a.txt", "first\nnaïve café here\n"), ("b.txt", &long)]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let b = f.root.join("b.txt").to_string_lossy().into_owned();
+    let run = |args: &[&str]| {
+        let out = csearch(&f.index).args(args).output().unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap()
+    };
+    // "café" starts 7 bytes into the second line, after the 2-byte "ï"
+    assert_eq!(run(&["-b", "caf"]), format!("{}:13:naïve café here\n", a));
+    assert_eq!(
+        run(&["-n", "--byte-offset", "caf"]),
+        format!("{}:2:13:naïve café here\n", a)
+    );
+    assert_eq!(
+        run(&["-b", "-B", "1", "caf"]),
+        format!("{}-0-first\n{}:13:naïve café here\n", a, a)
+    );
+    assert_eq!(
+        run(&["-b", "-U", "first\nna"]),
+        format!("{}:1-2:0:first\nnaïve café here\n", a)
+    );
+    // past the first buffer of the file
+    assert_eq!(run(&["-b", "needle"]), format!("{}:6004:the needle\n", b));
+
+    let out = csearch(&f.index)
+        .args(["--json", "-b", "caf"])
+        .output()
+        .unwrap();
+    let records = json_lines(&out.stdout);
+    assert_eq!(records[0]["byte_offset"], 13);
+    let out = csearch(&f.index).args(["--json", "caf"]).output().unwrap();
+    assert!(json_lines(&out.stdout)[0].get("byte_offset").is_none());
+}
+
+#[test]
+fn test_multiline() {
+    let f = fixture(&[
+        (
+            "a.rs",
+            "// a\nfn add(a: u32,\n       b: u32) -> u32 {\n    a + b\n}\n",
+        ),
+        ("b.rs", "fn one_line(x: u32) -> u32 {\n    x\n}\n"),
+    ]);
+    let a = f.root.join("a.rs").to_string_lossy().into_owned();
+    let b = f.root.join("b.rs").to_string_lossy().into_owned();
+    let run = |args: &[&str]| {
+        let out = csearch(&f.index).args(args).output().unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap()
+    };
+    // without -U, . doesn't match the newline
+    let out = csearch(&f.index)
+        .arg(r"fn \w+\(.*,.*b: u32")
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    assert_eq!(
+        run(&["-U", r"fn \w+\(.*,.*b: u32"]),
+        format!("{}:2-3:fn add(a: u32,\n       b: u32) -> u32 {{\n", a)
+    );
+    // a match on a single line prints just that line
+    assert_eq!(
+        run(&["-U", "--column", "one_line"]),
+        format!("{}:1:4:fn one_line(x: u32) -> u32 {{\n", b)
+    );
+    let mut counts = run(&["-U", "-c", r"u32\)"])
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    counts.sort();
+    assert_eq!(counts, vec![format!("{}:1", a), format!("{}:1", b)]);
+
+    let out = csearch(&f.index)
+        .args(["-U", "--json", r"\{\n\s+a \+ b"])
+        .output()
+        .unwrap();
+    let lines = json_lines(&out.stdout);
+    assert_eq!(lines[0]["line_number"], 3);
+    assert_eq!(lines[0]["lines"], 2);
+    assert_eq!(lines[0]["text"], "       b: u32) -> u32 {\n    a + b");
+}
+
+#[test]
+fn test_word_regexp() {
+    let f = fixture(&[("a.txt", "foo bar\nfoobar\nbar.foo\nfoo_bar\n")]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let out = csearch(&f.index)
+        .args(["-w", "-n", "foo"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{}:1:foo bar\n{}:3:bar.foo\n", a, a)
+    );
+    // an alternation is wrapped as a whole
+    let out = csearch(&f.index)
+        .args(["-w", "-n", "foo|foobar"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{}:1:foo bar\n{}:2:foobar\n{}:3:bar.foo\n", a, a, a)
+    );
+}
+
+#[test]
+fn test_fixed_strings() {
+    let f = fixture(&[
+        (
+            "a.txt",
+            "x = a+b
+x = aab
+foo[1] = 2
+",
+        ),
+        (
+            "b.txt", "aaab
+",
+        ),
+    ]);
+    let a = f.root.join("a.txt").to_string_lossy().into_owned();
+    let run = |args: &[&str]| {
+        let out = csearch(&f.index).args(args).output().unwrap();
+        assert!(out.status.success(), "{:?}", args);
+        String::from_utf8(out.stdout).unwrap()
+    };
+    // + is a quantifier in a regexp, but matches itself with -F
+    assert_eq!(
+        run(&["-F", "-n", "a+b"]),
+        format!(
+            "{}:1:x = a+b
+",
+            a
+        )
+    );
+    assert_eq!(
+        run(&["-F", "-n", "foo[1]"]),
+        format!(
+            "{}:3:foo[1] = 2
+",
+            a
+        )
+    );
+    assert_eq!(
+        run(&["--fixed-strings", "-n", "-i", "A+B"]),
+        format!(
+            "{}:1:x = a+b
+",
+            a
+        )
+    );
+
+    // the query holds the literal's trigrams
+    let out = csearch(&f.index)
+        .args(["-F", "--explain", "a+b"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&out.stderr).contains(r#"query: "a+b""#));
+}
+
+#[test]
+fn test_exit_codes() {
+    let f = sample();
+    let status = |args: &[&str]| csearch(&f.index).args(args).output().unwrap().status.code();
+    assert_eq!(status(&["hello"]), Some(0));
+    assert_eq!(status(&["-c", "hello"]), Some(0));
+    assert_eq!(status(&["-l", "hello"]), Some(0));
+    assert_eq!(status(&["--json", "hello"]), Some(0));
+    assert_eq!(status(&["no such text"]), Some(1));
+    assert_eq!(status(&["-c", "no such text"]), Some(1));
+    assert_eq!(status(&["--json", "no such text"]), Some(1));
+    assert_eq!(status(&["hel(lo"]), Some(2));
+    assert_eq!(status(&["-j", "0", "hello"]), Some(2));
+    assert_eq!(status(&["--no-such-flag", "hello"]), Some(2));
+    let missing = f.index.with_extension("missing");
+    let out = csearch(&missing).arg("hello").output().unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("index not found"));
+}
+
+#[test]
+fn test_files_from() {
+    let f = sample();
+    // a file that isn't in the index is searched all the same
+    let d = f.root.join("d.txt");
+    fs::write(&d, "hello from d\n").unwrap();
+    let (a, c, d) = (
+        f.root.join("a.txt").to_string_lossy().into_owned(),
+        f.root.join("c.txt").to_string_lossy().into_owned(),
+        d.to_string_lossy().into_owned(),
+    );
+    let list = f._out.path().join("list");
+    fs::write(&list, format!("{}\n\n{}\n", a, d)).unwrap();
+    let missing = f.index.with_extension("missing");
+    let out = csearch(&missing)
+        .arg("-l")
+        .arg("--files-from")
+        .arg(&list)
+        .arg("hello")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout), vec![a.clone(), d.clone()]);
+
+    let mut child = csearch(&f.index)
+        .args(["-L", "--files-from", "-", "again"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\n{}\n{}\n", a, c, d).as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout), vec![a.clone(), d]);
+
+    let out = csearch(&f.index)
+        .args(["--files-from", "-", "--root"])
+        .arg(&f.root)
+        .arg("hello")
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot be used with"));
+    let out = csearch(&f.index)
+        .args(["--files-from", "-", "--order", "relevance", "hello"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--order=relevance"));
+}
+
+#[test]
+fn test_multiple_patterns() {
+    let f = fixture(&[
+        ("a.rs", "old_open();\nold_close();\n"),
+        ("b.rs", "old_open(); old_close();\n"),
+        ("c.rs", "old_close();\n"),
+        ("d.rs", "new_open();\n"),
+    ]);
+    let name = |n: &str| f.root.join(n).to_string_lossy().into_owned();
+    let out = csearch(&f.index)
+        .args(["--explain", "-c", "-e", "old_open", "-e", "old_close"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    // every candidate is searched once, and a line matching both patterns
+    // is a single match
+    assert!(String::from_utf8_lossy(&out.stderr).contains("candidates: 3 of 4 files"));
+    assert_eq!(
+        stdout_lines(&out.stdout),
+        vec![
+            format!("{}:2", name("a.rs")),
+            format!("{}:1", name("b.rs")),
+            format!("{}:1", name("c.rs")),
+        ]
+    );
+
+    let out = csearch(&f.index)
+        .args(["--json", "-e", "old_open", "-e", "old_close"])
+        .output()
+        .unwrap();
+    let values = json_lines(&out.stdout);
+    let patterns = values
+        .iter()
+        .filter(|v| v["path"] == name("a.rs") || v["path"] == name("b.rs"))
+        .map(|v| v["pattern"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(patterns, vec!["old_open", "old_close", "old_open"]);
+    assert_eq!(values.last().unwrap()["summary"]["matches"], 4);
+
+    // a single -e is an ordinary search
+    let out = csearch(&f.index)
+        .args(["--json", "-e", "new_open"])
+        .output()
+        .unwrap();
+    let values = json_lines(&out.stdout);
+    assert_eq!(values.len(), 2);
+    assert!(values[0].get("pattern").is_none());
+
+    let out = csearch(&f.index)
+        .args(["-e", "old_open", "old_close"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn test_stats() {
+    let f = sample();
+    let out = csearch(&f.index)
+        .args(["--stats", "hel+o"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(stdout_lines(&out.stdout).len(), 3);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.starts_with("stats: candidates=2 scanned=2 matched_files=2 matches=3 elapsed="),
+        "{}",
+        stderr
+    );
+    let out = csearch(&f.index)
+        .args(["--stats", "-l", "first"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.starts_with("stats: candidates=1 scanned=1 matched_files=1 matches=1 "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_output() {
+    let f = sample();
+    let out_dir = TempDir::new().unwrap();
+    let results = out_dir.path().join("results");
+    fs::write(&results, "left over from before\n".repeat(100)).unwrap();
+    for args in &[
+        &["hello"][..],
+        &["--json", "hello"],
+        &["-C", "1", "hello"],
+        &["--files", "hello"],
+    ] {
+        let to_stdout = csearch(&f.index).args(*args).output().unwrap();
+        let to_file = csearch(&f.index)
+            .arg("-o")
+            .arg(&results)
+            .args(*args)
+            .output()
+            .unwrap();
+        assert_eq!(to_file.status.code(), to_stdout.status.code(), "{:?}", args);
+        assert!(to_file.stdout.is_empty(), "{:?}", args);
+        assert_eq!(fs::read(&results).unwrap(), to_stdout.stdout, "{:?}", args);
+    }
+
+    // results that can't be written are an error
+    let out = csearch(&f.index)
+        .arg("--output")
+        .arg(out_dir.path().join("missing/results"))
+        .arg("hello")
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    if Path::new("/dev/full").exists() {
+        let out = csearch(&f.index)
+            .args(["--output", "/dev/full", "hello"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("write /dev/full"), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_write_errors() {
+    let f = sample();
+    if Path::new("/dev/full").exists() {
+        let out = csearch(&f.index)
+            .arg("hello")
+            .stdout(
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open("/dev/full")
+                    .unwrap(),
+            )
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("write stdout"), "{}", stderr);
+    }
+
+    // a reader that stops reading, as with `| head`, isn't an error
+    for args in &[&["hello"][..], &["--files", "hello"]] {
+        let mut child = csearch(&f.index)
+            .args(*args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        drop(child.stdout.take());
+        let out = child.wait_with_output().unwrap();
+        assert_eq!(out.status.code(), Some(0), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.is_empty(), "{:?}: {}", args, stderr);
+    }
+}
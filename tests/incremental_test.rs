@@ -0,0 +1,161 @@
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+mod common;
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use self::libcindex::merge::merge;
+use self::libcindex::writer::{IndexWriter, IndexWriterBuilder};
+use self::libcsearch::reader::{IndexReader, PostReader};
+use self::tempfile::TempDir;
+
+use common::tri;
+
+fn write(path: &Path, contents: &str) {
+    fs::write(path, contents).unwrap();
+}
+
+fn build(out: &Path, previous: Option<IndexReader>, files: &[&PathBuf]) {
+    let mut ix = match previous {
+        Some(p) => IndexWriter::with_previous(out, p).unwrap(),
+        None => IndexWriter::new(out).unwrap(),
+    };
+    for f in files {
+        ix.add_file(f).unwrap();
+    }
+    ix.flush().unwrap();
+}
+
+fn files_with(ix: &IndexReader, t: &str) -> BTreeSet<String> {
+    let t = t.chars().collect::<Vec<_>>();
+    PostReader::list(ix, tri(t[0], t[1], t[2]), &None)
+        .unwrap()
+        .into_iter()
+        .map(|id| ix.name(id))
+        .collect()
+}
+
+#[test]
+fn test_incremental_carries_unchanged_files() {
+    let dir = TempDir::new().unwrap();
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    let c = dir.path().join("c");
+    write(&a, "hello world");
+    write(&b, "goodbye");
+    write(&c, "gone file");
+
+    let first = dir.path().join("index1");
+    build(&first, None, &[&a, &b, &c]);
+    let ix1 = IndexReader::open(&first).unwrap();
+    assert_eq!(ix1.file_metadata(1).unwrap().size, 7);
+
+    // Same size and mtime: the old contents should be carried forward
+    // without rereading the file.
+    let mtime = fs::metadata(&b).unwrap().modified().unwrap();
+    write(&b, "GOODBYE");
+    File::options()
+        .write(true)
+        .open(&b)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    write(&a, "hello there friend");
+    fs::remove_file(&c).unwrap();
+
+    let second = dir.path().join("index2");
+    build(&second, Some(ix1), &[&a, &b]);
+    let ix2 = IndexReader::open(&second).unwrap();
+
+    assert_eq!(ix2.num_name, 2);
+    let name_of = |p: &PathBuf| p.to_str().unwrap().to_string();
+    assert_eq!(
+        files_with(&ix2, "goo"),
+        vec![name_of(&b)].into_iter().collect()
+    );
+    assert_eq!(files_with(&ix2, "GOO"), BTreeSet::new());
+    assert_eq!(
+        files_with(&ix2, "the"),
+        vec![name_of(&a)].into_iter().collect()
+    );
+    assert_eq!(files_with(&ix2, "wor"), BTreeSet::new());
+    assert_eq!(files_with(&ix2, "gon"), BTreeSet::new());
+    assert_eq!(ix2.file_metadata(0).unwrap().size, 18);
+    assert_eq!(ix2.file_metadata(1).unwrap().size, 7);
+}
+
+#[test]
+fn test_incremental_relative_to() {
+    let dir = TempDir::new().unwrap();
+    let base = dir.path().join("base");
+    fs::create_dir(&base).unwrap();
+    let a = base.join("a");
+    write(&a, "hello world");
+    let build = |out: &Path, previous: Option<IndexReader>| {
+        let mut builder = IndexWriterBuilder::new().relative_to(&base);
+        if let Some(p) = previous {
+            builder = builder.previous(p);
+        }
+        let mut ix = builder.build(out).unwrap();
+        ix.add_file(&a).unwrap();
+        ix.flush().unwrap();
+    };
+    let first = dir.path().join("index1");
+    build(&first, None);
+
+    // the file is found under its relative name, so it isn't read again
+    let mtime = fs::metadata(&a).unwrap().modified().unwrap();
+    write(&a, "HELLO WORLD");
+    File::options()
+        .write(true)
+        .open(&a)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let second = dir.path().join("index2");
+    build(&second, Some(IndexReader::open(&first).unwrap()));
+    let ix2 = IndexReader::open(&second).unwrap();
+    assert_eq!(
+        files_with(&ix2, "hel"),
+        vec!["a".to_string()].into_iter().collect()
+    );
+    assert_eq!(files_with(&ix2, "HEL"), BTreeSet::new());
+}
+
+#[test]
+fn test_merge_keeps_file_metadata() {
+    let dir = TempDir::new().unwrap();
+    let x = dir.path().join("x");
+    let y = dir.path().join("y");
+    fs::create_dir(&x).unwrap();
+    fs::create_dir(&y).unwrap();
+    let x1 = x.join("1");
+    let y1 = y.join("1");
+    write(&x1, "some text");
+    write(&y1, "other text!");
+
+    let mut paths = Vec::new();
+    for (root, file) in &[(&x, &x1), (&y, &y1)] {
+        let out = dir
+            .path()
+            .join(root.file_name().unwrap())
+            .with_extension("ix");
+        let mut ix = IndexWriter::new(&out).unwrap();
+        ix.add_paths(vec![root.as_os_str().to_owned()]);
+        ix.add_file(file).unwrap();
+        ix.flush().unwrap();
+        paths.push(out);
+    }
+    let merged = dir.path().join("merged");
+    merge(&merged, &paths[0], &paths[1]).unwrap();
+
+    let ix = IndexReader::open(&merged).unwrap();
+    assert_eq!(ix.num_name, 2);
+    assert_eq!(ix.file_metadata(0).unwrap().size, 9);
+    assert_eq!(ix.file_metadata(1).unwrap().size, 11);
+}
@@ -0,0 +1,114 @@
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use self::tempfile::TempDir;
+
+use self::libcindex::merge::merge;
+use self::libcindex::writer::IndexWriter;
+use self::libcsearch::reader::{IndexReader, PostReader};
+
+/// Wraps the system allocator to track the peak number of bytes allocated
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            let now = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(now, Ordering::SeqCst);
+        }
+        p
+    }
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        System.dealloc(p, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Builds an index of `num_files` files of pseudo-random text under `root`
+fn build_synthetic_index(out: &Path, root: &str, num_files: usize, seed: u64) {
+    let mut state = seed;
+    let mut ix = IndexWriter::new(out).unwrap();
+    ix.add_paths(vec![PathBuf::from(root).into_os_string()]);
+    for i in 0..num_files {
+        let mut text = String::new();
+        for _ in 0..60 {
+            for _ in 0..60 {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                text.push((b'a' + (state % 26) as u8) as char);
+            }
+            text.push('\n');
+        }
+        let len = text.len() as u64;
+        let name = format!("{}/f{:05}", root, i);
+        ix.add(name, Cursor::new(text.into_bytes()), len).unwrap();
+    }
+    ix.flush().unwrap();
+}
+
+/// Returns the names of the files in `ix` that contain `trigram`
+fn names_with(ix: &IndexReader, trigram: u32) -> BTreeSet<String> {
+    PostReader::list(ix, trigram, &None)
+        .unwrap()
+        .into_iter()
+        .map(|i| ix.name(i))
+        .collect()
+}
+
+#[test]
+fn test_merge_memory_is_bounded() {
+    let dir = TempDir::new().unwrap();
+    let (src1, src2, dest) = (
+        dir.path().join("src1"),
+        dir.path().join("src2"),
+        dir.path().join("dest"),
+    );
+    build_synthetic_index(&src1, "/a", 500, 1);
+    build_synthetic_index(&src2, "/b", 500, 2);
+    let sources_len = [&src1, &src2]
+        .iter()
+        .map(|p| p.metadata().unwrap().len() as usize)
+        .sum::<usize>();
+
+    let before = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    merge(&dest, &src1, &src2).unwrap();
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+    // the posting lists are streamed, so only buffers and per-file
+    // bookkeeping are held in memory
+    assert!(
+        peak < sources_len / 8,
+        "merge used {} bytes merging {} bytes of indexes",
+        peak,
+        sources_len
+    );
+
+    let ix1 = IndexReader::open(&src1).unwrap();
+    let ix2 = IndexReader::open(&src2).unwrap();
+    let ix3 = IndexReader::open(&dest).unwrap();
+    assert_eq!(ix3.num_name, ix1.num_name + ix2.num_name);
+    assert_eq!(ix3.indexed_paths(), vec!["/a", "/b"]);
+    for t in &[b"abc", b"qzx", b"mmm", b"zzz", b"xyz"] {
+        let trigram = (t[0] as u32) << 16 | (t[1] as u32) << 8 | t[2] as u32;
+        let mut want = names_with(&ix1, trigram);
+        want.extend(names_with(&ix2, trigram));
+        assert_eq!(names_with(&ix3, trigram), want);
+    }
+}
@@ -6,11 +6,14 @@ extern crate libcsearch;
 mod common;
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io::Cursor;
 use std::path::PathBuf;
 
 use self::tempfile::NamedTempFile;
 
-use self::libcindex::merge::merge;
+use self::libcindex::merge::{merge, merge_many};
+use self::libcindex::writer::IndexWriterBuilder;
 use self::libcsearch::reader::{IndexReader, PostReader};
 
 use common::{build_index, tri};
@@ -78,7 +81,7 @@ fn test_merge() {
 
     fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
         let t = trig.chars().collect::<Vec<char>>();
-        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), &None);
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), &None).unwrap();
         let mut h: BTreeSet<u32> = BTreeSet::new();
         h.extend(l.iter().cloned());
         assert_eq!(l1, h);
@@ -95,3 +98,82 @@ fn test_merge() {
     check(&ix3, "now", &[3, 4, 6]);
     check(&ix3, "pot", &[4, 5, 7]);
 }
+
+#[test]
+fn test_merge_many() {
+    fn files(v: &[(&'static str, &'static str)]) -> BTreeMap<&'static str, &'static str> {
+        v.iter().cloned().collect()
+    }
+    let srcs = vec![
+        (
+            vec!["/a", "/b"],
+            files(&[("/a/x", "hello world"), ("/b/y", "old potatoes")]),
+        ),
+        (
+            vec!["/b"],
+            files(&[("/b/y", "new potatoes"), ("/b/z", "zebra world")]),
+        ),
+        (vec!["/a"], files(&[("/a/w", "world peace")])),
+    ];
+    let tmp = srcs
+        .into_iter()
+        .map(|(paths, data)| {
+            let f = NamedTempFile::new().unwrap();
+            build_index(f.path(), paths.iter().map(PathBuf::from).collect(), data);
+            f
+        })
+        .collect::<Vec<_>>();
+    let dest = NamedTempFile::new().unwrap();
+    merge_many(dest.path(), tmp.iter().map(|f| f.path()).collect()).unwrap();
+
+    let ix = IndexReader::open(dest.path()).unwrap();
+    assert_eq!(ix.indexed_paths(), vec!["/a", "/b"]);
+    let names = (0..ix.num_name as u32)
+        .map(|i| ix.name(i))
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["/a/w", "/b/y", "/b/z"]);
+
+    let check = |trig: &str, l: &[u32]| {
+        let t = trig.chars().collect::<Vec<char>>();
+        let got = PostReader::list(&ix, tri(t[0], t[1], t[2]), &None).unwrap();
+        assert_eq!(
+            got,
+            l.iter().cloned().collect::<BTreeSet<u32>>(),
+            "{}",
+            trig
+        );
+    };
+    check("wor", &[0, 2]);
+    check("pot", &[1]);
+    check("new", &[1]);
+    check("old", &[]);
+    check("hel", &[]);
+}
+
+#[test]
+fn test_merge_compressed() {
+    let compressed = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriterBuilder::new()
+        .compress(true)
+        .build(compressed.path())
+        .unwrap();
+    ix.add_paths(vec![OsString::from("/a")]);
+    ix.add("/a/x", Cursor::new("hello world"), 11).unwrap();
+    ix.flush().unwrap();
+    let plain = NamedTempFile::new().unwrap();
+    build_index(
+        plain.path(),
+        vec![PathBuf::from("/b")],
+        vec![("/b/y", "world peace")].into_iter().collect(),
+    );
+
+    // the merged index is compressed if either source is
+    let dest = NamedTempFile::new().unwrap();
+    merge(dest.path(), plain.path(), compressed.path()).unwrap();
+    let ix = IndexReader::open(dest.path()).unwrap();
+    assert!(ix.is_compressed());
+    ix.verify().unwrap();
+    assert_eq!(ix.posting_list(*b"wor").unwrap(), vec![0, 1]);
+    assert_eq!(ix.posting_list(*b"hel").unwrap(), vec![0]);
+    assert_eq!(ix.posting_list(*b"pea").unwrap(), vec![1]);
+}
@@ -1,5 +1,7 @@
 extern crate tempfile;
 
+extern crate crc32fast;
+extern crate libcindex;
 extern crate libcsearch;
 
 mod common;
@@ -42,9 +44,12 @@ fn make_index() -> IndexReader {
 #[test]
 fn test_postreader_list() {
     let ix = make_index();
-    assert_eq!(PostReader::list(&ix, tri('S', 'e', 'a'), &None), set![1, 3]);
     assert_eq!(
-        PostReader::list(&ix, tri('G', 'o', 'o'), &None),
+        PostReader::list(&ix, tri('S', 'e', 'a'), &None).unwrap(),
+        set![1, 3]
+    );
+    assert_eq!(
+        PostReader::list(&ix, tri('G', 'o', 'o'), &None).unwrap(),
         set![1, 2, 3]
     );
 }
@@ -55,19 +60,21 @@ fn test_postreader_and() {
     assert_eq!(
         PostReader::and(
             &ix,
-            PostReader::list(&ix, tri('S', 'e', 'a'), &None),
+            PostReader::list(&ix, tri('S', 'e', 'a'), &None).unwrap(),
             tri('G', 'o', 'o'),
             &None
-        ),
+        )
+        .unwrap(),
         set![1, 3]
     );
     assert_eq!(
         PostReader::and(
             &ix,
-            PostReader::list(&ix, tri('G', 'o', 'o'), &None),
+            PostReader::list(&ix, tri('G', 'o', 'o'), &None).unwrap(),
             tri('S', 'e', 'a'),
             &None
-        ),
+        )
+        .unwrap(),
         set![1, 3]
     );
 }
@@ -78,19 +85,389 @@ fn test_postreader_or() {
     assert_eq!(
         PostReader::or(
             &ix,
-            PostReader::list(&ix, tri('G', 'o', 'o'), &None),
+            PostReader::list(&ix, tri('G', 'o', 'o'), &None).unwrap(),
             tri('S', 'e', 'a'),
             &None
-        ),
+        )
+        .unwrap(),
         set![1, 2, 3]
     );
     assert_eq!(
         PostReader::or(
             &ix,
-            PostReader::list(&ix, tri('S', 'e', 'a'), &None),
+            PostReader::list(&ix, tri('S', 'e', 'a'), &None).unwrap(),
             tri('G', 'o', 'o'),
             &None
-        ),
+        )
+        .unwrap(),
         set![1, 2, 3]
     );
 }
+
+#[test]
+fn test_truncated_index() {
+    use self::libcsearch::reader::{CorruptIndex, OpenError};
+    use std::fs;
+    use std::io;
+
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let data = fs::read(f.path()).unwrap();
+    let cut = NamedTempFile::new().unwrap();
+    for len in (0..data.len()).rev() {
+        fs::write(cut.path(), &data[..len]).unwrap();
+        let e = match IndexReader::open(cut.path()) {
+            Ok(_) => panic!("opened index truncated to {} bytes", len),
+            Err(e) => e,
+        };
+        assert!(
+            matches!(
+                e,
+                OpenError::Truncated | OpenError::BadMagic | OpenError::Corrupt(_)
+            ),
+            "{} bytes: {:?}",
+            len,
+            e
+        );
+    }
+    fs::write(cut.path(), &data[..10]).unwrap();
+    assert!(matches!(
+        IndexReader::open(cut.path()),
+        Err(OpenError::Truncated)
+    ));
+    assert!(matches!(
+        IndexReader::open(cut.path().with_extension("missing")),
+        Err(OpenError::NotFound)
+    ));
+
+    // point the name index past the end of the file
+    let mut bad = data.clone();
+    let at = bad.len() - 16 - 4 - 8;
+    bad[at..at + 4].copy_from_slice(&[0xff; 4]);
+    fs::write(cut.path(), &bad).unwrap();
+    let e = IndexReader::open(cut.path()).err().unwrap();
+    assert!(matches!(e, OpenError::Corrupt(CorruptIndex::BadTrailer)));
+
+    // converted to an io::Error, the reason is kept
+    let e = io::Error::from(e);
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let reason = e.get_ref().unwrap().downcast_ref::<CorruptIndex>();
+    assert_eq!(reason, Some(&CorruptIndex::BadTrailer));
+}
+
+#[test]
+fn test_checksum() {
+    use self::libcsearch::reader::{CorruptIndex, OpenError};
+    use std::fs;
+
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let data = fs::read(f.path()).unwrap();
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert_eq!(ix.format_version(), 2);
+    drop(ix);
+
+    // flip a bit inside the posting lists
+    let cut = NamedTempFile::new().unwrap();
+    let mut bad = data.clone();
+    let at = bad.len() / 2;
+    bad[at] ^= 0x10;
+    fs::write(cut.path(), &bad).unwrap();
+    let e = IndexReader::open(cut.path()).err().unwrap();
+    assert!(matches!(e, OpenError::Corrupt(CorruptIndex::BadChecksum)));
+    assert!(e.to_string().contains("checksum"));
+
+    // an index in the old format, without a checksum, still opens
+    let trailer = 16;
+    let mut old = data.clone();
+    old[..16].copy_from_slice(b"csearch index 1\n");
+    let checksum_at = old.len() - trailer - 4;
+    old.drain(checksum_at..checksum_at + 4);
+    fs::write(cut.path(), &old).unwrap();
+    let ix = IndexReader::open(cut.path()).unwrap();
+    assert_eq!(ix.format_version(), 1);
+    assert_eq!(
+        PostReader::list(&ix, tri('a', 'b', 'c'), &None).unwrap(),
+        PostReader::list(
+            &IndexReader::open(f.path()).unwrap(),
+            tri('a', 'b', 'c'),
+            &None
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn test_unsupported_version() {
+    use self::libcsearch::reader::OpenError;
+    use std::fs;
+
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let data = fs::read(f.path()).unwrap();
+    let cut = NamedTempFile::new().unwrap();
+    for &(version, message) in &[(b'0', "too old"), (b'4', "newer")] {
+        let mut other = data.clone();
+        other[14] = version;
+        fs::write(cut.path(), &other).unwrap();
+        let e = IndexReader::open(cut.path()).err().unwrap();
+        match (version, &e) {
+            (b'0', &OpenError::VersionTooOld(0)) | (b'4', &OpenError::VersionTooNew(4)) => {}
+            _ => panic!("version {}: {:?}", version, e),
+        }
+        assert!(e.to_string().contains(message), "{}", e);
+        assert!(e.to_string().contains("cindex --reset"), "{}", e);
+    }
+}
+
+#[test]
+fn test_posting_list() {
+    use self::libcsearch::reader::CorruptIndex;
+    use std::fs;
+
+    let ix = make_index();
+    assert_eq!(ix.posting_list(*b"Sea"), Ok(vec![1, 3]));
+    assert_eq!(ix.posting_list(*b"Goo"), Ok(vec![1, 2, 3]));
+    assert_eq!(ix.posting_list(*b"zzz"), Ok(vec![]));
+    for id in 0..4 {
+        assert_eq!(ix.file_name(id), Some(&format!("file{}", id)[..]));
+    }
+    assert_eq!(ix.file_name(4), None);
+    assert_eq!(ix.file_name(u32::MAX), None);
+    assert_eq!(ix.num_files(), 4);
+    assert_eq!(
+        ix.file_names().collect::<Vec<_>>(),
+        vec!["file0", "file1", "file2", "file3"]
+    );
+
+    // point the first delta of "Goo" past the last file, in an index
+    // without a checksum so it still opens
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let mut data = fs::read(f.path()).unwrap();
+    data[..16].copy_from_slice(b"csearch index 1\n");
+    let checksum_at = data.len() - 16 - 4;
+    data.drain(checksum_at..checksum_at + 4);
+    let list = data.windows(3).position(|w| w == b"Goo").unwrap();
+    data[list + 3] = 0x7f;
+    fs::write(f.path(), &data).unwrap();
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert_eq!(ix.posting_list(*b"Goo"), Err(CorruptIndex::BadPostingList));
+    assert_eq!(ix.posting_list(*b"Sea"), Ok(vec![1, 3]));
+}
+
+#[test]
+fn test_corrupt_posting_list_query() {
+    use self::libcsearch::reader::{CorruptIndex, PostSet};
+    use self::libcsearch::regexp::query_from_regexp;
+    use std::fs;
+
+    // end the list of "Goo" before its first file, in an index without a
+    // checksum so it still opens
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let mut data = fs::read(f.path()).unwrap();
+    data[..16].copy_from_slice(b"csearch index 1\n");
+    let checksum_at = data.len() - 16 - 4;
+    data.drain(checksum_at..checksum_at + 4);
+    let list = data.windows(3).position(|w| w == b"Goo").unwrap();
+    data[list + 3] = 0;
+    fs::write(f.path(), &data).unwrap();
+
+    let ix = IndexReader::open(f.path()).unwrap();
+    let q = query_from_regexp("Google").unwrap();
+    assert_eq!(ix.query(q).err(), Some(CorruptIndex::BadPostingList));
+    assert_eq!(
+        PostReader::list(&ix, tri('G', 'o', 'o'), &None),
+        Err(CorruptIndex::BadPostingList)
+    );
+    assert!(PostSet::new(&ix).or(tri('G', 'o', 'o')).is_err());
+    // the other lists still decode
+    let q = query_from_regexp("Search").unwrap();
+    assert_eq!(ix.query(q).unwrap().into_inner(), set![1, 3]);
+}
+
+#[test]
+fn test_corrupt_posting_block() {
+    use self::libcindex::writer::IndexWriterBuilder;
+    use self::libcsearch::reader::CorruptIndex;
+    use self::libcsearch::regexp::query_from_regexp;
+    use std::fs;
+    use std::io::Cursor;
+
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriterBuilder::new()
+        .compress(true)
+        .build(f.path())
+        .unwrap();
+    for (name, contents) in post_files() {
+        ix.add(name, Cursor::new(contents), contents.len() as u64)
+            .unwrap();
+    }
+    ix.flush().unwrap();
+    let data = fs::read(f.path()).unwrap();
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert_eq!(ix.format_version(), 3);
+    drop(ix);
+
+    // the trailer is 7 fields, the checksum and the trailer magic; the
+    // posting block index ends where the trailer starts
+    let trailer_at = data.len() - 16 - 4 - 7 * 4;
+    let field = |data: &[u8], at: usize| {
+        u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as usize
+    };
+    let post_data = field(&data, trailer_at + 8);
+    let rewrite = |mut bad: Vec<u8>| {
+        let checksum_at = bad.len() - 16 - 4;
+        let checksum = crc32fast::hash(&bad[..checksum_at]);
+        bad[checksum_at..checksum_at + 4].copy_from_slice(&checksum.to_be_bytes());
+        fs::write(f.path(), &bad).unwrap();
+        IndexReader::open(f.path()).unwrap()
+    };
+
+    // a block that isn't zstd data
+    let mut bad = data.clone();
+    bad[post_data] ^= 0xff;
+    let ix = rewrite(bad);
+    let q = query_from_regexp("Google").unwrap();
+    assert_eq!(ix.query(q).err(), Some(CorruptIndex::BadPostingBlock));
+    assert_eq!(
+        PostReader::list(&ix, tri('G', 'o', 'o'), &None),
+        Err(CorruptIndex::BadPostingBlock)
+    );
+    assert!(ix.verify().is_err());
+
+    // a block that decompresses to less than its recorded length
+    let mut bad = data.clone();
+    let end_at = trailer_at - 8;
+    let end = field(&bad, end_at) as u32 + 1;
+    bad[end_at..end_at + 4].copy_from_slice(&end.to_be_bytes());
+    let ix = rewrite(bad);
+    let q = query_from_regexp("Google").unwrap();
+    assert_eq!(ix.query(q).err(), Some(CorruptIndex::BadPostingBlock));
+}
+
+#[test]
+fn test_verify() {
+    use self::libcsearch::reader::{CorruptIndex, POST_ENTRY_SIZE};
+    use std::fs;
+
+    let f = NamedTempFile::new().unwrap();
+    build_index(
+        f.path(),
+        vec!["/src".into(), "/usr/include".into()],
+        post_files(),
+    );
+    assert_eq!(IndexReader::open(f.path()).unwrap().verify(), Ok(()));
+
+    // damage a copy in the format without a checksum, so it still opens
+    let mut data = fs::read(f.path()).unwrap();
+    data[..16].copy_from_slice(b"csearch index 1\n");
+    let checksum_at = data.len() - 16 - 4;
+    data.drain(checksum_at..checksum_at + 4);
+    let verify = |change: &dyn Fn(&mut Vec<u8>)| {
+        let mut bad = data.clone();
+        change(&mut bad);
+        let cut = NamedTempFile::new().unwrap();
+        fs::write(cut.path(), &bad).unwrap();
+        IndexReader::open(cut.path())
+            .unwrap()
+            .verify()
+            .map_err(|e| e.kind)
+    };
+    assert_eq!(verify(&|_| ()), Ok(()));
+
+    // a file id past the last file
+    let err = verify(&|d| {
+        let list = d.windows(3).position(|w| w == b"Goo").unwrap();
+        d[list + 3] = 0x7f;
+    });
+    assert_eq!(err, Err(CorruptIndex::BadPostingList));
+
+    // a name running into the next one
+    let err = verify(&|d| {
+        let name = d.windows(6).position(|w| w == b"file1\0").unwrap();
+        d[name + 5] = b'x';
+    });
+    assert_eq!(err, Err(CorruptIndex::UnterminatedList));
+
+    // two posting list index entries out of order
+    let err = verify(&|d| {
+        let n = d.len() - 16 - 5 * 4;
+        let post_index = u32::from_be_bytes([d[n + 16], d[n + 17], d[n + 18], d[n + 19]]) as usize;
+        let (a, b) = (post_index, post_index + POST_ENTRY_SIZE);
+        let first = d[a..b].to_vec();
+        d.copy_within(b..b + POST_ENTRY_SIZE, a);
+        d[b..b + POST_ENTRY_SIZE].copy_from_slice(&first);
+    });
+    assert_eq!(err, Err(CorruptIndex::BadPostOffset));
+}
+
+#[test]
+fn test_buffered_read_mode() {
+    use self::libcsearch::reader::{CorruptIndex, OpenError, ReadMode};
+    use std::fs;
+
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let mapped = IndexReader::open_with(f.path(), ReadMode::Mmap).unwrap();
+    let buffered = IndexReader::open_with(f.path(), ReadMode::Buffered).unwrap();
+    assert_eq!(buffered.as_slice(), mapped.as_slice());
+    assert_eq!(buffered.num_name, 4);
+    assert_eq!(buffered.name(2), "file2");
+    assert_eq!(
+        PostReader::list(&buffered, tri('G', 'o', 'o'), &None).unwrap(),
+        set![1, 2, 3]
+    );
+    assert_eq!(buffered.posting_list(*b"Sea"), Ok(vec![1, 3]));
+
+    // the same checks apply to an index that's read up front
+    let mut bad = fs::read(f.path()).unwrap();
+    let at = bad.len() / 2;
+    bad[at] ^= 0x10;
+    let cut = NamedTempFile::new().unwrap();
+    fs::write(cut.path(), &bad).unwrap();
+    assert!(matches!(
+        IndexReader::open_with(cut.path(), ReadMode::Buffered),
+        Err(OpenError::Corrupt(CorruptIndex::BadChecksum))
+    ));
+}
+
+#[test]
+fn test_query_decodes_each_list_once() {
+    use self::libcsearch::regexp::{query_from_regexp, Query};
+
+    fn trigrams(q: &Query, out: &mut Vec<Vec<u8>>) {
+        out.extend(q.trigram.iter().cloned());
+        for each_sub in &q.sub {
+            trigrams(each_sub, out);
+        }
+    }
+
+    let ix = make_index();
+    let q = query_from_regexp("Google (Code|Web) Search|Google Code Project").unwrap();
+    let mut all = Vec::new();
+    trigrams(&q, &mut all);
+    let distinct = all.iter().collect::<BTreeSet<_>>().len();
+    assert!(distinct < all.len());
+
+    assert_eq!(ix.query(q).unwrap().into_inner(), set![1, 2, 3]);
+    assert!(ix.lists_decoded() <= distinct);
+}
+
+#[test]
+fn test_query_ast_evaluate() {
+    use self::libcsearch::regexp::{query_from_regexp, QueryAst};
+
+    let ix = make_index();
+    let q = query_from_regexp("Google (Code|Web) Search").unwrap();
+    let ast = QueryAst::from(&q);
+    assert_eq!(ast.evaluate(&ix).unwrap(), vec![1, 3]);
+    assert_eq!(
+        QueryAst::Or(vec![QueryAst::Trigram(*b"Pro"), QueryAst::Trigram(*b"Web")])
+            .evaluate(&ix)
+            .unwrap(),
+        vec![2, 3]
+    );
+    assert_eq!(QueryAst::None.evaluate(&ix).unwrap(), Vec::<u32>::new());
+}
@@ -1,10 +1,11 @@
 extern crate regex_syntax;
+extern crate serde_json;
 
 extern crate libcsearch;
 
 use regex_syntax::Expr;
 
-use libcsearch::regexp::RegexInfo;
+use libcsearch::regexp::{query_from_literal, query_from_regexp, Query, RegexInfo};
 
 macro_rules! regex_eq {
     ( $r:expr, $expected:expr ) => {{
@@ -50,6 +51,21 @@ fn test_query() {
     );
 }
 
+#[test]
+fn test_query_from_literal() {
+    // the same query as for a regexp without special characters
+    for literal in &["Abcdef", "hello world", "ab", ""] {
+        assert_eq!(
+            query_from_literal(literal.as_bytes()).to_string(),
+            query_from_regexp(literal).unwrap().to_string()
+        );
+    }
+    assert_eq!(
+        query_from_literal(b"a.*b").to_string(),
+        r#"".*b" AND "a.*""#
+    );
+}
+
 #[test]
 fn test_case_insensitive() {
     RegexInfo::new(Expr::parse(r"(?i)abcd efgh").unwrap()).unwrap();
@@ -167,3 +183,69 @@ fn test_query_word_boundary() {
     regex_eq!(r"ab\bc", "\"abc\"");
     regex_eq!(r"ab\Bc", "\"abc\"");
 }
+
+#[test]
+fn test_query_from_regexp_display() {
+    let display = |r: &str| query_from_regexp(r).unwrap().to_string();
+    assert_eq!(display(r"abc"), "\"abc\"");
+    assert_eq!(display(r"abcd"), "\"abc\" AND \"bcd\"");
+    assert_eq!(display(r"abc|def"), "\"abc\" OR \"def\"");
+    assert_eq!(
+        display(r"abc(def|ghi)"),
+        "\"abc\" AND (\"bcd\" AND \"cde\" AND \"def\" OR \"bcg\" AND \"cgh\" AND \"ghi\")"
+    );
+    // csearch -w doesn't lose the trigrams of the pattern it wraps
+    assert_eq!(display(r"\b(?:abc|def)\b"), display(r"abc|def"));
+    assert_eq!(display(r"a"), "+");
+    assert!(query_from_regexp(r"abc(").is_err());
+}
+
+#[test]
+fn test_query_case_insensitive_is_bounded() {
+    fn num_trigrams(q: &Query) -> usize {
+        q.trigram.len() + q.sub.iter().map(num_trigrams).sum::<usize>()
+    }
+    // Each trigram of a case-folded literal expands to at most its 8 case
+    // variants, so the query grows linearly with the literal, not
+    // exponentially.
+    let literal = "abcdefghijklmnopqrstuvwxyzabcdefghijklmn";
+    let q = query_from_regexp(&format!("(?i){}", literal)).unwrap();
+    assert!(num_trigrams(&q) <= 8 * (literal.len() - 2));
+    let q = query_from_regexp("(?i)Foo").unwrap();
+    assert_eq!(num_trigrams(&q), 8);
+    assert!(q.trigram.contains(b"foo".as_slice()));
+    assert!(q.trigram.contains(b"FOO".as_slice()));
+}
+
+#[test]
+fn test_query_ast() {
+    use libcsearch::regexp::QueryAst;
+
+    let q = query_from_regexp(r"abc(def|ghi)").unwrap();
+    let ast = QueryAst::from(&q);
+    assert_eq!(
+        ast,
+        QueryAst::And(vec![
+            QueryAst::Trigram(*b"abc"),
+            QueryAst::Or(vec![
+                QueryAst::And(vec![
+                    QueryAst::Trigram(*b"bcd"),
+                    QueryAst::Trigram(*b"cde"),
+                    QueryAst::Trigram(*b"def"),
+                ]),
+                QueryAst::And(vec![
+                    QueryAst::Trigram(*b"bcg"),
+                    QueryAst::Trigram(*b"cgh"),
+                    QueryAst::Trigram(*b"ghi"),
+                ]),
+            ]),
+        ])
+    );
+    // converting back gives the same query
+    assert_eq!(Query::from(&ast).to_string(), q.to_string());
+    assert_eq!(QueryAst::from(&Query::all()), QueryAst::All);
+    assert_eq!(QueryAst::from(&Query::none()), QueryAst::None);
+
+    let json = serde_json::to_string(&ast).unwrap();
+    assert_eq!(serde_json::from_str::<QueryAst>(&json).unwrap(), ast);
+}
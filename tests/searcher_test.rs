@@ -0,0 +1,136 @@
+extern crate flate2;
+extern crate tar;
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+use self::flate2::write::GzEncoder;
+use self::flate2::Compression;
+use self::tempfile::TempDir;
+
+use libcsearch::reader::OpenError;
+use libcsearch::searcher::{Match, SearchError, Searcher};
+use libcsearch::ContentsOpener;
+
+use common::index_dir;
+
+#[test]
+fn test_searcher() {
+    let src = TempDir::new().unwrap();
+    let root = fs::canonicalize(src.path()).unwrap();
+    let out = TempDir::new().unwrap();
+    let index = out.path().join("index");
+    index_dir(
+        &root,
+        &index,
+        &[
+            ("a.rs", "fn main() {\n    Hello();\n}\n"),
+            ("b.txt", "say hello\r\nbye\n"),
+            ("c.rs", "nothing here\n"),
+        ],
+    );
+
+    let searcher = Searcher::open(&index).unwrap();
+    let mut candidates = searcher.candidates("Hello").unwrap();
+    candidates.sort();
+    assert_eq!(candidates, vec![root.join("a.rs")]);
+    let matches = searcher.search("Hello").unwrap().collect::<Vec<_>>();
+    assert_eq!(
+        matches,
+        vec![Match {
+            path: root.join("a.rs"),
+            line_number: 2,
+            line: "    Hello();".to_string(),
+        }]
+    );
+
+    let searcher = Searcher::builder().ignore_case(true).open(&index).unwrap();
+    let mut matches = searcher.search("hello").unwrap().collect::<Vec<_>>();
+    matches.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(matches.len(), 2);
+    assert_eq!(matches[1].path, root.join("b.txt"));
+    assert_eq!(matches[1].line_number, 1);
+    assert_eq!(matches[1].line, "say hello");
+
+    let searcher = Searcher::builder()
+        .ignore_case(true)
+        .path_filter(r"\.txt$")
+        .open(&index)
+        .unwrap();
+    let matches = searcher.search("hello").unwrap().collect::<Vec<_>>();
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].path, root.join("b.txt"));
+
+    let mut matches = searcher.search("hello|bye").unwrap();
+    assert_eq!(matches.stats().candidates, 1);
+    assert_eq!(matches.by_ref().count(), 2);
+    let stats = matches.stats();
+    assert_eq!(
+        (
+            stats.files_scanned,
+            stats.files_matched,
+            stats.total_matches
+        ),
+        (1, 1, 2)
+    );
+    assert!(stats
+        .to_string()
+        .starts_with("candidates=1 scanned=1 matched_files=1 matches=2 elapsed="));
+
+    match searcher.search("(") {
+        Err(SearchError::Regex(_)) => {}
+        _ => panic!("expected a regex error"),
+    }
+    match Searcher::open(out.path().join("missing")) {
+        Err(SearchError::Open(OpenError::NotFound)) => {}
+        _ => panic!("expected a missing index"),
+    }
+}
+
+#[test]
+fn test_contents_opener() {
+    let src = TempDir::new().unwrap();
+    let root = fs::canonicalize(src.path()).unwrap();
+    let archive = root.join("vendor.tgz");
+    let mut b = tar::Builder::new(GzEncoder::new(
+        File::create(&archive).unwrap(),
+        Compression::default(),
+    ));
+    for &(name, contents) in &[("./a.c", "alpha\n"), ("b.c", "beta\n"), ("c.c", "gamma\n")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        b.append_data(&mut header, name, contents.as_bytes())
+            .unwrap();
+    }
+    b.into_inner().unwrap().finish().unwrap();
+    fs::write(root.join("plain.txt"), "plain\n").unwrap();
+
+    let name = |entry: &str| format!("{}!{}", archive.display(), entry);
+    let plain = root.join("plain.txt").to_string_lossy().into_owned();
+    let names = vec![name("a.c"), name("b.c"), plain.clone()];
+    let opener = ContentsOpener::new(&names);
+    let read = |name: &str| -> io::Result<String> {
+        let mut contents = String::new();
+        opener.open(name)?.read_to_string(&mut contents)?;
+        Ok(contents)
+    };
+    assert_eq!(read(&name("a.c")).unwrap(), "alpha\n");
+    // each file in an archive is opened once
+    let err = read(&name("a.c")).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    // the other file was read in the same pass, so the archive isn't read
+    // again for it
+    fs::write(&archive, "not an archive any more").unwrap();
+    assert_eq!(read(&name("b.c")).unwrap(), "beta\n");
+    assert!(read(&name("c.c")).is_err());
+    assert_eq!(read(&plain).unwrap(), "plain\n");
+}
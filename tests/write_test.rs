@@ -5,15 +5,22 @@ extern crate libcsearch;
 
 mod common;
 
-use std::collections::BTreeMap;
-use std::io::Read;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::num::Wrapping;
 use std::ops::DerefMut;
-use std::u32;
+use std::path::PathBuf;
 
-use self::tempfile::NamedTempFile;
+use self::tempfile::{NamedTempFile, TempDir};
 
-use common::build_flush_index;
+use libcindex::writer::{
+    FileStats, FlushStage, IndexError, IndexErrorKind, IndexWriter, IndexWriterBuilder,
+};
+use libcsearch::reader::{IndexReader, PostReader};
+
+use common::{build_flush_index, build_index, indexed_names, tri};
 
 fn trivial_files() -> BTreeMap<&'static str, &'static str> {
     let mut d = BTreeMap::new();
@@ -29,7 +36,7 @@ fn trivial_files() -> BTreeMap<&'static str, &'static str> {
 fn trivial_index() -> Vec<u8> {
     let mut s = Vec::<u8>::new();
     // header
-    s.extend_from_slice("csearch index 1\n".as_bytes());
+    s.extend_from_slice("csearch index 2\n".as_bytes());
 
     // list of paths
     s.extend_from_slice("\x00".as_bytes());
@@ -126,6 +133,8 @@ fn trivial_index() -> Vec<u8> {
     s.extend_from_slice(&u32_to_vec(16 + 1 + 38));
     s.extend_from_slice(&u32_to_vec(16 + 1 + 38 + 62));
     s.extend_from_slice(&u32_to_vec(16 + 1 + 38 + 62 + 28));
+    let checksum = crc32(&s);
+    s.extend_from_slice(&u32_to_vec(checksum));
 
     s.extend_from_slice("\ncsearch trailr\n".as_bytes());
 
@@ -149,6 +158,22 @@ fn file_list(list: Vec<u32>) -> Vec<u8> {
     buf
 }
 
+/// Bitwise crc32 (IEEE), to check the checksum written by the index writer
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
 fn u32_to_vec(value: u32) -> Vec<u8> {
     vec![
         (value >> 24) as u8,
@@ -193,3 +218,399 @@ fn test_write(do_flush: bool) {
         );
     }
 }
+
+#[test]
+fn test_builder_limits() {
+    let dir = TempDir::new().unwrap();
+    let out = dir.path().join("index");
+    for &ratio in &[-0.1, 1.5, f64::NAN] {
+        let err = IndexWriterBuilder::new()
+            .max_utf8_invalid(ratio)
+            .build(&out)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    assert!(!out.exists());
+
+    let mut ix = IndexWriterBuilder::new()
+        .max_file_len(16)
+        .max_line_len(4)
+        .build(&out)
+        .unwrap();
+    let err = ix
+        .add("long", Cursor::new(b"0123456789\n0123456789"), 21)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::FileTooLong);
+    let err = ix
+        .add("line", Cursor::new(b"0123456789\n"), 11)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::LineTooLong);
+    ix.add("ok", Cursor::new(b"ab\ncd\n"), 6).unwrap();
+}
+
+#[test]
+fn test_binary_sniff() {
+    let dir = TempDir::new().unwrap();
+    let out = dir.path().join("index");
+    let elf = b"\x7fELF\x02\x01\x01\x00 some text after the header\n";
+    let mut late_nul = b"x".repeat(32);
+    late_nul.extend_from_slice(b"\n\x00");
+
+    let mut ix = IndexWriterBuilder::new().build(&out).unwrap();
+    let err = ix
+        .add("elf", Cursor::new(&elf[..]), elf.len() as u64)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::BinaryFile);
+    let err = ix
+        .add("nul first", Cursor::new(b"\x00abc\n"), 5)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::BinaryFile);
+    ix.add("text", Cursor::new(b"abc\n"), 4).unwrap();
+    ix.flush().unwrap();
+    assert_eq!(indexed_names(&out), vec!["text"]);
+
+    // only the start of the file is sniffed
+    let mut ix = IndexWriterBuilder::new()
+        .binary_sniff_len(16)
+        .build(&out)
+        .unwrap();
+    let err = ix
+        .add("late", Cursor::new(&late_nul[..]), late_nul.len() as u64)
+        .err()
+        .map(|e| e.kind());
+    assert_ne!(err, Some(IndexErrorKind::BinaryFile));
+}
+
+#[test]
+fn test_file_stats() {
+    let dir = TempDir::new().unwrap();
+    let out = dir.path().join("index");
+    let mut ix = IndexWriterBuilder::new().dedup(true).build(&out).unwrap();
+    // "\nab", "abc", "bcd", "cd\n" and "d\na", then "\nab" and "abc" again
+    let stats = ix.add("a", Cursor::new(b"\nabcd\nabc"), 9).unwrap();
+    assert_eq!(
+        stats,
+        FileStats {
+            trigram_count: 5,
+            byte_len: 9,
+        }
+    );
+    // nothing new is indexed for a copy
+    let stats = ix.add("b", Cursor::new(b"\nabcd\nabc"), 9).unwrap();
+    assert_eq!(stats, FileStats::default());
+    ix.flush().unwrap();
+}
+
+#[test]
+fn test_max_index_len() {
+    let dir = TempDir::new().unwrap();
+    let out = dir.path().join("index");
+    let mut ix = IndexWriterBuilder::new().build(&out).unwrap();
+    for (name, contents) in trivial_files() {
+        ix.add(name, Cursor::new(contents), contents.len() as u64)
+            .unwrap();
+    }
+    let estimate = ix.size_estimate();
+    ix.flush().unwrap();
+    let len = fs::metadata(&out).unwrap().len();
+    assert!(
+        len <= estimate && estimate < 2 * len,
+        "{} {}",
+        len,
+        estimate
+    );
+
+    // one byte short, some files don't fit, and what's left does
+    let out = dir.path().join("limited");
+    let mut ix = IndexWriterBuilder::new()
+        .max_index_len(len - 1)
+        .build(&out)
+        .unwrap();
+    let mut full = 0;
+    for (name, contents) in trivial_files() {
+        match ix.add(name, Cursor::new(contents), contents.len() as u64) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == IndexErrorKind::IndexFull => full += 1,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    assert!(full > 0);
+    ix.flush().unwrap();
+    assert!(fs::metadata(&out).unwrap().len() < len);
+}
+
+#[test]
+fn test_carry_forward() {
+    let dir = TempDir::new().unwrap();
+    let old = dir.path().join("old");
+    let mut files = BTreeMap::new();
+    files.insert("/src/a", "apple\n");
+    files.insert("/src/b", "banana\n");
+    files.insert("/src/c", "cherry apple\n");
+    build_index(&old, vec![PathBuf::from("/src")], files);
+    let reader = IndexReader::open(&old).unwrap();
+
+    // b changes, and the others are copied as they are
+    let new = dir.path().join("new");
+    let mut ix = IndexWriter::new(&new).unwrap();
+    let skip = vec![PathBuf::from("/src/b")]
+        .into_iter()
+        .collect::<HashSet<_>>();
+    assert_eq!(ix.carry_forward(&reader, &skip).unwrap(), 2);
+    ix.add("/src/b", Cursor::new("blueberry\n"), 10).unwrap();
+    ix.flush().unwrap();
+
+    let reader = IndexReader::open(&new).unwrap();
+    assert_eq!(reader.indexed_paths(), vec!["/src"]);
+    assert_eq!(indexed_names(&new), vec!["/src/a", "/src/c", "/src/b"]);
+    let names = |t| {
+        PostReader::list(&reader, t, &None)
+            .unwrap()
+            .into_iter()
+            .map(|id| reader.name(id))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(tri('a', 'p', 'p')), vec!["/src/a", "/src/c"]);
+    assert_eq!(names(tri('c', 'h', 'e')), vec!["/src/c"]);
+    assert_eq!(names(tri('b', 'l', 'u')), vec!["/src/b"]);
+    assert!(names(tri('b', 'a', 'n')).is_empty());
+    reader.verify().unwrap();
+}
+
+#[test]
+fn test_carry_forward_max_index_len() {
+    let dir = TempDir::new().unwrap();
+    let old = dir.path().join("old");
+    let mut ix = IndexWriter::new(&old).unwrap();
+    for (name, contents) in trivial_files() {
+        ix.add(name, Cursor::new(contents), contents.len() as u64)
+            .unwrap();
+    }
+    let estimate = ix.size_estimate();
+    ix.flush().unwrap();
+    let reader = IndexReader::open(&old).unwrap();
+    let skip = HashSet::new();
+
+    // the copied files only just fit, and leave no room for another
+    let new = dir.path().join("new");
+    let mut ix = IndexWriterBuilder::new()
+        .max_index_len(estimate)
+        .build(&new)
+        .unwrap();
+    ix.carry_forward(&reader, &skip).unwrap();
+    assert_eq!(ix.size_estimate(), estimate);
+    let err = ix.add("/new", Cursor::new("more text"), 9).err().unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::IndexFull);
+    ix.flush().unwrap();
+    assert_eq!(indexed_names(&new), indexed_names(&old));
+
+    // one byte short, they don't
+    let mut ix = IndexWriterBuilder::new()
+        .max_index_len(estimate - 1)
+        .build(dir.path().join("short"))
+        .unwrap();
+    let err = ix.carry_forward(&reader, &skip).err().unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::IndexFull);
+}
+
+/// An in-memory index file that fails every write reaching `fail_at`
+struct FailingFile {
+    data: Cursor<Vec<u8>>,
+    fail_at: u64,
+}
+
+impl Read for FailingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Write for FailingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.data.position() + buf.len() as u64 > self.fail_at {
+            return Err(io::Error::other("disk full"));
+        }
+        self.data.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FailingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+#[test]
+fn test_flush_stage() {
+    // where each section of trivial_index() starts
+    let len = trivial_index().len() as u64;
+    let sections = [
+        (0, FlushStage::Header),
+        (16, FlushStage::Paths),
+        (16 + 1, FlushStage::Names),
+        (16 + 1 + 38, FlushStage::PostingLists),
+        (16 + 1 + 38 + 62, FlushStage::NameIndex),
+        (16 + 1 + 38 + 62 + 28, FlushStage::PostingIndex),
+        (len - 16 - 4 - 5 * 4, FlushStage::Trailer),
+    ];
+    for &(fail_at, stage) in &sections {
+        let out = FailingFile {
+            data: Cursor::new(Vec::new()),
+            fail_at,
+        };
+        let mut ix = IndexWriterBuilder::new().build_to(out).unwrap();
+        for (name, contents) in trivial_files() {
+            ix.add(name, Cursor::new(contents), contents.len() as u64)
+                .unwrap();
+        }
+        let err = ix.flush().err().unwrap();
+        assert_eq!(err.kind(), IndexErrorKind::FlushFailed(stage));
+        assert!(err.to_string().ends_with("disk full"), "{}", err);
+        let source = err.source().unwrap().downcast_ref::<io::Error>();
+        assert_eq!(source.unwrap().to_string(), "disk full");
+    }
+
+    // and with nowhere to fail, the index is written in full
+    let out = FailingFile {
+        data: Cursor::new(Vec::new()),
+        fail_at: len,
+    };
+    let mut ix = IndexWriterBuilder::new().build_to(out).unwrap();
+    for (name, contents) in trivial_files() {
+        ix.add(name, Cursor::new(contents), contents.len() as u64)
+            .unwrap();
+    }
+    ix.flush().unwrap();
+}
+
+#[test]
+fn test_error_source() {
+    fn open_missing() -> Result<(), Box<dyn Error>> {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        Err(IndexError::from(err))?;
+        Ok(())
+    }
+    let err = open_missing().err().unwrap();
+    assert_eq!(err.to_string(), "no such file");
+    let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+    assert_eq!(source.kind(), io::ErrorKind::NotFound);
+
+    // errors about a file's contents have no cause
+    let err = IndexError::new(IndexErrorKind::LineTooLong, "line 3");
+    assert_eq!(err.to_string(), "line too long");
+    assert!(err.source().is_none());
+}
+
+/// Returns `n` files of pseudo-random text over 16 letters, so between
+/// them they hold nearly every one of the 4096 trigrams of those letters
+fn letter_soup(n: usize) -> Vec<(String, Vec<u8>)> {
+    let mut seed = Wrapping(12345u32);
+    (0..n)
+        .map(|i| {
+            let data = (0..2000)
+                .map(|_| {
+                    seed = seed * Wrapping(1_103_515_245) + Wrapping(12345);
+                    b'a' + (seed.0 >> 16) as u8 % 16
+                })
+                .collect();
+            (format!("/soup/{:04}", i), data)
+        })
+        .collect()
+}
+
+fn build_soup(out: &std::path::Path, compress: bool) {
+    let mut ix = IndexWriterBuilder::new()
+        .compress(compress)
+        .build(out)
+        .unwrap();
+    for (i, (name, data)) in letter_soup(300).into_iter().enumerate() {
+        ix.add(&name, Cursor::new(&data), data.len() as u64)
+            .unwrap();
+        if i == 150 {
+            ix.flush_post().unwrap();
+        }
+    }
+    ix.flush().unwrap();
+}
+
+#[test]
+fn test_compress() {
+    let plain = NamedTempFile::new().unwrap();
+    let compressed = NamedTempFile::new().unwrap();
+    build_soup(plain.path(), false);
+    build_soup(compressed.path(), true);
+
+    let plain = IndexReader::open(plain.path()).unwrap();
+    let compressed = IndexReader::open(compressed.path()).unwrap();
+    assert!(!plain.is_compressed());
+    assert_eq!(plain.format_version(), 2);
+    assert!(compressed.is_compressed());
+    assert_eq!(compressed.format_version(), 3);
+    assert!(compressed.len() < plain.len() / 2);
+    assert_eq!(compressed.num_trigrams(), plain.num_trigrams());
+    compressed.verify().unwrap();
+
+    // the lists span many blocks, and each reads back the same
+    for x in b'a'..b'a' + 16 {
+        for y in b'a'..b'a' + 16 {
+            for z in b'a'..b'a' + 16 {
+                assert_eq!(
+                    compressed.posting_list([x, y, z]).unwrap(),
+                    plain.posting_list([x, y, z]).unwrap()
+                );
+            }
+        }
+    }
+    assert_eq!(compressed.posting_list(*b"zzz").unwrap(), vec![]);
+}
+
+#[test]
+fn test_trigrams() {
+    // the same trigrams the writer records for the file
+    let contents = "Google Code Search\nnaïve café\r\n\tend";
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(f.path()).unwrap();
+    ix.add("/f", Cursor::new(contents), contents.len() as u64)
+        .unwrap();
+    ix.flush().unwrap();
+    let ix = IndexReader::open(f.path()).unwrap();
+    let t = libcindex::trigrams(contents.as_bytes()).unwrap();
+    assert_eq!(t.len(), ix.num_trigrams());
+    for each in &t {
+        assert_eq!(ix.posting_list(*each).unwrap(), vec![0], "{:?}", each);
+    }
+
+    assert!(libcindex::trigrams(b"ab").unwrap().is_empty());
+    // as for files the writer reads, contents that are empty or only
+    // whitespace are skipped
+    for blank in [&b""[..], b" \t\r\n  \n"] {
+        let err = libcindex::trigrams(blank).err().unwrap();
+        assert_eq!(err.kind(), IndexErrorKind::NoTrigrams);
+    }
+    assert_eq!(
+        libcindex::trigrams(b"abc")
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec![*b"abc"]
+    );
+    // UTF-16 is transcoded first
+    let utf16 = [0xff, 0xfe, b'a', 0, b'b', 0, b'c', 0];
+    assert_eq!(
+        libcindex::trigrams(&utf16)
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec![*b"abc"]
+    );
+    let err = libcindex::trigrams(b"abc\0def").err().unwrap();
+    assert_eq!(err.kind(), IndexErrorKind::BinaryFile);
+}